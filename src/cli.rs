@@ -0,0 +1,330 @@
+// Command-line parsing for the renderer binary
+
+use crate::raytracer::tonemap::ToneMap;
+
+pub const USAGE: &str = "\
+Usage: rust_raytracer <COMMAND> [OPTIONS]
+
+Commands:
+  render    Render a scene file to an image
+  help      Print this message
+
+Run `rust_raytracer render --help` for the render options.";
+
+pub const RENDER_USAGE: &str = "\
+Usage: rust_raytracer render [OPTIONS]
+
+Options:
+  -s, --scene <FILE>      Scene file or glTF model to render [default: scenes/default.toml]
+  -o, --output <FILE>     Where to save the image [default: render.png]
+  -f, --format <FORMAT>   png, jpeg, bmp, tiff, ppm, or exr, pfm and hdr for
+                          unclamped linear output [default: from the output extension]
+  -W, --width <PIXELS>    Image width, overrides the scene
+  -H, --height <PIXELS>   Image height, overrides the scene
+  -n, --samples <N>       Samples per pixel, overrides the scene
+  -d, --max-depth <N>     Bounces per path, overrides the scene
+      --seed <N>          Seed for the sampling RNGs, overrides the scene
+  -t, --threads <N>       Worker threads, 0 uses every core, overrides the scene
+      --pass-samples <N>  Samples added to every pixel per pass [default: 4]
+      --checkpoint <FILE> Save progress to FILE while rendering
+      --checkpoint-interval <SECONDS>
+                          Time between checkpoints [default: 60]
+      --resume            Continue from --checkpoint. Raise --samples to add
+                          more samples to a render that already finished
+      --tonemap <OP>      clamp, reinhard, reinhard-extended, aces or agx [default: clamp]
+      --exposure <STOPS>  Brightens or darkens before tone mapping [default: 0]
+      --white <L>         Luminance reinhard-extended maps to white [default: brightest pixel]
+      --no-dither         Quantize to 8 bits without dithering
+  -h, --help              Print this message";
+
+// Exit codes: 1 when rendering fails, 2 for bad arguments
+pub const EXIT_FAILURE: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+
+pub enum Command {
+    Render(RenderArgs),
+    Help(&'static str)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Png,
+    Jpeg,
+    Bmp,
+    Tiff,
+    Ppm,
+    Exr,
+    Pfm,
+    Hdr
+}
+
+impl OutputFormat {
+    pub fn from_name(name: &str) -> Option<OutputFormat> {
+        match name.to_ascii_lowercase().as_str() {
+            "png" => Some(OutputFormat::Png),
+            "jpg" | "jpeg" => Some(OutputFormat::Jpeg),
+            "bmp" => Some(OutputFormat::Bmp),
+            "tif" | "tiff" => Some(OutputFormat::Tiff),
+            "ppm" => Some(OutputFormat::Ppm),
+            "exr" => Some(OutputFormat::Exr),
+            "pfm" => Some(OutputFormat::Pfm),
+            "hdr" => Some(OutputFormat::Hdr),
+            _ => None
+        }
+    }
+    // Float formats get the film as is instead of 8-bit color
+    pub fn is_hdr(&self) -> bool {
+        matches!(self, OutputFormat::Exr | OutputFormat::Pfm | OutputFormat::Hdr)
+    }
+    // Guesses the format from a file name's extension
+    pub fn from_path(path: &str) -> Option<OutputFormat> {
+        std::path::Path::new(path).extension()
+            .and_then(|e| e.to_str())
+            .and_then(OutputFormat::from_name)
+    }
+}
+
+// Options for the render subcommand. Anything left as None comes from the
+// scene file
+pub struct RenderArgs {
+    pub scene: String,
+    pub output: String,
+    pub format: OutputFormat,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub samples: Option<u16>,
+    pub max_depth: Option<u32>,
+    pub seed: Option<u64>,
+    pub threads: Option<usize>,
+    pub pass_samples: u32,
+    pub checkpoint: Option<String>,
+    pub checkpoint_interval: u64,
+    pub resume: bool,
+    // Only used for 8-bit output
+    pub tonemap: ToneMap,
+    pub exposure: f32,
+    pub white: Option<f32>,
+    pub dither: bool
+}
+
+// Runs the command line after the program name and returns the exit code.
+// render does the actual rendering, which keeps this testable
+pub fn run<I: Iterator<Item = String>>(args: I, render: impl FnOnce(&RenderArgs) -> Result<(), String>) -> i32 {
+    let command = match parse_args(args) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("error: {}\nRun `rust_raytracer help` for usage", e);
+            return EXIT_USAGE;
+        }
+    };
+    match command {
+        Command::Help(text) => {
+            println!("{}", text);
+            0
+        },
+        Command::Render(args) => match render(&args) {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                EXIT_FAILURE
+            }
+        }
+    }
+}
+
+// Parses the arguments after the program name
+pub fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Command, String> {
+    match args.next().as_deref() {
+        Some("render") => parse_render(args),
+        Some("help") | Some("-h") | Some("--help") => Ok(Command::Help(USAGE)),
+        Some(other) => Err(format!("unknown command `{}`", other)),
+        None => Err("no command given".to_string())
+    }
+}
+
+fn parse_render<I: Iterator<Item = String>>(mut args: I) -> Result<Command, String> {
+    let mut parsed = RenderArgs {
+        scene: "scenes/default.toml".to_string(),
+        output: "render.png".to_string(),
+        format: OutputFormat::Png,
+        width: None,
+        height: None,
+        samples: None,
+        max_depth: None,
+        seed: None,
+        threads: None,
+        pass_samples: 4,
+        checkpoint: None,
+        checkpoint_interval: 60,
+        resume: false,
+        tonemap: ToneMap::Clamp,
+        exposure: 0.0,
+        white: None,
+        dither: true
+    };
+    let mut format = None;
+
+    while let Some(arg) = args.next() {
+        // Accept both `--flag value` and `--flag=value`
+        let (flag, inline) = match arg.find('=') {
+            Some(i) if arg.starts_with("--") => (arg[..i].to_string(), Some(arg[i + 1..].to_string())),
+            _ => (arg.clone(), None)
+        };
+        if flag == "-h" || flag == "--help" {
+            return Ok(Command::Help(RENDER_USAGE));
+        }
+        if flag == "--no-dither" {
+            parsed.dither = false;
+            continue;
+        }
+        if flag == "--resume" {
+            parsed.resume = true;
+            continue;
+        }
+        let mut value = || -> Result<String, String> {
+            match inline.clone().or_else(|| args.next()) {
+                Some(v) => Ok(v),
+                None => Err(format!("{} needs a value", flag))
+            }
+        };
+        match flag.as_str() {
+            "-s" | "--scene" => parsed.scene = value()?,
+            "-o" | "--output" => parsed.output = value()?,
+            "-f" | "--format" => {
+                let name = value()?;
+                format = Some(OutputFormat::from_name(&name)
+                    .ok_or_else(|| format!("unknown output format `{}`, expected png, jpeg, bmp, tiff, ppm, exr, pfm or hdr", name))?);
+            },
+            "-W" | "--width" => parsed.width = Some(positive(&flag, &value()?)?),
+            "-H" | "--height" => parsed.height = Some(positive(&flag, &value()?)?),
+            "-n" | "--samples" => parsed.samples = Some(positive(&flag, &value()?)?),
+            "-d" | "--max-depth" => parsed.max_depth = Some(positive(&flag, &value()?)?),
+            "--seed" => parsed.seed = Some(number(&flag, &value()?)?),
+            "-t" | "--threads" => parsed.threads = Some(number(&flag, &value()?)?),
+            "--pass-samples" => parsed.pass_samples = positive(&flag, &value()?)?,
+            "--checkpoint" => parsed.checkpoint = Some(value()?),
+            "--checkpoint-interval" => parsed.checkpoint_interval = number(&flag, &value()?)?,
+            "--tonemap" => {
+                let name = value()?;
+                parsed.tonemap = ToneMap::from_name(&name).ok_or_else(|| format!(
+                    "unknown tone mapping operator `{}`, expected clamp, reinhard, reinhard-extended, aces or agx", name))?;
+            },
+            "--exposure" => parsed.exposure = decimal(&flag, &value()?)?,
+            "--white" => {
+                let white: f32 = decimal(&flag, &value()?)?;
+                if white <= 0.0 {
+                    return Err(format!("{} must be positive", flag));
+                }
+                parsed.white = Some(white);
+            },
+            _ => return Err(format!("unknown option `{}`", arg))
+        }
+    }
+
+    if parsed.resume && parsed.checkpoint.is_none() {
+        return Err("--resume needs --checkpoint to say what to resume from".to_string());
+    }
+    parsed.format = match format {
+        Some(f) => f,
+        None => OutputFormat::from_path(&parsed.output).ok_or_else(|| format!(
+            "can't tell the format of `{}` from its extension, pass --format", parsed.output))?
+    };
+    Ok(Command::Render(parsed))
+}
+
+fn number<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("{} expects a whole number, got `{}`", flag, value))
+}
+
+fn decimal(flag: &str, value: &str) -> Result<f32, String> {
+    match value.parse::<f32>() {
+        Ok(v) if v.is_finite() => Ok(v),
+        _ => Err(format!("{} expects a number, got `{}`", flag, value))
+    }
+}
+
+fn positive<T: std::str::FromStr + PartialEq + Default>(flag: &str, value: &str) -> Result<T, String> {
+    let n: T = number(flag, value)?;
+    if n == T::default() {
+        return Err(format!("{} must be at least 1", flag));
+    }
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> impl Iterator<Item = String> + '_ {
+        line.split_whitespace().map(|a| a.to_string())
+    }
+
+    fn render_args(line: &str) -> RenderArgs {
+        match parse_args(args(line)) {
+            Ok(Command::Render(parsed)) => parsed,
+            Ok(Command::Help(_)) => panic!("`{}` asked for help", line),
+            Err(e) => panic!("`{}` was rejected: {}", line, e)
+        }
+    }
+
+    fn error(line: &str) -> String {
+        match parse_args(args(line)) {
+            Ok(_) => panic!("`{}` should have been rejected", line),
+            Err(e) => e
+        }
+    }
+
+    #[test]
+    fn long_flags_take_values_either_way() {
+        let parsed = render_args("render --width=640 --height 480 --samples=16 --seed 7 --output=out.exr \
+                                  --tonemap aces --exposure=-1.5 --white=4 --checkpoint=c.rtck --resume --no-dither");
+        assert_eq!((parsed.width, parsed.height, parsed.samples, parsed.seed), (Some(640), Some(480), Some(16), Some(7)));
+        assert_eq!(parsed.output, "out.exr");
+        assert_eq!(parsed.format, OutputFormat::Exr);
+        assert_eq!(parsed.tonemap, ToneMap::Aces);
+        assert_eq!((parsed.exposure, parsed.white), (-1.5, Some(4.0)));
+        assert_eq!(parsed.checkpoint.as_deref(), Some("c.rtck"));
+        assert!(parsed.resume && !parsed.dither);
+    }
+
+    #[test]
+    fn short_flags() {
+        let parsed = render_args("render -s scenes/glass.toml -o out.png -f hdr -W 32 -H 16 -n 2 -d 5 -t 3");
+        assert_eq!(parsed.scene, "scenes/glass.toml");
+        // --format wins over the extension
+        assert_eq!(parsed.format, OutputFormat::Hdr);
+        assert_eq!((parsed.width, parsed.height, parsed.samples, parsed.max_depth, parsed.threads), (Some(32), Some(16), Some(2), Some(5), Some(3)));
+        // Everything else keeps its default
+        let parsed = render_args("render");
+        assert_eq!((parsed.scene.as_str(), parsed.output.as_str(), parsed.format), ("scenes/default.toml", "render.png", OutputFormat::Png));
+        assert_eq!((parsed.pass_samples, parsed.checkpoint_interval, parsed.width), (4, 60, None));
+        assert!(matches!(parse_args(args("render -h")), Ok(Command::Help(RENDER_USAGE))));
+    }
+
+    #[test]
+    fn bad_arguments_say_what_is_wrong() {
+        assert_eq!(error(""), "no command given");
+        assert_eq!(error("draw"), "unknown command `draw`");
+        assert_eq!(error("render --colour red"), "unknown option `--colour`");
+        assert_eq!(error("render --width"), "--width needs a value");
+        assert_eq!(error("render -W 0"), "-W must be at least 1");
+        assert_eq!(error("render --samples=lots"), "--samples expects a whole number, got `lots`");
+        assert_eq!(error("render --exposure=nan"), "--exposure expects a number, got `nan`");
+        assert_eq!(error("render --white 0"), "--white must be positive");
+        assert!(error("render --resume").contains("needs --checkpoint"));
+        assert!(error("render -o out.xyz").contains("pass --format"));
+        assert!(error("render --tonemap filmic").contains("unknown tone mapping operator `filmic`"));
+    }
+
+    #[test]
+    fn exit_codes() {
+        assert_eq!(run(args("render --bogus"), |_| panic!("bad arguments shouldn't render")), EXIT_USAGE);
+        assert_eq!(run(args(""), |_| panic!("no command shouldn't render")), EXIT_USAGE);
+        assert_eq!(run(args("help"), |_| panic!("help shouldn't render")), 0);
+        assert_eq!(run(args("render -o out.ppm"), |_| Err("disk full".to_string())), EXIT_FAILURE);
+        assert_eq!(run(args("render -o out.ppm"), |parsed| {
+            assert_eq!(parsed.format, OutputFormat::Ppm);
+            Ok(())
+        }), 0);
+    }
+}
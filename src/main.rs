@@ -1,73 +1,164 @@
+
 extern crate image;
-use image::{Rgb, ImageBuffer, RgbImage};
+use image::{Rgb, ImageBuffer, RgbImage, ImageFormat};
 
-extern crate rand;
-use rand::Rng;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::process::exit;
+use std::time::{Duration, Instant};
 
+mod cli;
 mod raytracer;
-use raytracer::math::vec3::*;
-use raytracer::camera::*;
-use raytracer::object::*;
-
-const FILENAME: &'static str = "render.png"; // Output filename
-const DIMS: (u32, u32) = (2000, 1000);         // Image dimensions
-const AA_ROUNDS: u16 = 100;                  // Samples per pixel
+use cli::*;
+use raytracer::render::*;
+use raytracer::bvh::*;
+use raytracer::ray::*;
+use raytracer::scene::*;
+use raytracer::film::*;
+use raytracer::progressive::*;
+use raytracer::tonemap::*;
+use raytracer::formats::{exr::*, pfm::*, hdr::*, ppm::*, Pixel};
 
 fn main() {
-    let mut img: RgbImage = ImageBuffer::new(DIMS.0, DIMS.1);
-    render(&mut img);
-    match img.save(FILENAME) {
-        Ok(_) => println!("Saved {}x{} output as {}", DIMS.0, DIMS.1, FILENAME),
-        Err(e) => println!("Failed to save {}: {}", FILENAME, e)
+    let code = run(std::env::args().skip(1), run_render);
+    if code != 0 {
+        exit(code);
     }
 }
 
-// Handles pretty much everything related to generating the image
-fn render(img: &mut RgbImage) {
-    // lets us create rays
-    let from = Vec3::new(13.0, 2.0, 3.0);
-    let at   = Vec3::new(0.0, 0.0, 0.0);
-    let cam = Camera::new(
-            from,
-            at,
-            Vec3::new(0.0, 1.0, 0.0),
-            20.0, // FOV
-            DIMS.0 as f32 / DIMS.1 as f32, // Aspect ratio
-            0.1, // Aperture
-            10.0 // Focal length
-        );
+// Loads the scene, applies the command-line overrides, renders and saves
+fn run_render(args: &RenderArgs) -> Result<(), String> {
+    // Catch a bad output path before spending minutes on the render
+    let dir = std::path::Path::new(&args.output).parent().filter(|d| !d.as_os_str().is_empty());
+    if let Some(dir) = dir {
+        if !dir.is_dir() {
+            return Err(format!("can't save to {}: {} is not a directory", args.output, dir.display()));
+        }
+    }
+    let mut scene = load_scene(&args.scene).map_err(|e| format!("failed to load {}: {}", args.scene, e))?;
+    let settings = &mut scene.settings;
+    settings.width = args.width.unwrap_or(settings.width);
+    settings.height = args.height.unwrap_or(settings.height);
+    settings.samples = args.samples.unwrap_or(settings.samples);
+    settings.max_depth = args.max_depth.unwrap_or(settings.max_depth);
+    settings.seed = args.seed.unwrap_or(settings.seed);
+    settings.threads = args.threads.unwrap_or(settings.threads);
 
-    // Defining the materials used in the scene
-    let world = World::random();
-    // Random numbers for antialiasing
-    let mut rng = rand::thread_rng();
+    let (width, height) = (scene.settings.width, scene.settings.height);
+    let film = render_film(scene, args)?;
+    let mut mapper = ToneMapper::new(args.tonemap, args.exposure);
+    mapper.dither = args.dither;
+    match args.white {
+        Some(white) => mapper.white_point = white,
+        None => mapper.white_from_film(&film)
+    }
+    save(&film, &args.output, args.format, &mapper).map_err(|e| format!("failed to save {}: {}", args.output, e))?;
+    println!("Saved {}x{} output as {}", width, height, args.output);
+    Ok(())
+}
 
-    for x in 0..DIMS.0 {
-        for y in 0..DIMS.1 {
-            // Rendering logic goes here
-            // Base color
-            let mut color = Vec3::all(0.0);
-            // Loops for antialiasing
-            for _ in 0..AA_ROUNDS {
-                let u = (x as f32 + rng.gen::<f32>()) / DIMS.0 as f32;
-                let v = (y as f32 + rng.gen::<f32>()) / DIMS.1 as f32;
-                let ray = cam.get_ray(u, v);
-                let tmp_color = ray.get_color(&world, 0);
-                color.add_by_vec_eq(&tmp_color);
+// Hands the scene off to the tile renderer a pass at a time, saving
+// checkpoints along the way when asked to
+fn render_film(scene: Scene, args: &RenderArgs) -> Result<Film, String> {
+    let settings = &scene.settings;
+    // Anything that changes what a sample looks like has to be part of the
+    // hash, the sample count doesn't since resuming can add more
+    let mut hash = scene.hash;
+    for n in &[settings.width, settings.height, settings.max_depth] {
+        hash = fnv1a(&n.to_le_bytes(), hash);
+    }
+    let mut acc = match (&args.checkpoint, args.resume) {
+        (Some(path), true) => {
+            let acc = Accumulator::resume(path, settings.width, settings.height, hash, args.seed)?;
+            println!("Resuming from {} with {} samples done", path, acc.samples_done());
+            acc
+        },
+        _ => Accumulator::new(settings.width, settings.height, settings.seed, hash)
+    };
+
+    let cam = scene.camera.build(settings.width as f32 / settings.height as f32);
+    // Compiling the objects into a BVH so rays don't test every one of them
+    // Lights are sampled directly, so they're picked out before the BVH
+    // takes the objects
+    let lights = scene.world.lights();
+    let world = Bvh::new(scene.world);
+    println!("Built BVH with {} nodes over {} objects", world.node_count(), world.object_count());
+    if !lights.is_empty() {
+        println!("Sampling {} lights", lights.len());
+    }
+    let view = SceneView {
+        world: &world,
+        lights: &lights,
+        environment: &scene.environment,
+        fog: scene.fog.as_ref()
+    };
+
+    let target = settings.samples as u32;
+    let interval = Duration::from_secs(args.checkpoint_interval);
+    let mut last_checkpoint = Instant::now();
+    let mut stats = TraversalStats::default();
+    while acc.samples_done() < target {
+        let samples = args.pass_samples.min(target - acc.samples_done());
+        stats.add_eq(&render_pass(&view, &cam, settings, samples, &mut acc));
+        println!("Pass {}: {}/{} samples", acc.passes, acc.samples_done(), target);
+        if let Some(path) = &args.checkpoint {
+            if last_checkpoint.elapsed() >= interval {
+                acc.save(path).map_err(|e| format!("failed to save checkpoint {}: {}", path, e))?;
+                last_checkpoint = Instant::now();
             }
-            // Average each sample
-            color.div_eq(AA_ROUNDS as f32);
-            // Write to pixel
-            let out = color_transform(&color);
-            img[(x, DIMS.1 - y - 1)] = Rgb([out.0, out.1, out.2]);
         }
     }
+    // Always leave a final checkpoint so the render can be continued later
+    if let Some(path) = &args.checkpoint {
+        acc.save(path).map_err(|e| format!("failed to save checkpoint {}: {}", path, e))?;
+    }
+    if stats.rays > 0 {
+        println!("Visited {:.2} BVH nodes and {:.2} objects per ray", stats.nodes_per_ray(), stats.primitives_per_ray());
+    }
+    Ok(acc.to_film())
 }
 
-fn color_transform(triad: &Vec3) -> (u8, u8, u8) {
-    (
-        (triad.x.sqrt() * 255.99) as u8,
-        (triad.y.sqrt() * 255.99) as u8,
-        (triad.z.sqrt() * 255.99) as u8
-    )
-}
\ No newline at end of file
+// Float formats get the linear film as is, everything else goes through
+// the tone mapper down to 8-bit sRGB
+fn save(film: &Film, path: &str, format: OutputFormat, mapper: &ToneMapper) -> Result<(), Box<dyn std::error::Error>> {
+    if format.is_hdr() {
+        let mut out = BufWriter::new(File::create(path)?);
+        match format {
+            OutputFormat::Exr => write_exr(film, &mut out)?,
+            OutputFormat::Pfm => write_pfm(film, &mut out)?,
+            _ => write_hdr(film, &mut out)?
+        }
+        out.flush()?;
+        return Ok(());
+    }
+
+    let (width, height) = film.get_dimensions();
+    if format == OutputFormat::Ppm {
+        let mut ppm = Ppm::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                let out = mapper.to_rgb8(film.get(x, y), x, y);
+                ppm.set(x, y, Pixel::new(out[0], out[1], out[2]))?;
+            }
+        }
+        let mut out = BufWriter::new(File::create(path)?);
+        ppm.write_p6(&mut out)?;
+        out.flush()?;
+        return Ok(());
+    }
+
+    let mut img: RgbImage = ImageBuffer::new(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            img[(x, y)] = Rgb(mapper.to_rgb8(film.get(x, y), x, y));
+        }
+    }
+    let format = match format {
+        OutputFormat::Jpeg => ImageFormat::JPEG,
+        OutputFormat::Bmp => ImageFormat::BMP,
+        OutputFormat::Tiff => ImageFormat::TIFF,
+        _ => ImageFormat::PNG
+    };
+    img.save_with_format(path, format)?;
+    Ok(())
+}
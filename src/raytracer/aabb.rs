@@ -0,0 +1,115 @@
+#![allow(dead_code)]
+
+use super::math::vec3::*;
+use super::ray::*;
+
+// Rounding bound for the slab test, see pbrt's gamma(3)
+const GAMMA_3: f32 = 3.0 * f32::EPSILON * 0.5 / (1.0 - 3.0 * f32::EPSILON * 0.5);
+
+// Axis-aligned bounding box, used by the BVH to skip whole groups of objects
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3
+}
+
+impl Aabb {
+    pub fn new(min: Vec3, max: Vec3) -> Aabb {
+        Aabb {
+            min,
+            max
+        }
+    }
+    // A box that contains nothing, growing it by anything gives that thing's box
+    pub fn empty() -> Aabb {
+        Aabb {
+            min: Vec3::all(f32::INFINITY),
+            max: Vec3::all(f32::NEG_INFINITY)
+        }
+    }
+    pub fn copy(&self) -> Aabb {
+        Aabb::new(self.min.copy(), self.max.copy())
+    }
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+    // Smallest box containing both boxes
+    pub fn surrounding(&self, other: &Aabb) -> Aabb {
+        let mut res = self.copy();
+        res.grow_eq(other);
+        res
+    }
+    pub fn grow_eq(&mut self, other: &Aabb) {
+        for a in 0..3 {
+            self.min[a] = self.min[a].min(other.min[a]);
+            self.max[a] = self.max[a].max(other.max[a]);
+        }
+    }
+    pub fn grow_by_point_eq(&mut self, p: &Vec3) {
+        for a in 0..3 {
+            self.min[a] = self.min[a].min(p[a]);
+            self.max[a] = self.max[a].max(p[a]);
+        }
+    }
+    // Widens the box slightly so hits computed with rounding error on the
+    // surface of an object never fall just outside of it
+    pub fn padded(&self) -> Aabb {
+        let mut res = self.copy();
+        for a in 0..3 {
+            let pad = 1e-5 * self.min[a].abs().max(self.max[a].abs()).max(1.0);
+            res.min[a] -= pad;
+            res.max[a] += pad;
+        }
+        res
+    }
+    pub fn centroid(&self) -> Vec3 {
+        self.min.add_by_vec(&self.max).mul(0.5)
+    }
+    pub fn extent(&self) -> Vec3 {
+        self.max.sub_by_vec(&self.min)
+    }
+    pub fn surface_area(&self) -> f32 {
+        if self.is_empty() {
+            return 0.0;
+        }
+        let d = self.extent();
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+    pub fn largest_axis(&self) -> usize {
+        let d = self.extent();
+        if d.x >= d.y && d.x >= d.z {
+            0
+        } else if d.y >= d.z {
+            1
+        } else {
+            2
+        }
+    }
+    // Slab test, inv_dir is 1 / r.direction computed once per ray
+    pub fn hit(&self, r: &Ray, inv_dir: &Vec3, t_min: f32, t_max: f32) -> bool {
+        self.clip(r, inv_dir, t_min, t_max).is_some()
+    }
+    // The part of t_min..t_max the ray spends inside the box, if any
+    pub fn clip(&self, r: &Ray, inv_dir: &Vec3, t_min: f32, t_max: f32) -> Option<(f32, f32)> {
+        let mut t0 = t_min;
+        let mut t1 = t_max;
+        for a in 0..3 {
+            let mut near = (self.min[a] - r.origin[a]) * inv_dir[a];
+            let mut far = (self.max[a] - r.origin[a]) * inv_dir[a];
+            if near > far {
+                std::mem::swap(&mut near, &mut far);
+            }
+            far *= 1.0 + 2.0 * GAMMA_3;
+            // Written so that a NaN from 0 * inf leaves the interval alone
+            if near > t0 {
+                t0 = near;
+            }
+            if far < t1 {
+                t1 = far;
+            }
+            if t0 > t1 {
+                return None;
+            }
+        }
+        Some((t0, t1))
+    }
+}
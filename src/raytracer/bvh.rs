@@ -0,0 +1,477 @@
+#![allow(dead_code)]
+
+use std::cell::Cell;
+
+use super::math::vec3::*;
+use super::ray::*;
+use super::object::*;
+use super::aabb::*;
+use super::light::*;
+
+// Relative costs used by the surface area heuristic
+const TRAVERSAL_COST: f32 = 0.125;
+const INTERSECT_COST: f32 = 1.0;
+// Number of buckets candidate splits are evaluated at along each axis
+const SAH_BUCKETS: usize = 12;
+// Nodes with this many objects or fewer may become leaves
+const MAX_LEAF_SIZE: usize = 4;
+// Starting size of the traversal stack, enough for all but degenerate trees
+const STACK_SIZE: usize = 64;
+
+// How much work tracing rays through a BVH took
+#[derive(Clone, Copy, Default, Debug)]
+pub struct TraversalStats {
+    pub rays: u64,
+    pub nodes: u64,
+    pub primitives: u64
+}
+
+impl TraversalStats {
+    pub fn add_eq(&mut self, other: &TraversalStats) {
+        self.rays += other.rays;
+        self.nodes += other.nodes;
+        self.primitives += other.primitives;
+    }
+    pub fn nodes_per_ray(&self) -> f64 {
+        self.nodes as f64 / self.rays.max(1) as f64
+    }
+    pub fn primitives_per_ray(&self) -> f64 {
+        self.primitives as f64 / self.rays.max(1) as f64
+    }
+}
+
+thread_local! {
+    // Filled in by Object::check_hit so render threads can report totals
+    static THREAD_STATS: Cell<TraversalStats> = Cell::new(TraversalStats::default());
+    // How many BVHs the current ray is inside of. Meshes and instances
+    // have BVHs of their own, and their nodes count towards the ray that
+    // reached them rather than as more rays
+    static DEPTH: Cell<u32> = const { Cell::new(0) };
+}
+
+// Returns and clears the counters gathered on the calling thread
+pub fn take_thread_stats() -> TraversalStats {
+    THREAD_STATS.with(|s| s.replace(TraversalStats::default()))
+}
+
+// Flattened tree node. Interior nodes keep their first child right after
+// themselves and the second one at `offset`. Leaves have count > 0 and
+// own objects[offset..offset + count]
+struct BvhNode {
+    bounds: Aabb,
+    offset: usize,
+    count: usize,
+    axis: usize
+}
+
+// Per-object data only needed while building
+struct BuildItem {
+    bounds: Aabb,
+    centroid: Vec3,
+    index: usize
+}
+
+// Bounding volume hierarchy over a World's objects, built with the surface
+// area heuristic. Hits are identical to World's linear loop, including
+// which object wins when two are hit at the same distance
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    // Bounded objects in tree order, with their index in the original World
+    objects: Vec<Box<dyn Object>>,
+    order: Vec<usize>,
+    // Objects without bounds (e.g. infinite planes) get tested on every ray
+    unbounded: Vec<Box<dyn Object>>,
+    unbounded_order: Vec<usize>,
+    // Positions in objects of the ones shadow rays lose light through
+    media: Vec<usize>
+}
+
+impl Bvh {
+    pub fn new(world: World) -> Bvh {
+        let mut items = Vec::new();
+        let mut slots: Vec<Option<Box<dyn Object>>> = Vec::new();
+        let mut unbounded = Vec::new();
+        let mut unbounded_order = Vec::new();
+        for (index, obj) in world.into_objects().into_iter().enumerate() {
+            match obj.bounding_box() {
+                Some(b) => {
+                    let bounds = b.padded();
+                    items.push(BuildItem {
+                        centroid: bounds.centroid(),
+                        bounds,
+                        index
+                    });
+                    slots.push(Some(obj));
+                },
+                None => {
+                    unbounded.push(obj);
+                    unbounded_order.push(index);
+                    slots.push(None);
+                }
+            }
+        }
+
+        let mut nodes = Vec::new();
+        let mut order = Vec::with_capacity(items.len());
+        if !items.is_empty() {
+            build(&mut items, &mut nodes, &mut order);
+        }
+        let objects: Vec<Box<dyn Object>> = order.iter().map(|&i| slots[i].take().unwrap()).collect();
+        let media = (0..objects.len()).filter(|&i| objects[i].has_media()).collect();
+
+        Bvh {
+            nodes,
+            objects,
+            order,
+            unbounded,
+            unbounded_order,
+            media
+        }
+    }
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+    pub fn object_count(&self) -> usize {
+        self.objects.len() + self.unbounded.len()
+    }
+    // Same as check_hit, but also records how much of the tree was visited
+    pub fn check_hit_counted(&self, r: &Ray, t_min: f32, t_max: f32, rec: &mut HitRecord, stats: &mut TraversalStats) -> bool {
+        stats.rays += 1;
+        let mut closest = t_max;
+        let mut best: Option<usize> = None;
+        // Like World, objects write into temp and only hits are kept, so
+        // one that fills in part of it and then misses can't spoil found
+        let mut temp = HitRecord::default();
+        let mut found = HitRecord::default();
+
+        for (obj, &index) in self.unbounded.iter().zip(&self.unbounded_order) {
+            stats.primitives += 1;
+            if obj.check_hit(r, t_min, hit_limit(closest, best, index), &mut temp) {
+                closest = temp.t;
+                best = Some(index);
+                std::mem::swap(&mut temp, &mut found);
+            }
+        }
+        if self.nodes.is_empty() {
+            if best.is_some() {
+                *rec = found;
+            }
+            return best.is_some();
+        }
+
+        let inv_dir = Vec3::new(1.0 / r.direction.x, 1.0 / r.direction.y, 1.0 / r.direction.z);
+        let dir_is_neg = [inv_dir.x < 0.0, inv_dir.y < 0.0, inv_dir.z < 0.0];
+        let mut stack: Vec<usize> = Vec::with_capacity(STACK_SIZE);
+        let mut current = 0;
+        loop {
+            let node = &self.nodes[current];
+            stats.nodes += 1;
+            // Boxes are tested against the widest limit any object could use
+            if node.bounds.hit(r, &inv_dir, t_min, closest.next_up()) {
+                if node.count > 0 {
+                    for i in node.offset..node.offset + node.count {
+                        stats.primitives += 1;
+                        let index = self.order[i];
+                        if self.objects[i].check_hit(r, t_min, hit_limit(closest, best, index), &mut temp) {
+                            closest = temp.t;
+                            best = Some(index);
+                            std::mem::swap(&mut temp, &mut found);
+                        }
+                    }
+                } else {
+                    // Visit the child nearer to the ray origin first
+                    if dir_is_neg[node.axis] {
+                        stack.push(current + 1);
+                        current = node.offset;
+                    } else {
+                        stack.push(node.offset);
+                        current += 1;
+                    }
+                    continue;
+                }
+            }
+            match stack.pop() {
+                Some(next) => current = next,
+                None => break
+            }
+        }
+        if best.is_some() {
+            *rec = found;
+        }
+        best.is_some()
+    }
+}
+
+// The linear loop keeps the earliest object on ties, so objects that came
+// before the current best may also win at exactly the same distance
+fn hit_limit(closest: f32, best: Option<usize>, index: usize) -> f32 {
+    match best {
+        Some(b) if index < b => closest.next_up(),
+        _ => closest
+    }
+}
+
+// Recursively builds the subtree for items, returning its node index
+fn build(items: &mut [BuildItem], nodes: &mut Vec<BvhNode>, order: &mut Vec<usize>) -> usize {
+    let mut bounds = Aabb::empty();
+    let mut centroid_bounds = Aabb::empty();
+    for item in items.iter() {
+        bounds.grow_eq(&item.bounds);
+        centroid_bounds.grow_by_point_eq(&item.centroid);
+    }
+    let node_index = nodes.len();
+    let axis = centroid_bounds.largest_axis();
+    nodes.push(BvhNode {
+        bounds,
+        offset: 0,
+        count: 0,
+        axis
+    });
+
+    let split = if items.len() > 1 { find_split(items, &centroid_bounds, &nodes[node_index].bounds) } else { None };
+    match split {
+        Some((axis, mid)) => {
+            build(&mut items[..mid], nodes, order);
+            let second = build(&mut items[mid..], nodes, order);
+            let node = &mut nodes[node_index];
+            node.axis = axis;
+            node.offset = second;
+        },
+        None => {
+            let node = &mut nodes[node_index];
+            node.offset = order.len();
+            node.count = items.len();
+            order.extend(items.iter().map(|item| item.index));
+        }
+    }
+    node_index
+}
+
+// Picks the cheapest bucket boundary over all three axes and partitions
+// items around it. Returns None when a leaf is cheaper than any split
+fn find_split(items: &mut [BuildItem], centroid_bounds: &Aabb, bounds: &Aabb) -> Option<(usize, usize)> {
+    let extent = centroid_bounds.extent();
+    let bucket_of = |c: &Vec3, axis: usize| -> usize {
+        let rel = (c[axis] - centroid_bounds.min[axis]) / extent[axis];
+        ((rel * SAH_BUCKETS as f32) as usize).min(SAH_BUCKETS - 1)
+    };
+
+    let mut best: Option<(f32, usize, usize)> = None;
+    for axis in 0..3 {
+        if extent[axis] <= 0.0 {
+            continue;
+        }
+        let mut counts = [0usize; SAH_BUCKETS];
+        let mut boxes: Vec<Aabb> = (0..SAH_BUCKETS).map(|_| Aabb::empty()).collect();
+        for item in items.iter() {
+            let b = bucket_of(&item.centroid, axis);
+            counts[b] += 1;
+            boxes[b].grow_eq(&item.bounds);
+        }
+        // Sweep from the right to get the area and count above each boundary
+        let mut above_area = [0.0f32; SAH_BUCKETS];
+        let mut above_count = [0usize; SAH_BUCKETS];
+        let mut acc = Aabb::empty();
+        let mut n = 0;
+        for b in (1..SAH_BUCKETS).rev() {
+            acc.grow_eq(&boxes[b]);
+            n += counts[b];
+            above_area[b] = acc.surface_area();
+            above_count[b] = n;
+        }
+        let mut below = Aabb::empty();
+        let mut below_count = 0;
+        for b in 1..SAH_BUCKETS {
+            below.grow_eq(&boxes[b - 1]);
+            below_count += counts[b - 1];
+            if below_count == 0 || above_count[b] == 0 {
+                continue;
+            }
+            let cost = below.surface_area() * below_count as f32 + above_area[b] * above_count[b] as f32;
+            if best.is_none_or(|(c, _, _)| cost < c) {
+                best = Some((cost, axis, b));
+            }
+        }
+    }
+
+    let (cost, axis, boundary) = best?;
+    let split_cost = TRAVERSAL_COST + INTERSECT_COST * cost / bounds.surface_area();
+    let leaf_cost = INTERSECT_COST * items.len() as f32;
+    if items.len() <= MAX_LEAF_SIZE && leaf_cost <= split_cost {
+        return None;
+    }
+
+    // Partition in place, objects in lower buckets go first
+    let mut mid = 0;
+    for i in 0..items.len() {
+        if bucket_of(&items[i].centroid, axis) < boundary {
+            items.swap(i, mid);
+            mid += 1;
+        }
+    }
+    Some((axis, mid))
+}
+
+impl Object for Bvh {
+    fn check_hit(&self, r: &Ray, t_min: f32, t_max: f32, rec: &mut HitRecord) -> bool {
+        let mut stats = TraversalStats::default();
+        let nested = DEPTH.with(|d| d.replace(d.get() + 1)) > 0;
+        let hit = self.check_hit_counted(r, t_min, t_max, rec, &mut stats);
+        DEPTH.with(|d| d.set(d.get() - 1));
+        if nested {
+            stats.rays = 0;
+        }
+        THREAD_STATS.with(|s| {
+            let mut total = s.get();
+            total.add_eq(&stats);
+            s.set(total);
+        });
+        hit
+    }
+    fn bounding_box(&self) -> Option<Aabb> {
+        if !self.unbounded.is_empty() {
+            return None;
+        }
+        self.nodes.first().map(|n| n.bounds.copy())
+    }
+    fn add_light_shapes(&self, shapes: &mut Vec<LightShape>) {
+        for obj in self.objects.iter().chain(&self.unbounded) {
+            obj.add_light_shapes(shapes);
+        }
+    }
+    // There are rarely more than a few media, so they're all tried
+    // rather than walking the tree
+    fn transmittance(&self, r: &Ray, t_min: f32, t_max: f32) -> f32 {
+        let mut res = 1.0;
+        for &i in &self.media {
+            res *= self.objects[i].transmittance(r, t_min, t_max);
+        }
+        for obj in &self.unbounded {
+            res *= obj.transmittance(r, t_min, t_max);
+        }
+        res
+    }
+    fn has_media(&self) -> bool {
+        !self.media.is_empty() || self.unbounded.iter().any(|o| o.has_media())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use super::super::material::*;
+    use super::super::sampler::*;
+    use super::super::triangle::*;
+    use super::super::transform::*;
+    use super::super::math::mat4::*;
+
+    // Glows with its object's index, so hits can be told apart
+    struct Tag(usize);
+
+    impl Material for Tag {
+        fn scatter(&self, _ray: &Ray, _rec: &HitRecord, _attenuation: &mut Vec3, _scattered: &mut Ray, _sampler: &mut dyn Sampler) -> bool {
+            false
+        }
+        fn emitted(&self, _ray: &Ray, _rec: &HitRecord) -> Vec3 {
+            Vec3::all(self.0 as f32)
+        }
+        fn copy(&self) -> Box<dyn Material> {
+            Box::new(Tag(self.0))
+        }
+    }
+
+    // Scribbles over the record and then misses
+    struct Scribbler(Aabb);
+
+    impl Object for Scribbler {
+        fn check_hit(&self, _r: &Ray, _t_min: f32, _t_max: f32, rec: &mut HitRecord) -> bool {
+            rec.t = -1.0;
+            rec.normal = Vec3::all(f32::NAN);
+            rec.material = Box::new(Tag(usize::MAX));
+            false
+        }
+        fn bounding_box(&self) -> Option<Aabb> {
+            Some(self.0.copy())
+        }
+    }
+
+    // Overlapping spheres and triangles, with exact copies of some so they
+    // tie on distance
+    fn scene() -> World {
+        let mut world = World::new();
+        let mut sampler = RandomSampler::new(7);
+        let mut next = |scale: f32| (sampler.next_f32() - 0.5) * scale;
+        for i in 0..60 {
+            let index = world.len();
+            match i % 3 {
+                0 | 1 => {
+                    let center = Vec3::new(next(6.0), next(6.0), next(6.0));
+                    let radius = 0.3 + next(0.4).abs();
+                    world.add_object(Box::new(Sphere::new(center.copy(), radius, Box::new(Tag(index)))));
+                    if i % 4 == 0 {
+                        world.add_object(Box::new(Sphere::new(center, radius, Box::new(Tag(index + 1)))));
+                    }
+                },
+                _ => {
+                    let v = [Vec3::new(next(6.0), next(6.0), next(6.0)), Vec3::new(next(6.0), next(6.0), next(6.0)), Vec3::new(next(6.0), next(6.0), next(6.0))];
+                    world.add_object(Box::new(Triangle::new(v[0].copy(), v[1].copy(), v[2].copy(), Box::new(Tag(index)))));
+                    if i % 5 == 0 {
+                        world.add_object(Box::new(Triangle::new(v[0].copy(), v[1].copy(), v[2].copy(), Box::new(Tag(index + 1)))));
+                    }
+                }
+            }
+        }
+        world.add_object(Box::new(Scribbler(Aabb::new(Vec3::all(-4.0), Vec3::all(4.0)))));
+        world
+    }
+
+    #[test]
+    fn matches_linear_loop() {
+        let world = scene();
+        let bvh = Bvh::new(scene());
+        let mut sampler = RandomSampler::new(3);
+        let mut hits = 0;
+        for _ in 0..20000 {
+            let mut next = || (sampler.next_f32() - 0.5) * 12.0;
+            let origin = Vec3::new(next(), next(), next());
+            let target = Vec3::new(next() / 2.0, next() / 2.0, next() / 2.0);
+            let r = Ray::new(origin.copy(), target.sub_by_vec(&origin), 0.0);
+            let (mut a, mut b) = (HitRecord::default(), HitRecord::default());
+            let hit = world.check_hit(&r, 0.001, f32::MAX, &mut a);
+            assert_eq!(hit, bvh.check_hit(&r, 0.001, f32::MAX, &mut b));
+            if hit {
+                hits += 1;
+                assert_eq!(a.t, b.t);
+                assert_eq!([a.normal.x, a.normal.y, a.normal.z], [b.normal.x, b.normal.y, b.normal.z]);
+                assert_eq!(a.material.emitted(&r, &a).x, b.material.emitted(&r, &b).x);
+            }
+        }
+        assert!(hits > 1000);
+    }
+
+    #[test]
+    fn nested_bvhs_count_as_one_ray() {
+        let mesh: Arc<dyn Object> = Arc::new(Bvh::new(scene()));
+        let mut world = World::new();
+        for x in [-20.0, 20.0] {
+            world.add_object(Box::new(Transformed::new(Arc::clone(&mesh), Mat4::translation(&Vec3::new(x, 0.0, 0.0))).unwrap()));
+        }
+        let bvh = Bvh::new(world);
+        take_thread_stats();
+        let mut hits = 0;
+        for i in 0..100 {
+            let x = if i % 2 == 0 { -20.0 } else { 20.0 };
+            let r = Ray::new(Vec3::new(x, 0.0, 30.0), Vec3::new(i as f32 * 0.01, 0.0, -1.0), 0.0);
+            if bvh.check_hit(&r, 0.001, f32::MAX, &mut HitRecord::default()) {
+                hits += 1;
+            }
+        }
+        let stats = take_thread_stats();
+        assert!(hits > 0);
+        assert_eq!(stats.rays, 100);
+        // The instances' own nodes still count towards those rays
+        assert!(stats.nodes_per_ray() > bvh.node_count() as f64);
+    }
+}
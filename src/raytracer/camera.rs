@@ -1,9 +1,7 @@
 #![allow(dead_code)]
-extern crate rand;
-use rand::Rng;
-
 use super::math::vec3::*;
 use super::ray::*;
+use super::sampler::*;
 
 pub struct Camera {
     lower_left_corner: Vec3,
@@ -13,20 +11,44 @@ pub struct Camera {
     lens_radius: f32,
     u: Vec3,
     v: Vec3,
-    w: Vec3
+    w: Vec3,
+    // When the shutter opens and closes, rays are spread evenly between
+    time0: f32,
+    time1: f32
+}
+
+// Where the camera is and how it's set up, without the image's aspect ratio
+// so the output size can still change after a scene is loaded
+pub struct CameraSetup {
+    pub lookfrom: Vec3,
+    pub lookat: Vec3,
+    pub vup: Vec3,
+    pub vfov: f32,
+    pub aperture: f32,
+    pub focus_dist: f32,
+    // Open and close times, the same for a still image
+    pub shutter: (f32, f32)
 }
 
-fn random_in_unit_disk() -> Vec3 {
-    let mut rng = rand::thread_rng();
+impl CameraSetup {
+    pub fn build(&self, aspect: f32) -> Camera {
+        Camera::new(self.lookfrom.copy(), self.lookat.copy(), self.vup.copy(), self.vfov, aspect, self.aperture, self.focus_dist)
+            .with_shutter(self.shutter.0, self.shutter.1)
+    }
+}
+
+fn random_in_unit_disk(sampler: &mut dyn Sampler) -> Vec3 {
     let mut p: Vec3;
     while {
-        p = Vec3::new(rng.gen::<f32>(), rng.gen::<f32>(), 0.0).mul(2.0).sub_by_vec(&Vec3::new(1.0, 1.0, 0.0)); 
+        let (a, b) = sampler.next_2d();
+        p = Vec3::new(a, b, 0.0).mul(2.0).sub_by_vec(&Vec3::new(1.0, 1.0, 0.0)); 
         p.dot(&p) >= 1.0
     } {}
     p
 }
 
 impl Camera {
+    #[allow(clippy::redundant_field_names, clippy::needless_borrow)]
     pub fn new(lookfrom: Vec3, lookat: Vec3, vup: Vec3, vfov: f32, aspect: f32,  aperture: f32, focus_dist: f32) -> Camera {
         let theta = (vfov * std::f32::consts::PI)/180.0;
         let half_height = (theta / 2.0).tan();
@@ -46,20 +68,34 @@ impl Camera {
             lens_radius: aperture / 2.0,
             u: u,
             v: v,
-            w: w
+            w: w,
+            time0: 0.0,
+            time1: 0.0
         }
     }
-    pub fn get_ray(&self, u: f32, v: f32) -> Ray {
-        let rd = random_in_unit_disk().mul(self.lens_radius);
+    pub fn with_shutter(mut self, time0: f32, time1: f32) -> Camera {
+        self.time0 = time0;
+        self.time1 = time1;
+        self
+    }
+    pub fn get_ray(&self, u: f32, v: f32, sampler: &mut dyn Sampler) -> Ray {
+        let rd = random_in_unit_disk(sampler).mul(self.lens_radius);
         let offset = self.u.mul(rd.x).add_by_vec(&self.v.mul(rd.y));
         let u_component = self.horizontal.mul(u);
         let v_component = self.vertical.mul(v);
         let direction = self.lower_left_corner.add_by_vec(
             &u_component.add_by_vec(&v_component)
         ).sub_by_vec(&self.origin).sub_by_vec(&offset);
+        // Still images don't use up a sample dimension on the time
+        let time = if self.time1 > self.time0 {
+            self.time0 + sampler.next_f32() * (self.time1 - self.time0)
+        } else {
+            self.time0
+        };
         Ray::new(
             self.origin.add_by_vec(&offset),
-            direction
+            direction,
+            time
         )
     }
 }
\ No newline at end of file
@@ -0,0 +1,281 @@
+#![allow(dead_code)]
+
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+use super::math::vec3::*;
+use super::sampler::*;
+use super::texture::*;
+
+// Where the light from far away comes from
+pub enum Source {
+    // The same color in every direction, black for scenes lit only by
+    // their emissive objects
+    Color(Vec3),
+    // Blends from bottom straight down to top straight up
+    Gradient { bottom: Vec3, top: Vec3 },
+    // Latitude and longitude, with the middle of the image looking along -z
+    // and the top row straight up
+    Equirect(Arc<Image>),
+    // Six square faces of the same size, in the order +x, -x, +y, -y, +z, -z
+    Cubemap(Vec<Arc<Image>>)
+}
+
+// What rays that escape the scene see. It also lights everything they
+// bounced off on the way out
+pub struct Environment {
+    source: Source,
+    intensity: f32,
+    // Turn around the y axis
+    sin: f32,
+    cos: f32,
+    // Images get sampled by brightness, so a small sun in an HDR map gets
+    // found by light sampling instead of the odd lucky bounce
+    distribution: Option<Distribution>
+}
+
+impl Default for Environment {
+    // White at the horizon fading to light blue straight up
+    fn default() -> Environment {
+        Environment::new(Source::Gradient { bottom: Vec3::all(1.0), top: Vec3::new(0.5, 0.7, 1.0) }, 1.0, 0.0)
+    }
+}
+
+impl Environment {
+    // rotation is in degrees around the y axis
+    pub fn new(source: Source, intensity: f32, rotation: f32) -> Environment {
+        let distribution = match &source {
+            Source::Color(_) | Source::Gradient { .. } => None,
+            Source::Equirect(image) => {
+                let (w, h) = (image.width, image.height);
+                // Rows near the poles cover less of the sphere
+                Distribution::new((0..w * h).map(|i| {
+                    let (x, row) = (i % w, i / w);
+                    brightest_around(image, x, row, true) * (PI * (row as f32 + 0.5) / h as f32).sin()
+                }))
+            },
+            Source::Cubemap(faces) => {
+                let size = faces[0].width;
+                // Pixels further from a face's center cover less of the sphere
+                Distribution::new((0..6 * size * size).map(|i| {
+                    let (face, x, row) = (i / (size * size), i % size, i / size % size);
+                    let (sc, tc) = (2.0 * (x as f32 + 0.5) / size as f32 - 1.0, 2.0 * (row as f32 + 0.5) / size as f32 - 1.0);
+                    brightest_around(&faces[face as usize], x, row, false) / (1.0 + sc * sc + tc * tc).powf(1.5)
+                }))
+            }
+        };
+        let (sin, cos) = rotation.to_radians().sin_cos();
+        Environment {
+            source,
+            intensity,
+            sin,
+            cos,
+            distribution
+        }
+    }
+    // Light arriving from direction
+    pub fn color(&self, direction: &Vec3) -> Vec3 {
+        let color = match &self.source {
+            Source::Color(c) => c.copy(),
+            Source::Gradient { bottom, top } => {
+                let unit_direction = direction.as_unit();
+                let t = (unit_direction.y + 1.0) * 0.5;
+                // Saving memory by modifying vectors in place
+                let mut step1 = top.copy();
+                step1.mul_eq(t);
+                let mut res = bottom.copy();
+                res.mul_eq(1.0 - t);
+                res.add_by_vec_eq(&step1);
+                res
+            },
+            Source::Equirect(image) => {
+                let (u, v) = equirect_uv(&self.to_local(direction));
+                bilinear(image, u * image.width as f32, v * image.height as f32, true)
+            },
+            Source::Cubemap(faces) => {
+                let (face, s, t) = cube_face(&self.to_local(direction));
+                let image = &faces[face];
+                bilinear(image, s * image.width as f32, t * image.height as f32, false)
+            }
+        };
+        color.mul(self.intensity)
+    }
+    // Whether sample can be used, only images have anything to aim for
+    pub fn is_sampled(&self) -> bool {
+        self.distribution.is_some()
+    }
+    // A unit direction picked in proportion to brightness, and its density
+    // per unit solid angle. Only for environments where is_sampled is true
+    pub fn sample(&self, sampler: &mut dyn Sampler) -> (Vec3, f32) {
+        let distribution = self.distribution.as_ref().unwrap();
+        let index = distribution.sample(sampler.next_f32());
+        let (du, dv) = sampler.next_2d();
+        let local = match &self.source {
+            Source::Equirect(image) => {
+                let (w, h) = (image.width, image.height);
+                let u = ((index % w) as f32 + du) / w as f32;
+                let v = ((index / w) as f32 + dv) / h as f32;
+                let (theta, phi) = (PI * v, 2.0 * PI * (u - 0.5));
+                Vec3::new(theta.sin() * phi.sin(), theta.cos(), -theta.sin() * phi.cos())
+            },
+            Source::Cubemap(faces) => {
+                let size = faces[0].width;
+                let (face, x, row) = (index / (size * size), index % size, index / size % size);
+                let sc = 2.0 * (x as f32 + du) / size as f32 - 1.0;
+                let tc = 2.0 * (row as f32 + dv) / size as f32 - 1.0;
+                match face {
+                    0 => Vec3::new(1.0, -tc, -sc),
+                    1 => Vec3::new(-1.0, -tc, sc),
+                    2 => Vec3::new(sc, 1.0, tc),
+                    3 => Vec3::new(sc, -1.0, -tc),
+                    4 => Vec3::new(sc, -tc, 1.0),
+                    _ => Vec3::new(-sc, -tc, -1.0)
+                }.as_unit()
+            },
+            _ => unreachable!()
+        };
+        let pdf = self.local_pdf(&local);
+        (self.to_world(&local), pdf)
+    }
+    // Density of sample picking the direction, zero for environments that
+    // aren't sampled
+    pub fn pdf(&self, direction: &Vec3) -> f32 {
+        if self.distribution.is_none() {
+            return 0.0;
+        }
+        self.local_pdf(&self.to_local(direction))
+    }
+    fn local_pdf(&self, local: &Vec3) -> f32 {
+        let distribution = match &self.distribution {
+            Some(d) => d,
+            None => return 0.0
+        };
+        match &self.source {
+            Source::Equirect(image) => {
+                let (w, h) = (image.width, image.height);
+                let (u, v) = equirect_uv(local);
+                let sin_theta = (PI * v).sin();
+                if sin_theta <= 0.0 {
+                    return 0.0;
+                }
+                let (x, row) = (((u * w as f32) as u32).min(w - 1), ((v * h as f32) as u32).min(h - 1));
+                // From density over the image to over the sphere
+                distribution.probability(row * w + x) * (w * h) as f32 / (2.0 * PI * PI * sin_theta)
+            },
+            Source::Cubemap(faces) => {
+                let size = faces[0].width;
+                let (face, s, t) = cube_face(local);
+                let (sc, tc) = (2.0 * s - 1.0, 2.0 * t - 1.0);
+                let (x, row) = (((s * size as f32) as u32).min(size - 1), ((t * size as f32) as u32).min(size - 1));
+                let index = face as u32 * size * size + row * size + x;
+                distribution.probability(index) * (size * size) as f32 / 4.0 * (1.0 + sc * sc + tc * tc).powf(1.5)
+            },
+            _ => 0.0
+        }
+    }
+    fn to_local(&self, d: &Vec3) -> Vec3 {
+        Vec3::new(d.x * self.cos - d.z * self.sin, d.y, d.x * self.sin + d.z * self.cos).as_unit()
+    }
+    fn to_world(&self, d: &Vec3) -> Vec3 {
+        Vec3::new(d.x * self.cos + d.z * self.sin, d.y, -d.x * self.sin + d.z * self.cos)
+    }
+}
+
+fn luminance(c: &Vec3) -> f32 {
+    (0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z).max(0.0)
+}
+
+// The largest luminance of a pixel and its neighbors. Bilinear lookups
+// blend in the neighbors, so a pixel next to the sun needs sampling nearly
+// as much as the sun itself or rays that find it become fireflies
+fn brightest_around(image: &Image, x: u32, row: u32, wrap: bool) -> f32 {
+    let (w, h) = (image.width as i64, image.height as i64);
+    let mut res = 0.0f32;
+    for dy in -1..=1 {
+        for dx in -1..=1 {
+            let i = x as i64 + dx;
+            let column = if wrap { i.rem_euclid(w) } else { i.clamp(0, w - 1) };
+            res = res.max(luminance(image.pixel(column as u32, (row as i64 + dy).clamp(0, h - 1) as u32)));
+        }
+    }
+    res
+}
+
+// u around from -x through -z, v down from the top
+fn equirect_uv(d: &Vec3) -> (f32, f32) {
+    let u = 0.5 + d.x.atan2(-d.z) / (2.0 * PI);
+    let v = d.y.clamp(-1.0, 1.0).acos() / PI;
+    (u - u.floor(), v)
+}
+
+// Which face a unit direction goes through and where on it, with s to the
+// right and t down the image like OpenGL's cube maps
+fn cube_face(d: &Vec3) -> (usize, f32, f32) {
+    let (ax, ay, az) = (d.x.abs(), d.y.abs(), d.z.abs());
+    let (face, sc, tc, major) = if ax >= ay && ax >= az {
+        if d.x > 0.0 { (0, -d.z, -d.y, ax) } else { (1, d.z, -d.y, ax) }
+    } else if ay >= az {
+        if d.y > 0.0 { (2, d.x, d.z, ay) } else { (3, d.x, -d.z, ay) }
+    } else if d.z > 0.0 {
+        (4, d.x, -d.y, az)
+    } else {
+        (5, -d.x, -d.y, az)
+    };
+    let to_unit = |c: f32| ((c / major + 1.0) * 0.5).clamp(0.0, 1.0);
+    (face, to_unit(sc), to_unit(tc))
+}
+
+// Blends the four pixels around (x, y) in pixel space. Columns wrap around
+// for equirect maps, everything else stops at the edges
+fn bilinear(image: &Image, x: f32, y: f32, wrap: bool) -> Vec3 {
+    let (x, y) = (x - 0.5, y - 0.5);
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let (w, h) = (image.width as i64, image.height as i64);
+    let column = |i: i64| if wrap { i.rem_euclid(w) } else { i.clamp(0, w - 1) } as u32;
+    let row = |i: i64| i.clamp(0, h - 1) as u32;
+    let (x0, y0) = (x0 as i64, y0 as i64);
+    let top = image.pixel(column(x0), row(y0)).mul(1.0 - fx).add_by_vec(&image.pixel(column(x0 + 1), row(y0)).mul(fx));
+    let bottom = image.pixel(column(x0), row(y0 + 1)).mul(1.0 - fx).add_by_vec(&image.pixel(column(x0 + 1), row(y0 + 1)).mul(fx));
+    top.mul(1.0 - fy).add_by_vec(&bottom.mul(fy))
+}
+
+// Picks indices in proportion to a list of weights
+struct Distribution {
+    // Each index's share of the total
+    probabilities: Vec<f32>,
+    // Running totals of the shares, f64 so tiny weights still get their turn
+    cdf: Vec<f64>
+}
+
+impl Distribution {
+    // None if there's nothing to pick from
+    fn new(weights: impl Iterator<Item = f32>) -> Option<Distribution> {
+        let weights: Vec<f32> = weights.map(|w| if w.is_finite() { w.max(0.0) } else { 0.0 }).collect();
+        let total: f64 = weights.iter().map(|&w| w as f64).sum();
+        if total <= 0.0 {
+            return None;
+        }
+        let mut sum = 0.0;
+        let cdf = weights.iter().map(|&w| {
+            sum += w as f64 / total;
+            sum
+        }).collect();
+        Some(Distribution {
+            probabilities: weights.iter().map(|&w| (w as f64 / total) as f32).collect(),
+            cdf
+        })
+    }
+    fn sample(&self, u: f32) -> u32 {
+        let index = self.cdf.partition_point(|&c| c <= u as f64);
+        // Skips zero weights left over from rounding at the very end
+        let mut index = index.min(self.cdf.len() - 1);
+        while index > 0 && self.probabilities[index] == 0.0 {
+            index -= 1;
+        }
+        index as u32
+    }
+    fn probability(&self, index: u32) -> f32 {
+        self.probabilities[index as usize]
+    }
+}
@@ -0,0 +1,46 @@
+#![allow(dead_code)]
+
+use super::math::vec3::*;
+
+// Holds the rendered image as linear-light f32 RGB, before any clamping or
+// gamma is applied. Rows run top to bottom
+pub struct Film {
+    width: u32,
+    height: u32,
+    pixels: Vec<Vec3>
+}
+
+impl Film {
+    // A black film
+    pub fn new(width: u32, height: u32) -> Film {
+        Film {
+            width,
+            height,
+            pixels: (0..width as usize * height as usize).map(|_| Vec3::all(0.0)).collect()
+        }
+    }
+    pub fn get_dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+    pub fn get(&self, x: u32, y: u32) -> &Vec3 {
+        &self.pixels[self.index(x, y)]
+    }
+    pub fn set(&mut self, x: u32, y: u32, color: Vec3) {
+        let i = self.index(x, y);
+        self.pixels[i] = color;
+    }
+    // All pixels in row-major order, top row first
+    pub fn pixels(&self) -> &[Vec3] {
+        &self.pixels
+    }
+    // One row of pixels, y = 0 is the top of the image
+    pub fn row(&self, y: u32) -> &[Vec3] {
+        let start = y as usize * self.width as usize;
+        &self.pixels[start..start + self.width as usize]
+    }
+    fn index(&self, x: u32, y: u32) -> usize {
+        assert!(x < self.width && y < self.height,
+            "({}, {}) out of bounds for {}x{} film", x, y, self.width, self.height);
+        y as usize * self.width as usize + x as usize
+    }
+}
@@ -0,0 +1,458 @@
+// OpenEXR: uncompressed single-part scanline files with 32-bit float channels
+// The reader also takes half and uint channels, and RLE and zlib compression
+extern crate inflate;
+
+use std::io::{self, Write};
+
+use super::super::film::*;
+use super::super::math::vec3::*;
+use super::super::texture::*;
+
+const MAGIC: [u8; 4] = [0x76, 0x2f, 0x31, 0x01];
+// Version 2, no flags set: single-part scanline image
+const VERSION: [u8; 4] = [2, 0, 0, 0];
+const PIXEL_TYPE_UINT: i32 = 0;
+const PIXEL_TYPE_HALF: i32 = 1;
+const PIXEL_TYPE_FLOAT: i32 = 2;
+const NO_COMPRESSION: u8 = 0;
+const RLE_COMPRESSION: u8 = 1;
+// zlib, one scanline per chunk for ZIPS and 16 for ZIP
+const ZIPS_COMPRESSION: u8 = 2;
+const ZIP_COMPRESSION: u8 = 3;
+// Most each compression can shrink pixels by: a two byte RLE run holds
+// 128 bytes, and deflate tops out a little over 1000 to 1
+const MAX_RLE_RATIO: usize = 64;
+const MAX_ZIP_RATIO: usize = 1032;
+// Flags in the version field for files that aren't plain scanline images
+const TILED_FLAG: u32 = 0x200;
+const DEEP_FLAG: u32 = 0x800;
+const MULTIPART_FLAG: u32 = 0x1000;
+const INCREASING_Y: u8 = 0;
+
+// Builds one header attribute: name, type name, size, then the value
+fn attribute(header: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    header.extend_from_slice(name.as_bytes());
+    header.push(0);
+    header.extend_from_slice(kind.as_bytes());
+    header.push(0);
+    header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    header.extend_from_slice(value);
+}
+
+fn box2i(width: u32, height: u32) -> Vec<u8> {
+    let mut v = Vec::with_capacity(16);
+    for n in &[0, 0, width as i32 - 1, height as i32 - 1] {
+        v.extend_from_slice(&n.to_le_bytes());
+    }
+    v
+}
+
+// Writes the film with one R, G and B float channel, one scanline per chunk
+pub fn write_exr<W: Write>(film: &Film, out: &mut W) -> io::Result<()> {
+    let (width, height) = film.get_dimensions();
+    // Channels have to be listed in alphabetical order
+    let channels = ["B", "G", "R"];
+
+    let mut chlist = Vec::new();
+    for name in &channels {
+        chlist.extend_from_slice(name.as_bytes());
+        chlist.push(0);
+        chlist.extend_from_slice(&PIXEL_TYPE_FLOAT.to_le_bytes());
+        chlist.extend_from_slice(&[0, 0, 0, 0]); // pLinear and reserved bytes
+        chlist.extend_from_slice(&1i32.to_le_bytes()); // x sampling
+        chlist.extend_from_slice(&1i32.to_le_bytes()); // y sampling
+    }
+    chlist.push(0);
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&MAGIC);
+    header.extend_from_slice(&VERSION);
+    attribute(&mut header, "channels", "chlist", &chlist);
+    attribute(&mut header, "compression", "compression", &[NO_COMPRESSION]);
+    attribute(&mut header, "dataWindow", "box2i", &box2i(width, height));
+    attribute(&mut header, "displayWindow", "box2i", &box2i(width, height));
+    attribute(&mut header, "lineOrder", "lineOrder", &[INCREASING_Y]);
+    attribute(&mut header, "pixelAspectRatio", "float", &1f32.to_le_bytes());
+    attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+    attribute(&mut header, "screenWindowWidth", "float", &1f32.to_le_bytes());
+    header.push(0);
+    out.write_all(&header)?;
+
+    // Offset table pointing at each scanline chunk from the start of the file
+    let data_size = width as u64 * channels.len() as u64 * 4;
+    let chunk_size = 8 + data_size;
+    let first_chunk = header.len() as u64 + height as u64 * 8;
+    let mut offsets = Vec::with_capacity(height as usize * 8);
+    for y in 0..height as u64 {
+        offsets.extend_from_slice(&(first_chunk + y * chunk_size).to_le_bytes());
+    }
+    out.write_all(&offsets)?;
+
+    // Each chunk is the line number, the data size, then every channel's
+    // values for that line one after another
+    let mut chunk = Vec::with_capacity(chunk_size as usize);
+    for y in 0..height {
+        chunk.clear();
+        chunk.extend_from_slice(&(y as i32).to_le_bytes());
+        chunk.extend_from_slice(&(data_size as i32).to_le_bytes());
+        let row = film.row(y);
+        for p in row {
+            chunk.extend_from_slice(&p.z.to_le_bytes());
+        }
+        for p in row {
+            chunk.extend_from_slice(&p.y.to_le_bytes());
+        }
+        for p in row {
+            chunk.extend_from_slice(&p.x.to_le_bytes());
+        }
+        out.write_all(&chunk)?;
+    }
+    Ok(())
+}
+
+// A channel as listed in the header
+struct Channel {
+    name: String,
+    pixel_type: i32
+}
+
+impl Channel {
+    fn size(&self) -> usize {
+        if self.pixel_type == PIXEL_TYPE_HALF { 2 } else { 4 }
+    }
+    fn value(&self, b: &[u8]) -> f32 {
+        match self.pixel_type {
+            PIXEL_TYPE_UINT => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32,
+            PIXEL_TYPE_HALF => half_to_f32(u16::from_le_bytes([b[0], b[1]])),
+            _ => f32::from_le_bytes([b[0], b[1], b[2], b[3]])
+        }
+    }
+}
+
+fn half_to_f32(h: u16) -> f32 {
+    let sign = if h & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = (h >> 10) & 0x1f;
+    let mantissa = (h & 0x3ff) as u32;
+    match exponent {
+        // Subnormal, mantissa * 2^-24
+        0 => sign * mantissa as f32 / (1u32 << 24) as f32,
+        31 if mantissa == 0 => sign * f32::INFINITY,
+        31 => f32::NAN,
+        _ => f32::from_bits((h as u32 & 0x8000) << 16 | (exponent as u32 + 112) << 23 | mantissa << 13)
+    }
+}
+
+// Reads a single-part scanline file. Files with R, G and B channels keep
+// their colors, ones with only Y come out gray
+pub fn read_exr(bytes: &[u8]) -> Result<Image, String> {
+    if !bytes.starts_with(&MAGIC) {
+        return Err("not an OpenEXR file".to_string());
+    }
+    let version = bytes.get(4..8).map_or(0, |v| u32::from_le_bytes([v[0], v[1], v[2], v[3]]));
+    if version & 0xff != 2 {
+        return Err(format!("unsupported OpenEXR version {}", version & 0xff));
+    }
+    if version & (TILED_FLAG | DEEP_FLAG | MULTIPART_FLAG) != 0 {
+        return Err("only single-part scanline OpenEXR files are supported".to_string());
+    }
+
+    // Header attributes, up to an empty name
+    let truncated = || "OpenEXR file is truncated".to_string();
+    let mut pos = 8;
+    let mut channels = Vec::new();
+    let mut compression = None;
+    let mut window = None;
+    let read_string = |pos: &mut usize| -> Result<String, String> {
+        let end = bytes[*pos..].iter().position(|&b| b == 0).ok_or_else(truncated)?;
+        let s = String::from_utf8_lossy(&bytes[*pos..*pos + end]).to_string();
+        *pos += end + 1;
+        Ok(s)
+    };
+    loop {
+        let name = read_string(&mut pos)?;
+        if name.is_empty() {
+            break;
+        }
+        let _kind = read_string(&mut pos)?;
+        let size = bytes.get(pos..pos + 4).map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]])).ok_or_else(truncated)?;
+        let value = bytes.get(pos + 4..pos + 4 + size.max(0) as usize).ok_or_else(truncated)?;
+        pos += 4 + value.len();
+        let int = |i: usize| value.get(i * 4..i * 4 + 4).map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+        match name.as_str() {
+            "channels" => {
+                let mut at = 0;
+                while at < value.len() && value[at] != 0 {
+                    let end = value[at..].iter().position(|&b| b == 0).ok_or_else(truncated)?;
+                    let name = String::from_utf8_lossy(&value[at..at + end]).to_string();
+                    let fields = value.get(at + end + 1..at + end + 17).ok_or_else(truncated)?;
+                    let pixel_type = i32::from_le_bytes([fields[0], fields[1], fields[2], fields[3]]);
+                    let sampling = [&fields[8..12], &fields[12..16]];
+                    if sampling.iter().any(|s| i32::from_le_bytes([s[0], s[1], s[2], s[3]]) != 1) {
+                        return Err(format!("channel {} is subsampled, which isn't supported", name));
+                    }
+                    if !(PIXEL_TYPE_UINT..=PIXEL_TYPE_FLOAT).contains(&pixel_type) {
+                        return Err(format!("channel {} has unknown pixel type {}", name, pixel_type));
+                    }
+                    channels.push(Channel { name, pixel_type });
+                    at += end + 17;
+                }
+            },
+            "compression" => compression = value.first().copied(),
+            "dataWindow" => {
+                window = match (int(0), int(1), int(2), int(3)) {
+                    (Some(x0), Some(y0), Some(x1), Some(y1)) => Some((x0, y0, x1, y1)),
+                    _ => return Err(truncated())
+                };
+            },
+            _ => {}
+        }
+    }
+
+    let (x0, y0, x1, y1) = window.ok_or_else(|| "OpenEXR header has no dataWindow".to_string())?;
+    // The corners come straight from the file, so x1 - x0 + 1 can overflow
+    let span = |a: i32, b: i32| b.checked_sub(a).and_then(|d| d.checked_add(1)).filter(|n| *n > 0);
+    let (width, height) = match (span(x0, x1), span(y0, y1)) {
+        (Some(w), Some(h)) => (w as usize, h as usize),
+        _ => return Err("OpenEXR data window is empty or too large".to_string())
+    };
+    let lines_per_chunk = match compression.unwrap_or(NO_COMPRESSION) {
+        NO_COMPRESSION | RLE_COMPRESSION | ZIPS_COMPRESSION => 1,
+        ZIP_COMPRESSION => 16,
+        other => return Err(format!("OpenEXR compression {} isn't supported, only none, RLE, ZIPS and ZIP are", other))
+    };
+    let find = |name: &str| channels.iter().position(|c| c.name == name);
+    let rgb = match (find("R"), find("G"), find("B"), find("Y")) {
+        (Some(r), Some(g), Some(b), _) => [r, g, b],
+        (_, _, _, Some(y)) => [y, y, y],
+        _ => return Err("OpenEXR file has no R, G and B or Y channels".to_string())
+    };
+    // Where each channel's values start within a scanline
+    let mut starts = Vec::with_capacity(channels.len());
+    let mut line_size: usize = 0;
+    for c in &channels {
+        starts.push(line_size);
+        line_size = c.size().checked_mul(width).and_then(|n| n.checked_add(line_size)).ok_or_else(|| "OpenEXR data window is too large".to_string())?;
+    }
+    // Even compressed, the pixels can only take up so much more room than
+    // the file, which keeps a bogus data window from allocating it all
+    let max_ratio = match compression.unwrap_or(NO_COMPRESSION) {
+        NO_COMPRESSION => 1,
+        RLE_COMPRESSION => MAX_RLE_RATIO,
+        _ => MAX_ZIP_RATIO
+    };
+    if line_size.checked_mul(height).is_none_or(|n| n > bytes.len().saturating_mul(max_ratio)) {
+        return Err(format!("OpenEXR data window of {}x{} is larger than the file can hold", width, height));
+    }
+
+    let chunks = height.div_ceil(lines_per_chunk);
+    let mut pixels: Vec<Vec3> = (0..width * height).map(|_| Vec3::all(0.0)).collect();
+    for i in 0..chunks {
+        let entry = bytes.get(pos + i * 8..pos + i * 8 + 8).ok_or_else(truncated)?;
+        let offset = u64::from_le_bytes([entry[0], entry[1], entry[2], entry[3], entry[4], entry[5], entry[6], entry[7]]) as usize;
+        let start = offset.checked_add(8).ok_or_else(truncated)?;
+        let header = bytes.get(offset..start).ok_or_else(truncated)?;
+        let first = i32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let size = i32::from_le_bytes([header[4], header[5], header[6], header[7]]).max(0) as usize;
+        let data = bytes.get(start..start.checked_add(size).ok_or_else(truncated)?).ok_or_else(truncated)?;
+        if first < y0 || first > y1 {
+            return Err(format!("OpenEXR chunk for line {} is outside the data window", first));
+        }
+        let row = (first - y0) as usize;
+        let lines = lines_per_chunk.min(height - row);
+        let expected = line_size * lines;
+        // Chunks that wouldn't get any smaller are stored as they are
+        let raw = if size == expected || compression.unwrap_or(NO_COMPRESSION) == NO_COMPRESSION {
+            data.to_vec()
+        } else {
+            decompress(data, compression.unwrap_or(NO_COMPRESSION), expected)?
+        };
+        if raw.len() < expected {
+            return Err(format!("OpenEXR chunk for line {} is too short", first));
+        }
+        for line in 0..lines {
+            let base = line * line_size;
+            for x in 0..width {
+                let mut color = [0.0; 3];
+                for (c, &ch) in color.iter_mut().zip(&rgb) {
+                    let channel = &channels[ch];
+                    let at = base + starts[ch] + x * channel.size();
+                    *c = channel.value(&raw[at..at + channel.size()]);
+                }
+                pixels[(row + line) * width + x] = Vec3::new(color[0], color[1], color[2]);
+            }
+        }
+    }
+    Ok(Image::new(width as u32, height as u32, pixels))
+}
+
+// Undoes RLE or zlib, then the byte shuffling and delta coding both use
+fn decompress(data: &[u8], compression: u8, expected: usize) -> Result<Vec<u8>, String> {
+    let mut t = if compression == RLE_COMPRESSION {
+        let mut out = Vec::with_capacity(expected);
+        let mut i = 0;
+        while i < data.len() {
+            let count = data[i] as i8;
+            if count < 0 {
+                let literal = data.get(i + 1..i + 1 + (-(count as i32)) as usize).ok_or_else(|| "OpenEXR RLE data is truncated".to_string())?;
+                out.extend_from_slice(literal);
+                i += 1 + literal.len();
+            } else {
+                let value = *data.get(i + 1).ok_or_else(|| "OpenEXR RLE data is truncated".to_string())?;
+                out.resize(out.len() + count as usize + 1, value);
+                i += 2;
+            }
+            if out.len() > expected {
+                return Err("OpenEXR RLE data is longer than its chunk".to_string());
+            }
+        }
+        out
+    } else {
+        // Inflated a piece at a time so a chunk that unpacks to far more
+        // than its scanlines is caught before it's all in memory
+        let mut stream = inflate::InflateStream::from_zlib();
+        let mut out = Vec::with_capacity(expected);
+        let mut read = 0;
+        while read < data.len() {
+            let (n, piece) = stream.update(&data[read..]).map_err(|e| format!("OpenEXR zlib data is corrupt: {}", e))?;
+            if n == 0 && piece.is_empty() {
+                break;
+            }
+            if out.len() + piece.len() > expected {
+                return Err("OpenEXR zlib data is longer than its chunk".to_string());
+            }
+            out.extend_from_slice(piece);
+            read += n;
+        }
+        out
+    };
+    for i in 1..t.len() {
+        t[i] = (t[i - 1] as i32 + t[i] as i32 - 128) as u8;
+    }
+    // The first half holds the even bytes and the second the odd ones
+    let half = t.len().div_ceil(2);
+    let mut out = vec![0; t.len()];
+    for (i, b) in out.iter_mut().enumerate() {
+        *b = if i % 2 == 0 { t[i / 2] } else { t[half + i / 2] };
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    use super::*;
+
+    fn small_film() -> Film {
+        let mut film = Film::new(3, 2);
+        for y in 0..2 {
+            for x in 0..3 {
+                let i = (y * 3 + x) as f32;
+                film.set(x, y, Vec3::new(i * 0.5, 2.0 - i, 1e-3 * (i + 1.0).powi(4)));
+            }
+        }
+        film
+    }
+
+    fn small_file() -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_exr(&small_film(), &mut bytes).unwrap();
+        bytes
+    }
+
+    // Undoes decompress's delta coding and byte shuffling, and wraps the
+    // result in a zlib stream of stored blocks
+    fn zlib(raw: &[u8]) -> Vec<u8> {
+        let half = raw.len().div_ceil(2);
+        let mut t = vec![0u8; raw.len()];
+        for (i, b) in raw.iter().enumerate() {
+            t[if i % 2 == 0 { i / 2 } else { half + i / 2 }] = *b;
+        }
+        for i in (1..t.len()).rev() {
+            t[i] = (t[i] as i32 - t[i - 1] as i32 + 128) as u8;
+        }
+        let mut res = vec![0x78, 0x01, 0x01];
+        res.extend_from_slice(&(t.len() as u16).to_le_bytes());
+        res.extend_from_slice(&(!(t.len() as u16)).to_le_bytes());
+        res.extend_from_slice(&t);
+        let (mut a, mut b) = (1u32, 0u32);
+        for x in &t {
+            a = (a + *x as u32) % 65521;
+            b = (b + a) % 65521;
+        }
+        res.extend_from_slice(&((b << 16) | a).to_be_bytes());
+        res
+    }
+
+    // The same image with each scanline squeezed by squeeze and the file
+    // marked as ZIPS, which also has one scanline per chunk
+    fn zipped(squeeze: &dyn Fn(&[u8]) -> Vec<u8>) -> Vec<u8> {
+        let bytes = small_file();
+        let table = value_at(&bytes, "screenWindowWidth") + 5;
+        let lines = 2;
+        let mut res = bytes[..table + lines * 8].to_vec();
+        res[value_at(&bytes, "compression")] = ZIPS_COMPRESSION;
+        for i in 0..lines {
+            let offset = u64::from_le_bytes(bytes[table + i * 8..table + i * 8 + 8].try_into().unwrap()) as usize;
+            let size = i32::from_le_bytes(bytes[offset + 4..offset + 8].try_into().unwrap()) as usize;
+            let data = squeeze(&bytes[offset + 8..offset + 8 + size]);
+            let at = res.len() as u64;
+            res[table + i * 8..table + i * 8 + 8].copy_from_slice(&at.to_le_bytes());
+            res.extend_from_slice(&bytes[offset..offset + 4]);
+            res.extend_from_slice(&(data.len() as i32).to_le_bytes());
+            res.extend_from_slice(&data);
+        }
+        res
+    }
+
+    // Position of the first byte after an attribute's size
+    fn value_at(bytes: &[u8], name: &str) -> usize {
+        let key = [name.as_bytes(), b"\0"].concat();
+        let at = bytes.windows(key.len()).position(|w| w == &key[..]).unwrap() + key.len();
+        at + bytes[at..].iter().position(|&b| b == 0).unwrap() + 1 + 4
+    }
+
+    fn with_window(window: [i32; 4]) -> Vec<u8> {
+        let mut bytes = small_file();
+        let at = value_at(&bytes, "dataWindow");
+        for (i, v) in window.iter().enumerate() {
+            bytes[at + i * 4..at + i * 4 + 4].copy_from_slice(&v.to_le_bytes());
+        }
+        bytes
+    }
+
+    #[test]
+    fn reads_what_it_writes() {
+        let film = small_film();
+        for bytes in [small_file(), zipped(&zlib)] {
+            let image = read_exr(&bytes).unwrap();
+            assert_eq!((image.width, image.height), (3, 2));
+            for y in 0..2 {
+                for x in 0..3 {
+                    assert_eq!(image.pixel(x, y), film.get(x, y), "pixel {}, {}", x, y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_chunks_that_inflate_too_far() {
+        assert!(read_exr(&zipped(&|raw| zlib(&[raw, &[0; 64]].concat()))).is_err());
+        assert!(read_exr(&zipped(&|raw| zlib(&raw[1..]))).is_err());
+    }
+
+    #[test]
+    fn rejects_bogus_windows_and_offsets() {
+        assert!(read_exr(&with_window([i32::MIN, 0, i32::MAX, 1])).is_err());
+        assert!(read_exr(&with_window([0, i32::MIN, 2, i32::MAX])).is_err());
+        assert!(read_exr(&with_window([5, 0, 2, 1])).is_err());
+        assert!(read_exr(&with_window([0, 0, 99999, 99999])).is_err());
+
+        // The offset table comes right after the header's closing zero
+        let mut bytes = small_file();
+        let end = value_at(&bytes, "screenWindowWidth") + 4;
+        assert_eq!(bytes[end], 0);
+        bytes[end + 1..end + 9].copy_from_slice(&(u64::MAX - 4).to_le_bytes());
+        assert!(read_exr(&bytes).is_err());
+    }
+}
@@ -0,0 +1,799 @@
+// glTF 2.0 scenes, as .gltf JSON with external or embedded buffers, or as
+// single-file .glb binaries
+
+extern crate serde;
+extern crate serde_json;
+use serde::Deserialize;
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::path::Path;
+use std::sync::Arc;
+
+use super::super::math::vec3::*;
+use super::super::math::mat4::*;
+use super::super::material::*;
+use super::super::microfacet::*;
+use super::super::object::*;
+use super::super::triangle::*;
+use super::super::camera::*;
+use super::super::texture::*;
+
+const GLB_MAGIC: &[u8; 4] = b"glTF";
+const GLB_JSON: u32 = 0x4e4f_534a;
+const GLB_BIN: u32 = 0x004e_4942;
+// Index of refraction glTF assumes when KHR_materials_ior isn't used
+const DEFAULT_IOR: f32 = 1.5;
+// Most elements an accessor without a buffer view can have. Those are all
+// zeros with nothing in the file to check their count against
+const MAX_ZERO_ELEMENTS: usize = 1 << 24;
+
+// What a glTF file turns into: the triangles of every mesh in the default
+// scene with their transforms baked in, and the first perspective camera if
+// there is one
+pub struct Gltf {
+    pub triangles: Vec<Triangle>,
+    pub camera: Option<CameraSetup>
+}
+
+impl Gltf {
+    pub fn load(path: &str) -> Result<Gltf, String> {
+        let bytes = std::fs::read(path).map_err(|e| format!("could not read {}: {}", path, e))?;
+        let dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+        Gltf::read(&bytes, dir).map_err(|e| format!("{}: {}", path, e))
+    }
+    // Parses a .gltf or .glb file's contents, telling them apart by the GLB
+    // magic number. Other files the scene refers to are relative to dir
+    pub fn read(bytes: &[u8], dir: &Path) -> Result<Gltf, String> {
+        let (json, bin) = if bytes.starts_with(GLB_MAGIC) {
+            split_glb(bytes)?
+        } else {
+            (bytes, None)
+        };
+        let doc: Document = serde_json::from_slice(json).map_err(|e| e.to_string())?;
+        if !doc.asset.version.starts_with("2.") {
+            return Err(format!("glTF version {} isn't supported, only 2.x", doc.asset.version));
+        }
+        let mut loader = Loader {
+            doc: &doc,
+            dir,
+            buffers: Vec::new(),
+            materials: HashMap::new(),
+            images: HashMap::new(),
+            visited: vec![false; doc.nodes.len()],
+            triangles: Vec::new(),
+            camera: None
+        };
+        loader.load_buffers(bin)?;
+        loader.load_scene()?;
+        Ok(Gltf {
+            triangles: loader.triangles,
+            camera: loader.camera
+        })
+    }
+    pub fn add_to(self, world: &mut World) {
+        for t in self.triangles {
+            world.add_object(Box::new(t));
+        }
+    }
+}
+
+// Finds the JSON and binary chunks of a GLB file
+fn split_glb(bytes: &[u8]) -> Result<(&[u8], Option<&[u8]>), String> {
+    let u32_at = |pos: usize| -> Result<u32, String> {
+        bytes.get(pos..pos + 4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+            .ok_or_else(|| "GLB file is truncated".to_string())
+    };
+    let version = u32_at(4)?;
+    if version != 2 {
+        return Err(format!("GLB version {} isn't supported, only 2", version));
+    }
+    let length = (u32_at(8)? as usize).min(bytes.len());
+    let mut chunks = Vec::new();
+    let mut pos = 12;
+    while pos + 8 <= length {
+        let size = u32_at(pos)? as usize;
+        let kind = u32_at(pos + 4)?;
+        let data = bytes.get(pos + 8..pos + 8 + size).ok_or_else(|| "GLB chunk runs past the end of the file".to_string())?;
+        chunks.push((kind, data));
+        // Chunks are padded to 4 bytes
+        pos += 8 + size.div_ceil(4) * 4;
+    }
+    match chunks.first() {
+        Some((GLB_JSON, json)) => Ok((json, chunks.get(1).filter(|(k, _)| *k == GLB_BIN).map(|(_, b)| *b))),
+        _ => Err("GLB file doesn't start with a JSON chunk".to_string())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Document {
+    asset: Asset,
+    scene: Option<usize>,
+    #[serde(default)]
+    scenes: Vec<SceneDesc>,
+    #[serde(default)]
+    nodes: Vec<Node>,
+    #[serde(default)]
+    meshes: Vec<MeshDesc>,
+    #[serde(default)]
+    accessors: Vec<Accessor>,
+    #[serde(default)]
+    buffer_views: Vec<BufferView>,
+    #[serde(default)]
+    buffers: Vec<Buffer>,
+    #[serde(default)]
+    materials: Vec<MaterialDesc>,
+    #[serde(default)]
+    textures: Vec<TextureDesc>,
+    #[serde(default)]
+    images: Vec<ImageDesc>,
+    #[serde(default)]
+    samplers: Vec<SamplerDesc>,
+    #[serde(default)]
+    cameras: Vec<CameraDesc>
+}
+
+#[derive(Deserialize)]
+struct Asset {
+    version: String
+}
+
+#[derive(Deserialize)]
+struct SceneDesc {
+    #[serde(default)]
+    nodes: Vec<usize>
+}
+
+#[derive(Deserialize)]
+struct Node {
+    #[serde(default)]
+    children: Vec<usize>,
+    matrix: Option<[f32; 16]>,
+    translation: Option<[f32; 3]>,
+    rotation: Option<[f32; 4]>,
+    scale: Option<[f32; 3]>,
+    mesh: Option<usize>,
+    camera: Option<usize>
+}
+
+#[derive(Deserialize)]
+struct MeshDesc {
+    primitives: Vec<Primitive>
+}
+
+#[derive(Deserialize)]
+struct Primitive {
+    attributes: HashMap<String, usize>,
+    indices: Option<usize>,
+    material: Option<usize>,
+    #[serde(default = "default_mode")]
+    mode: u32
+}
+
+fn default_mode() -> u32 {
+    MODE_TRIANGLES
+}
+
+const MODE_TRIANGLES: u32 = 4;
+const MODE_TRIANGLE_STRIP: u32 = 5;
+const MODE_TRIANGLE_FAN: u32 = 6;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Accessor {
+    buffer_view: Option<usize>,
+    #[serde(default)]
+    byte_offset: usize,
+    component_type: u32,
+    #[serde(default)]
+    normalized: bool,
+    count: usize,
+    #[serde(rename = "type")]
+    kind: String,
+    sparse: Option<serde::de::IgnoredAny>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BufferView {
+    buffer: usize,
+    #[serde(default)]
+    byte_offset: usize,
+    byte_length: usize,
+    byte_stride: Option<usize>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Buffer {
+    uri: Option<String>,
+    byte_length: usize
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MaterialDesc {
+    pbr_metallic_roughness: Option<PbrDesc>,
+    emissive_factor: Option<[f32; 3]>,
+    alpha_mode: Option<String>,
+    #[serde(default)]
+    extensions: MaterialExtensions
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PbrDesc {
+    base_color_factor: Option<[f32; 4]>,
+    base_color_texture: Option<TextureRef>,
+    metallic_factor: Option<f32>,
+    roughness_factor: Option<f32>
+}
+
+#[derive(Deserialize, Default)]
+struct MaterialExtensions {
+    #[serde(rename = "KHR_materials_transmission")]
+    transmission: Option<Transmission>,
+    #[serde(rename = "KHR_materials_ior")]
+    ior: Option<Ior>,
+    #[serde(rename = "KHR_materials_emissive_strength")]
+    emissive_strength: Option<EmissiveStrength>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Transmission {
+    #[serde(default)]
+    transmission_factor: f32
+}
+
+#[derive(Deserialize)]
+struct Ior {
+    ior: Option<f32>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EmissiveStrength {
+    emissive_strength: Option<f32>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TextureRef {
+    index: usize,
+    // Which TEXCOORD_n attribute the texture is looked up with
+    #[serde(default)]
+    tex_coord: usize
+}
+
+#[derive(Deserialize)]
+struct TextureDesc {
+    source: Option<usize>,
+    sampler: Option<usize>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SamplerDesc {
+    mag_filter: Option<u32>,
+    wrap_s: Option<u32>
+}
+
+// The OpenGL enums glTF samplers use
+const FILTER_NEAREST: u32 = 9728;
+const WRAP_CLAMP: u32 = 33071;
+const WRAP_MIRROR: u32 = 33648;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ImageDesc {
+    uri: Option<String>,
+    buffer_view: Option<usize>
+}
+
+#[derive(Deserialize)]
+struct CameraDesc {
+    perspective: Option<Perspective>
+}
+
+#[derive(Deserialize)]
+struct Perspective {
+    yfov: f32
+}
+
+// A glTF material reduced to what the renderer's materials can show
+struct Pbr {
+    base: Vec3,
+    // The base color texture times base, if there is one
+    texture: Option<Arc<dyn Texture>>,
+    tex_coord: usize,
+    alpha: f32,
+    metallic: f32,
+    roughness: f32,
+    transmission: f32,
+    ior: f32,
+    blend: bool,
+    // emissiveFactor times the strength, emissive textures aren't used
+    emission: Vec3
+}
+
+impl Pbr {
+    // Glowing things become lights, everything else maps straight onto the
+    // principled material. Vertex colors tint the base color
+    fn to_material(&self, colors: Option<[Vec3; 3]>) -> Box<dyn Material> {
+        if self.emission.x.max(self.emission.y).max(self.emission.z) > 0.0 {
+            return Box::new(DiffuseLight::new(self.emission.copy(), 1.0));
+        }
+        let mut principled = Principled::new(self.albedo());
+        principled.vertex_colors = colors;
+        principled.metallic = self.metallic;
+        principled.set_roughness(Ggx::from_roughness(self.roughness, self.roughness));
+        // Mostly see-through blended surfaces are taken to be glass
+        principled.transmission = if self.blend && self.alpha < 0.5 { 1.0 } else { self.transmission };
+        principled.ior = self.ior;
+        // Specular 0.5 is the 4% reflection of ior 1.5
+        let f0 = ((self.ior - 1.0) / (self.ior + 1.0)).powi(2);
+        principled.specular = f0 / 0.08;
+        Box::new(principled)
+    }
+    fn albedo(&self) -> Arc<dyn Texture> {
+        match &self.texture {
+            Some(t) => Arc::clone(t),
+            None => Arc::new(ConstantTexture::new(self.base.copy()))
+        }
+    }
+}
+
+struct Loader<'a> {
+    doc: &'a Document,
+    dir: &'a Path,
+    buffers: Vec<Vec<u8>>,
+    // Materials and images are loaded once each
+    materials: HashMap<Option<usize>, Pbr>,
+    images: HashMap<usize, Arc<Image>>,
+    // Nodes already placed, glTF doesn't allow reaching one twice
+    visited: Vec<bool>,
+    triangles: Vec<Triangle>,
+    camera: Option<CameraSetup>
+}
+
+impl<'a> Loader<'a> {
+    fn load_buffers(&mut self, bin: Option<&[u8]>) -> Result<(), String> {
+        for (i, buffer) in self.doc.buffers.iter().enumerate() {
+            let data = match &buffer.uri {
+                Some(uri) => self.read_uri(uri).map_err(|e| format!("buffer {}: {}", i, e))?,
+                // Only the first buffer of a GLB can leave out its uri
+                None if i == 0 => bin.ok_or_else(|| "buffer 0 has no uri and there's no GLB binary chunk".to_string())?.to_vec(),
+                None => return Err(format!("buffer {} has no uri", i))
+            };
+            if data.len() < buffer.byte_length {
+                return Err(format!("buffer {} is {} bytes, expected {}", i, data.len(), buffer.byte_length));
+            }
+            self.buffers.push(data);
+        }
+        Ok(())
+    }
+    // Data URIs are decoded in place, anything else is a path relative to
+    // the glTF file
+    fn read_uri(&self, uri: &str) -> Result<Vec<u8>, String> {
+        if let Some(data) = uri.strip_prefix("data:") {
+            let (header, payload) = data.split_once(',').ok_or_else(|| "data URI has no comma".to_string())?;
+            if !header.ends_with(";base64") {
+                return Err("only base64 data URIs are supported".to_string());
+            }
+            return base64_decode(payload);
+        }
+        let path = self.dir.join(percent_decode(uri));
+        std::fs::read(&path).map_err(|e| format!("could not read {}: {}", path.display(), e))
+    }
+    fn load_scene(&mut self) -> Result<(), String> {
+        let doc = self.doc;
+        let roots: Vec<usize> = match doc.scene.or(if doc.scenes.is_empty() { None } else { Some(0) }) {
+            Some(s) => doc.scenes.get(s).ok_or_else(|| format!("scene {} doesn't exist", s))?.nodes.clone(),
+            // Without scenes, every node that isn't somebody's child is a root
+            None => {
+                let mut is_child = vec![false; doc.nodes.len()];
+                for n in &doc.nodes {
+                    for c in &n.children {
+                        if let Some(flag) = is_child.get_mut(*c) {
+                            *flag = true;
+                        }
+                    }
+                }
+                (0..doc.nodes.len()).filter(|i| !is_child[*i]).collect()
+            }
+        };
+        for root in roots {
+            self.load_node(root, &Mat4::identity())?;
+        }
+        Ok(())
+    }
+    fn load_node(&mut self, index: usize, parent: &Mat4) -> Result<(), String> {
+        let doc = self.doc;
+        let node = doc.nodes.get(index).ok_or_else(|| format!("node {} doesn't exist", index))?;
+        if self.visited[index] {
+            return Err(format!("node {} is reached twice, the node hierarchy has to be a tree", index));
+        }
+        self.visited[index] = true;
+
+        let local = match &node.matrix {
+            Some(m) => Mat4::from_cols(m),
+            None => Mat4::from_trs(
+                &node.translation.map_or(Vec3::all(0.0), |t| Vec3::new(t[0], t[1], t[2])),
+                &node.rotation.unwrap_or([0.0, 0.0, 0.0, 1.0]),
+                &node.scale.map_or(Vec3::all(1.0), |s| Vec3::new(s[0], s[1], s[2]))
+            )
+        };
+        let transform = parent.mul_by_mat(&local);
+
+        if let Some(mesh) = node.mesh {
+            self.load_mesh(mesh, &transform).map_err(|e| format!("node {}: {}", index, e))?;
+        }
+        if let (Some(camera), None) = (node.camera, &self.camera) {
+            self.camera = self.load_camera(camera, &transform).map_err(|e| format!("node {}: {}", index, e))?;
+        }
+        for child in &node.children {
+            self.load_node(*child, &transform)?;
+        }
+        Ok(())
+    }
+    // Perspective cameras look down their node's -z with +y up. None for
+    // orthographic ones, which the renderer can't do
+    fn load_camera(&self, index: usize, transform: &Mat4) -> Result<Option<CameraSetup>, String> {
+        let desc = self.doc.cameras.get(index).ok_or_else(|| format!("camera {} doesn't exist", index))?;
+        let perspective = match &desc.perspective {
+            Some(p) => p,
+            None => return Ok(None)
+        };
+        let from = transform.transform_point(&Vec3::all(0.0));
+        let forward = transform.transform_vector(&Vec3::new(0.0, 0.0, -1.0)).as_unit();
+        Ok(Some(CameraSetup {
+            lookat: from.add_by_vec(&forward),
+            lookfrom: from,
+            vup: transform.transform_vector(&Vec3::new(0.0, 1.0, 0.0)),
+            vfov: perspective.yfov.to_degrees(),
+            aperture: 0.0,
+            focus_dist: 1.0,
+            shutter: (0.0, 0.0)
+        }))
+    }
+    fn load_mesh(&mut self, index: usize, transform: &Mat4) -> Result<(), String> {
+        let doc = self.doc;
+        let mesh = doc.meshes.get(index).ok_or_else(|| format!("mesh {} doesn't exist", index))?;
+        let normal_matrix = transform.inverse().ok_or_else(|| "transform squashes the mesh flat".to_string())?;
+        // Mirroring turns the winding around, so the corners get swapped back
+        let mirrored = transform.determinant3() < 0.0;
+
+        for (p, primitive) in mesh.primitives.iter().enumerate() {
+            let err = |e: String| format!("mesh {} primitive {}: {}", index, p, e);
+            if !matches!(primitive.mode, MODE_TRIANGLES | MODE_TRIANGLE_STRIP | MODE_TRIANGLE_FAN) {
+                // Points and lines have no surface to hit
+                continue;
+            }
+            let attribute = |name: &str| primitive.attributes.get(name).copied();
+            let position = attribute("POSITION").ok_or_else(|| err("has no POSITION attribute".to_string()))?;
+            let positions: Vec<Vec3> = self.read_floats(position).map_err(err)?.iter()
+                .map(|v| transform.transform_point(&Vec3::new(v[0], v[1], v[2])))
+                .collect();
+            let normals: Option<Vec<Vec3>> = match attribute("NORMAL") {
+                Some(a) => Some(self.read_floats(a).map_err(err)?.iter()
+                    .map(|v| normal_matrix.transform_normal(&Vec3::new(v[0], v[1], v[2])))
+                    .collect()),
+                None => None
+            };
+            if !self.materials.contains_key(&primitive.material) {
+                let pbr = self.load_material(primitive.material)?;
+                self.materials.insert(primitive.material, pbr);
+            }
+            let pbr = &self.materials[&primitive.material];
+
+            // glTF puts v = 0 at the top of images, the renderer at the bottom
+            let uvs: Option<Vec<[f32; 2]>> = match attribute(&format!("TEXCOORD_{}", pbr.tex_coord)) {
+                Some(a) => Some(self.read_floats(a).map_err(err)?.iter().map(|v| [v[0], 1.0 - v[1]]).collect()),
+                None => None
+            };
+            let colors: Option<Vec<Vec3>> = match attribute("COLOR_0") {
+                Some(a) => Some(self.read_floats(a).map_err(err)?.iter().map(|v| Vec3::new(v[0], v[1], v[2])).collect()),
+                None => None
+            };
+            let indices: Vec<u32> = match primitive.indices {
+                Some(a) => self.read_indices(a).map_err(err)?,
+                None => (0..positions.len() as u32).collect()
+            };
+            let count = positions.len();
+            if let Some(bad) = indices.iter().find(|i| **i as usize >= count) {
+                return Err(err(format!("index {} is out of range, there are {} vertices", bad, count)));
+            }
+            let lengths = [normals.as_ref().map(|n| n.len()), uvs.as_ref().map(|u| u.len()), colors.as_ref().map(|c| c.len())];
+            if lengths.iter().flatten().any(|len| *len < count) {
+                return Err(err("attributes have fewer elements than POSITION".to_string()));
+            }
+
+            for mut corners in triangles(&indices, primitive.mode) {
+                if mirrored {
+                    corners.swap(1, 2);
+                }
+                let [a, b, c] = corners.map(|i| i as usize);
+                let vertex_colors = colors.as_ref().map(|col| [col[a].copy(), col[b].copy(), col[c].copy()]);
+                let material = pbr.to_material(vertex_colors);
+                let (v0, v1, v2) = (positions[a].copy(), positions[b].copy(), positions[c].copy());
+                let mut t = match &normals {
+                    Some(n) if [a, b, c].iter().all(|i| n[*i].squared_length() > 0.0) => {
+                        Triangle::with_normals(v0, v1, v2, [n[a].copy(), n[b].copy(), n[c].copy()], material)
+                    },
+                    _ => Triangle::new(v0, v1, v2, material)
+                };
+                if t.double_area() == 0.0 {
+                    continue;
+                }
+                if let Some(uv) = &uvs {
+                    t.set_uvs([uv[a], uv[b], uv[c]]);
+                }
+                self.triangles.push(t);
+            }
+        }
+        Ok(())
+    }
+    // Primitives without a material get glTF's default: white, fully
+    // metallic and fully rough
+    fn load_material(&mut self, index: Option<usize>) -> Result<Pbr, String> {
+        let desc = match index {
+            Some(i) => self.doc.materials.get(i).ok_or_else(|| format!("material {} doesn't exist", i))?,
+            None => return Ok(Pbr {
+                base: Vec3::all(1.0),
+                texture: None,
+                tex_coord: 0,
+                alpha: 1.0,
+                metallic: 1.0,
+                roughness: 1.0,
+                transmission: 0.0,
+                ior: DEFAULT_IOR,
+                blend: false,
+                emission: Vec3::all(0.0)
+            })
+        };
+        let pbr = desc.pbr_metallic_roughness.as_ref();
+        let emissive = desc.emissive_factor.unwrap_or([0.0; 3]);
+        let strength = desc.extensions.emissive_strength.as_ref().and_then(|e| e.emissive_strength).unwrap_or(1.0);
+        let factor = pbr.and_then(|p| p.base_color_factor).unwrap_or([1.0; 4]);
+        let base = Vec3::new(factor[0], factor[1], factor[2]);
+        let base_texture = pbr.and_then(|p| p.base_color_texture.as_ref());
+        let texture = match base_texture {
+            Some(t) => {
+                let texture = self.load_texture(t.index).map_err(|e| format!("material {}: {}", index.unwrap(), e))?;
+                Some(if factor[..3] == [1.0; 3] {
+                    texture
+                } else {
+                    Arc::new(ScaledTexture { texture, scale: base.copy() })
+                })
+            },
+            None => None
+        };
+        Ok(Pbr {
+            base,
+            texture,
+            tex_coord: base_texture.map_or(0, |t| t.tex_coord),
+            alpha: factor[3],
+            metallic: pbr.and_then(|p| p.metallic_factor).unwrap_or(1.0),
+            roughness: pbr.and_then(|p| p.roughness_factor).unwrap_or(1.0),
+            transmission: desc.extensions.transmission.as_ref().map_or(0.0, |t| t.transmission_factor),
+            ior: desc.extensions.ior.as_ref().and_then(|i| i.ior).filter(|i| *i >= 1.0).unwrap_or(DEFAULT_IOR),
+            blend: desc.alpha_mode.as_deref() == Some("BLEND"),
+            emission: Vec3::new(emissive[0], emissive[1], emissive[2]).mul(strength)
+        })
+    }
+    fn load_texture(&mut self, index: usize) -> Result<Arc<dyn Texture>, String> {
+        let doc = self.doc;
+        let desc = doc.textures.get(index).ok_or_else(|| format!("texture {} doesn't exist", index))?;
+        let source = desc.source.ok_or_else(|| format!("texture {} has no image", index))?;
+        let image = self.load_image(source).map_err(|e| format!("image {}: {}", source, e))?;
+        let sampler = match desc.sampler {
+            Some(s) => Some(doc.samplers.get(s).ok_or_else(|| format!("sampler {} doesn't exist", s))?),
+            None => None
+        };
+        let filter = match sampler.and_then(|s| s.mag_filter) {
+            Some(FILTER_NEAREST) => Filter::Nearest,
+            _ => Filter::Bilinear
+        };
+        // Textures only have one wrap mode, so wrapT is ignored
+        let wrap = match sampler.and_then(|s| s.wrap_s) {
+            Some(WRAP_CLAMP) => Wrap::Clamp,
+            Some(WRAP_MIRROR) => Wrap::Mirror,
+            _ => Wrap::Repeat
+        };
+        Ok(Arc::new(ImageTexture { image, filter, wrap }))
+    }
+    fn load_image(&mut self, index: usize) -> Result<Arc<Image>, String> {
+        if let Some(image) = self.images.get(&index) {
+            return Ok(Arc::clone(image));
+        }
+        let desc = self.doc.images.get(index).ok_or_else(|| "doesn't exist".to_string())?;
+        let bytes = match (&desc.uri, desc.buffer_view) {
+            (Some(uri), _) => self.read_uri(uri)?,
+            (None, Some(view)) => self.view_bytes(view)?.to_vec(),
+            (None, None) => return Err("has neither a uri nor a bufferView".to_string())
+        };
+        let image = Arc::new(Image::decode(&bytes)?);
+        self.images.insert(index, Arc::clone(&image));
+        Ok(image)
+    }
+    fn view_bytes(&self, index: usize) -> Result<&[u8], String> {
+        let view = self.doc.buffer_views.get(index).ok_or_else(|| format!("buffer view {} doesn't exist", index))?;
+        let buffer = self.buffers.get(view.buffer).ok_or_else(|| format!("buffer {} doesn't exist", view.buffer))?;
+        view.byte_offset.checked_add(view.byte_length).and_then(|end| buffer.get(view.byte_offset..end))
+            .ok_or_else(|| format!("buffer view {} runs past the end of buffer {}", index, view.buffer))
+    }
+    // Reads an accessor's elements as up to four floats each, undoing the
+    // normalization of integer types
+    fn read_floats(&self, index: usize) -> Result<Vec<[f32; 4]>, String> {
+        self.read_accessor(index, true)
+    }
+    fn read_indices(&self, index: usize) -> Result<Vec<u32>, String> {
+        let values = self.read_accessor(index, false)?;
+        Ok(values.iter().map(|v| v[0] as u32).collect())
+    }
+    fn read_accessor(&self, index: usize, floats: bool) -> Result<Vec<[f32; 4]>, String> {
+        let err = |e: String| format!("accessor {}: {}", index, e);
+        let accessor = self.doc.accessors.get(index).ok_or_else(|| err("doesn't exist".to_string()))?;
+        if accessor.sparse.is_some() {
+            return Err(err("sparse accessors aren't supported".to_string()));
+        }
+        let components = match accessor.kind.as_str() {
+            "SCALAR" => 1,
+            "VEC2" => 2,
+            "VEC3" => 3,
+            "VEC4" => 4,
+            other => return Err(err(format!("type {} can't be used here", other)))
+        };
+        let (size, read): (usize, fn(&[u8]) -> f32) = match accessor.component_type {
+            5120 => (1, |b| b[0] as i8 as f32),
+            5121 => (1, |b| b[0] as f32),
+            5122 => (2, |b| i16::from_le_bytes([b[0], b[1]]) as f32),
+            5123 => (2, |b| u16::from_le_bytes([b[0], b[1]]) as f32),
+            5125 => (4, |b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32),
+            5126 => (4, |b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+            other => return Err(err(format!("unknown component type {}", other)))
+        };
+        if !floats && (accessor.component_type == 5126 || components != 1) {
+            return Err(err("indices have to be unsigned integer scalars".to_string()));
+        }
+        // Normalized integers map onto 0 to 1, or -1 to 1 for signed ones
+        let scale = match (accessor.normalized, accessor.component_type) {
+            (true, 5120) => 1.0 / 127.0,
+            (true, 5121) => 1.0 / 255.0,
+            (true, 5122) => 1.0 / 32767.0,
+            (true, 5123) => 1.0 / 65535.0,
+            _ => 1.0
+        };
+
+        let view_index = match accessor.buffer_view {
+            Some(v) => v,
+            // No buffer view means all zeros
+            None if accessor.count > MAX_ZERO_ELEMENTS => return Err(err(format!("has {} elements and no buffer view", accessor.count))),
+            None => return Ok(vec![[0.0; 4]; accessor.count])
+        };
+        let data = self.view_bytes(view_index).map_err(err)?;
+        let element = size * components;
+        let stride = self.doc.buffer_views[view_index].byte_stride.unwrap_or(element);
+        // The count comes from the file, so the end is checked before
+        // anything gets allocated for it
+        if accessor.count > 0 {
+            let end = accessor.count.checked_sub(1)
+                .and_then(|last| stride.checked_mul(last))
+                .and_then(|n| n.checked_add(accessor.byte_offset))
+                .and_then(|n| n.checked_add(element));
+            if end.is_none_or(|end| end > data.len()) {
+                return Err(err(format!("reads past the end of buffer view {}", view_index)));
+            }
+        }
+        let mut res = vec![[0.0; 4]; accessor.count];
+        for (i, out) in res.iter_mut().enumerate() {
+            let start = accessor.byte_offset + i * stride;
+            for (c, value) in out.iter_mut().enumerate().take(components) {
+                let v = read(&data[start + c * size..]) * scale;
+                *value = if scale < 1.0 { v.max(-1.0) } else { v };
+            }
+        }
+        Ok(res)
+    }
+}
+
+// Corner indices of each triangle in a triangle list, strip or fan
+fn triangles(indices: &[u32], mode: u32) -> Vec<[u32; 3]> {
+    match mode {
+        MODE_TRIANGLE_STRIP => (0..indices.len().saturating_sub(2))
+            .map(|i| if i % 2 == 0 {
+                [indices[i], indices[i + 1], indices[i + 2]]
+            } else {
+                [indices[i], indices[i + 2], indices[i + 1]]
+            })
+            .collect(),
+        MODE_TRIANGLE_FAN => (1..indices.len().saturating_sub(1))
+            .map(|i| [indices[i], indices[i + 1], indices[0]])
+            .collect(),
+        _ => indices.chunks_exact(3).map(|c| [c[0], c[1], c[2]]).collect()
+    }
+}
+
+// Turns %20 and friends in relative URIs back into the characters they stand for
+fn percent_decode(uri: &str) -> String {
+    let bytes = uri.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok()).and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            },
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// Standard base64, padding optional
+fn base64_decode(text: &str) -> Result<Vec<u8>, String> {
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    let mut bits = 0u32;
+    let mut count = 0;
+    for c in text.bytes().filter(|c| !c.is_ascii_whitespace() && *c != b'=') {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return Err(format!("`{}` isn't a base64 character", c as char))
+        };
+        bits = bits << 6 | value as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One triangle, with the position accessor's fields filled in by the test
+    fn triangle(accessor: &str) -> Result<Gltf, String> {
+        let json = format!(r#"{{
+            "asset": {{ "version": "2.0" }},
+            "scene": 0,
+            "scenes": [{{ "nodes": [0] }}],
+            "nodes": [{{ "mesh": 0 }}],
+            "meshes": [{{ "primitives": [{{ "attributes": {{ "POSITION": 0 }} }}] }}],
+            "accessors": [{{ "componentType": 5126, "type": "VEC3", {} }}],
+            "bufferViews": [{{ "buffer": 0, "byteLength": 36 }}],
+            "buffers": [{{ "byteLength": 36, "uri": "data:application/octet-stream;base64,AAAAAAAAAAAAAAAAAACAPwAAAAAAAAAAAAAAAAAAgD8AAAAA" }}]
+        }}"#, accessor);
+        Gltf::read(json.as_bytes(), Path::new("."))
+    }
+
+    #[test]
+    fn reads_accessors_in_bounds() {
+        assert_eq!(triangle(r#""bufferView": 0, "count": 3"#).unwrap().triangles.len(), 1);
+    }
+
+    #[test]
+    fn rejects_accessors_out_of_bounds() {
+        assert!(triangle(r#""bufferView": 0, "count": 4"#).is_err());
+        assert!(triangle(r#""bufferView": 0, "count": 3, "byteOffset": 4"#).is_err());
+        // Counts whose end overflows, or that would be huge to allocate
+        assert!(triangle(&format!(r#""bufferView": 0, "count": {}"#, usize::MAX)).is_err());
+        assert!(triangle(&format!(r#""bufferView": 0, "count": {}"#, usize::MAX / 12 + 2)).is_err());
+        assert!(triangle(&format!(r#""bufferView": 0, "count": 3, "byteOffset": {}"#, usize::MAX - 8)).is_err());
+        assert!(triangle(&format!(r#""count": {}"#, usize::MAX)).is_err());
+    }
+}
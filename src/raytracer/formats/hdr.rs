@@ -0,0 +1,192 @@
+// Radiance RGBE (.hdr): a shared 8-bit exponent with 8-bit mantissas
+
+use std::io::{self, Write};
+
+use super::super::film::*;
+use super::super::math::vec3::*;
+
+// Scanlines outside this width range can't use the run-length encoding
+const RLE_MIN_WIDTH: u32 = 8;
+const RLE_MAX_WIDTH: u32 = 0x7fff;
+// Longest run or literal block a single count byte can describe
+const MAX_RUN: usize = 127;
+const MAX_LITERAL: usize = 128;
+// Repeats shorter than this are cheaper to store as literals
+const MIN_RUN: usize = 4;
+// Brighter than this needs an exponent past 127, so it gets clamped
+const RGBE_LIMIT: f32 = 1.7014118e38; // 2^127
+
+// Converts a linear color to RGBE, like Greg Ward's float2rgbe
+pub fn to_rgbe(color: &Vec3) -> [u8; 4] {
+    let v = color.x.max(color.y).max(color.z);
+    if v.is_nan() || v < 1e-32 {
+        return [0, 0, 0, 0];
+    }
+    if v >= RGBE_LIMIT {
+        // The largest exponent with each channel kept relative to the
+        // brightest, infinite ones counting as the brightest
+        let clamp = |c: f32| if c == f32::INFINITY { 255 } else { (c.max(0.0) / v * 255.0) as u8 };
+        return [clamp(color.x), clamp(color.y), clamp(color.z), 255];
+    }
+    let (mantissa, exponent) = frexp(v);
+    let scale = mantissa * 256.0 / v;
+    [
+        (color.x.max(0.0) * scale) as u8,
+        (color.y.max(0.0) * scale) as u8,
+        (color.z.max(0.0) * scale) as u8,
+        (exponent + 128) as u8
+    ]
+}
+
+// Splits v into a mantissa in [0.5, 1) and a power of two
+fn frexp(v: f32) -> (f32, i32) {
+    let exponent = v.log2().floor() as i32 + 1;
+    let mantissa = v / 2f32.powi(exponent);
+    // log2 can be off by one right at powers of two
+    if mantissa >= 1.0 {
+        (mantissa / 2.0, exponent + 1)
+    } else if mantissa < 0.5 {
+        (mantissa * 2.0, exponent - 1)
+    } else {
+        (mantissa, exponent)
+    }
+}
+
+// Writes the film as a run-length encoded Radiance picture, top row first
+pub fn write_hdr<W: Write>(film: &Film, out: &mut W) -> io::Result<()> {
+    let (width, height) = film.get_dimensions();
+    write!(out, "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n", height, width)?;
+
+    let mut rgbe: Vec<[u8; 4]> = Vec::with_capacity(width as usize);
+    let mut channel: Vec<u8> = Vec::with_capacity(width as usize);
+    let mut encoded: Vec<u8> = Vec::new();
+    for y in 0..height {
+        rgbe.clear();
+        rgbe.extend(film.row(y).iter().map(to_rgbe));
+        if !(RLE_MIN_WIDTH..=RLE_MAX_WIDTH).contains(&width) {
+            // Flat scanline, readers tell these apart by the missing 2, 2 marker
+            for p in &rgbe {
+                out.write_all(p)?;
+            }
+            continue;
+        }
+        encoded.clear();
+        encoded.extend_from_slice(&[2, 2, (width >> 8) as u8, (width & 0xff) as u8]);
+        // Each component gets encoded separately
+        for c in 0..4 {
+            channel.clear();
+            channel.extend(rgbe.iter().map(|p| p[c]));
+            encode_runs(&channel, &mut encoded);
+        }
+        out.write_all(&encoded)?;
+    }
+    Ok(())
+}
+
+// Appends data as a mix of runs (128 + length, value) and literal blocks
+// (length, values...)
+fn encode_runs(data: &[u8], out: &mut Vec<u8>) {
+    let mut i = 0;
+    while i < data.len() {
+        // Look for the next run long enough to be worth encoding
+        let mut run_start = i;
+        let mut run_len = 0;
+        while run_start < data.len() {
+            run_len = 1;
+            while run_start + run_len < data.len() && run_len < MAX_RUN && data[run_start + run_len] == data[run_start] {
+                run_len += 1;
+            }
+            if run_len >= MIN_RUN {
+                break;
+            }
+            run_start += run_len;
+        }
+        if run_len < MIN_RUN {
+            run_start = data.len();
+        }
+        // Everything before the run goes out as literals
+        while i < run_start {
+            let n = (run_start - i).min(MAX_LITERAL);
+            out.push(n as u8);
+            out.extend_from_slice(&data[i..i + n]);
+            i += n;
+        }
+        if run_start < data.len() {
+            out.push(128 + run_len as u8);
+            out.push(data[run_start]);
+            i = run_start + run_len;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clamps_what_rgbe_cannot_hold() {
+        assert_eq!(to_rgbe(&Vec3::new(f32::INFINITY, 1.0, 0.0)), [255, 0, 0, 255]);
+        assert_eq!(to_rgbe(&Vec3::all(f32::MAX)), [255, 255, 255, 255]);
+        assert_eq!(to_rgbe(&Vec3::new(RGBE_LIMIT, RGBE_LIMIT / 2.0, 0.0)), [255, 127, 0, 255]);
+        // Just under the limit still fits
+        let [r, _, _, e] = to_rgbe(&Vec3::all(RGBE_LIMIT * 0.99));
+        assert_eq!((r, e), (253, 255));
+        assert_eq!(to_rgbe(&Vec3::new(1.0, 0.5, 0.0)), [128, 64, 0, 129]);
+    }
+
+    // Reads back one run-length encoded scanline, returning it and the
+    // bytes after it
+    fn decode_scanline(bytes: &[u8], width: usize) -> (Vec<[u8; 4]>, &[u8]) {
+        assert_eq!(bytes[..4], [2, 2, (width >> 8) as u8, (width & 0xff) as u8]);
+        let mut pixels = vec![[0; 4]; width];
+        let mut i = 4;
+        for c in 0..4 {
+            let mut x = 0;
+            while x < width {
+                let count = bytes[i] as usize;
+                if count > 128 {
+                    for p in &mut pixels[x..x + count - 128] {
+                        p[c] = bytes[i + 1];
+                    }
+                    x += count - 128;
+                    i += 2;
+                } else {
+                    for (p, b) in pixels[x..x + count].iter_mut().zip(&bytes[i + 1..]) {
+                        p[c] = *b;
+                    }
+                    x += count;
+                    i += 1 + count;
+                }
+            }
+            assert_eq!(x, width, "runs ran past the end of the scanline");
+        }
+        (pixels, &bytes[i..])
+    }
+
+    #[test]
+    fn scanlines_decode_to_the_pixels() {
+        let (width, height) = (20, 2);
+        let mut film = Film::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                // A long run of one color, then a different one each pixel
+                let v = if x < 10 { 0.25 } else { x as f32 * (y + 1) as f32 };
+                film.set(x, y, Vec3::new(v, v * 0.5, 3.0));
+            }
+        }
+        let mut bytes = Vec::new();
+        write_hdr(&film, &mut bytes).unwrap();
+        let header = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 2 +X 20\n";
+        assert_eq!(&bytes[..header.len()], header);
+        let mut rest = &bytes[header.len()..];
+        for y in 0..height {
+            let (pixels, after) = decode_scanline(rest, width as usize);
+            let expected: Vec<[u8; 4]> = film.row(y).iter().map(to_rgbe).collect();
+            assert_eq!(pixels, expected, "row {}", y);
+            rest = after;
+        }
+        assert!(rest.is_empty());
+        // The run has to have been used rather than spelled out
+        assert!(bytes.len() < header.len() + (4 + 4 * width as usize) * height as usize);
+    }
+}
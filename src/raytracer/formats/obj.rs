@@ -0,0 +1,413 @@
+// Wavefront OBJ meshes and the MTL material libraries they pull in
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use super::super::math::vec3::*;
+use super::super::material::*;
+use super::super::object::*;
+use super::super::triangle::*;
+use super::super::texture::*;
+
+// Faces that come before any g or o statement end up in this group
+const DEFAULT_GROUP: &str = "default";
+// MTL's own default diffuse color, also used for faces without a usemtl
+const DEFAULT_KD: f32 = 0.8;
+// For transparent materials that don't give an index of refraction
+const DEFAULT_NI: f32 = 1.5;
+
+// One g or o block of the file, already triangulated
+pub struct ObjGroup {
+    pub name: String,
+    pub triangles: Vec<Triangle>
+}
+
+// Everything in an OBJ file, grouped the way the file groups it
+pub struct Obj {
+    pub groups: Vec<ObjGroup>
+}
+
+impl Obj {
+    // Reads an OBJ file, along with any MTL files next to it that it uses
+    pub fn load(path: &str) -> Result<Obj, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("could not read {}: {}", path, e))?;
+        let dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+        Obj::parse(&text, path, dir)
+    }
+    // Parses the contents of an OBJ file. name is only used in errors, and
+    // mtllib paths are relative to dir
+    pub fn parse(text: &str, name: &str, dir: &Path) -> Result<Obj, String> {
+        let mut positions: Vec<Vec3> = Vec::new();
+        let mut normals: Vec<Vec3> = Vec::new();
+        let mut uvs: Vec<[f32; 2]> = Vec::new();
+        let mut materials: HashMap<String, Box<dyn Material>> = HashMap::new();
+        let mut current: Box<dyn Material> = Box::new(Lambertian::new(Vec3::all(DEFAULT_KD)));
+        let mut groups = vec![ObjGroup { name: DEFAULT_GROUP.to_string(), triangles: Vec::new() }];
+        let mut group = 0;
+
+        for (i, line) in text.lines().enumerate() {
+            let err = |message: String| format!("{}:{}: {}", name, i + 1, message);
+            let line = line.split('#').next().unwrap_or("");
+            let mut words = line.split_whitespace();
+            let keyword = match words.next() {
+                Some(k) => k,
+                None => continue
+            };
+            let args: Vec<&str> = words.collect();
+            match keyword {
+                // Anything past x y z is either w or a vertex color, neither is used
+                "v" => {
+                    let v = numbers(&args, 3, 7).map_err(err)?;
+                    positions.push(Vec3::new(v[0], v[1], v[2]));
+                },
+                "vn" => {
+                    let n = numbers(&args, 3, 3).map_err(err)?;
+                    normals.push(Vec3::new(n[0], n[1], n[2]));
+                },
+                "vt" => {
+                    let t = numbers(&args, 1, 3).map_err(err)?;
+                    uvs.push([t[0], t.get(1).copied().unwrap_or(0.0)]);
+                },
+                "f" => {
+                    if args.len() < 3 {
+                        return Err(err(format!("a face needs at least 3 vertices, got {}", args.len())));
+                    }
+                    let mut corners = Vec::with_capacity(args.len());
+                    for arg in &args {
+                        corners.push(corner(arg, positions.len(), uvs.len(), normals.len()).map_err(err)?);
+                    }
+                    // Fan out from the first corner, which is right for the
+                    // convex polygons OBJ exporters write
+                    for k in 1..corners.len() - 1 {
+                        let tri = [&corners[0], &corners[k], &corners[k + 1]];
+                        if let Some(t) = triangle(&tri, &positions, &uvs, &normals, current.as_ref()) {
+                            groups[group].triangles.push(t);
+                        }
+                    }
+                },
+                "g" | "o" => {
+                    let group_name = if args.is_empty() { DEFAULT_GROUP.to_string() } else { args.join(" ") };
+                    group = match groups.iter().position(|g| g.name == group_name) {
+                        Some(index) => index,
+                        None => {
+                            groups.push(ObjGroup { name: group_name, triangles: Vec::new() });
+                            groups.len() - 1
+                        }
+                    };
+                },
+                "mtllib" => {
+                    if args.is_empty() {
+                        return Err(err("mtllib needs a file name".to_string()));
+                    }
+                    for file in &args {
+                        let path = dir.join(file);
+                        let text = std::fs::read_to_string(&path)
+                            .map_err(|e| err(format!("could not read {}: {}", path.display(), e)))?;
+                        let mtl_dir = path.parent().unwrap_or_else(|| Path::new(""));
+                        materials.extend(parse_mtl(&text, &path.display().to_string(), mtl_dir)?);
+                    }
+                },
+                "usemtl" => {
+                    let material = args.join(" ");
+                    current = match materials.get(&material) {
+                        Some(m) => m.copy(),
+                        None => return Err(err(format!("unknown material `{}`", material)))
+                    };
+                },
+                // Smoothing groups, lines, points and the free-form geometry
+                // statements don't affect triangle meshes
+                _ => {}
+            }
+        }
+        groups.retain(|g| !g.triangles.is_empty());
+        Ok(Obj { groups })
+    }
+    pub fn triangle_count(&self) -> usize {
+        self.groups.iter().map(|g| g.triangles.len()).sum()
+    }
+    // Moves every triangle into world
+    pub fn add_to(self, world: &mut World) {
+        for group in self.groups {
+            for t in group.triangles {
+                world.add_object(Box::new(t));
+            }
+        }
+    }
+}
+
+// Indices of one face corner into the position, uv and normal lists
+struct Corner {
+    position: usize,
+    uv: Option<usize>,
+    normal: Option<usize>
+}
+
+// Parses v, v/vt, v//vn or v/vt/vn. Indices start at 1, negative ones
+// count back from the last element read so far
+fn corner(text: &str, positions: usize, uvs: usize, normals: usize) -> Result<Corner, String> {
+    let parts: Vec<&str> = text.split('/').collect();
+    if parts.len() > 3 {
+        return Err(format!("bad face vertex `{}`", text));
+    }
+    let index = |part: &str, len: usize, what: &str| -> Result<usize, String> {
+        let i: i64 = part.parse().map_err(|_| format!("bad {} index `{}` in face vertex `{}`", what, part, text))?;
+        let resolved = if i < 0 { len as i64 + i } else { i - 1 };
+        if i == 0 || resolved < 0 || resolved >= len as i64 {
+            return Err(format!("{} index {} is out of range, there are {} so far", what, i, len));
+        }
+        Ok(resolved as usize)
+    };
+    let optional = |n: usize, len: usize, what: &str| match parts.get(n) {
+        Some(part) if !part.is_empty() => index(part, len, what).map(Some),
+        _ => Ok(None)
+    };
+    Ok(Corner {
+        position: index(parts[0], positions, "position")?,
+        uv: optional(1, uvs, "texture coordinate")?,
+        normal: optional(2, normals, "normal")?
+    })
+}
+
+// Builds a triangle from three corners, or None if it has no area
+fn triangle(c: &[&Corner; 3], positions: &[Vec3], uvs: &[[f32; 2]], normals: &[Vec3], material: &dyn Material) -> Option<Triangle> {
+    let v = |i: usize| positions[c[i].position].copy();
+    // Smooth shading needs a usable normal at every corner
+    let n: Option<Vec<Vec3>> = c.iter()
+        .map(|c| c.normal.map(|n| normals[n].copy()).filter(|n| n.squared_length() > 0.0))
+        .collect();
+    let mut t = match n {
+        Some(n) => Triangle::with_normals(v(0), v(1), v(2), [n[0].copy(), n[1].copy(), n[2].copy()], material.copy()),
+        None => Triangle::new(v(0), v(1), v(2), material.copy())
+    };
+    if t.double_area() == 0.0 {
+        return None;
+    }
+    if let (Some(a), Some(b), Some(d)) = (c[0].uv, c[1].uv, c[2].uv) {
+        t.set_uvs([uvs[a], uvs[b], uvs[d]]);
+    }
+    Some(t)
+}
+
+// Parses between min and max numbers
+fn numbers(args: &[&str], min: usize, max: usize) -> Result<Vec<f32>, String> {
+    if args.len() < min || args.len() > max {
+        return Err(if min == max {
+            format!("expected {} numbers, got {}", min, args.len())
+        } else {
+            format!("expected {} to {} numbers, got {}", min, max, args.len())
+        });
+    }
+    args.iter()
+        .map(|a| a.parse::<f32>().ok().filter(|v| v.is_finite()).ok_or_else(|| format!("`{}` is not a number", a)))
+        .collect()
+}
+
+// The parts of an MTL material the renderer's materials can use
+struct MtlDesc {
+    kd: [f32; 3],
+    ks: [f32; 3],
+    ke: [f32; 3],
+    // Texture maps replace the flat colors, which is what Blender does too
+    map_kd: Option<Arc<dyn Texture>>,
+    map_ks: Option<Arc<dyn Texture>>,
+    ns: Option<f32>,
+    ni: Option<f32>,
+    d: f32,
+    illum: Option<u32>
+}
+
+impl MtlDesc {
+    fn new() -> MtlDesc {
+        MtlDesc {
+            kd: [DEFAULT_KD; 3],
+            ks: [0.0; 3],
+            ke: [0.0; 3],
+            map_kd: None,
+            map_ks: None,
+            ns: None,
+            ni: None,
+            d: 1.0,
+            illum: None
+        }
+    }
+    // Anything glowing becomes a light, anything see-through glass,
+    // anything more specular than diffuse metal with its roughness from Ns,
+    // and the rest is diffuse. Partial transparency and tinted glass can't
+    // be represented
+    fn to_material(&self) -> Box<dyn Material> {
+        let max = |c: &[f32; 3]| c[0].max(c[1]).max(c[2]);
+        let refracts = matches!(self.illum, Some(4) | Some(6) | Some(7) | Some(9));
+        if max(&self.ke) > 0.0 {
+            Box::new(DiffuseLight::new(Vec3::new(self.ke[0], self.ke[1], self.ke[2]), 1.0))
+        } else if self.d < 1.0 || refracts {
+            Box::new(Dielectric::new(self.ni.filter(|ni| *ni > 0.0).unwrap_or(DEFAULT_NI)))
+        } else if self.illum == Some(3) || max(&self.ks) > max(&self.kd) {
+            // Same mapping from Phong exponent to roughness as Blender uses
+            let fuzz = self.ns.map_or(0.0, |ns| (2.0 / (ns.max(0.0) + 2.0)).sqrt());
+            Box::new(Metal::textured(color(&self.map_ks, &self.ks), fuzz))
+        } else {
+            Box::new(Lambertian::textured(color(&self.map_kd, &self.kd)))
+        }
+    }
+}
+
+fn color(map: &Option<Arc<dyn Texture>>, rgb: &[f32; 3]) -> Arc<dyn Texture> {
+    match map {
+        Some(t) => Arc::clone(t),
+        None => Arc::new(ConstantTexture::new(Vec3::new(rgb[0], rgb[1], rgb[2])))
+    }
+}
+
+// Reads every material in an MTL file. name is only used in errors, and
+// texture maps are relative to dir
+fn parse_mtl(text: &str, name: &str, dir: &Path) -> Result<HashMap<String, Box<dyn Material>>, String> {
+    let mut descs: Vec<(String, MtlDesc)> = Vec::new();
+    // Materials often share a map, so each image is only loaded once
+    let mut images: HashMap<PathBuf, Arc<Image>> = HashMap::new();
+    for (i, line) in text.lines().enumerate() {
+        let err = |message: String| format!("{}:{}: {}", name, i + 1, message);
+        let line = line.split('#').next().unwrap_or("");
+        let mut words = line.split_whitespace();
+        let keyword = match words.next() {
+            Some(k) => k,
+            None => continue
+        };
+        let args: Vec<&str> = words.collect();
+        if keyword == "newmtl" {
+            if args.is_empty() {
+                return Err(err("newmtl needs a name".to_string()));
+            }
+            descs.push((args.join(" "), MtlDesc::new()));
+            continue;
+        }
+        let desc = match descs.last_mut() {
+            Some((_, desc)) => desc,
+            None if ["Kd", "Ks", "Ke", "Ns", "Ni", "d", "Tr", "illum", "map_Kd", "map_Ks"].contains(&keyword) => {
+                return Err(err(format!("{} before any newmtl", keyword)));
+            },
+            None => continue
+        };
+        match keyword {
+            "Kd" | "Ks" | "Ke" => {
+                if args.first().is_some_and(|a| *a == "spectral" || *a == "xyz") {
+                    return Err(err(format!("{} {} colors aren't supported, use RGB", keyword, args[0])));
+                }
+                // g and b default to r
+                let c = numbers(&args, 1, 3).map_err(err)?;
+                let rgb = [c[0], *c.get(1).unwrap_or(&c[0]), *c.get(2).unwrap_or(&c[0])];
+                if rgb.iter().any(|c| *c < 0.0) {
+                    return Err(err(format!("{} components must not be negative", keyword)));
+                }
+                match keyword {
+                    "Kd" => desc.kd = rgb,
+                    "Ks" => desc.ks = rgb,
+                    _ => desc.ke = rgb
+                }
+            },
+            "Ns" => desc.ns = Some(numbers(&args, 1, 1).map_err(err)?[0]),
+            "Ni" => desc.ni = Some(numbers(&args, 1, 1).map_err(err)?[0]),
+            "d" => {
+                // Some exporters write -halo before the value
+                let args = if args.first() == Some(&"-halo") { &args[1..] } else { &args[..] };
+                desc.d = numbers(args, 1, 1).map_err(err)?[0];
+            },
+            "Tr" => desc.d = 1.0 - numbers(&args, 1, 1).map_err(err)?[0],
+            "illum" => {
+                let illum = args.first().and_then(|a| a.parse().ok()).filter(|_| args.len() == 1);
+                desc.illum = Some(illum.ok_or_else(|| err("illum needs a single whole number".to_string()))?);
+            },
+            "map_Kd" | "map_Ks" => {
+                let (file, wrap) = map_args(&args).map_err(err)?;
+                let path = dir.join(file);
+                let image = match images.get(&path) {
+                    Some(image) => Arc::clone(image),
+                    None => {
+                        let image = Arc::new(Image::load(&path.to_string_lossy()).map_err(err)?);
+                        images.insert(path, Arc::clone(&image));
+                        image
+                    }
+                };
+                let texture: Arc<dyn Texture> = Arc::new(ImageTexture { image, filter: Filter::Bilinear, wrap });
+                if keyword == "map_Kd" {
+                    desc.map_kd = Some(texture);
+                } else {
+                    desc.map_ks = Some(texture);
+                }
+            },
+            // Ambient colors and the other maps aren't used yet
+            _ => {}
+        }
+    }
+    Ok(descs.into_iter().map(|(name, desc)| (name, desc.to_material())).collect())
+}
+
+// Splits a texture map statement into its file name and wrap mode. Of the
+// options only -clamp is used, the rest are skipped along with their values
+fn map_args(args: &[&str]) -> Result<(String, Wrap), String> {
+    let mut wrap = Wrap::Repeat;
+    let mut i = 0;
+    while i < args.len() && args[i].starts_with('-') {
+        let option = args[i];
+        let values = match option {
+            "-mm" => 2,
+            // u, with v and w optional
+            "-o" | "-s" | "-t" => 1 + args.iter().skip(i + 2).take(2).take_while(|a| a.parse::<f32>().is_ok()).count(),
+            _ => 1
+        };
+        if option == "-clamp" {
+            wrap = if args.get(i + 1) == Some(&"on") { Wrap::Clamp } else { Wrap::Repeat };
+        }
+        i += 1 + values;
+    }
+    if i >= args.len() {
+        return Err("texture map needs a file name".to_string());
+    }
+    Ok((args[i..].join(" "), wrap))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Obj, String> {
+        Obj::parse(text, "mesh.obj", Path::new(""))
+    }
+
+    #[test]
+    fn negative_indices_count_back_from_the_last_vertex() {
+        let text = "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\nv 5 5 5\nv 6 5 5\nv 5 6 5\nf -3 -2 -1\n";
+        let obj = parse(text).unwrap();
+        let triangles = &obj.groups[0].triangles;
+        assert_eq!(triangles.len(), 2);
+        assert_eq!(triangles[0].vertices()[1].x, 1.0);
+        // -3 is the fourth vertex, not the first
+        let v = triangles[1].vertices();
+        assert_eq!((v[0].x, v[1].x, v[2].y), (5.0, 6.0, 6.0));
+        // Texture coordinates and normals count back on their own
+        let text = "v 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0 0\nvt 1 0\nvt 0 1\nvt 0.5 0.5\nf 1/-4 2/-3 3/-2\n";
+        let obj = parse(text).unwrap();
+        assert_eq!(obj.groups[0].triangles[0].uvs(), Some(&[[0.0, 0.0], [1.0, 0.0], [0.0, 1.0]]));
+    }
+
+    #[test]
+    fn out_of_range_indices_say_where() {
+        let cases = [
+            ("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 4\n", "mesh.obj:4: position index 4 is out of range, there are 3 so far"),
+            ("v 0 0 0\nv 1 0 0\nv 0 1 0\n\nf 1 2 0\n", "mesh.obj:5: position index 0 is out of range, there are 3 so far"),
+            ("v 0 0 0\nv 1 0 0\nv 0 1 0\nf -4 2 3\n", "mesh.obj:4: position index -4 is out of range, there are 3 so far"),
+            // A vertex defined after the face doesn't count yet
+            ("v 0 0 0\nv 1 0 0\nf 1 2 3\nv 0 1 0\n", "mesh.obj:3: position index 3 is out of range, there are 2 so far"),
+            ("v 0 0 0\nv 1 0 0\nv 0 1 0\nvn 0 0 1\nf 1//1 2//2 3//1\n", "mesh.obj:5: normal index 2 is out of range, there are 1 so far"),
+            ("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1/1 2/1 3/1\n", "mesh.obj:4: texture coordinate index 1 is out of range, there are 0 so far"),
+            ("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 x\n", "mesh.obj:4: bad position index `x` in face vertex `x`"),
+            ("v 0 0 0\nv 1 0 0\nf 1 2\n", "mesh.obj:3: a face needs at least 3 vertices, got 2"),
+            ("v 0 0\n", "mesh.obj:1: expected 3 to 7 numbers, got 2"),
+            ("# comment\nv 0 nan 0\n", "mesh.obj:2: `nan` is not a number"),
+            ("v 0 0 0\nusemtl missing\n", "mesh.obj:2: unknown material `missing`")
+        ];
+        for (text, expected) in cases.iter() {
+            assert_eq!(parse(text).err().as_deref(), Some(*expected), "{:?}", text);
+        }
+    }
+}
@@ -0,0 +1,48 @@
+// Portable float map: a netpbm-style header followed by raw f32 RGB
+
+use std::io::{self, Write};
+
+use super::super::film::*;
+
+// Writes the film as little-endian color PFM. PFM stores rows bottom to
+// top, and a negative scale marks the data as little-endian
+pub fn write_pfm<W: Write>(film: &Film, out: &mut W) -> io::Result<()> {
+    let (width, height) = film.get_dimensions();
+    write!(out, "PF\n{} {}\n-1.0\n", width, height)?;
+    let mut line = Vec::with_capacity(width as usize * 12);
+    for y in (0..height).rev() {
+        line.clear();
+        for p in film.row(y) {
+            line.extend_from_slice(&p.x.to_le_bytes());
+            line.extend_from_slice(&p.y.to_le_bytes());
+            line.extend_from_slice(&p.z.to_le_bytes());
+        }
+        out.write_all(&line)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::super::math::vec3::*;
+
+    #[test]
+    fn bottom_row_comes_first() {
+        let mut film = Film::new(3, 2);
+        for y in 0..2 {
+            for x in 0..3 {
+                film.set(x, y, Vec3::new(x as f32, y as f32, -1.5));
+            }
+        }
+        let mut bytes = Vec::new();
+        write_pfm(&film, &mut bytes).unwrap();
+        // A negative scale means little-endian
+        let header = b"PF\n3 2\n-1.0\n";
+        assert_eq!(&bytes[..header.len()], header);
+        let floats: Vec<f32> = bytes[header.len()..].chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect();
+        assert_eq!(floats.len(), 3 * 2 * 3);
+        assert_eq!(floats[..9], [0.0, 1.0, -1.5, 1.0, 1.0, -1.5, 2.0, 1.0, -1.5]);
+        assert_eq!(floats[9..], [0.0, 0.0, -1.5, 1.0, 0.0, -1.5, 2.0, 0.0, -1.5]);
+    }
+}
@@ -0,0 +1,458 @@
+// Stanford PLY meshes, in ASCII or either binary byte order
+
+use std::convert::TryInto;
+use std::io::Read;
+
+use super::super::math::vec3::*;
+use super::super::material::*;
+use super::super::object::*;
+use super::super::triangle::*;
+
+// Diffuse color for meshes without vertex colors
+const DEFAULT_ALBEDO: f32 = 0.8;
+
+// Names different exporters use for the vertex attributes, first match wins
+const UV_NAMES: [[&str; 2]; 4] = [["u", "v"], ["s", "t"], ["texture_u", "texture_v"], ["texture_s", "texture_t"]];
+const COLOR_NAMES: [[&str; 3]; 2] = [["red", "green", "blue"], ["diffuse_red", "diffuse_green", "diffuse_blue"]];
+const INDEX_NAMES: [&str; 2] = ["vertex_indices", "vertex_index"];
+
+// The triangles of a PLY mesh. Vertex colors become a VertexColors
+// material on every triangle, otherwise they're plain diffuse
+pub struct Ply {
+    pub triangles: Vec<Triangle>,
+    pub vertex_count: usize
+}
+
+impl Ply {
+    pub fn load(path: &str) -> Result<Ply, String> {
+        let mut file = std::fs::File::open(path).map_err(|e| format!("could not open {}: {}", path, e))?;
+        Ply::read(&mut file, path)
+    }
+    // Reads a whole PLY file, name is only used in errors
+    pub fn read<R: Read>(input: &mut R, name: &str) -> Result<Ply, String> {
+        let mut bytes = Vec::new();
+        input.read_to_end(&mut bytes).map_err(|e| format!("could not read {}: {}", name, e))?;
+        let (header, body_start) = Header::parse(&bytes).map_err(|e| format!("{}: {}", name, e))?;
+        let mut body = Body {
+            bytes: &bytes,
+            pos: body_start,
+            encoding: header.encoding,
+            line: header.lines + 1
+        };
+        let mesh = Mesh::read(&header, &mut body).map_err(|e| format!("{}: {}", name, e))?;
+        mesh.triangulate().map_err(|e| format!("{}: {}", name, e))
+    }
+    pub fn add_to(self, world: &mut World) {
+        for t in self.triangles {
+            world.add_object(Box::new(t));
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Encoding {
+    Ascii,
+    LittleEndian,
+    BigEndian
+}
+
+#[derive(Clone, Copy)]
+enum Scalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64
+}
+
+impl Scalar {
+    fn from_name(name: &str) -> Option<Scalar> {
+        match name {
+            "char" | "int8" => Some(Scalar::I8),
+            "uchar" | "uint8" => Some(Scalar::U8),
+            "short" | "int16" => Some(Scalar::I16),
+            "ushort" | "uint16" => Some(Scalar::U16),
+            "int" | "int32" => Some(Scalar::I32),
+            "uint" | "uint32" => Some(Scalar::U32),
+            "float" | "float32" => Some(Scalar::F32),
+            "double" | "float64" => Some(Scalar::F64),
+            _ => None
+        }
+    }
+    fn size(self) -> usize {
+        match self {
+            Scalar::I8 | Scalar::U8 => 1,
+            Scalar::I16 | Scalar::U16 => 2,
+            Scalar::I32 | Scalar::U32 | Scalar::F32 => 4,
+            Scalar::F64 => 8
+        }
+    }
+    // What a color channel of this type counts as full brightness
+    fn color_scale(self) -> f32 {
+        match self {
+            Scalar::U8 | Scalar::I8 => 255.0,
+            Scalar::U16 | Scalar::I16 => 65535.0,
+            Scalar::U32 | Scalar::I32 => u32::MAX as f32,
+            Scalar::F32 | Scalar::F64 => 1.0
+        }
+    }
+}
+
+struct Property {
+    name: String,
+    kind: Scalar,
+    // Lists start with a count of this type
+    list_count: Option<Scalar>
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>
+}
+
+impl Element {
+    fn find(&self, name: &str) -> Option<usize> {
+        self.properties.iter().position(|p| p.name == name && p.list_count.is_none())
+    }
+    fn find_all<const N: usize>(&self, names: &[[&str; N]]) -> Option<[usize; N]> {
+        names.iter().find_map(|set| {
+            let found: Vec<usize> = set.iter().filter_map(|n| self.find(n)).collect();
+            found.try_into().ok()
+        })
+    }
+}
+
+struct Header {
+    encoding: Encoding,
+    elements: Vec<Element>,
+    // Header lines, so ASCII bodies can report their own line numbers
+    lines: usize
+}
+
+impl Header {
+    // Returns the header and where the body starts
+    fn parse(bytes: &[u8]) -> Result<(Header, usize), String> {
+        let mut pos = 0;
+        let mut line_number = 0;
+        let mut encoding = None;
+        let mut elements: Vec<Element> = Vec::new();
+        loop {
+            let end = match bytes[pos..].iter().position(|b| *b == b'\n') {
+                Some(end) => pos + end,
+                None => return Err("header has no end_header line".to_string())
+            };
+            line_number += 1;
+            let line = std::str::from_utf8(&bytes[pos..end]).map_err(|_| format!("line {}: header is not text", line_number))?;
+            pos = end + 1;
+            let err = |message: String| format!("line {}: {}", line_number, message);
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if line_number == 1 {
+                if words != ["ply"] {
+                    return Err("not a PLY file, the first line should be `ply`".to_string());
+                }
+                continue;
+            }
+            match words.first().copied() {
+                Some("format") => {
+                    encoding = Some(match words.get(1).copied() {
+                        Some("ascii") => Encoding::Ascii,
+                        Some("binary_little_endian") => Encoding::LittleEndian,
+                        Some("binary_big_endian") => Encoding::BigEndian,
+                        _ => return Err(err(format!("unknown format `{}`", words[1..].join(" "))))
+                    });
+                },
+                Some("element") => {
+                    if words.len() != 3 {
+                        return Err(err("expected `element <name> <count>`".to_string()));
+                    }
+                    let count = words[2].parse().map_err(|_| err(format!("bad element count `{}`", words[2])))?;
+                    elements.push(Element { name: words[1].to_string(), count, properties: Vec::new() });
+                },
+                Some("property") => {
+                    let element = elements.last_mut().ok_or_else(|| err("property before any element".to_string()))?;
+                    let scalar = |name: &str| Scalar::from_name(name).ok_or_else(|| err(format!("unknown property type `{}`", name)));
+                    let property = match words.len() {
+                        3 => Property { name: words[2].to_string(), kind: scalar(words[1])?, list_count: None },
+                        5 if words[1] == "list" => Property {
+                            name: words[4].to_string(),
+                            kind: scalar(words[3])?,
+                            list_count: Some(scalar(words[2])?)
+                        },
+                        _ => return Err(err("expected `property <type> <name>` or `property list <count type> <type> <name>`".to_string()))
+                    };
+                    element.properties.push(property);
+                },
+                Some("end_header") => break,
+                Some("comment") | Some("obj_info") | None => {},
+                Some(other) => return Err(err(format!("unknown header keyword `{}`", other)))
+            }
+        }
+        let encoding = encoding.ok_or_else(|| "header has no format line".to_string())?;
+        Ok((Header { encoding, elements, lines: line_number }, pos))
+    }
+}
+
+// Reads values out of the body in whichever encoding the file uses
+struct Body<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    encoding: Encoding,
+    // Current line, only tracked for ASCII
+    line: usize
+}
+
+impl<'a> Body<'a> {
+    fn value(&mut self, kind: Scalar) -> Result<f64, String> {
+        if self.encoding == Encoding::Ascii {
+            return self.token();
+        }
+        let size = kind.size();
+        let raw = self.bytes.get(self.pos..self.pos + size).ok_or_else(|| "unexpected end of file".to_string())?;
+        self.pos += size;
+        let mut b = [0u8; 8];
+        b[..size].copy_from_slice(raw);
+        if self.encoding == Encoding::BigEndian {
+            b[..size].reverse();
+        }
+        let v = match kind {
+            Scalar::I8 => b[0] as i8 as f64,
+            Scalar::U8 => b[0] as f64,
+            Scalar::I16 => i16::from_le_bytes([b[0], b[1]]) as f64,
+            Scalar::U16 => u16::from_le_bytes([b[0], b[1]]) as f64,
+            Scalar::I32 => i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            Scalar::U32 => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            Scalar::F32 => f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            Scalar::F64 => f64::from_le_bytes(b)
+        };
+        // Same as an ASCII nan or inf
+        if !v.is_finite() {
+            return Err(format!("`{}` is not a number", v));
+        }
+        Ok(v)
+    }
+    fn token(&mut self) -> Result<f64, String> {
+        while let Some(&b) = self.bytes.get(self.pos) {
+            if !b.is_ascii_whitespace() {
+                break;
+            }
+            if b == b'\n' {
+                self.line += 1;
+            }
+            self.pos += 1;
+        }
+        let start = self.pos;
+        while self.pos < self.bytes.len() && !self.bytes[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Err("unexpected end of file".to_string());
+        }
+        let token = String::from_utf8_lossy(&self.bytes[start..self.pos]);
+        token.parse::<f64>().ok()
+            .filter(|v| v.is_finite())
+            .ok_or_else(|| format!("line {}: `{}` is not a number", self.line, token))
+    }
+}
+
+// The raw vertex and face data, before it's turned into triangles
+struct Mesh {
+    positions: Vec<Vec3>,
+    normals: Vec<Vec3>,
+    uvs: Vec<[f32; 2]>,
+    colors: Vec<Vec3>,
+    // Each face's corner indices, with where it was for errors
+    faces: Vec<(Vec<i64>, String)>
+}
+
+impl Mesh {
+    fn read(header: &Header, body: &mut Body) -> Result<Mesh, String> {
+        let mut mesh = Mesh {
+            positions: Vec::new(),
+            normals: Vec::new(),
+            uvs: Vec::new(),
+            colors: Vec::new(),
+            faces: Vec::new()
+        };
+        let mut values: Vec<f64> = Vec::new();
+        let mut list: Vec<i64> = Vec::new();
+        for element in &header.elements {
+            let is_vertex = element.name == "vertex";
+            let is_face = element.name == "face";
+            let position = element.find_all(&[["x", "y", "z"]]);
+            if is_vertex && position.is_none() {
+                return Err("vertex element has no x, y and z properties".to_string());
+            }
+            let normal = element.find_all(&[["nx", "ny", "nz"]]);
+            let uv = element.find_all(&UV_NAMES);
+            let color = element.find_all(&COLOR_NAMES);
+            let indices = element.properties.iter().position(|p| INDEX_NAMES.contains(&p.name.as_str()) && p.list_count.is_some());
+            if is_face && indices.is_none() {
+                return Err("face element has no vertex_indices list".to_string());
+            }
+
+            for item in 0..element.count {
+                let err = |e: String| format!("{} {}: {}", element.name, item, e);
+                values.clear();
+                for (p, property) in element.properties.iter().enumerate() {
+                    match property.list_count {
+                        Some(count_kind) => {
+                            let count = body.value(count_kind).map_err(err)?;
+                            if count < 0.0 || count.fract() != 0.0 {
+                                return Err(err(format!("bad list length {}", count)));
+                            }
+                            let keep = is_face && Some(p) == indices;
+                            if keep {
+                                list.clear();
+                            }
+                            for _ in 0..count as usize {
+                                let v = body.value(property.kind).map_err(err)?;
+                                if keep {
+                                    // Float-typed indices are fine as long as they're whole
+                                    if v.fract() != 0.0 {
+                                        return Err(err(format!("vertex index {} is not a whole number", v)));
+                                    }
+                                    list.push(v as i64);
+                                }
+                            }
+                            values.push(0.0);
+                        },
+                        None => values.push(body.value(property.kind).map_err(err)?)
+                    }
+                }
+                if is_vertex {
+                    // Doubles can be finite and still too big for an f32
+                    let float = |i: usize| match values[i] as f32 {
+                        v if v.is_finite() => Ok(v),
+                        _ => Err(err(format!("{} {:e} is too large", element.properties[i].name, values[i])))
+                    };
+                    let vec = |[a, b, c]: [usize; 3]| -> Result<Vec3, String> { Ok(Vec3::new(float(a)?, float(b)?, float(c)?)) };
+                    mesh.positions.push(vec(position.unwrap())?);
+                    if let Some(n) = normal {
+                        mesh.normals.push(vec(n)?);
+                    }
+                    if let Some([u, v]) = uv {
+                        mesh.uvs.push([float(u)?, float(v)?]);
+                    }
+                    if let Some(c) = color {
+                        mesh.colors.push(vec(c)?.div(element.properties[c[0]].kind.color_scale()));
+                    }
+                } else if is_face {
+                    let at = if body.encoding == Encoding::Ascii {
+                        format!("face {} on line {}", item, body.line)
+                    } else {
+                        format!("face {}", item)
+                    };
+                    mesh.faces.push((list.clone(), at));
+                }
+            }
+        }
+        Ok(mesh)
+    }
+    fn triangulate(self) -> Result<Ply, String> {
+        let count = self.positions.len();
+        let mut triangles = Vec::new();
+        for (face, location) in &self.faces {
+            if face.len() < 3 {
+                return Err(format!("{}: a face needs at least 3 vertices, got {}", location, face.len()));
+            }
+            if let Some(bad) = face.iter().find(|i| **i < 0 || **i >= count as i64) {
+                return Err(format!("{}: vertex index {} is out of range, there are {}", location, bad, count));
+            }
+            // Fan out from the first corner, like the OBJ loader
+            for k in 1..face.len() - 1 {
+                let corners = [face[0] as usize, face[k] as usize, face[k + 1] as usize];
+                if let Some(t) = self.triangle(corners) {
+                    triangles.push(t);
+                }
+            }
+        }
+        Ok(Ply {
+            triangles,
+            vertex_count: count
+        })
+    }
+    // Builds one triangle, or None if it has no area
+    fn triangle(&self, [a, b, c]: [usize; 3]) -> Option<Triangle> {
+        let material: Box<dyn Material> = if self.colors.is_empty() {
+            Box::new(Lambertian::new(Vec3::all(DEFAULT_ALBEDO)))
+        } else {
+            Box::new(VertexColors { colors: [self.colors[a].copy(), self.colors[b].copy(), self.colors[c].copy()] })
+        };
+        let (v0, v1, v2) = (self.positions[a].copy(), self.positions[b].copy(), self.positions[c].copy());
+        let smooth = !self.normals.is_empty() && [a, b, c].iter().all(|i| self.normals[*i].squared_length() > 0.0);
+        let mut t = if smooth {
+            Triangle::with_normals(v0, v1, v2, [self.normals[a].copy(), self.normals[b].copy(), self.normals[c].copy()], material)
+        } else {
+            Triangle::new(v0, v1, v2, material)
+        };
+        if t.double_area() == 0.0 {
+            return None;
+        }
+        if !self.uvs.is_empty() {
+            t.set_uvs([self.uvs[a], self.uvs[b], self.uvs[c]]);
+        }
+        Some(t)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &str = "element vertex 3\nproperty float x\nproperty float y\nproperty double z\nelement face 1\nproperty list uchar float vertex_indices\nend_header\n";
+
+    // The same three vertices and one face, written out either way
+    fn ascii(z: &str, index: &str) -> Vec<u8> {
+        format!("ply\nformat ascii 1.0\n{}0 0 {}\n1 0 0\n0 1 0\n3 0 {} 2\n", HEADER, z, index).into_bytes()
+    }
+    fn binary(z: f64, index: f32) -> Vec<u8> {
+        let mut bytes = format!("ply\nformat binary_little_endian 1.0\n{}", HEADER).into_bytes();
+        for (x, y, z) in &[(0.0f32, 0.0f32, z), (1.0, 0.0, 0.0), (0.0, 1.0, 0.0)] {
+            bytes.extend_from_slice(&x.to_le_bytes());
+            bytes.extend_from_slice(&y.to_le_bytes());
+            bytes.extend_from_slice(&z.to_le_bytes());
+        }
+        bytes.push(3);
+        for i in &[0.0f32, index, 2.0] {
+            bytes.extend_from_slice(&i.to_le_bytes());
+        }
+        bytes
+    }
+    fn read(bytes: &[u8]) -> Result<Ply, String> {
+        Ply::read(&mut &bytes[..], "mesh.ply")
+    }
+
+    #[test]
+    fn ascii_and_binary_read_the_same() {
+        for bytes in &[ascii("0.5", "1"), ascii("0.5", "1.0"), binary(0.5, 1.0)] {
+            let ply = read(bytes).unwrap();
+            assert_eq!(ply.vertex_count, 3);
+            assert_eq!(ply.triangles.len(), 1);
+            let v = ply.triangles[0].vertices();
+            assert_eq!((v[0].z, v[1].x, v[2].y), (0.5, 1.0, 1.0));
+        }
+    }
+
+    #[test]
+    fn ascii_and_binary_reject_the_same() {
+        let cases = [
+            (ascii("nan", "1"), "mesh.ply: vertex 0: line 10: `nan` is not a number"),
+            (binary(f64::NAN, 1.0), "mesh.ply: vertex 0: `NaN` is not a number"),
+            (ascii("inf", "1"), "mesh.ply: vertex 0: line 10: `inf` is not a number"),
+            (binary(f64::NEG_INFINITY, 1.0), "mesh.ply: vertex 0: `-inf` is not a number"),
+            (ascii("1e300", "1"), "mesh.ply: vertex 0: z 1e300 is too large"),
+            (binary(1e300, 1.0), "mesh.ply: vertex 0: z 1e300 is too large"),
+            (ascii("0", "1.5"), "mesh.ply: face 0: vertex index 1.5 is not a whole number"),
+            (binary(0.0, 1.5), "mesh.ply: face 0: vertex index 1.5 is not a whole number"),
+            (ascii("0", "3"), "mesh.ply: face 0 on line 13: vertex index 3 is out of range, there are 3"),
+            (binary(0.0, 3.0), "mesh.ply: face 0: vertex index 3 is out of range, there are 3")
+        ];
+        for (bytes, expected) in cases.iter() {
+            assert_eq!(read(bytes).err().as_deref(), Some(*expected));
+        }
+    }
+}
@@ -0,0 +1,256 @@
+// Netpbm color images: ASCII P3 and binary P6, 8 or 16 bits per sample
+
+use std::io::{self, Read, Write};
+
+use super::Pixel;
+
+// Represents the contents of a PPM file
+// Samples are kept as u16 so 16-bit files survive a round trip
+pub struct Ppm {
+    width: u32,
+    height: u32,
+    maxval: u16,
+    data: Vec<[u16; 3]> // row-major, top row first
+}
+
+impl Ppm {
+    // An 8-bit image filled with white
+    pub fn new(width: u32, height: u32) -> Ppm {
+        Ppm::with_maxval(width, height, 255)
+    }
+    // A white image whose samples go from 0 to maxval (1 to 65535)
+    pub fn with_maxval(width: u32, height: u32, maxval: u16) -> Ppm {
+        assert!(maxval > 0, "PPM maxval must be at least 1");
+        Ppm {
+            width,
+            height,
+            maxval,
+            data: vec![[maxval; 3]; width as usize * height as usize]
+        }
+    }
+    pub fn get_dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+    pub fn maxval(&self) -> u16 {
+        self.maxval
+    }
+    pub fn get(&self, x: u32, y: u32) -> Option<[u16; 3]> {
+        if x < self.width && y < self.height {
+            Some(self.data[self.index(x, y)])
+        } else {
+            None
+        }
+    }
+    // Stores raw samples, returning the old ones
+    pub fn set_samples(&mut self, x: u32, y: u32, samples: [u16; 3]) -> Result<[u16; 3], String> {
+        if !(x < self.width && y < self.height) {
+            return Err(format!("set({}, {}) out of bounds for {}x{} image", x, y, self.width, self.height));
+        }
+        if samples.iter().any(|s| *s > self.maxval) {
+            return Err(format!("samples {:?} are above the maxval of {}", samples, self.maxval));
+        }
+        let i = self.index(x, y);
+        Ok(std::mem::replace(&mut self.data[i], samples))
+    }
+    // Stores an 8-bit pixel, scaled up to the image's maxval
+    pub fn set(&mut self, x: u32, y: u32, pixel: Pixel) -> Result<Pixel, String> {
+        let scale = |v: u8| ((v as u32 * self.maxval as u32 + 127) / 255) as u16;
+        let old = self.set_samples(x, y, [scale(pixel.r), scale(pixel.g), scale(pixel.b)])?;
+        let unscale = |v: u16| ((v as u32 * 255 + self.maxval as u32 / 2) / self.maxval as u32) as u8;
+        Ok(Pixel::new(unscale(old[0]), unscale(old[1]), unscale(old[2])))
+    }
+    fn index(&self, x: u32, y: u32) -> usize {
+        y as usize * self.width as usize + x as usize
+    }
+    fn header(&self, magic: &str) -> String {
+        format!("{}\n{} {}\n{}\n", magic, self.width, self.height, self.maxval)
+    }
+    // Binary P6. Samples take two bytes, big-endian, when maxval is over 255
+    // Written a row at a time, wrap out in a BufWriter for files
+    pub fn write_p6<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_all(self.header("P6").as_bytes())?;
+        let wide = self.maxval > 255;
+        let mut row = Vec::with_capacity(self.width as usize * if wide { 6 } else { 3 });
+        for line in self.data.chunks(self.width.max(1) as usize) {
+            row.clear();
+            for sample in line.iter().flatten() {
+                if wide {
+                    row.extend_from_slice(&sample.to_be_bytes());
+                } else {
+                    row.push(*sample as u8);
+                }
+            }
+            out.write_all(&row)?;
+        }
+        Ok(())
+    }
+    // ASCII P3 with one pixel per line, which keeps lines under the 70
+    // character limit at any maxval
+    pub fn write_p3<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_all(self.header("P3").as_bytes())?;
+        for p in &self.data {
+            writeln!(out, "{} {} {}", p[0], p[1], p[2])?;
+        }
+        Ok(())
+    }
+    // Reads a P3 or P6 file, including comments and 16-bit samples
+    pub fn read<R: Read>(input: &mut R) -> Result<Ppm, String> {
+        let mut bytes = Vec::new();
+        input.read_to_end(&mut bytes).map_err(|e| format!("could not read PPM: {}", e))?;
+        let mut parser = Parser { bytes: &bytes, pos: 0 };
+
+        let binary = match parser.token()? {
+            "P3" => false,
+            "P6" => true,
+            other => return Err(format!("unsupported magic number `{}`, expected P3 or P6", other))
+        };
+        let width = parser.number("width")?;
+        let height = parser.number("height")?;
+        let maxval = parser.number("maxval")?;
+        if maxval == 0 || maxval > 65535 {
+            return Err(format!("maxval must be between 1 and 65535, got {}", maxval));
+        }
+        // The header can claim anything, so make sure the file is big
+        // enough to hold that many pixels before making room for them
+        let count = (width as usize).checked_mul(height as usize)
+            .filter(|c| c.checked_mul(6).is_some())
+            .ok_or_else(|| format!("{}x{} image is too large", width, height))?;
+        let size = if maxval > 255 { 2 } else { 1 };
+        // P6 needs every sample's bytes, P3 at least a digit and a space each
+        let needed = if binary { count * 3 * size } else { count * 3 };
+        if needed > bytes.len().saturating_sub(parser.pos) {
+            return Err("unexpected end of file in the pixel data".to_string());
+        }
+        let mut ppm = Ppm::with_maxval(width, height, maxval as u16);
+
+        if binary {
+            // Exactly one whitespace byte separates the header from the data
+            parser.pos += 1;
+            let data = bytes.get(parser.pos..parser.pos + count * 3 * size)
+                .ok_or_else(|| "unexpected end of file in the pixel data".to_string())?;
+            for (i, px) in data.chunks(3 * size).enumerate() {
+                for c in 0..3 {
+                    ppm.data[i][c] = if size == 2 {
+                        u16::from_be_bytes([px[c * 2], px[c * 2 + 1]])
+                    } else {
+                        px[c] as u16
+                    };
+                }
+            }
+            if ppm.data.iter().flatten().any(|s| *s as u32 > maxval) {
+                return Err(format!("pixel data has samples above the maxval of {}", maxval));
+            }
+        } else {
+            for i in 0..count {
+                for c in 0..3 {
+                    // Checked before narrowing, so 65536 doesn't become 0
+                    let sample = parser.number("sample")?;
+                    if sample > maxval {
+                        return Err(format!("sample {} is above the maxval of {}", sample, maxval));
+                    }
+                    ppm.data[i][c] = sample as u16;
+                }
+            }
+        }
+        Ok(ppm)
+    }
+}
+
+// Pulls whitespace-separated tokens out of the header or P3 data
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize
+}
+
+impl<'a> Parser<'a> {
+    fn token(&mut self) -> Result<&'a str, String> {
+        // Skip whitespace and comments, which run to the end of the line
+        while let Some(&b) = self.bytes.get(self.pos) {
+            if b == b'#' {
+                while self.pos < self.bytes.len() && self.bytes[self.pos] != b'\n' {
+                    self.pos += 1;
+                }
+            } else if b.is_ascii_whitespace() {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        let start = self.pos;
+        while self.pos < self.bytes.len() && !self.bytes[self.pos].is_ascii_whitespace() && self.bytes[self.pos] != b'#' {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Err("unexpected end of file".to_string());
+        }
+        std::str::from_utf8(&self.bytes[start..self.pos]).map_err(|_| "header is not ASCII".to_string())
+    }
+    fn number(&mut self, what: &str) -> Result<u32, String> {
+        let token = self.token().map_err(|e| format!("{} while reading the {}", e, what))?;
+        token.parse().map_err(|_| format!("expected a number for the {}, got `{}`", what, token))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gradient(maxval: u16) -> Ppm {
+        let mut ppm = Ppm::with_maxval(5, 3, maxval);
+        for y in 0..3 {
+            for x in 0..5 {
+                let s = |k: u32| ((x * 7 + y * 13 + k) * maxval as u32 / 60) as u16;
+                ppm.set_samples(x, y, [s(0), s(1), s(2)]).unwrap();
+            }
+        }
+        ppm
+    }
+
+    fn assert_same(a: &Ppm, b: &Ppm) {
+        assert_eq!(a.get_dimensions(), b.get_dimensions());
+        assert_eq!(a.maxval(), b.maxval());
+        assert_eq!(a.data, b.data);
+    }
+
+    #[test]
+    fn round_trips() {
+        for maxval in [255, 65535] {
+            let ppm = gradient(maxval);
+            let mut p3 = Vec::new();
+            ppm.write_p3(&mut p3).unwrap();
+            assert_same(&ppm, &Ppm::read(&mut &p3[..]).unwrap());
+            let mut p6 = Vec::new();
+            ppm.write_p6(&mut p6).unwrap();
+            assert_same(&ppm, &Ppm::read(&mut &p6[..]).unwrap());
+        }
+    }
+
+    #[test]
+    fn reads_comments_and_mixed_whitespace() {
+        for maxval in [255u16, 65535] {
+            let ppm = gradient(maxval);
+            let header = format!("P6 # binary\n#size next\n5\t3\r\n  # then maxval\n{}\n", maxval);
+            let mut p6 = Vec::new();
+            ppm.write_p6(&mut p6).unwrap();
+            let data = &p6[format!("P6\n5 3\n{}\n", maxval).len()..];
+            let file = [header.as_bytes(), data].concat();
+            assert_same(&ppm, &Ppm::read(&mut &file[..]).unwrap());
+
+            let mut p3 = format!("P3\n# a comment\n 5 3 # trailing\n\t{}\n", maxval);
+            for p in &ppm.data {
+                p3.push_str(&format!("{}\t {}\n\n{} # pixel\n", p[0], p[1], p[2]));
+            }
+            assert_same(&ppm, &Ppm::read(&mut p3.as_bytes()).unwrap());
+        }
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        assert!(Ppm::read(&mut &b"P6 4294967295 4294967295 255\n"[..]).is_err());
+        assert!(Ppm::read(&mut &b"P3 4294967295 4294967295 255\n0 0 0"[..]).is_err());
+        assert!(Ppm::read(&mut &b"P6 2 1 255\n\0\0\0"[..]).is_err());
+        assert!(Ppm::read(&mut &b"P3 1 1 65535\n65536 0 0\n"[..]).is_err());
+        assert!(Ppm::read(&mut &b"P3 1 1 255\n256 0 0\n"[..]).is_err());
+        assert!(Ppm::read(&mut &b"P6 1 1 255\n\0\0\0"[..]).is_ok());
+    }
+}
@@ -0,0 +1,142 @@
+// Dense voxel grids: Mitsuba's binary .vol files, or headerless raw
+// little-endian f32 when the resolution is given separately
+
+// A single channel of values, x changing fastest, then y, then z
+pub struct Grid {
+    pub resolution: [usize; 3],
+    pub values: Vec<f32>,
+    // The box the grid fills, if the file says. Raw files fill 0..1
+    pub bounds: Option<([f32; 3], [f32; 3])>
+}
+
+impl Grid {
+    pub fn load(path: &str, resolution: Option<[usize; 3]>) -> Result<Grid, String> {
+        let bytes = std::fs::read(path).map_err(|e| format!("could not open {}: {}", path, e))?;
+        Grid::read(&bytes, resolution).map_err(|e| format!("{}: {}", path, e))
+    }
+    // Files starting with VOL get their size from the header, anything
+    // else is raw and needs resolution
+    pub fn read(bytes: &[u8], resolution: Option<[usize; 3]>) -> Result<Grid, String> {
+        if bytes.starts_with(b"VOL") {
+            return read_vol(bytes);
+        }
+        let resolution = resolution.ok_or("not a .vol file, raw grids need a resolution")?;
+        if resolution.contains(&0) {
+            return Err("resolution can't be zero".to_string());
+        }
+        let size = byte_count(&resolution, 1);
+        if size != Some(bytes.len()) {
+            let expected = size.map_or("more".to_string(), |s| s.to_string());
+            return Err(format!("expected {} bytes for a {}x{}x{} grid, found {}",
+                expected, resolution[0], resolution[1], resolution[2], bytes.len()));
+        }
+        Ok(Grid {
+            resolution,
+            values: floats(bytes),
+            bounds: None
+        })
+    }
+    pub fn max(&self) -> f32 {
+        self.values.iter().fold(0.0, |a, &b| a.max(b))
+    }
+}
+
+// Bytes taken up by a grid with this many channels, None if it's more than
+// fits in memory
+fn byte_count(resolution: &[usize; 3], channels: usize) -> Option<usize> {
+    resolution.iter().try_fold(channels * 4, |size, &n| size.checked_mul(n))
+}
+
+fn floats(bytes: &[u8]) -> Vec<f32> {
+    bytes.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect()
+}
+
+// 'VOL', version 3, then int32 encoding (1 for float32), the resolution,
+// the channel count and the box as 6 floats. Only the first channel is kept
+fn read_vol(bytes: &[u8]) -> Result<Grid, String> {
+    const HEADER: usize = 48;
+    if bytes.len() < HEADER {
+        return Err("header cut short".to_string());
+    }
+    if bytes[3] != 3 {
+        return Err(format!("unsupported .vol version {}, expected 3", bytes[3]));
+    }
+    let int = |at: usize| i32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]]);
+    if int(4) != 1 {
+        return Err(format!("unsupported encoding {}, only float32 (1) is", int(4)));
+    }
+    let (x, y, z, channels) = (int(8), int(12), int(16), int(20));
+    if x <= 0 || y <= 0 || z <= 0 || channels <= 0 {
+        return Err(format!("bad size {}x{}x{} with {} channels", x, y, z, channels));
+    }
+    let resolution = [x as usize, y as usize, z as usize];
+    let channels = channels as usize;
+    // Sizes come straight from the file, so check them against how much
+    // is actually there before trusting them
+    let size = match byte_count(&resolution, channels) {
+        Some(size) if size <= bytes.len() - HEADER => size,
+        _ => return Err(format!("header says {}x{}x{} voxels with {} channels, file is cut short", x, y, z, channels))
+    };
+    let b = floats(&bytes[24..HEADER]);
+    if b.iter().any(|v| !v.is_finite()) || (0..3).any(|i| b[i] >= b[i + 3]) {
+        return Err(format!("bad box from {:?} to {:?}", &b[..3], &b[3..]));
+    }
+    let values = bytes[HEADER..HEADER + size].chunks_exact(channels * 4)
+        .map(|v| f32::from_le_bytes([v[0], v[1], v[2], v[3]]))
+        .collect();
+    Ok(Grid {
+        resolution,
+        values,
+        bounds: Some(([b[0], b[1], b[2]], [b[3], b[4], b[5]]))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A version 3 float32 .vol file
+    fn vol(resolution: [i32; 3], channels: i32, bounds: [f32; 6], values: &[f32]) -> Vec<u8> {
+        let mut res = b"VOL\x03".to_vec();
+        for n in [1, resolution[0], resolution[1], resolution[2], channels] {
+            res.extend_from_slice(&n.to_le_bytes());
+        }
+        for v in bounds.iter().chain(values) {
+            res.extend_from_slice(&v.to_le_bytes());
+        }
+        res
+    }
+
+    #[test]
+    fn reads_what_it_writes() {
+        let values: Vec<f32> = (0..2 * 3 * 4 * 2).map(|i| i as f32 * 0.25).collect();
+        let grid = Grid::read(&vol([2, 3, 4], 2, [-1.0, 0.0, 1.0, 1.0, 2.0, 3.0], &values), None).unwrap();
+        assert_eq!(grid.resolution, [2, 3, 4]);
+        // Only the first channel is kept
+        assert_eq!(grid.values, values.iter().step_by(2).copied().collect::<Vec<f32>>());
+        assert_eq!(grid.bounds, Some(([-1.0, 0.0, 1.0], [1.0, 2.0, 3.0])));
+
+        let raw: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+        let grid = Grid::read(&raw, Some([4, 3, 4])).unwrap();
+        assert_eq!(grid.values, values);
+        assert!(grid.bounds.is_none());
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let unit = [0.0, 0.0, 0.0, 1.0, 1.0, 1.0];
+        // Sizes that overflow or claim far more than the file holds
+        assert!(Grid::read(&vol([2097152, 2097152, 2097152], 1, unit, &[0.0]), None).is_err());
+        assert!(Grid::read(&vol([i32::MAX, i32::MAX, 2], i32::MAX, unit, &[0.0]), None).is_err());
+        assert!(Grid::read(&vol([2, 2, 2], 1, unit, &[0.0; 7]), None).is_err());
+        assert!(Grid::read(&vol([0, 2, 2], 1, unit, &[]), None).is_err());
+        // Boxes that are NaN, infinite, empty or inside out
+        for bounds in [[f32::NAN, 0.0, 0.0, 1.0, 1.0, 1.0], [0.0, 0.0, 0.0, f32::INFINITY, 1.0, 1.0],
+                       [0.0, 0.0, 0.0, 1.0, 0.0, 1.0], [0.0, 0.0, 2.0, 1.0, 1.0, 1.0]] {
+            assert!(Grid::read(&vol([1, 1, 1], 1, bounds, &[0.0]), None).is_err(), "{:?}", bounds);
+        }
+        // Raw resolutions whose byte count overflows
+        assert!(Grid::read(&[0; 16], Some([usize::MAX, 2, 2])).is_err());
+        assert!(Grid::read(&[0; 16], Some([2, 2, 2])).is_err());
+    }
+}
@@ -0,0 +1,155 @@
+#![allow(dead_code)]
+
+use super::math::vec3::*;
+use super::math::mat4::*;
+use super::sampler::*;
+
+// The surface of an emitting object, for picking points on it
+pub enum LightShape {
+    // The radius keeps its sign so the normals match the sphere's own
+    Sphere { center: Vec3, radius: f32 },
+    Triangle { vertices: [Vec3; 3] }
+}
+
+impl LightShape {
+    pub fn area(&self) -> f32 {
+        match self {
+            LightShape::Sphere { radius, .. } => 4.0 * std::f32::consts::PI * radius * radius,
+            LightShape::Triangle { vertices: [v0, v1, v2] } => 0.5 * v1.sub_by_vec(v0).cross(&v2.sub_by_vec(v0)).magnitude()
+        }
+    }
+    // The same surface moved by m. None for spheres that m doesn't scale
+    // evenly, since those turn into ellipsoids
+    pub fn transformed(&self, m: &Mat4) -> Option<LightShape> {
+        match self {
+            LightShape::Sphere { center, radius } => {
+                let axes = [Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, 0.0, 1.0)];
+                let [x, y, z] = axes.map(|a| m.transform_vector(&a));
+                let scale = x.magnitude();
+                let even = |a: f32, b: f32| (a - b).abs() <= 1e-4 * scale;
+                if !even(y.magnitude(), scale) || !even(z.magnitude(), scale)
+                    || !even(dot_product(&x, &y), 0.0) || !even(dot_product(&y, &z), 0.0) || !even(dot_product(&z, &x), 0.0) {
+                    return None;
+                }
+                Some(LightShape::Sphere { center: m.transform_point(center), radius: radius * scale })
+            },
+            LightShape::Triangle { vertices: [v0, v1, v2] } => {
+                let (v0, v1, v2) = (m.transform_point(v0), m.transform_point(v1), m.transform_point(v2));
+                // Mirroring flips the winding, swapping two corners keeps the
+                // normal on the side that gives off light
+                Some(LightShape::Triangle { vertices: if m.determinant3() < 0.0 { [v0, v2, v1] } else { [v0, v1, v2] } })
+            }
+        }
+    }
+    // A point spread evenly over the surface, and the normal there
+    pub fn sample(&self, sampler: &mut dyn Sampler) -> (Vec3, Vec3) {
+        let (u1, u2) = sampler.next_2d();
+        match self {
+            LightShape::Sphere { center, radius } => {
+                let z = 1.0 - 2.0 * u1;
+                let r = (1.0 - z * z).max(0.0).sqrt();
+                let phi = 2.0 * std::f32::consts::PI * u2;
+                let d = Vec3::new(r * phi.cos(), r * phi.sin(), z);
+                (center.add_by_vec(&d.mul(radius.abs())), d.mul(radius.signum()))
+            },
+            LightShape::Triangle { vertices: [v0, v1, v2] } => {
+                // Folding the unit square onto the triangle with a square
+                // root keeps the density even
+                let s = u1.sqrt();
+                let (b1, b2) = (u2 * s, 1.0 - s);
+                let p = v0.mul(1.0 - b1 - b2).add_by_vec(&v1.mul(b1)).add_by_vec(&v2.mul(b2));
+                (p, v1.sub_by_vec(v0).cross(&v2.sub_by_vec(v0)).as_unit())
+            }
+        }
+    }
+}
+
+// Every emitting object in a scene. Shapes are picked in proportion to
+// their area, so every point on every light is equally likely and the
+// density of a point doesn't depend on which light it's on
+pub struct Lights {
+    shapes: Vec<LightShape>,
+    // Running totals of the areas, for picking shapes
+    cumulative: Vec<f64>,
+    total_area: f32
+}
+
+impl Lights {
+    pub fn new(shapes: Vec<LightShape>) -> Lights {
+        let mut sum = 0.0f64;
+        let cumulative = shapes.iter().map(|s| {
+            sum += s.area() as f64;
+            sum
+        }).collect();
+        Lights {
+            shapes,
+            cumulative,
+            total_area: sum as f32
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.shapes.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.total_area <= 0.0
+    }
+    // A point on one of the lights and the normal there
+    pub fn sample(&self, sampler: &mut dyn Sampler) -> (Vec3, Vec3) {
+        let target = sampler.next_f32() as f64 * self.total_area as f64;
+        let index = self.cumulative.partition_point(|&c| c <= target).min(self.shapes.len() - 1);
+        self.shapes[index].sample(sampler)
+    }
+    // Density per unit solid angle, as seen from origin, of sample picking
+    // the point p with normal n
+    pub fn pdf(&self, origin: &Vec3, p: &Vec3, n: &Vec3) -> f32 {
+        let to_light = p.sub_by_vec(origin);
+        let distance_squared = to_light.squared_length();
+        let cosine = dot_product(n, &to_light).abs() / distance_squared.sqrt();
+        if self.is_empty() || cosine <= 0.0 {
+            return 0.0;
+        }
+        distance_squared / (cosine * self.total_area)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Solid angle a triangle covers seen from the origin, by Van Oosterom
+    // and Strackee's formula
+    fn solid_angle(a: &Vec3, b: &Vec3, c: &Vec3) -> f64 {
+        let (la, lb, lc) = (a.magnitude() as f64, b.magnitude() as f64, c.magnitude() as f64);
+        let triple = dot_product(a, &b.cross(c)).abs() as f64;
+        let below = la * lb * lc + dot_product(a, b) as f64 * lc + dot_product(a, c) as f64 * lb + dot_product(b, c) as f64 * la;
+        2.0 * triple.atan2(below)
+    }
+
+    // If sample picks points with the density pdf claims, averaging 1 / pdf
+    // gives the solid angle the lights cover. Spheres count twice, since
+    // points on their far side can be picked too
+    #[test]
+    fn pdf_matches_sample() {
+        let origin = Vec3::new(0.2, -0.1, 0.3);
+        let vertices = [Vec3::new(2.0, -1.0, 1.0), Vec3::new(2.5, 1.0, 0.0), Vec3::new(1.5, 0.5, -1.0)];
+        let (center, radius) = (Vec3::new(-1.0, 1.0, -2.0), 0.5f32);
+        let triangle = solid_angle(&vertices[0].sub_by_vec(&origin), &vertices[1].sub_by_vec(&origin), &vertices[2].sub_by_vec(&origin));
+        let sin = radius as f64 / center.sub_by_vec(&origin).magnitude() as f64;
+        let sphere = 2.0 * std::f64::consts::PI * (1.0 - (1.0 - sin * sin).sqrt());
+
+        let lights = Lights::new(vec![
+            LightShape::Triangle { vertices },
+            LightShape::Sphere { center, radius }
+        ]);
+        let mut sampler = RandomSampler::new(11);
+        let samples = 200000;
+        let mut sum = 0.0;
+        for _ in 0..samples {
+            let (p, n) = lights.sample(&mut sampler);
+            sum += 1.0 / lights.pdf(&origin, &p, &n) as f64;
+        }
+        let expected = triangle + 2.0 * sphere;
+        let mean = sum / samples as f64;
+        assert!((mean - expected).abs() < 0.01 * expected, "averaged {}, expected {}", mean, expected);
+    }
+}
@@ -1,14 +1,38 @@
 #![allow(dead_code)]
 
-extern crate rand;
-use rand::Rng;
-
 use super::math::vec3::*;
 use super::ray::*;
 use super::object::*;
+use super::sampler::*;
+use super::texture::*;
+use super::microfacet::*;
+
+use std::sync::Arc;
 
-pub trait Material {
-    fn scatter(&self, ray: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray) -> bool;
+// Send + Sync so scenes can be shared between render threads
+pub trait Material: Send + Sync {
+    fn scatter(&self, ray: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray, sampler: &mut dyn Sampler) -> bool;
+    // Light the surface gives off towards where the ray came from
+    fn emitted(&self, _ray: &Ray, _rec: &HitRecord) -> Vec3 {
+        Vec3::all(0.0)
+    }
+    // Whether emitted can be anything but black. Objects made of these get
+    // sampled as lights
+    fn is_light(&self) -> bool {
+        false
+    }
+    // The BSDF times the cosine at the surface, for light arriving from the
+    // unit vector direction and leaving back along the ray. Black for
+    // materials like mirrors and glass that only scatter into single
+    // directions, those can't be lit by sampling lights
+    fn bsdf(&self, _ray: &Ray, _rec: &HitRecord, _direction: &Vec3) -> Vec3 {
+        Vec3::all(0.0)
+    }
+    // Density per unit solid angle of scatter picking the unit vector
+    // direction. Zero for single direction materials
+    fn pdf(&self, _ray: &Ray, _rec: &HitRecord, _direction: &Vec3) -> f32 {
+        0.0
+    }
     fn copy(&self) -> Box<dyn Material>;
 }
 
@@ -37,68 +61,252 @@ fn schlick(cosine: f32, ref_idx: f32) -> f32 {
     r0 * r0 + ( 1.0 - r0 ) * ( 1.0 - cosine ).powf(5.0)
 }
 
+// The normal flipped onto the side the ray came from, so both sides of a
+// triangle reflect
+fn facing_normal(ray: &Ray, rec: &HitRecord) -> Vec3 {
+    if dot_product(&ray.direction, &rec.normal) > 0.0 {
+        rec.normal.neg()
+    } else {
+        rec.normal.copy()
+    }
+}
+
+// Bounces off in a random direction around the normal, tinted by albedo
+// Directions are cosine weighted, which cancels out the BSDF's cosine so
+// the attenuation is just the albedo
+fn diffuse_scatter(ray: &Ray, rec: &HitRecord, albedo: Vec3, attenuation: &mut Vec3, scattered: &mut Ray, sampler: &mut dyn Sampler) -> bool {
+    let n = facing_normal(ray, rec);
+    let (t, b) = orthonormal_basis(&n);
+    let (u1, u2) = sampler.next_2d();
+    let r = u1.sqrt();
+    let phi = 2.0 * std::f32::consts::PI * u2;
+    scattered.origin = rec.p.copy();
+    scattered.direction = t.mul(r * phi.cos()).add_by_vec(&b.mul(r * phi.sin())).add_by_vec(&n.mul((1.0 - u1).max(0.0).sqrt()));
+    *attenuation = albedo;
+    true
+}
+
+fn diffuse_pdf(ray: &Ray, rec: &HitRecord, direction: &Vec3) -> f32 {
+    dot_product(&facing_normal(ray, rec), direction).max(0.0) / std::f32::consts::PI
+}
+
 pub struct Lambertian {
-    pub albedo: Vec3
+    pub albedo: Arc<dyn Texture>
 }
 
 impl Lambertian {
     pub fn new(albedo: Vec3) -> Lambertian {
+        Lambertian::textured(Arc::new(ConstantTexture::new(albedo)))
+    }
+    pub fn textured(albedo: Arc<dyn Texture>) -> Lambertian {
         Lambertian {
-            albedo: albedo
+            albedo
         }
     }
 }
 
 impl Material for Lambertian {
-    fn scatter(&self, _ray: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray) -> bool {
-        let target = rec.p.add_by_vec(&rec.normal).add_by_vec(&random_in_unit_sphere());
-        scattered.origin = rec.p.copy();
-        scattered.direction = target.sub_by_vec(&rec.p);
-        attenuation.x = self.albedo.x;
-        attenuation.y = self.albedo.y;
-        attenuation.z = self.albedo.z;
-        return true;
+    fn scatter(&self, ray: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray, sampler: &mut dyn Sampler) -> bool {
+        diffuse_scatter(ray, rec, self.albedo.value(rec.u, rec.v, &rec.p), attenuation, scattered, sampler)
+    }
+    fn bsdf(&self, ray: &Ray, rec: &HitRecord, direction: &Vec3) -> Vec3 {
+        self.albedo.value(rec.u, rec.v, &rec.p).mul(diffuse_pdf(ray, rec, direction))
+    }
+    fn pdf(&self, ray: &Ray, rec: &HitRecord, direction: &Vec3) -> f32 {
+        diffuse_pdf(ray, rec, direction)
     }
     fn copy(&self) -> Box<dyn Material> {
-        Box::new(Lambertian { albedo: self.albedo.copy() })
+        Box::new(Lambertian { albedo: Arc::clone(&self.albedo) })
     }
 }
 
 pub struct Metal {
-    pub albedo: Vec3,
+    pub albedo: Arc<dyn Texture>,
     pub fuzz: f32
 }
 
 impl Metal {
     pub fn new(albedo: Vec3, fuzz: f32) -> Metal {
+        Metal::textured(Arc::new(ConstantTexture::new(albedo)), fuzz)
+    }
+    pub fn textured(albedo: Arc<dyn Texture>, fuzz: f32) -> Metal {
         Metal {
-            albedo: albedo,
-            fuzz: fuzz
+            albedo,
+            fuzz
         }
     }
 }
 
 impl Material for Metal {
-    fn scatter(&self, ray: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray) -> bool {
+    fn scatter(&self, ray: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray, sampler: &mut dyn Sampler) -> bool {
         let reflected = reflect(&ray.direction.as_unit(), &rec.normal);
         scattered.origin = rec.p.copy();
-        scattered.direction = reflected.add_by_vec(&random_in_unit_sphere().mul(self.fuzz));
-        attenuation.x = self.albedo.x;
-        attenuation.y = self.albedo.y;
-        attenuation.z = self.albedo.z;
+        scattered.direction = reflected.add_by_vec(&random_in_unit_sphere(sampler).mul(self.fuzz));
+        *attenuation = self.albedo.value(rec.u, rec.v, &rec.p);
         dot_product(&scattered.direction, &rec.normal) > 0.0
     }
     fn copy(&self) -> Box<dyn Material> {
-        Box::new(Metal { albedo: self.albedo.copy(), fuzz: self.fuzz })
+        Box::new(Metal { albedo: Arc::clone(&self.albedo), fuzz: self.fuzz })
     }
 }
 
+// Complex indices of refraction (eta + ik) for red, green and blue
+pub const GOLD: ([f32; 3], [f32; 3]) = ([0.143, 0.374, 1.442], [3.983, 2.385, 1.603]);
+pub const COPPER: ([f32; 3], [f32; 3]) = ([0.200, 0.924, 1.102], [3.912, 2.452, 2.142]);
+pub const ALUMINIUM: ([f32; 3], [f32; 3]) = ([1.657, 0.880, 0.521], [9.224, 6.270, 4.837]);
+pub const SILVER: ([f32; 3], [f32; 3]) = ([0.155, 0.117, 0.138], [4.828, 3.122, 2.147]);
+
+// A metal with GGX microfacets. Its color comes from the Fresnel term of
+// its complex index of refraction, so it tints towards white at grazing
+// angles like real metals. Rough ones reflect as much light as they get,
+// apart from what facets shadow from each other
+pub struct Conductor {
+    pub eta: Vec3,
+    pub k: Vec3,
+    pub distribution: Ggx
+}
+
+impl Conductor {
+    pub fn new(eta: Vec3, k: Vec3, distribution: Ggx) -> Conductor {
+        Conductor {
+            eta,
+            k,
+            distribution
+        }
+    }
+    fn fresnel(&self, cos: f32) -> Vec3 {
+        Vec3::new(
+            fresnel_conductor(cos, self.eta.x, self.k.x),
+            fresnel_conductor(cos, self.eta.y, self.k.y),
+            fresnel_conductor(cos, self.eta.z, self.k.z)
+        )
+    }
+}
+
+// The frame at the hit, on the side the ray came from, and the direction
+// back along the ray in it
+fn shading_frame(ray: &Ray, rec: &HitRecord) -> (Frame, Vec3) {
+    let frame = Frame::new(facing_normal(ray, rec), &rec.tangent);
+    let wo = frame.to_local(&ray.direction.as_unit().neg());
+    (frame, wo)
+}
+
+impl Material for Conductor {
+    fn scatter(&self, ray: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray, sampler: &mut dyn Sampler) -> bool {
+        let (frame, wo) = shading_frame(ray, rec);
+        if wo.z <= 0.0 {
+            return false;
+        }
+        scattered.origin = rec.p.copy();
+        if self.distribution.is_smooth() {
+            scattered.direction = frame.to_world(&Vec3::new(-wo.x, -wo.y, wo.z));
+            *attenuation = self.fresnel(wo.z);
+            return true;
+        }
+        let (u1, u2) = sampler.next_2d();
+        let h = self.distribution.sample_visible(&wo, u1, u2);
+        let wi = reflect_about(&wo, &h);
+        if wi.z <= 0.0 {
+            return false;
+        }
+        scattered.direction = frame.to_world(&wi);
+        // The facet was picked by how much of it wo sees, which leaves the
+        // share of it wi sees too
+        let d = &self.distribution;
+        *attenuation = self.fresnel(wo.dot(&h)).mul(d.g(&wo, &wi) / d.g1(&wo));
+        true
+    }
+    fn bsdf(&self, ray: &Ray, rec: &HitRecord, direction: &Vec3) -> Vec3 {
+        if self.distribution.is_smooth() {
+            return Vec3::all(0.0);
+        }
+        let (frame, wo) = shading_frame(ray, rec);
+        let wi = frame.to_local(direction);
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return Vec3::all(0.0);
+        }
+        let h = wo.add_by_vec(&wi).as_unit();
+        let d = &self.distribution;
+        // The cosine at wi cancels out of F D G / (4 cos_o cos_i)
+        self.fresnel(wo.dot(&h)).mul(d.d(&h) * d.g(&wo, &wi) / (4.0 * wo.z))
+    }
+    fn pdf(&self, ray: &Ray, rec: &HitRecord, direction: &Vec3) -> f32 {
+        if self.distribution.is_smooth() {
+            return 0.0;
+        }
+        let (frame, wo) = shading_frame(ray, rec);
+        let wi = frame.to_local(direction);
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return 0.0;
+        }
+        let h = wo.add_by_vec(&wi).as_unit();
+        // Reflecting turns the facet density into one for directions
+        self.distribution.pdf_visible(&wo, &h) / (4.0 * wo.dot(&h))
+    }
+    fn copy(&self) -> Box<dyn Material> {
+        Box::new(Conductor {
+            eta: self.eta.copy(),
+            k: self.k.copy(),
+            distribution: self.distribution
+        })
+    }
+}
+
+// Glass, water and the like. Rough ones blur what's seen through them with
+// GGX facets, following Walter et al.'s "Microfacet Models for Refraction
+// through Rough Surfaces". Light loses absorption per unit of distance it
+// travels inside, so thick parts get tinted more than thin ones. Thin ones
+// are a single sheet like a window or a bubble, that light goes straight
+// through without bending
+//
+// Radiance going through gets squeezed into a narrower cone on the denser
+// side, so it's scaled by the square of the ratio of the indices. Going in
+// and back out cancels that out, so closed objects look the same either way
 pub struct Dielectric {
-    pub ref_idx: f32 // index of refraction
+    pub ref_idx: f32, // index of refraction
+    pub distribution: Ggx,
+    pub absorption: Vec3,
+    pub thin: bool
 }
 
-impl Material for Dielectric {
-    fn scatter(&self, ray: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray) -> bool {
+impl Dielectric {
+    // Smooth and clear
+    pub fn new(ref_idx: f32) -> Dielectric {
+        Dielectric {
+            ref_idx,
+            distribution: Ggx::from_roughness(0.0, 0.0),
+            absorption: Vec3::all(0.0),
+            thin: false
+        }
+    }
+    fn is_absorbing(&self) -> bool {
+        self.absorption.x > 0.0 || self.absorption.y > 0.0 || self.absorption.z > 0.0
+    }
+    // How much light is left after coming to rec from inside, since the
+    // ray started where it got in
+    fn absorbed(&self, ray: &Ray, rec: &HitRecord) -> Vec3 {
+        if !self.is_absorbing() || dot_product(&ray.direction, &rec.normal) <= 0.0 {
+            return Vec3::all(1.0);
+        }
+        let distance = rec.t * ray.direction.magnitude();
+        Vec3::new((-self.absorption.x * distance).exp(), (-self.absorption.y * distance).exp(), (-self.absorption.z * distance).exp())
+    }
+    // Index on the far side of the surface over the one on the ray's side
+    fn eta(&self, ray: &Ray, rec: &HitRecord) -> f32 {
+        if dot_product(&ray.direction, &rec.normal) > 0.0 { 1.0 / self.ref_idx } else { self.ref_idx }
+    }
+    // How much radiance changes by going out along direction, 1 / eta^2 if
+    // that's through the surface. Thin sheets put it back the way it was
+    fn spreading(&self, ray: &Ray, rec: &HitRecord, direction: &Vec3) -> f32 {
+        if !self.thin && (dot_product(&ray.direction, &rec.normal) > 0.0) == (dot_product(direction, &rec.normal) > 0.0) {
+            let eta = self.eta(ray, rec);
+            1.0 / (eta * eta)
+        } else {
+            1.0
+        }
+    }
+    #[allow(clippy::needless_late_init)]
+    fn smooth_scatter(&self, ray: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray, sampler: &mut dyn Sampler) {
         let outward_normal: Vec3;
         let reflected = reflect(&ray.direction, &rec.normal);
         let ni_over_nt: f32;
@@ -108,7 +316,6 @@ impl Material for Dielectric {
         let mut refracted = Vec3::all(0.0);
         let reflect_prob: f32;
         let cosine: f32;
-        let mut rng = rand::thread_rng();
         if dot_product(&ray.direction, &rec.normal) > 0.0 {
             outward_normal = rec.normal.neg();
             ni_over_nt = self.ref_idx;
@@ -123,16 +330,843 @@ impl Material for Dielectric {
         } else {
             reflect_prob = 1.0;
         }
-        if rng.gen::<f32>() < reflect_prob {
+        if sampler.next_f32() < reflect_prob {
             scattered.origin = rec.p.copy();
             scattered.direction = reflected.copy();
         } else {
             scattered.origin = rec.p.copy();
             scattered.direction = refracted.copy();
         }
-        return true;
+    }
+    // Light bounces back and forth inside the sheet, and all of what comes
+    // out on the near side counts as reflected
+    fn thin_scatter(&self, ray: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray, sampler: &mut dyn Sampler) {
+        let cos = dot_product(&ray.direction, &rec.normal).abs() / ray.direction.magnitude();
+        let mut reflect_prob = fresnel_dielectric(cos, self.ref_idx);
+        if reflect_prob < 1.0 {
+            let t = 1.0 - reflect_prob;
+            reflect_prob += t * t * reflect_prob / (1.0 - reflect_prob * reflect_prob);
+        }
+        scattered.origin = rec.p.copy();
+        scattered.direction = if sampler.next_f32() < reflect_prob {
+            reflect(&ray.direction, &rec.normal)
+        } else {
+            ray.direction.copy()
+        };
+        *attenuation = Vec3::all(1.0);
+    }
+    // A direction off a facet picked by how much wo sees of it, in the
+    // frame on wo's side
+    fn sample_rough(&self, wo: &Vec3, eta: f32, sampler: &mut dyn Sampler) -> Option<Vec3> {
+        let (u1, u2) = sampler.next_2d();
+        let h = self.distribution.sample_visible(wo, u1, u2);
+        // Reflecting or going through with the chances Fresnel gives them,
+        // which cancel out of the weight
+        let reflected = sampler.next_f32() < fresnel_dielectric(wo.dot(&h), eta);
+        let wi = if reflected { reflect_about(wo, &h) } else { refract_about(wo, &h, eta)? };
+        // Facets can send light out the wrong side of the surface itself
+        if (wi.z > 0.0) != reflected || wi.z == 0.0 {
+            return None;
+        }
+        Some(wi)
+    }
+    // The BSDF times the cosine at wi and the density of picking wi, both
+    // in the frame on wo's side
+    fn rough_eval(&self, wo: &Vec3, wi: &Vec3, eta: f32) -> (f32, f32) {
+        let d = &self.distribution;
+        if wi.z > 0.0 {
+            let h = wo.add_by_vec(wi).as_unit();
+            let cos_o = wo.dot(&h);
+            if cos_o <= 0.0 || wi.dot(&h) <= 0.0 {
+                return (0.0, 0.0);
+            }
+            let f = fresnel_dielectric(cos_o, eta);
+            return (f * d.d(&h) * d.g(wo, wi) / (4.0 * wo.z), f * d.pdf_visible(wo, &h) / (4.0 * cos_o));
+        }
+        // The facet that bends wo into wi
+        let h = wo.add_by_vec(&wi.mul(eta));
+        if h.squared_length() == 0.0 {
+            return (0.0, 0.0);
+        }
+        let h = if h.z < 0.0 { h.as_unit().neg() } else { h.as_unit() };
+        let (cos_o, cos_i) = (wo.dot(&h), wi.dot(&h));
+        if cos_o <= 0.0 || cos_i >= 0.0 {
+            return (0.0, 0.0);
+        }
+        let t = 1.0 - fresnel_dielectric(cos_o, eta);
+        let denom = (cos_i + cos_o / eta) * (cos_i + cos_o / eta);
+        let value = t * d.d(&h) * d.g(wo, wi) * (cos_i * cos_o).abs() / (wo.z * denom);
+        (value / (eta * eta), t * d.pdf_visible(wo, &h) * cos_i.abs() / denom)
+    }
+}
+
+impl Material for Dielectric {
+    fn scatter(&self, ray: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray, sampler: &mut dyn Sampler) -> bool {
+        if self.thin {
+            self.thin_scatter(ray, rec, attenuation, scattered, sampler);
+            return true;
+        }
+        if self.distribution.is_smooth() {
+            self.smooth_scatter(ray, rec, attenuation, scattered, sampler);
+            *attenuation = self.absorbed(ray, rec).mul(self.spreading(ray, rec, &scattered.direction));
+            return true;
+        }
+        let (frame, wo) = shading_frame(ray, rec);
+        if wo.z <= 0.0 {
+            return false;
+        }
+        let wi = match self.sample_rough(&wo, self.eta(ray, rec), sampler) {
+            Some(wi) => wi,
+            None => return false
+        };
+        scattered.origin = rec.p.copy();
+        scattered.direction = frame.to_world(&wi);
+        let d = &self.distribution;
+        *attenuation = self.absorbed(ray, rec).mul(d.g(&wo, &wi) / d.g1(&wo) * self.spreading(ray, rec, &scattered.direction));
+        true
+    }
+    fn bsdf(&self, ray: &Ray, rec: &HitRecord, direction: &Vec3) -> Vec3 {
+        if self.thin || self.distribution.is_smooth() {
+            return Vec3::all(0.0);
+        }
+        let (frame, wo) = shading_frame(ray, rec);
+        if wo.z <= 0.0 {
+            return Vec3::all(0.0);
+        }
+        let (value, _) = self.rough_eval(&wo, &frame.to_local(direction), self.eta(ray, rec));
+        self.absorbed(ray, rec).mul(value)
+    }
+    fn pdf(&self, ray: &Ray, rec: &HitRecord, direction: &Vec3) -> f32 {
+        if self.thin || self.distribution.is_smooth() {
+            return 0.0;
+        }
+        let (frame, wo) = shading_frame(ray, rec);
+        if wo.z <= 0.0 {
+            return 0.0;
+        }
+        self.rough_eval(&wo, &frame.to_local(direction), self.eta(ray, rec)).1
+    }
+    fn copy(&self) -> Box<dyn Material> {
+        Box::new(Dielectric {
+            ref_idx: self.ref_idx,
+            distribution: self.distribution,
+            absorption: self.absorption.copy(),
+            thin: self.thin
+        })
+    }
+}
+
+// Smoothest a principled lobe gets. They can't be perfect mirrors, since
+// every lobe has to have a density to be mixed with the others
+const PRINCIPLED_MIN_ALPHA: f32 = 2e-3;
+
+// Burley's principled material from Disney, covering plastic, metal, glass
+// and most things between with one set of parameters from 0 to 1. It's
+// the same metallic-roughness model glTF uses, with specular 0.5 being the
+// usual 4% reflection. Each bounce picks a lobe by how much it's weighted,
+// and is then weighed by the density of all of them together
+pub struct Principled {
+    pub base_color: Arc<dyn Texture>,
+    // Multiplies base_color, for meshes with colors at their vertices
+    pub vertex_colors: Option<[Vec3; 3]>,
+    pub metallic: f32,
+    pub distribution: Ggx,
+    pub specular: f32,
+    // How much non-metal reflections take on base_color's hue
+    pub specular_tint: f32,
+    // Soft glow at grazing angles, like on cloth
+    pub sheen: f32,
+    pub sheen_tint: f32,
+    // A second, clear layer of varnish on top
+    pub clearcoat: f32,
+    pub clearcoat_gloss: f32,
+    // How much of the non-metal part is glass instead of diffuse
+    pub transmission: f32,
+    pub ior: f32
+}
+
+// Lobes in the order they're weighted and picked
+const DIFFUSE_LOBE: usize = 0;
+const SPECULAR_LOBE: usize = 1;
+const GLASS_LOBE: usize = 2;
+const CLEARCOAT_LOBE: usize = 3;
+
+impl Principled {
+    // Plain plastic with roughness 0.5, other parameters at Disney's defaults
+    pub fn new(base_color: Arc<dyn Texture>) -> Principled {
+        Principled {
+            base_color,
+            vertex_colors: None,
+            metallic: 0.0,
+            distribution: Ggx::from_roughness(0.5, 0.5),
+            specular: 0.5,
+            specular_tint: 0.0,
+            sheen: 0.0,
+            sheen_tint: 0.5,
+            clearcoat: 0.0,
+            clearcoat_gloss: 1.0,
+            transmission: 0.0,
+            ior: 1.5
+        }
+    }
+    // Kept just short of a mirror, see PRINCIPLED_MIN_ALPHA
+    pub fn set_roughness(&mut self, distribution: Ggx) {
+        self.distribution = Ggx {
+            alpha_x: distribution.alpha_x.max(PRINCIPLED_MIN_ALPHA),
+            alpha_y: distribution.alpha_y.max(PRINCIPLED_MIN_ALPHA)
+        };
+    }
+    fn base(&self, rec: &HitRecord) -> Vec3 {
+        let color = self.base_color.value(rec.u, rec.v, &rec.p);
+        match &self.vertex_colors {
+            Some([c0, c1, c2]) => {
+                let blended = c0.mul(1.0 - rec.b1 - rec.b2).add_by_vec(&c1.mul(rec.b1)).add_by_vec(&c2.mul(rec.b2));
+                color.mul_by_vec(&blended)
+            },
+            None => color
+        }
+    }
+    fn weights(&self) -> [f32; 4] {
+        let dielectric = 1.0 - self.metallic;
+        [
+            dielectric * (1.0 - self.transmission),
+            1.0 - self.transmission * dielectric,
+            dielectric * self.transmission,
+            0.25 * self.clearcoat
+        ]
+    }
+    fn glass(&self) -> Dielectric {
+        let mut glass = Dielectric::new(self.ior);
+        glass.distribution = self.distribution;
+        glass
+    }
+    fn clearcoat_distribution(&self) -> Gtr1 {
+        Gtr1 { alpha: 0.1 + (0.001 - 0.1) * self.clearcoat_gloss }
+    }
+    // The BSDF times the cosine at wi, and the density of picking wi, for
+    // all the lobes together in the frame on wo's side
+    fn eval(&self, wo: &Vec3, wi: &Vec3, eta: f32, base: &Vec3) -> (Vec3, f32) {
+        let weights = self.weights();
+        let total: f32 = weights.iter().sum();
+        let mut f = Vec3::all(0.0);
+        let mut pdf = 0.0;
+        let luminance = 0.2126 * base.x + 0.7152 * base.y + 0.0722 * base.z;
+        let hue = if luminance > 0.0 { base.div(luminance) } else { Vec3::all(1.0) };
+        let tinted = |amount: f32| Vec3::all(1.0 - amount).add_by_vec(&hue.mul(amount));
+        if wi.z > 0.0 {
+            let h = wo.add_by_vec(wi).as_unit();
+            let (cos_o, cos_i, cos_d) = (wo.z, wi.z, wi.dot(&h));
+            if weights[DIFFUSE_LOBE] > 0.0 {
+                // Burley's diffuse, darker at grazing angles when smooth
+                // and brighter when rough
+                let roughness = (self.distribution.alpha_x * self.distribution.alpha_y).sqrt().sqrt();
+                let fd90 = 0.5 + 2.0 * roughness * cos_d * cos_d;
+                let fl = 1.0 + (fd90 - 1.0) * (1.0 - cos_i).powi(5);
+                let fv = 1.0 + (fd90 - 1.0) * (1.0 - cos_o).powi(5);
+                let sheen = tinted(self.sheen_tint).mul(self.sheen * (1.0 - cos_d).max(0.0).powi(5));
+                let diffuse = base.mul(fl * fv / std::f32::consts::PI).add_by_vec(&sheen);
+                f.add_by_vec_eq(&diffuse.mul(weights[DIFFUSE_LOBE] * cos_i));
+                pdf += weights[DIFFUSE_LOBE] / total * cos_i / std::f32::consts::PI;
+            }
+            let cos_h = wo.dot(&h);
+            if weights[SPECULAR_LOBE] > 0.0 && cos_h > 0.0 {
+                let d = &self.distribution;
+                let f0 = tinted(self.specular_tint).mul(0.08 * self.specular * (1.0 - self.metallic)).add_by_vec(&base.mul(self.metallic));
+                let specular = schlick_fresnel(&f0, cos_d).mul(d.d(&h) * d.g(wo, wi) / (4.0 * cos_o));
+                f.add_by_vec_eq(&specular.mul(weights[SPECULAR_LOBE]));
+                pdf += weights[SPECULAR_LOBE] / total * d.pdf_visible(wo, &h) / (4.0 * cos_h);
+            }
+            if weights[CLEARCOAT_LOBE] > 0.0 && cos_h > 0.0 {
+                let d = self.clearcoat_distribution().d(&h);
+                let g = Ggx::from_roughness(0.5, 0.5).g(wo, wi);
+                let coat = schlick_fresnel(&Vec3::all(0.04), cos_d).mul(d * g / (4.0 * cos_o));
+                f.add_by_vec_eq(&coat.mul(weights[CLEARCOAT_LOBE]));
+                pdf += weights[CLEARCOAT_LOBE] / total * d * h.z / (4.0 * cos_h);
+            }
+        }
+        if weights[GLASS_LOBE] > 0.0 {
+            let (value, glass_pdf) = self.glass().rough_eval(wo, wi, eta);
+            // Light going through takes on the base color
+            let glass = if wi.z < 0.0 { base.mul(value) } else { Vec3::all(value) };
+            f.add_by_vec_eq(&glass.mul(weights[GLASS_LOBE]));
+            pdf += weights[GLASS_LOBE] / total * glass_pdf;
+        }
+        (f, pdf)
+    }
+}
+
+impl Material for Principled {
+    fn scatter(&self, ray: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray, sampler: &mut dyn Sampler) -> bool {
+        let (frame, wo) = shading_frame(ray, rec);
+        if wo.z <= 0.0 {
+            return false;
+        }
+        let weights = self.weights();
+        let mut pick = sampler.next_f32() * weights.iter().sum::<f32>();
+        let mut lobe = 0;
+        while lobe < CLEARCOAT_LOBE && (pick >= weights[lobe] || weights[lobe] == 0.0) {
+            pick -= weights[lobe];
+            lobe += 1;
+        }
+        let glass = self.glass();
+        let wi = match lobe {
+            DIFFUSE_LOBE => {
+                let (u1, u2) = sampler.next_2d();
+                let r = u1.sqrt();
+                let phi = 2.0 * std::f32::consts::PI * u2;
+                Vec3::new(r * phi.cos(), r * phi.sin(), (1.0 - u1).max(0.0).sqrt())
+            },
+            SPECULAR_LOBE => {
+                let (u1, u2) = sampler.next_2d();
+                reflect_about(&wo, &self.distribution.sample_visible(&wo, u1, u2))
+            },
+            GLASS_LOBE => match glass.sample_rough(&wo, glass.eta(ray, rec), sampler) {
+                Some(wi) => wi,
+                None => return false
+            },
+            _ => {
+                let (u1, u2) = sampler.next_2d();
+                let h = self.clearcoat_distribution().sample(u1, u2);
+                // Unlike the specular lobe's, these facets can face away
+                if wo.dot(&h) <= 0.0 {
+                    return false;
+                }
+                reflect_about(&wo, &h)
+            }
+        };
+        // Reflections have to stay on wo's side, or their density would be
+        // counted as the glass lobe's
+        if wi.z == 0.0 || (lobe != GLASS_LOBE && wi.z < 0.0) {
+            return false;
+        }
+        let base = self.base(rec);
+        let (f, pdf) = self.eval(&wo, &wi, glass.eta(ray, rec), &base);
+        if pdf <= 0.0 {
+            return false;
+        }
+        scattered.origin = rec.p.copy();
+        scattered.direction = frame.to_world(&wi);
+        *attenuation = f.div(pdf);
+        true
+    }
+    fn bsdf(&self, ray: &Ray, rec: &HitRecord, direction: &Vec3) -> Vec3 {
+        let (frame, wo) = shading_frame(ray, rec);
+        if wo.z <= 0.0 {
+            return Vec3::all(0.0);
+        }
+        self.eval(&wo, &frame.to_local(direction), self.glass().eta(ray, rec), &self.base(rec)).0
+    }
+    fn pdf(&self, ray: &Ray, rec: &HitRecord, direction: &Vec3) -> f32 {
+        let (frame, wo) = shading_frame(ray, rec);
+        if wo.z <= 0.0 {
+            return 0.0;
+        }
+        self.eval(&wo, &frame.to_local(direction), self.glass().eta(ray, rec), &self.base(rec)).1
+    }
+    fn copy(&self) -> Box<dyn Material> {
+        Box::new(Principled {
+            base_color: Arc::clone(&self.base_color),
+            vertex_colors: self.vertex_colors.as_ref().map(|[a, b, c]| [a.copy(), b.copy(), c.copy()]),
+            metallic: self.metallic,
+            distribution: self.distribution,
+            specular: self.specular,
+            specular_tint: self.specular_tint,
+            sheen: self.sheen,
+            sheen_tint: self.sheen_tint,
+            clearcoat: self.clearcoat,
+            clearcoat_gloss: self.clearcoat_gloss,
+            transmission: self.transmission,
+            ior: self.ior
+        })
+    }
+}
+
+// Diffuse, with the albedo blended from colors at a triangle's corners
+// using the barycentric weights the triangle leaves in the hit record
+pub struct VertexColors {
+    pub colors: [Vec3; 3]
+}
+
+impl VertexColors {
+    fn albedo(&self, rec: &HitRecord) -> Vec3 {
+        let [c0, c1, c2] = &self.colors;
+        c0.mul(1.0 - rec.b1 - rec.b2).add_by_vec(&c1.mul(rec.b1)).add_by_vec(&c2.mul(rec.b2))
+    }
+}
+
+impl Material for VertexColors {
+    fn scatter(&self, ray: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray, sampler: &mut dyn Sampler) -> bool {
+        diffuse_scatter(ray, rec, self.albedo(rec), attenuation, scattered, sampler)
+    }
+    fn bsdf(&self, ray: &Ray, rec: &HitRecord, direction: &Vec3) -> Vec3 {
+        self.albedo(rec).mul(diffuse_pdf(ray, rec, direction))
+    }
+    fn pdf(&self, ray: &Ray, rec: &HitRecord, direction: &Vec3) -> f32 {
+        diffuse_pdf(ray, rec, direction)
+    }
+    fn copy(&self) -> Box<dyn Material> {
+        Box::new(VertexColors { colors: [self.colors[0].copy(), self.colors[1].copy(), self.colors[2].copy()] })
+    }
+}
+
+// Glows with color times intensity and doesn't reflect anything. Only the
+// side the normal points out of lights up, so a triangle's winding decides
+// which way it shines
+pub struct DiffuseLight {
+    pub emit: Arc<dyn Texture>,
+    pub intensity: f32
+}
+
+impl DiffuseLight {
+    pub fn new(color: Vec3, intensity: f32) -> DiffuseLight {
+        DiffuseLight::textured(Arc::new(ConstantTexture::new(color)), intensity)
+    }
+    pub fn textured(emit: Arc<dyn Texture>, intensity: f32) -> DiffuseLight {
+        DiffuseLight {
+            emit,
+            intensity
+        }
+    }
+}
+
+impl Material for DiffuseLight {
+    fn scatter(&self, _ray: &Ray, _rec: &HitRecord, _attenuation: &mut Vec3, _scattered: &mut Ray, _sampler: &mut dyn Sampler) -> bool {
+        false
+    }
+    fn emitted(&self, ray: &Ray, rec: &HitRecord) -> Vec3 {
+        if dot_product(&ray.direction, &rec.normal) < 0.0 {
+            self.emit.value(rec.u, rec.v, &rec.p).mul(self.intensity)
+        } else {
+            Vec3::all(0.0)
+        }
+    }
+    fn is_light(&self) -> bool {
+        self.intensity > 0.0
+    }
+    fn copy(&self) -> Box<dyn Material> {
+        Box::new(DiffuseLight { emit: Arc::clone(&self.emit), intensity: self.intensity })
+    }
+}
+
+// Wraps the material of a light that can't be sampled, like one that moves
+// while the shutter is open. It still glows, but rays that happen to hit
+// it get all of its light instead of sharing it with light sampling
+pub struct UnsampledLight(pub Box<dyn Material>);
+
+impl Material for UnsampledLight {
+    fn scatter(&self, ray: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray, sampler: &mut dyn Sampler) -> bool {
+        self.0.scatter(ray, rec, attenuation, scattered, sampler)
+    }
+    fn emitted(&self, ray: &Ray, rec: &HitRecord) -> Vec3 {
+        self.0.emitted(ray, rec)
+    }
+    fn bsdf(&self, ray: &Ray, rec: &HitRecord, direction: &Vec3) -> Vec3 {
+        self.0.bsdf(ray, rec, direction)
+    }
+    fn pdf(&self, ray: &Ray, rec: &HitRecord, direction: &Vec3) -> f32 {
+        self.0.pdf(ray, rec, direction)
+    }
+    fn copy(&self) -> Box<dyn Material> {
+        Box::new(UnsampledLight(self.0.copy()))
+    }
+}
+
+// Phase functions, for what's inside a ConstantMedium. They don't care
+// about normals, only how far a ray gets turned from where it was going,
+// and unlike surfaces there's no cosine to weigh light by
+
+// Henyey-Greenstein density for turning by an angle with cosine cos.
+// g = 0 scatters evenly, towards 1 mostly forwards and towards -1 back
+fn henyey_greenstein(cos: f32, g: f32) -> f32 {
+    let denom = 1.0 + g * g - 2.0 * g * cos;
+    (1.0 - g * g) / (4.0 * std::f32::consts::PI * denom * denom.max(0.0).sqrt())
+}
+
+// A direction picked with density henyey_greenstein around forward, which
+// has to be unit length
+fn sample_henyey_greenstein(forward: &Vec3, g: f32, sampler: &mut dyn Sampler) -> Vec3 {
+    let (u1, u2) = sampler.next_2d();
+    let cos = if g.abs() < 1e-3 {
+        1.0 - 2.0 * u1
+    } else {
+        let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * u1);
+        ((1.0 + g * g - s * s) / (2.0 * g)).clamp(-1.0, 1.0)
+    };
+    let sin = (1.0 - cos * cos).max(0.0).sqrt();
+    let phi = 2.0 * std::f32::consts::PI * u2;
+    let (t, b) = orthonormal_basis(forward);
+    t.mul(sin * phi.cos()).add_by_vec(&b.mul(sin * phi.sin())).add_by_vec(&forward.mul(cos))
+}
+
+// Scatters the same amount in every direction, tinted by albedo
+pub struct Isotropic {
+    pub albedo: Arc<dyn Texture>
+}
+
+impl Isotropic {
+    pub fn new(albedo: Vec3) -> Isotropic {
+        Isotropic::textured(Arc::new(ConstantTexture::new(albedo)))
+    }
+    pub fn textured(albedo: Arc<dyn Texture>) -> Isotropic {
+        Isotropic {
+            albedo
+        }
+    }
+}
+
+impl Material for Isotropic {
+    fn scatter(&self, ray: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray, sampler: &mut dyn Sampler) -> bool {
+        scattered.origin = rec.p.copy();
+        scattered.direction = sample_henyey_greenstein(&ray.direction.as_unit(), 0.0, sampler);
+        *attenuation = self.albedo.value(rec.u, rec.v, &rec.p);
+        true
+    }
+    fn bsdf(&self, _ray: &Ray, rec: &HitRecord, _direction: &Vec3) -> Vec3 {
+        self.albedo.value(rec.u, rec.v, &rec.p).mul(1.0 / (4.0 * std::f32::consts::PI))
+    }
+    fn pdf(&self, _ray: &Ray, _rec: &HitRecord, _direction: &Vec3) -> f32 {
+        1.0 / (4.0 * std::f32::consts::PI)
+    }
+    fn copy(&self) -> Box<dyn Material> {
+        Box::new(Isotropic { albedo: Arc::clone(&self.albedo) })
+    }
+}
+
+// Scatters mostly forwards for positive anisotropy, like haze and clouds,
+// or mostly back for negative anisotropy. Anisotropy has to be strictly
+// between -1 and 1
+pub struct HenyeyGreenstein {
+    pub albedo: Arc<dyn Texture>,
+    pub anisotropy: f32
+}
+
+impl HenyeyGreenstein {
+    pub fn new(albedo: Vec3, anisotropy: f32) -> HenyeyGreenstein {
+        HenyeyGreenstein::textured(Arc::new(ConstantTexture::new(albedo)), anisotropy)
+    }
+    pub fn textured(albedo: Arc<dyn Texture>, anisotropy: f32) -> HenyeyGreenstein {
+        HenyeyGreenstein {
+            albedo,
+            anisotropy
+        }
+    }
+}
+
+impl Material for HenyeyGreenstein {
+    fn scatter(&self, ray: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray, sampler: &mut dyn Sampler) -> bool {
+        scattered.origin = rec.p.copy();
+        scattered.direction = sample_henyey_greenstein(&ray.direction.as_unit(), self.anisotropy, sampler);
+        // Sampled exactly in proportion to the phase function, so only the
+        // albedo is left
+        *attenuation = self.albedo.value(rec.u, rec.v, &rec.p);
+        true
+    }
+    fn bsdf(&self, ray: &Ray, rec: &HitRecord, direction: &Vec3) -> Vec3 {
+        self.albedo.value(rec.u, rec.v, &rec.p).mul(self.pdf(ray, rec, direction))
+    }
+    fn pdf(&self, ray: &Ray, _rec: &HitRecord, direction: &Vec3) -> f32 {
+        henyey_greenstein(dot_product(&ray.direction.as_unit(), direction), self.anisotropy)
     }
     fn copy(&self) -> Box<dyn Material> {
-        Box::new(Dielectric { ref_idx: self.ref_idx })
+        Box::new(HenyeyGreenstein { albedo: Arc::clone(&self.albedo), anisotropy: self.anisotropy })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    // The sphere of directions gets split into cells evenly spaced in z and
+    // around it, so they all cover the same solid angle
+    const Z_CELLS: usize = 8;
+    const PHI_CELLS: usize = 8;
+
+    fn cell(w: &Vec3) -> usize {
+        let z = (((w.z + 1.0) / 2.0 * Z_CELLS as f32) as usize).min(Z_CELLS - 1);
+        let phi = ((w.y.atan2(w.x) / (2.0 * PI) + 0.5) * PHI_CELLS as f32) as usize;
+        z * PHI_CELLS + phi.min(PHI_CELLS - 1)
+    }
+
+    // A hit at the origin on a surface facing +z with its tangent along +x,
+    // and a ray arriving there from the unit vector from
+    fn setup(material: &dyn Material, from: &Vec3) -> (Ray, HitRecord) {
+        let mut rec = HitRecord::default();
+        rec.normal = Vec3::new(0.0, 0.0, 1.0);
+        rec.tangent = Vec3::new(1.0, 0.0, 0.0);
+        rec.material = material.copy();
+        (Ray::new(from.copy(), from.neg(), 0.0), rec)
     }
-}
\ No newline at end of file
+
+    // Scatters lots of times and checks it against bsdf and pdf: each
+    // attenuation has to be bsdf / pdf, directions have to land in each cell
+    // as often as pdf says, and the average attenuation has to be what
+    // integrating bsdf gives. Returns that average, which is how much of a
+    // white furnace's light comes back
+    fn check_sampling(material: &dyn Material, from: &Vec3) -> Vec3 {
+        let (ray, rec) = setup(material, from);
+        let mut sampler = RandomSampler::new(3);
+        let samples = 200000;
+        let mut sum = [0.0f64; 3];
+        let mut counts = [0usize; Z_CELLS * PHI_CELLS];
+        for _ in 0..samples {
+            let mut attenuation = Vec3::all(0.0);
+            let mut scattered = Ray::new(Vec3::all(0.0), Vec3::all(0.0), 0.0);
+            if !material.scatter(&ray, &rec, &mut attenuation, &mut scattered, &mut sampler) {
+                continue;
+            }
+            let w = scattered.direction.as_unit();
+            let pdf = material.pdf(&ray, &rec, &w);
+            assert!(pdf > 0.0, "scattered towards {:?} with a pdf of {}", (w.x, w.y, w.z), pdf);
+            let expected = material.bsdf(&ray, &rec, &w).div(pdf);
+            for (i, s) in sum.iter_mut().enumerate() {
+                assert!((attenuation[i] - expected[i]).abs() <= 2e-3 * expected[i].max(1.0),
+                    "attenuation {} where bsdf / pdf is {}", attenuation[i], expected[i]);
+                *s += attenuation[i] as f64;
+            }
+            counts[cell(&w)] += 1;
+        }
+
+        // Each cell gets integrated over a jittered grid, so narrow peaks
+        // don't slip between the points
+        let grid = 64;
+        let per_cell = grid * grid;
+        let cell_angle = 4.0 * PI as f64 / (Z_CELLS * PHI_CELLS) as f64;
+        let mut integrated = [0.0f64; 3];
+        for z in 0..Z_CELLS {
+            for phi in 0..PHI_CELLS {
+                let mut pdf = 0.0f64;
+                for j in 0..per_cell {
+                    let (u1, u2) = sampler.next_2d();
+                    let (u1, u2) = (((j / grid) as f32 + u1) / grid as f32, ((j % grid) as f32 + u2) / grid as f32);
+                    let cos = -1.0 + 2.0 * (z as f32 + u1) / Z_CELLS as f32;
+                    let angle = 2.0 * PI * ((phi as f32 + u2) / PHI_CELLS as f32 - 0.5);
+                    let sin = (1.0 - cos * cos).max(0.0).sqrt();
+                    let w = Vec3::new(sin * angle.cos(), sin * angle.sin(), cos);
+                    pdf += material.pdf(&ray, &rec, &w) as f64 * cell_angle / per_cell as f64;
+                    let bsdf = material.bsdf(&ray, &rec, &w);
+                    for (i, s) in integrated.iter_mut().enumerate() {
+                        *s += bsdf[i] as f64 * cell_angle / per_cell as f64;
+                    }
+                }
+                let found = counts[z * PHI_CELLS + phi] as f64 / samples as f64;
+                assert!((found - pdf).abs() < 0.002 + 0.03 * pdf, "cell {}, {}: sampled {} of the time, pdf integrates to {}", z, phi, found, pdf);
+            }
+        }
+        let albedo: Vec<f64> = sum.iter().map(|s| s / samples as f64).collect();
+        for i in 0..3 {
+            assert!((albedo[i] - integrated[i]).abs() < 0.005 + 0.02 * integrated[i], "sampling averaged {}, bsdf integrates to {}", albedo[i], integrated[i]);
+        }
+        Vec3::new(albedo[0] as f32, albedo[1] as f32, albedo[2] as f32)
+    }
+
+    fn close(a: &Vec3, b: &Vec3, tolerance: f32) -> bool {
+        (a.x - b.x).abs() <= tolerance && (a.y - b.y).abs() <= tolerance && (a.z - b.z).abs() <= tolerance
+    }
+
+    #[test]
+    fn phase_functions_keep_their_albedo() {
+        let from = Vec3::new(0.6, 0.0, 0.8);
+        let albedo = Vec3::new(0.2, 0.5, 1.0);
+        let found = check_sampling(&Isotropic::new(albedo.copy()), &from);
+        assert!(close(&found, &albedo, 1e-4), "{:?}", (found.x, found.y, found.z));
+        for g in &[-0.6, 0.0, 0.3, 0.8] {
+            let found = check_sampling(&HenyeyGreenstein::new(albedo.copy(), *g), &from);
+            assert!(close(&found, &albedo, 1e-4), "g = {}: {:?}", g, (found.x, found.y, found.z));
+        }
+    }
+
+    // g is the average cosine of the angle light gets turned by
+    #[test]
+    fn henyey_greenstein_turns_by_g_on_average() {
+        let forward = Vec3::new(0.0, 0.6, -0.8);
+        let mut sampler = RandomSampler::new(5);
+        let samples = 100000;
+        for g in &[-0.6f32, 0.0, 0.3, 0.8, 0.95] {
+            let mut sum = 0.0f64;
+            for _ in 0..samples {
+                sum += sample_henyey_greenstein(&forward, *g, &mut sampler).dot(&forward) as f64;
+            }
+            let mean = sum / samples as f64;
+            assert!((mean - *g as f64).abs() < 0.01, "g = {}: averaged {}", g, mean);
+        }
+    }
+
+    fn metal((eta, k): ([f32; 3], [f32; 3]), alpha_x: f32, alpha_y: f32) -> Conductor {
+        Conductor::new(Vec3::new(eta[0], eta[1], eta[2]), Vec3::new(k[0], k[1], k[2]), Ggx { alpha_x, alpha_y })
+    }
+
+    #[test]
+    fn conductors_sample_their_pdf() {
+        let views = [Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.48, 0.36, 0.8), Vec3::new(-0.8, 0.3, 0.52)];
+        for from in &views {
+            check_sampling(&metal(GOLD, 0.3, 0.3), from);
+            check_sampling(&metal(ALUMINIUM, 0.1, 0.5), from);
+            check_sampling(&metal(COPPER, 0.6, 0.2), from);
+        }
+    }
+
+    // With all of it reflected by every facet, only what facets block from
+    // each other or send into the surface is lost. There's no multiple
+    // scattering between facets, so that gets to be a lot when rough
+    #[test]
+    fn white_furnace_conductors_only_lose_what_facets_block() {
+        let mirror = ([1.0; 3], [1e4; 3]);
+        assert!(fresnel_conductor(0.1, 1.0, 1e4) > 0.999);
+        let from = Vec3::new(0.48, 0.36, 0.8);
+        // Integrated separately over the visible normals
+        for (alpha, expected) in &[(0.2, 0.93), (0.5, 0.68), (0.9, 0.40)] {
+            let albedo = check_sampling(&metal(mirror, *alpha, *alpha), &from).x;
+            assert!((albedo - expected).abs() < 0.01, "alpha {} reflected {}", alpha, albedo);
+        }
+        // Smooth ones are a mirror tinted by Fresnel
+        let gold = metal(GOLD, 0.0, 0.0);
+        let (ray, rec) = setup(&gold, &from);
+        let mut attenuation = Vec3::all(0.0);
+        let mut scattered = Ray::new(Vec3::all(0.0), Vec3::all(0.0), 0.0);
+        assert!(gold.scatter(&ray, &rec, &mut attenuation, &mut scattered, &mut RandomSampler::new(1)));
+        assert!(close(&scattered.direction, &Vec3::new(-0.48, -0.36, 0.8), 1e-6));
+        assert!(close(&attenuation, &gold.fresnel(0.8), 0.0));
+        assert_eq!(gold.pdf(&ray, &rec, &scattered.direction), 0.0);
+    }
+
+    fn glass(ref_idx: f32, alpha_x: f32, alpha_y: f32) -> Dielectric {
+        let mut glass = Dielectric::new(ref_idx);
+        glass.distribution = Ggx { alpha_x, alpha_y };
+        glass
+    }
+
+    // Averages attenuation over lots of scatters, and the same with what
+    // going through does to radiance undone, which is the share of light
+    // that gets past
+    fn furnace(material: &Dielectric, from: &Vec3) -> (f64, f64) {
+        let (ray, rec) = setup(material, from);
+        let mut sampler = RandomSampler::new(4);
+        let samples = 200000;
+        let (mut sum, mut kept) = (0.0f64, 0.0f64);
+        for _ in 0..samples {
+            let mut attenuation = Vec3::all(0.0);
+            let mut scattered = Ray::new(Vec3::all(0.0), Vec3::all(0.0), 0.0);
+            if material.scatter(&ray, &rec, &mut attenuation, &mut scattered, &mut sampler) {
+                sum += attenuation.x as f64;
+                kept += (attenuation.x / material.spreading(&ray, &rec, &scattered.direction)) as f64;
+            }
+        }
+        (sum / samples as f64, kept / samples as f64)
+    }
+
+    // Seen from outside and from inside, the last past the angle where all
+    // of it gets reflected back in. None are straight on, since the cells
+    // are too coarse at the poles for how narrow going through gets
+    #[test]
+    fn rough_dielectrics_sample_their_pdf() {
+        let views = [Vec3::new(0.48, 0.36, 0.8), Vec3::new(-0.8, 0.0, 0.6), Vec3::new(-0.6, 0.0, -0.8), Vec3::new(0.8, 0.0, -0.6)];
+        for from in &views {
+            check_sampling(&glass(1.5, 0.3, 0.3), from);
+            check_sampling(&glass(1.33, 0.2, 0.5), from);
+        }
+    }
+
+    #[test]
+    fn white_furnace_dielectrics_keep_their_light() {
+        // Smooth glass only scales what goes through. Straight on, Schlick
+        // reflects exactly 4% and the rest goes in
+        let smooth = Dielectric::new(1.5);
+        let up = Vec3::new(0.0, 0.0, 1.0);
+        let (albedo, kept) = furnace(&smooth, &up);
+        assert!((kept - 1.0).abs() < 1e-6);
+        assert!((albedo - (0.04 + 0.96 / 2.25)).abs() < 0.002, "entering averaged {}", albedo);
+
+        // Rough ones lose what facets block, like conductors do. Past the
+        // critical angle inside it's all grazing reflections, which lose
+        // the most
+        for (from, least) in &[(up.copy(), 0.98), (Vec3::new(0.48, 0.36, 0.8), 0.97), (Vec3::new(0.8, 0.0, -0.6), 0.78)] {
+            let (_, kept) = furnace(&glass(1.5, 0.3, 0.3), from);
+            assert!(kept <= 1.0 && kept > *least, "{:?} kept {}", (from.x, from.y, from.z), kept);
+        }
+
+        // Every sheet of thin glass lets through or reflects all of it,
+        // reflecting R + T^2 R / (1 - R^2) = 2R / (1 + R)
+        let mut thin = Dielectric::new(1.5);
+        thin.thin = true;
+        assert_eq!(furnace(&thin, &up), (1.0, 1.0));
+        let (ray, rec) = setup(&thin, &up);
+        let mut sampler = RandomSampler::new(6);
+        let samples = 100000;
+        let mut reflected = 0;
+        for _ in 0..samples {
+            let mut attenuation = Vec3::all(0.0);
+            let mut scattered = Ray::new(Vec3::all(0.0), Vec3::all(0.0), 0.0);
+            assert!(thin.scatter(&ray, &rec, &mut attenuation, &mut scattered, &mut sampler));
+            if scattered.direction.z > 0.0 {
+                reflected += 1;
+            } else {
+                assert!(close(&scattered.direction, &ray.direction, 0.0));
+            }
+        }
+        let r = fresnel_dielectric(1.0, 1.5);
+        let fraction = reflected as f32 / samples as f32;
+        assert!((fraction - 2.0 * r / (1.0 + r)).abs() < 0.003, "reflected {}", fraction);
+
+        // Coming out after 2 units inside loses the same to absorption
+        // whichever way it goes
+        let mut tinted = glass(1.5, 0.3, 0.3);
+        tinted.absorption = Vec3::new(0.5, 0.0, 1.0);
+        let (ray, mut rec) = setup(&tinted, &Vec3::new(0.0, 0.0, -1.0));
+        rec.t = 2.0;
+        for _ in 0..1000 {
+            let mut attenuation = Vec3::all(0.0);
+            let mut scattered = Ray::new(Vec3::all(0.0), Vec3::all(0.0), 0.0);
+            if tinted.scatter(&ray, &rec, &mut attenuation, &mut scattered, &mut sampler) {
+                let left = attenuation.div(attenuation.y);
+                assert!(close(&left, &Vec3::new((-1.0f32).exp(), 1.0, (-2.0f32).exp()), 1e-5));
+            }
+        }
+    }
+
+    fn principled(color: Vec3, alpha: f32) -> Principled {
+        let mut p = Principled::new(Arc::new(ConstantTexture::new(color)));
+        p.set_roughness(Ggx { alpha_x: alpha, alpha_y: alpha });
+        p
+    }
+
+    #[test]
+    fn principled_lobes_sample_their_pdf() {
+        let mut sheen = principled(Vec3::new(0.2, 0.5, 0.8), 0.4);
+        sheen.sheen = 1.0;
+        sheen.clearcoat = 1.0;
+        sheen.clearcoat_gloss = 0.3;
+        let mut metal = principled(Vec3::new(1.0, 0.8, 0.3), 0.3);
+        metal.metallic = 1.0;
+        metal.specular_tint = 0.5;
+        let mut glass = principled(Vec3::new(0.9, 1.0, 0.9), 0.3);
+        glass.transmission = 0.7;
+        let materials = [principled(Vec3::all(0.8), 0.25), sheen, metal, glass];
+        for material in &materials {
+            for from in &[Vec3::new(0.48, 0.36, 0.8), Vec3::new(-0.8, 0.0, 0.6)] {
+                check_sampling(material, from);
+            }
+        }
+        // Only the glass lobe can go through, seen from either side
+        check_sampling(&materials[3], &Vec3::new(-0.6, 0.0, -0.8));
+    }
+
+    // With only one lobe left, a white principled material is just the
+    // other material that lobe is
+    #[test]
+    fn white_furnace_principled_matches_its_lobes() {
+        let from = Vec3::new(0.48, 0.36, 0.8);
+        let mut metal = principled(Vec3::all(1.0), 0.3);
+        metal.metallic = 1.0;
+        let mirror = Conductor::new(Vec3::all(1.0), Vec3::all(1e4), Ggx { alpha_x: 0.3, alpha_y: 0.3 });
+        let (a, b) = (check_sampling(&metal, &from).x, check_sampling(&mirror, &from).x);
+        assert!((a - b).abs() < 0.01, "principled metal reflected {}, a mirror conductor {}", a, b);
+
+        let mut clear = principled(Vec3::all(1.0), 0.3);
+        clear.transmission = 1.0;
+        for from in &[from.copy(), Vec3::new(-0.6, 0.0, -0.8)] {
+            let (a, b) = (check_sampling(&clear, from), check_sampling(&glass(1.5, 0.3, 0.3), from));
+            assert!(close(&a, &b, 0.01), "principled glass averaged {}, glass {}", a.x, b.x);
+        }
+    }
+}
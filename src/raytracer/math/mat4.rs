@@ -0,0 +1,208 @@
+#![allow(dead_code)]
+
+use super::vec3::*;
+
+// A 4x4 affine transform, stored row by row. Points are column vectors,
+// so a.mul_by_mat(&b) applies b first and then a
+#[derive(Debug, PartialEq)]
+pub struct Mat4 {
+    pub m: [[f32; 4]; 4]
+}
+
+impl Mat4 {
+    pub fn identity() -> Mat4 {
+        Mat4::scale(&Vec3::all(1.0))
+    }
+    // Builds a matrix from 16 values in column-major order, the way glTF
+    // and OpenGL lay them out
+    pub fn from_cols(v: &[f32; 16]) -> Mat4 {
+        let mut m = [[0.0; 4]; 4];
+        for (col, chunk) in v.chunks(4).enumerate() {
+            for (row, value) in chunk.iter().enumerate() {
+                m[row][col] = *value;
+            }
+        }
+        Mat4 { m }
+    }
+    pub fn copy(&self) -> Mat4 {
+        Mat4 { m: self.m }
+    }
+    pub fn translation(t: &Vec3) -> Mat4 {
+        let mut res = Mat4::identity();
+        res.m[0][3] = t.x;
+        res.m[1][3] = t.y;
+        res.m[2][3] = t.z;
+        res
+    }
+    pub fn scale(s: &Vec3) -> Mat4 {
+        let mut m = [[0.0; 4]; 4];
+        m[0][0] = s.x;
+        m[1][1] = s.y;
+        m[2][2] = s.z;
+        m[3][3] = 1.0;
+        Mat4 { m }
+    }
+    // Rotation from a unit quaternion given as x, y, z, w
+    pub fn rotation(q: &[f32; 4]) -> Mat4 {
+        let [x, y, z, w] = *q;
+        Mat4 {
+            m: [
+                [1.0 - 2.0 * (y * y + z * z), 2.0 * (x * y - z * w), 2.0 * (x * z + y * w), 0.0],
+                [2.0 * (x * y + z * w), 1.0 - 2.0 * (x * x + z * z), 2.0 * (y * z - x * w), 0.0],
+                [2.0 * (x * z - y * w), 2.0 * (y * z + x * w), 1.0 - 2.0 * (x * x + y * y), 0.0],
+                [0.0, 0.0, 0.0, 1.0]
+            ]
+        }
+    }
+    // Rotation of angle degrees around axis, which doesn't need to be unit length
+    pub fn rotation_axis(axis: &Vec3, angle: f32) -> Mat4 {
+        Mat4::rotation(&quat_axis(axis, angle))
+    }
+    // Scale, then rotate, then translate
+    pub fn from_trs(t: &Vec3, r: &[f32; 4], s: &Vec3) -> Mat4 {
+        Mat4::translation(t).mul_by_mat(&Mat4::rotation(r)).mul_by_mat(&Mat4::scale(s))
+    }
+    pub fn mul_by_mat(&self, other: &Mat4) -> Mat4 {
+        let mut m = [[0.0; 4]; 4];
+        for (i, row) in m.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value = (0..4).map(|k| self.m[i][k] * other.m[k][j]).sum();
+            }
+        }
+        Mat4 { m }
+    }
+    pub fn transpose(&self) -> Mat4 {
+        let mut m = [[0.0; 4]; 4];
+        for (i, row) in m.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value = self.m[j][i];
+            }
+        }
+        Mat4 { m }
+    }
+    // Applies the whole transform, including translation
+    pub fn transform_point(&self, p: &Vec3) -> Vec3 {
+        let m = &self.m;
+        Vec3::new(
+            m[0][0] * p.x + m[0][1] * p.y + m[0][2] * p.z + m[0][3],
+            m[1][0] * p.x + m[1][1] * p.y + m[1][2] * p.z + m[1][3],
+            m[2][0] * p.x + m[2][1] * p.y + m[2][2] * p.z + m[2][3]
+        )
+    }
+    // Directions ignore the translation
+    pub fn transform_vector(&self, v: &Vec3) -> Vec3 {
+        let m = &self.m;
+        Vec3::new(
+            m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+            m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+            m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z
+        )
+    }
+    // Normals have to go through the inverse transpose to stay
+    // perpendicular under non-uniform scaling. Call this on the inverse
+    pub fn transform_normal(&self, n: &Vec3) -> Vec3 {
+        self.transpose().transform_vector(n)
+    }
+    // Determinant of the upper 3x3, negative when the transform mirrors
+    pub fn determinant3(&self) -> f32 {
+        let m = &self.m;
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    }
+    // None for transforms that squash space flat
+    pub fn inverse(&self) -> Option<Mat4> {
+        // Cofactor expansion over the 2x2 sub-determinants
+        let m = &self.m;
+        let s0 = m[0][0] * m[1][1] - m[1][0] * m[0][1];
+        let s1 = m[0][0] * m[1][2] - m[1][0] * m[0][2];
+        let s2 = m[0][0] * m[1][3] - m[1][0] * m[0][3];
+        let s3 = m[0][1] * m[1][2] - m[1][1] * m[0][2];
+        let s4 = m[0][1] * m[1][3] - m[1][1] * m[0][3];
+        let s5 = m[0][2] * m[1][3] - m[1][2] * m[0][3];
+        let c5 = m[2][2] * m[3][3] - m[3][2] * m[2][3];
+        let c4 = m[2][1] * m[3][3] - m[3][1] * m[2][3];
+        let c3 = m[2][1] * m[3][2] - m[3][1] * m[2][2];
+        let c2 = m[2][0] * m[3][3] - m[3][0] * m[2][3];
+        let c1 = m[2][0] * m[3][2] - m[3][0] * m[2][2];
+        let c0 = m[2][0] * m[3][1] - m[3][0] * m[2][1];
+        let det = s0 * c5 - s1 * c4 + s2 * c3 + s3 * c2 - s4 * c1 + s5 * c0;
+        // A determinant that's tiny but not zero still blows up here
+        let d = 1.0 / det;
+        if det == 0.0 || !det.is_finite() || !d.is_finite() {
+            return None;
+        }
+        Some(Mat4 {
+            m: [
+                [
+                    (m[1][1] * c5 - m[1][2] * c4 + m[1][3] * c3) * d,
+                    (-m[0][1] * c5 + m[0][2] * c4 - m[0][3] * c3) * d,
+                    (m[3][1] * s5 - m[3][2] * s4 + m[3][3] * s3) * d,
+                    (-m[2][1] * s5 + m[2][2] * s4 - m[2][3] * s3) * d
+                ],
+                [
+                    (-m[1][0] * c5 + m[1][2] * c2 - m[1][3] * c1) * d,
+                    (m[0][0] * c5 - m[0][2] * c2 + m[0][3] * c1) * d,
+                    (-m[3][0] * s5 + m[3][2] * s2 - m[3][3] * s1) * d,
+                    (m[2][0] * s5 - m[2][2] * s2 + m[2][3] * s1) * d
+                ],
+                [
+                    (m[1][0] * c4 - m[1][1] * c2 + m[1][3] * c0) * d,
+                    (-m[0][0] * c4 + m[0][1] * c2 - m[0][3] * c0) * d,
+                    (m[3][0] * s4 - m[3][1] * s2 + m[3][3] * s0) * d,
+                    (-m[2][0] * s4 + m[2][1] * s2 - m[2][3] * s0) * d
+                ],
+                [
+                    (-m[1][0] * c3 + m[1][1] * c1 - m[1][2] * c0) * d,
+                    (m[0][0] * c3 - m[0][1] * c1 + m[0][2] * c0) * d,
+                    (-m[3][0] * s3 + m[3][1] * s1 - m[3][2] * s0) * d,
+                    (m[2][0] * s3 - m[2][1] * s1 + m[2][2] * s0) * d
+                ]
+            ]
+        })
+    }
+}
+
+// Quaternions are [x, y, z, w] like in Mat4::rotation
+
+// The quaternion turning angle degrees around axis
+pub fn quat_axis(axis: &Vec3, angle: f32) -> [f32; 4] {
+    let a = axis.as_unit();
+    let (s, c) = (angle.to_radians() / 2.0).sin_cos();
+    [a.x * s, a.y * s, a.z * s, c]
+}
+
+// Turns by b, then by a
+pub fn quat_mul(a: &[f32; 4], b: &[f32; 4]) -> [f32; 4] {
+    let [ax, ay, az, aw] = *a;
+    let [bx, by, bz, bw] = *b;
+    [
+        aw * bx + ax * bw + ay * bz - az * by,
+        aw * by - ax * bz + ay * bw + az * bx,
+        aw * bz + ax * by - ay * bx + az * bw,
+        aw * bw - ax * bx - ay * by - az * bz
+    ]
+}
+
+// Spherical interpolation, turning at a steady rate the short way round
+// from a at t = 0 to b at t = 1
+pub fn slerp(a: &[f32; 4], b: &[f32; 4], t: f32) -> [f32; 4] {
+    let mut cos = a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
+    // q and -q are the same rotation, flip b to take the shorter arc
+    let sign = if cos < 0.0 { -1.0 } else { 1.0 };
+    cos *= sign;
+    let (wa, wb) = if cos > 0.9995 {
+        // Nearly the same, where plain blending is accurate and sin is tiny
+        (1.0 - t, t)
+    } else {
+        let angle = cos.acos();
+        let sin = angle.sin();
+        (((1.0 - t) * angle).sin() / sin, (t * angle).sin() / sin)
+    };
+    let mut res = [0.0; 4];
+    for (i, r) in res.iter_mut().enumerate() {
+        *r = wa * a[i] + wb * sign * b[i];
+    }
+    let length = res.iter().map(|x| x * x).sum::<f32>().sqrt();
+    res.map(|x| x / length)
+}
@@ -1 +1,2 @@
-pub mod vec3;
\ No newline at end of file
+pub mod vec3;
+pub mod mat4;
@@ -18,6 +18,7 @@ pub struct Vec3 {
 // speed and memory efficiency
 impl Vec3 {
     // Just create a new vector the usual way
+    #[allow(clippy::redundant_field_names)]
     pub fn new(x: f32, y: f32, z: f32) -> Vec3 {
         Vec3 {
             x: x,
@@ -179,4 +180,12 @@ pub fn dot_product(vec1: &Vec3, vec2: &Vec3) -> f32 {
 
 pub fn cross_product(vec1: &Vec3, vec2: &Vec3) -> Vec3 {
     vec1.cross(vec2)
-}
\ No newline at end of file
+}
+// Two unit vectors at right angles to each other and to the unit vector n,
+// from Duff et al.'s "Building an Orthonormal Basis, Revisited"
+pub fn orthonormal_basis(n: &Vec3) -> (Vec3, Vec3) {
+    let sign = 1.0f32.copysign(n.z);
+    let a = -1.0 / (sign + n.z);
+    let b = n.x * n.y * a;
+    (Vec3::new(1.0 + sign * n.x * n.x * a, sign * b, -sign * n.x), Vec3::new(b, sign + n.y * n.y * a, -n.y))
+}
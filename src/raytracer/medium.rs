@@ -0,0 +1,413 @@
+#![allow(dead_code)]
+
+use std::sync::Arc;
+
+use super::math::vec3::*;
+use super::ray::*;
+use super::object::*;
+use super::material::*;
+use super::aabb::*;
+use super::sampler::*;
+use super::formats::vol::*;
+
+// Smoke, fog or anything else that fills the inside of a closed boundary
+// evenly. Rays going through it stop somewhere inside with a chance that
+// grows with density and how far they travel, and get scattered there by
+// the phase function, usually an Isotropic or HenyeyGreenstein material
+pub struct ConstantMedium {
+    boundary: Arc<dyn Object>,
+    density: f32,
+    phase: Box<dyn Material>
+}
+
+impl ConstantMedium {
+    pub fn new(boundary: Arc<dyn Object>, density: f32, phase: Box<dyn Material>) -> ConstantMedium {
+        ConstantMedium {
+            boundary,
+            density,
+            phase
+        }
+    }
+}
+
+impl ConstantMedium {
+    // Where r is inside the boundary between t_min and t_max. Checked from
+    // anywhere along the line since rays scattered inside start within it
+    fn span(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<(f32, f32)> {
+        let mut enter = HitRecord::default();
+        if !self.boundary.check_hit(r, -f32::MAX, f32::MAX, &mut enter) {
+            return None;
+        }
+        let mut exit = HitRecord::default();
+        if !self.boundary.check_hit(r, enter.t + 0.0001, f32::MAX, &mut exit) {
+            return None;
+        }
+        let (t_enter, t_exit) = (enter.t.max(t_min), exit.t.min(t_max));
+        if t_enter >= t_exit {
+            return None;
+        }
+        Some((t_enter, t_exit))
+    }
+}
+
+impl Object for ConstantMedium {
+    fn check_hit(&self, r: &Ray, t_min: f32, t_max: f32, rec: &mut HitRecord) -> bool {
+        if r.skip_media {
+            return false;
+        }
+        let (t_enter, t_exit) = match self.span(r, t_min, t_max) {
+            Some(span) => span,
+            None => return false
+        };
+        let mut sampler = ray_sampler(r, t_enter);
+        let speed = r.direction.magnitude();
+        let distance = -(1.0 - sampler.next_f32()).ln() / self.density;
+        if distance >= (t_exit - t_enter) * speed {
+            return false;
+        }
+        medium_hit(r, t_enter + distance / speed, self.phase.copy(), rec);
+        true
+    }
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.boundary.bounding_box()
+    }
+    // Beer-Lambert, the same everywhere inside
+    fn transmittance(&self, r: &Ray, t_min: f32, t_max: f32) -> f32 {
+        match self.span(r, t_min, t_max) {
+            Some((t_enter, t_exit)) => (-self.density * (t_exit - t_enter) * r.direction.magnitude()).exp(),
+            None => 1.0
+        }
+    }
+    fn has_media(&self) -> bool {
+        true
+    }
+}
+
+// check_hit isn't handed a sampler, so the ray picks its own random
+// numbers. Where it enters is mixed in so media lined up along the same
+// ray don't all make the same choice
+fn ray_sampler(r: &Ray, t_enter: f32) -> RandomSampler {
+    let (o, d) = (&r.origin, &r.direction);
+    RandomSampler::from_bits(&[
+        o.x.to_bits(), o.y.to_bits(), o.z.to_bits(),
+        d.x.to_bits(), d.y.to_bits(), d.z.to_bits(), r.time.to_bits(), t_enter.to_bits()
+    ])
+}
+
+// Scattering at t inside a medium
+fn medium_hit(r: &Ray, t: f32, material: Box<dyn Material>, rec: &mut HitRecord) {
+    rec.t = t;
+    rec.p = r.point_at_parameter(t);
+    // Phase functions don't use it, but it has to point somewhere
+    rec.normal = Vec3::new(1.0, 0.0, 0.0);
+    rec.u = 0.0;
+    rec.v = 0.0;
+    rec.b1 = 0.0;
+    rec.b2 = 0.0;
+    rec.tangent = Vec3::all(0.0);
+    rec.material = material;
+}
+
+// Entries in the table of blackbody colors from 0 up to the hottest voxel
+const GLOW_STEPS: usize = 256;
+// Transmittance below this starts getting cut short by russian roulette
+const ROULETTE_BELOW: f32 = 0.05;
+
+// Smoke or fire from a simulation, with the density stored per voxel in a
+// box. Values are blended trilinearly between voxel centers. Rays find
+// where they stop by delta tracking: taking steps as if the whole box was
+// as thick as its densest voxel, and treating each stop as real with a
+// chance of how thick it actually is there. Shadow rays use ratio tracking
+// over the same steps to get a smooth transmittance instead of 0 or 1
+//
+// With a temperature grid (in Kelvin, after temperature_scale) stops also
+// glow the blackbody color of the temperature there. The hottest voxel
+// glows with brightness emission, cooler ones by the fourth power of how
+// much cooler they are
+pub struct GridMedium {
+    density: Grid,
+    temperature: Option<Grid>,
+    bounds: Aabb,
+    majorant: f32,
+    phase: Box<dyn Material>,
+    glow: Vec<Vec3>,
+    hottest: f32
+}
+
+impl GridMedium {
+    // density is multiplied into every voxel. The grid fills bounds
+    pub fn new(mut grid: Grid, density: f32, temperature: Option<Grid>, emission: f32, bounds: Aabb, phase: Box<dyn Material>) -> GridMedium {
+        for v in grid.values.iter_mut() {
+            *v = v.max(0.0) * density;
+        }
+        let majorant = grid.max();
+        let hottest = temperature.as_ref().map_or(0.0, |t| t.max());
+        // Nothing glows if it's all at 0 K
+        let temperature = temperature.filter(|_| emission > 0.0 && hottest > 0.0);
+        let glow = match temperature {
+            Some(_) => (0..GLOW_STEPS).map(|i| {
+                let kelvin = hottest * i as f32 / (GLOW_STEPS - 1) as f32;
+                blackbody(kelvin).mul(emission * (kelvin / hottest).powi(4))
+            }).collect(),
+            None => Vec::new()
+        };
+        GridMedium {
+            density: grid,
+            temperature,
+            bounds,
+            majorant,
+            phase,
+            glow,
+            hottest
+        }
+    }
+    // Voxel coordinates of p, with voxel centers on whole numbers
+    fn voxel_position(&self, p: &Vec3, resolution: &[usize; 3]) -> [f32; 3] {
+        let (min, max) = (&self.bounds.min, &self.bounds.max);
+        [
+            (p.x - min.x) / (max.x - min.x) * resolution[0] as f32 - 0.5,
+            (p.y - min.y) / (max.y - min.y) * resolution[1] as f32 - 0.5,
+            (p.z - min.z) / (max.z - min.z) * resolution[2] as f32 - 0.5
+        ]
+    }
+    fn lookup(&self, grid: &Grid, p: &Vec3) -> f32 {
+        let pos = self.voxel_position(p, &grid.resolution);
+        let mut corners = [[0usize; 2]; 3];
+        let mut weights = [0.0f32; 3];
+        for axis in 0..3 {
+            let last = grid.resolution[axis] - 1;
+            let base = pos[axis].floor();
+            weights[axis] = pos[axis] - base;
+            // Values past the outer voxel centers stay at the edge value
+            corners[axis] = [(base.max(0.0) as usize).min(last), ((base + 1.0).max(0.0) as usize).min(last)];
+        }
+        let [nx, ny, _] = grid.resolution;
+        let mut res = 0.0;
+        for i in 0..8 {
+            let (a, b, c) = (i & 1, (i >> 1) & 1, (i >> 2) & 1);
+            let w = (if a == 1 { weights[0] } else { 1.0 - weights[0] })
+                * (if b == 1 { weights[1] } else { 1.0 - weights[1] })
+                * (if c == 1 { weights[2] } else { 1.0 - weights[2] });
+            res += w * grid.values[(corners[2][c] * ny + corners[1][b]) * nx + corners[0][a]];
+        }
+        res
+    }
+    // The part of r inside the box between t_min and t_max
+    fn span(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<(f32, f32)> {
+        if self.majorant <= 0.0 {
+            return None;
+        }
+        let d = &r.direction;
+        let inv_dir = Vec3::new(1.0 / d.x, 1.0 / d.y, 1.0 / d.z);
+        self.bounds.clip(r, &inv_dir, t_min, t_max)
+    }
+    // The material for stopping at p, glowing if it's hot there
+    fn material_at(&self, p: &Vec3) -> Box<dyn Material> {
+        let temperature = match &self.temperature {
+            Some(t) => self.lookup(t, p),
+            None => return self.phase.copy()
+        };
+        let x = (temperature / self.hottest).clamp(0.0, 1.0) * (GLOW_STEPS - 1) as f32;
+        let i = (x as usize).min(GLOW_STEPS - 2);
+        let f = x - i as f32;
+        let color = self.glow[i].mul(1.0 - f).add_by_vec(&self.glow[i + 1].mul(f));
+        Box::new(Glowing {
+            phase: self.phase.copy(),
+            color
+        })
+    }
+}
+
+impl Object for GridMedium {
+    fn check_hit(&self, r: &Ray, t_min: f32, t_max: f32, rec: &mut HitRecord) -> bool {
+        if r.skip_media {
+            return false;
+        }
+        let (t_enter, t_exit) = match self.span(r, t_min, t_max) {
+            Some(span) => span,
+            None => return false
+        };
+        let mut sampler = ray_sampler(r, t_enter);
+        let step = 1.0 / (self.majorant * r.direction.magnitude());
+        let mut t = t_enter;
+        loop {
+            t -= (1.0 - sampler.next_f32()).ln() * step;
+            if t >= t_exit {
+                return false;
+            }
+            let p = r.point_at_parameter(t);
+            if sampler.next_f32() * self.majorant < self.lookup(&self.density, &p) {
+                let material = self.material_at(&p);
+                medium_hit(r, t, material, rec);
+                return true;
+            }
+        }
+    }
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(self.bounds.copy())
+    }
+    fn transmittance(&self, r: &Ray, t_min: f32, t_max: f32) -> f32 {
+        let (t_enter, t_exit) = match self.span(r, t_min, t_max) {
+            Some(span) => span,
+            None => return 1.0
+        };
+        let mut sampler = ray_sampler(r, t_enter);
+        let step = 1.0 / (self.majorant * r.direction.magnitude());
+        let mut t = t_enter;
+        let mut res = 1.0;
+        loop {
+            t -= (1.0 - sampler.next_f32()).ln() * step;
+            if t >= t_exit {
+                return res;
+            }
+            res *= 1.0 - self.lookup(&self.density, &r.point_at_parameter(t)) / self.majorant;
+            // Not worth following through thick smoke for the last bit
+            if res < ROULETTE_BELOW {
+                if sampler.next_f32() < 0.75 {
+                    return 0.0;
+                }
+                res /= 0.25;
+            }
+        }
+    }
+    fn has_media(&self) -> bool {
+        true
+    }
+}
+
+// A phase function that also gives off light where it's hit
+struct Glowing {
+    phase: Box<dyn Material>,
+    color: Vec3
+}
+
+impl Material for Glowing {
+    fn scatter(&self, ray: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray, sampler: &mut dyn Sampler) -> bool {
+        self.phase.scatter(ray, rec, attenuation, scattered, sampler)
+    }
+    fn emitted(&self, _ray: &Ray, _rec: &HitRecord) -> Vec3 {
+        self.color.copy()
+    }
+    fn bsdf(&self, ray: &Ray, rec: &HitRecord, direction: &Vec3) -> Vec3 {
+        self.phase.bsdf(ray, rec, direction)
+    }
+    fn pdf(&self, ray: &Ray, rec: &HitRecord, direction: &Vec3) -> f32 {
+        self.phase.pdf(ray, rec, direction)
+    }
+    fn copy(&self) -> Box<dyn Material> {
+        Box::new(Glowing {
+            phase: self.phase.copy(),
+            color: self.color.copy()
+        })
+    }
+}
+
+// The color of a blackbody at kelvin in linear sRGB, scaled to a luminance
+// of 1. Planck's law is weighed by Wyman, Sloan and Shirley's fit of the CIE
+// 1931 color matching functions
+pub fn blackbody(kelvin: f32) -> Vec3 {
+    if kelvin <= 0.0 {
+        return Vec3::all(0.0);
+    }
+    let lobe = |x: f64, mu: f64, below: f64, above: f64| {
+        let s = (x - mu) / if x < mu { below } else { above };
+        (-0.5 * s * s).exp()
+    };
+    let (mut x, mut y, mut z) = (0.0f64, 0.0f64, 0.0f64);
+    for nm in (380..=780).step_by(5) {
+        let l = nm as f64;
+        let meters = l * 1e-9;
+        let planck = 1.0 / (meters.powi(5) * ((1.4388e-2 / (meters * kelvin as f64)).exp() - 1.0));
+        x += planck * (1.056 * lobe(l, 599.8, 37.9, 31.0) + 0.362 * lobe(l, 442.0, 16.0, 26.7) - 0.065 * lobe(l, 501.1, 20.4, 26.2));
+        y += planck * (0.821 * lobe(l, 568.8, 46.9, 40.5) + 0.286 * lobe(l, 530.9, 16.3, 31.1));
+        z += planck * (1.217 * lobe(l, 437.0, 11.8, 36.0) + 0.681 * lobe(l, 459.0, 26.0, 13.8));
+    }
+    if y <= 0.0 {
+        return Vec3::all(0.0);
+    }
+    let (x, z) = (x / y, z / y);
+    Vec3::new(
+        (3.2406 * x - 1.5372 - 0.4986 * z).max(0.0) as f32,
+        (-0.9689 * x + 1.8758 + 0.0415 * z).max(0.0) as f32,
+        (0.0557 * x - 0.2040 + 1.0570 * z).max(0.0) as f32
+    )
+}
+
+// Haze filling the whole scene, only along the rays leaving the camera so
+// it doesn't cost anything further down the paths. Distant objects fade
+// into light scattered towards the camera by the fog itself
+pub struct Fog {
+    pub density: f32,
+    pub phase: Box<dyn Material>
+}
+
+impl Fog {
+    // Where a ray stops in the fog, as a value of t, if it gets that far
+    pub fn sample_distance(&self, r: &Ray, sampler: &mut dyn Sampler) -> f32 {
+        -(1.0 - sampler.next_f32()).ln() / (self.density * r.direction.magnitude())
+    }
+    // The record for scattering at t
+    pub fn hit_at(&self, r: &Ray, t: f32) -> HitRecord {
+        let mut rec = HitRecord::default();
+        rec.t = t;
+        rec.p = r.point_at_parameter(t);
+        rec.normal = Vec3::new(1.0, 0.0, 0.0);
+        rec.material = self.phase.copy();
+        rec
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn isotropic() -> Box<dyn Material> {
+        Box::new(Isotropic::new(Vec3::all(1.0)))
+    }
+
+    // Straight through the middle of a unit sphere, rays have to get past
+    // as often as transmittance says, and stop at exponentially distributed
+    // distances when they don't
+    #[test]
+    fn rays_stop_as_often_as_transmittance_says() {
+        let boundary: Arc<dyn Object> = Arc::new(Sphere::new(Vec3::all(0.0), 1.0, isotropic()));
+        let density = 0.5;
+        let medium = ConstantMedium::new(boundary, density, isotropic());
+        let samples = 20000;
+        for t_max in &[f32::MAX, 3.0] {
+            let (mut passed, mut distance) = (0, 0.0f64);
+            for i in 0..samples {
+                // Each ray picks its own random numbers from where it starts
+                let r = Ray::new(Vec3::new(i as f32 * 1e-7, 0.0, -3.0), Vec3::new(0.0, 0.0, 1.0), 0.0);
+                let mut rec = HitRecord::default();
+                if medium.check_hit(&r, 0.001, *t_max, &mut rec) {
+                    assert!(rec.t > 2.0 && rec.t < t_max.min(4.0), "stopped at {}", rec.t);
+                    distance += (rec.t - 2.0) as f64;
+                } else {
+                    passed += 1;
+                }
+            }
+            let r = Ray::new(Vec3::new(0.0, 0.0, -3.0), Vec3::new(0.0, 0.0, 1.0), 0.0);
+            let length = t_max.min(4.0) as f64 - 2.0;
+            let expected = (-density as f64 * length).exp();
+            assert!((medium.transmittance(&r, 0.001, *t_max) as f64 - expected).abs() < 1e-4);
+            let fraction = passed as f64 / samples as f64;
+            assert!((fraction - expected).abs() < 0.015, "{} got through, expected {}", fraction, expected);
+            // The mean of an exponential distribution cut off at length
+            let mean = distance / (samples - passed) as f64;
+            let expected = 1.0 / density as f64 - length * expected / (1.0 - expected);
+            assert!((mean - expected).abs() < 0.02, "stopped {} in on average, expected {}", mean, expected);
+        }
+    }
+
+    #[test]
+    fn fog_distances_average_one_over_density() {
+        let fog = Fog { density: 0.25, phase: isotropic() };
+        // Twice as fast covers the same distance in half the t
+        let r = Ray::new(Vec3::all(0.0), Vec3::new(0.0, 2.0, 0.0), 0.0);
+        let mut sampler = RandomSampler::new(9);
+        let samples = 100000;
+        let mean = (0..samples).map(|_| fog.sample_distance(&r, &mut sampler) as f64).sum::<f64>() / samples as f64;
+        assert!((mean - 2.0).abs() < 0.03, "averaged {}", mean);
+    }
+}
@@ -0,0 +1,211 @@
+#![allow(dead_code)]
+
+use super::math::vec3::*;
+
+// Rough surfaces are made of tiny mirror facets pointing every which way.
+// Everything here works in a local frame where the surface normal is +z,
+// the tangent +x and the bitangent +y
+
+// Shading frame around a normal, with x lined up with the surface's
+// tangent when it has one
+pub struct Frame {
+    pub t: Vec3,
+    pub b: Vec3,
+    pub n: Vec3
+}
+
+impl Frame {
+    // n has to be unit length. tangent can be any length, and gets made
+    // perpendicular to n
+    pub fn new(n: Vec3, tangent: &Vec3) -> Frame {
+        let t = tangent.sub_by_vec(&n.mul(n.dot(tangent)));
+        let length = t.magnitude();
+        let (t, b) = if length > 1e-6 {
+            let t = t.div(length);
+            let b = n.cross(&t);
+            (t, b)
+        } else {
+            orthonormal_basis(&n)
+        };
+        Frame {
+            t,
+            b,
+            n
+        }
+    }
+    pub fn to_local(&self, v: &Vec3) -> Vec3 {
+        Vec3::new(v.dot(&self.t), v.dot(&self.b), v.dot(&self.n))
+    }
+    pub fn to_world(&self, v: &Vec3) -> Vec3 {
+        self.t.mul(v.x).add_by_vec(&self.b.mul(v.y)).add_by_vec(&self.n.mul(v.z))
+    }
+}
+
+// Below this both ways, surfaces are treated as perfectly smooth
+const SMOOTH_ALPHA: f32 = 1e-3;
+// Smoothest a single direction gets, so rough-one-way surfaces don't
+// divide by zero
+const MIN_ALPHA: f32 = 1e-4;
+
+// Trowbridge-Reitz (GGX) facet distribution, with alpha_x and alpha_y the
+// roughness along the tangent and bitangent
+#[derive(Clone, Copy)]
+pub struct Ggx {
+    pub alpha_x: f32,
+    pub alpha_y: f32
+}
+
+impl Ggx {
+    // From the usual roughness in 0..1 along each direction, which gets
+    // squared so it looks about evenly spaced
+    pub fn from_roughness(u: f32, v: f32) -> Ggx {
+        Ggx {
+            alpha_x: (u * u).max(MIN_ALPHA),
+            alpha_y: (v * v).max(MIN_ALPHA)
+        }
+    }
+    // Too smooth to sample, these get a mirror direction instead
+    pub fn is_smooth(&self) -> bool {
+        self.alpha_x.max(self.alpha_y) < SMOOTH_ALPHA
+    }
+    // Density of facets facing h, per unit area of the surface
+    pub fn d(&self, h: &Vec3) -> f32 {
+        if h.z <= 0.0 {
+            return 0.0;
+        }
+        let (x, y) = (h.x / self.alpha_x, h.y / self.alpha_y);
+        let e = x * x + y * y + h.z * h.z;
+        1.0 / (std::f32::consts::PI * self.alpha_x * self.alpha_y * e * e)
+    }
+    // Smith's shadowing term, how much of the facets are hidden seen from w
+    fn lambda(&self, w: &Vec3) -> f32 {
+        let (x, y) = (w.x * self.alpha_x, w.y * self.alpha_y);
+        let tan2 = (x * x + y * y) / (w.z * w.z);
+        if !tan2.is_finite() {
+            return f32::MAX;
+        }
+        ((1.0 + tan2).sqrt() - 1.0) / 2.0
+    }
+    // The share of facets seen from w
+    pub fn g1(&self, w: &Vec3) -> f32 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+    // The share seen from both wo and wi, with taller facets more likely
+    // to be seen from both
+    pub fn g(&self, wo: &Vec3, wi: &Vec3) -> f32 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+    // A facet normal picked in proportion to how much of it wo sees, from
+    // Heitz's "Sampling the GGX Distribution of Visible Normals". wo has
+    // to be above the surface
+    pub fn sample_visible(&self, wo: &Vec3, u1: f32, u2: f32) -> Vec3 {
+        // Stretch so the facets make up a hemisphere
+        let v = Vec3::new(self.alpha_x * wo.x, self.alpha_y * wo.y, wo.z).as_unit();
+        let length2 = v.x * v.x + v.y * v.y;
+        let t1 = if length2 > 0.0 { Vec3::new(-v.y, v.x, 0.0).div(length2.sqrt()) } else { Vec3::new(1.0, 0.0, 0.0) };
+        let t2 = v.cross(&t1);
+        // A point on the disk under the hemisphere, squashed towards the
+        // half of it that's seen from the side
+        let r = u1.sqrt();
+        let phi = 2.0 * std::f32::consts::PI * u2;
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1.0 + v.z);
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).max(0.0).sqrt() + s * r * phi.sin();
+        let p3 = (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt();
+        let h = t1.mul(p1).add_by_vec(&t2.mul(p2)).add_by_vec(&v.mul(p3));
+        Vec3::new(self.alpha_x * h.x, self.alpha_y * h.y, h.z.max(1e-6)).as_unit()
+    }
+    // Density of sample_visible picking h
+    pub fn pdf_visible(&self, wo: &Vec3, h: &Vec3) -> f32 {
+        if wo.z <= 0.0 {
+            return 0.0;
+        }
+        self.g1(wo) * wo.dot(h).max(0.0) * self.d(h) / wo.z
+    }
+}
+
+// Burley's GTR1 distribution, with a longer tail than GGX. Only used for
+// clearcoats, so it's always the same both ways
+pub struct Gtr1 {
+    pub alpha: f32
+}
+
+impl Gtr1 {
+    pub fn d(&self, h: &Vec3) -> f32 {
+        if h.z <= 0.0 {
+            return 0.0;
+        }
+        let a2 = self.alpha * self.alpha;
+        if a2 >= 1.0 {
+            return 1.0 / std::f32::consts::PI;
+        }
+        (a2 - 1.0) / (std::f32::consts::PI * a2.ln() * (1.0 + (a2 - 1.0) * h.z * h.z))
+    }
+    // A facet normal picked with density d(h) times h.z
+    pub fn sample(&self, u1: f32, u2: f32) -> Vec3 {
+        let a2 = self.alpha * self.alpha;
+        let cos2 = if a2 >= 1.0 { 1.0 - u1 } else { (1.0 - a2.powf(1.0 - u1)) / (1.0 - a2) };
+        let cos = cos2.clamp(0.0, 1.0).sqrt();
+        let sin = (1.0 - cos * cos).max(0.0).sqrt();
+        let phi = 2.0 * std::f32::consts::PI * u2;
+        Vec3::new(sin * phi.cos(), sin * phi.sin(), cos)
+    }
+}
+
+// Schlick's approximation of Fresnel reflectance, from f0 straight on up
+// to 1 at grazing angles
+pub fn schlick_fresnel(f0: &Vec3, cos: f32) -> Vec3 {
+    let w = (1.0 - cos.clamp(0.0, 1.0)).powi(5);
+    f0.mul(1.0 - w).add_by_vec(&Vec3::all(w))
+}
+
+// Mirror direction of w around unit normal n, both pointing away
+pub fn reflect_about(w: &Vec3, n: &Vec3) -> Vec3 {
+    n.mul(2.0 * w.dot(n)).sub_by_vec(w)
+}
+
+// Exact Fresnel reflectance of a metal with complex index of refraction
+// eta + ik, for light arriving cos away from the normal
+pub fn fresnel_conductor(cos: f32, eta: f32, k: f32) -> f32 {
+    let cos = cos.clamp(0.0, 1.0);
+    let cos2 = cos * cos;
+    let sin2 = 1.0 - cos2;
+    let (eta2, k2) = (eta * eta, k * k);
+    let t0 = eta2 - k2 - sin2;
+    let a2b2 = (t0 * t0 + 4.0 * eta2 * k2).sqrt();
+    let t1 = a2b2 + cos2;
+    let a = (0.5 * (a2b2 + t0)).max(0.0).sqrt();
+    let t2 = 2.0 * cos * a;
+    let rs = (t1 - t2) / (t1 + t2);
+    let t3 = cos2 * a2b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+    0.5 * (rp + rs)
+}
+
+// Fresnel reflectance going from one side of a clear boundary to the other,
+// eta being the index on the far side over the one on the near side. 1 when
+// all of it gets reflected
+pub fn fresnel_dielectric(cos: f32, eta: f32) -> f32 {
+    let cos = cos.clamp(0.0, 1.0);
+    let sin2_t = (1.0 - cos * cos) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let parallel = (eta * cos - cos_t) / (eta * cos + cos_t);
+    let perpendicular = (cos - eta * cos_t) / (cos + eta * cos_t);
+    (parallel * parallel + perpendicular * perpendicular) / 2.0
+}
+
+// w bent through the boundary with unit normal n on w's side, eta as in
+// fresnel_dielectric. None when it all gets reflected
+pub fn refract_about(w: &Vec3, n: &Vec3, eta: f32) -> Option<Vec3> {
+    let cos_i = w.dot(n);
+    let sin2_t = (1.0 - cos_i * cos_i).max(0.0) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(w.neg().div(eta).add_by_vec(&n.mul(cos_i / eta - cos_t)))
+}
@@ -3,4 +3,20 @@ pub mod object;
 pub mod ray;
 pub mod math;
 pub mod formats;
-pub mod material;
\ No newline at end of file
+pub mod material;
+pub mod render;
+pub mod aabb;
+pub mod bvh;
+pub mod scene;
+pub mod film;
+pub mod tonemap;
+pub mod progressive;
+pub mod sampler;
+pub mod triangle;
+pub mod texture;
+pub mod noise;
+pub mod environment;
+pub mod light;
+pub mod transform;
+pub mod medium;
+pub mod microfacet;
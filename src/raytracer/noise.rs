@@ -0,0 +1,266 @@
+#![allow(dead_code)]
+
+use std::sync::Arc;
+
+use super::math::vec3::*;
+use super::sampler::*;
+use super::texture::*;
+
+// Lattice noise over 3D space. The permutation table and the cells' feature
+// points come from a seeded RandomSampler, so the same seed always gives
+// the same pattern
+pub struct Noise {
+    // 0..256 shuffled, then repeated so lookups can skip wrapping
+    perm: Vec<usize>,
+    // Where each Worley cell's point sits within the cell
+    features: Vec<Vec3>
+}
+
+// The 12 edge directions of a cube, used as gradients by both kinds of
+// gradient noise
+const GRADIENTS: [[f32; 3]; 12] = [
+    [1.0, 1.0, 0.0], [-1.0, 1.0, 0.0], [1.0, -1.0, 0.0], [-1.0, -1.0, 0.0],
+    [1.0, 0.0, 1.0], [-1.0, 0.0, 1.0], [1.0, 0.0, -1.0], [-1.0, 0.0, -1.0],
+    [0.0, 1.0, 1.0], [0.0, -1.0, 1.0], [0.0, 1.0, -1.0], [0.0, -1.0, -1.0]
+];
+
+impl Noise {
+    pub fn new(seed: u64) -> Noise {
+        let mut sampler = RandomSampler::new(seed);
+        let mut table: Vec<usize> = (0..256).collect();
+        // Fisher-Yates
+        for i in (1..256).rev() {
+            let j = (sampler.next_u64() % (i as u64 + 1)) as usize;
+            table.swap(i, j);
+        }
+        let features = (0..256).map(|_| Vec3::new(sampler.next_f32(), sampler.next_f32(), sampler.next_f32())).collect();
+        Noise {
+            perm: table.iter().chain(table.iter()).copied().collect(),
+            features
+        }
+    }
+    fn hash(&self, x: i64, y: i64, z: i64) -> usize {
+        let p = &self.perm;
+        p[p[p[(x & 255) as usize] + (y & 255) as usize] + (z & 255) as usize]
+    }
+    // Ken Perlin's improved noise, roughly -1 to 1
+    pub fn perlin(&self, p: &Vec3) -> f32 {
+        let (fx, fy, fz) = (p.x.floor(), p.y.floor(), p.z.floor());
+        let (x, y, z) = (p.x - fx, p.y - fy, p.z - fz);
+        let (ix, iy, iz) = (fx as i64, fy as i64, fz as i64);
+        let fade = |t: f32| t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+        let (u, v, w) = (fade(x), fade(y), fade(z));
+        let corner = |dx: i64, dy: i64, dz: i64| {
+            let g = GRADIENTS[self.hash(ix + dx, iy + dy, iz + dz) % 12];
+            g[0] * (x - dx as f32) + g[1] * (y - dy as f32) + g[2] * (z - dz as f32)
+        };
+        let lerp = |t: f32, a: f32, b: f32| a + t * (b - a);
+        lerp(w,
+            lerp(v, lerp(u, corner(0, 0, 0), corner(1, 0, 0)), lerp(u, corner(0, 1, 0), corner(1, 1, 0))),
+            lerp(v, lerp(u, corner(0, 0, 1), corner(1, 0, 1)), lerp(u, corner(0, 1, 1), corner(1, 1, 1))))
+    }
+    // Simplex noise, roughly -1 to 1. Fewer corners per lookup than Perlin
+    // and no visible grid alignment
+    pub fn simplex(&self, p: &Vec3) -> f32 {
+        const F3: f32 = 1.0 / 3.0;
+        const G3: f32 = 1.0 / 6.0;
+        // Skew into the lattice of tetrahedra and find which one p is in
+        let s = (p.x + p.y + p.z) * F3;
+        let (i, j, k) = ((p.x + s).floor(), (p.y + s).floor(), (p.z + s).floor());
+        let t = (i + j + k) * G3;
+        let x0 = [p.x - (i - t), p.y - (j - t), p.z - (k - t)];
+        // Order the axes by how far along them p is to get the two middle corners
+        let (first, second) = if x0[0] >= x0[1] {
+            if x0[1] >= x0[2] {
+                ([1, 0, 0], [1, 1, 0])
+            } else if x0[0] >= x0[2] {
+                ([1, 0, 0], [1, 0, 1])
+            } else {
+                ([0, 0, 1], [1, 0, 1])
+            }
+        } else if x0[1] < x0[2] {
+            ([0, 0, 1], [0, 1, 1])
+        } else if x0[0] < x0[2] {
+            ([0, 1, 0], [0, 1, 1])
+        } else {
+            ([0, 1, 0], [1, 1, 0])
+        };
+        let (i, j, k) = (i as i64, j as i64, k as i64);
+        let mut sum = 0.0;
+        for (n, offset) in [[0, 0, 0], first, second, [1, 1, 1]].iter().enumerate() {
+            let d = [
+                x0[0] - offset[0] as f32 + n as f32 * G3,
+                x0[1] - offset[1] as f32 + n as f32 * G3,
+                x0[2] - offset[2] as f32 + n as f32 * G3
+            ];
+            let falloff = 0.6 - d[0] * d[0] - d[1] * d[1] - d[2] * d[2];
+            if falloff > 0.0 {
+                let g = GRADIENTS[self.hash(i + offset[0], j + offset[1], k + offset[2]) % 12];
+                sum += falloff.powi(4) * (g[0] * d[0] + g[1] * d[1] + g[2] * d[2]);
+            }
+        }
+        // Brings the result out to about -1 to 1
+        32.0 * sum
+    }
+    pub fn basis(&self, basis: Basis, p: &Vec3) -> f32 {
+        match basis {
+            Basis::Perlin => self.perlin(p),
+            Basis::Simplex => self.simplex(p)
+        }
+    }
+    // Fractional Brownian motion: octaves of noise, each lacunarity times
+    // finer and gain times fainter than the last. Normalized to about -1 to 1
+    pub fn fbm(&self, basis: Basis, p: &Vec3, octaves: u32, lacunarity: f32, gain: f32) -> f32 {
+        self.octaves(p, octaves, lacunarity, gain, |q| self.basis(basis, q))
+    }
+    // Like fbm but adding up the magnitudes, which gives sharp creases where
+    // the noise crosses zero. About 0 to 1
+    pub fn turbulence(&self, basis: Basis, p: &Vec3, octaves: u32, lacunarity: f32, gain: f32) -> f32 {
+        self.octaves(p, octaves, lacunarity, gain, |q| self.basis(basis, q).abs())
+    }
+    fn octaves(&self, p: &Vec3, octaves: u32, lacunarity: f32, gain: f32, f: impl Fn(&Vec3) -> f32) -> f32 {
+        let mut sum = 0.0;
+        let mut total = 0.0;
+        let mut amplitude = 1.0;
+        let mut q = p.copy();
+        for _ in 0..octaves.max(1) {
+            sum += amplitude * f(&q);
+            total += amplitude;
+            amplitude *= gain;
+            q.mul_eq(lacunarity);
+        }
+        sum / total
+    }
+    // Worley's cellular noise: the distances to the closest and second
+    // closest of the points scattered one per unit cell
+    pub fn worley(&self, p: &Vec3) -> (f32, f32) {
+        let (cx, cy, cz) = (p.x.floor() as i64, p.y.floor() as i64, p.z.floor() as i64);
+        let mut f1 = f32::MAX;
+        let mut f2 = f32::MAX;
+        for dz in -1..=1 {
+            for dy in -1..=1 {
+                for dx in -1..=1 {
+                    let (x, y, z) = (cx + dx, cy + dy, cz + dz);
+                    let feature = &self.features[self.hash(x, y, z)];
+                    let point = Vec3::new(x as f32 + feature.x, y as f32 + feature.y, z as f32 + feature.z);
+                    let d = point.sub_by_vec(p).squared_length();
+                    if d < f1 {
+                        f2 = f1;
+                        f1 = d;
+                    } else if d < f2 {
+                        f2 = d;
+                    }
+                }
+            }
+        }
+        (f1.sqrt(), f2.sqrt())
+    }
+}
+
+// Which gradient noise a texture is built from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Basis {
+    Perlin,
+    Simplex
+}
+
+// Mixes two textures, t = 0 gives all low and t = 1 all high
+fn blend(low: &Arc<dyn Texture>, high: &Arc<dyn Texture>, t: f32, u: f32, v: f32, p: &Vec3) -> Vec3 {
+    let t = t.clamp(0.0, 1.0);
+    low.value(u, v, p).mul(1.0 - t).add_by_vec(&high.value(u, v, p).mul(t))
+}
+
+// Plain noise, fbm or turbulence between two colors
+pub struct NoiseTexture {
+    pub noise: Noise,
+    pub basis: Basis,
+    // Features per unit of distance
+    pub scale: f32,
+    pub octaves: u32,
+    pub lacunarity: f32,
+    pub gain: f32,
+    pub turbulence: bool,
+    pub low: Arc<dyn Texture>,
+    pub high: Arc<dyn Texture>
+}
+
+impl Texture for NoiseTexture {
+    fn value(&self, u: f32, v: f32, p: &Vec3) -> Vec3 {
+        let q = p.mul(self.scale);
+        let t = if self.turbulence {
+            self.noise.turbulence(self.basis, &q, self.octaves, self.lacunarity, self.gain)
+        } else {
+            0.5 * (1.0 + self.noise.fbm(self.basis, &q, self.octaves, self.lacunarity, self.gain))
+        };
+        blend(&self.low, &self.high, t, u, v, p)
+    }
+}
+
+// Which distance a Worley texture shows
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Feature {
+    // To the closest point, round cells
+    F1,
+    // To the second closest
+    F2,
+    // The difference, dark along the edges between cells
+    F2MinusF1
+}
+
+pub struct WorleyTexture {
+    pub noise: Noise,
+    pub scale: f32,
+    pub feature: Feature,
+    pub low: Arc<dyn Texture>,
+    pub high: Arc<dyn Texture>
+}
+
+impl Texture for WorleyTexture {
+    fn value(&self, u: f32, v: f32, p: &Vec3) -> Vec3 {
+        let (f1, f2) = self.noise.worley(&p.mul(self.scale));
+        let t = match self.feature {
+            Feature::F1 => f1,
+            Feature::F2 => f2,
+            Feature::F2MinusF1 => f2 - f1
+        };
+        blend(&self.low, &self.high, t, u, v, p)
+    }
+}
+
+// Bands along x bent by turbulence into veins
+pub struct MarbleTexture {
+    pub noise: Noise,
+    pub scale: f32,
+    // How far the turbulence pushes the bands around
+    pub distortion: f32,
+    pub octaves: u32,
+    pub low: Arc<dyn Texture>,
+    pub high: Arc<dyn Texture>
+}
+
+impl Texture for MarbleTexture {
+    fn value(&self, u: f32, v: f32, p: &Vec3) -> Vec3 {
+        let q = p.mul(self.scale);
+        let turbulence = self.noise.turbulence(Basis::Perlin, &q, self.octaves, 2.0, 0.5);
+        let t = 0.5 * (1.0 + (q.x + self.distortion * turbulence).sin());
+        blend(&self.low, &self.high, t, u, v, p)
+    }
+}
+
+// Rings around the y axis, wobbled a little by noise
+pub struct WoodTexture {
+    pub noise: Noise,
+    // Rings per unit of distance
+    pub scale: f32,
+    pub distortion: f32,
+    pub low: Arc<dyn Texture>,
+    pub high: Arc<dyn Texture>
+}
+
+impl Texture for WoodTexture {
+    fn value(&self, u: f32, v: f32, p: &Vec3) -> Vec3 {
+        let q = p.mul(self.scale);
+        let rings = (q.x * q.x + q.z * q.z).sqrt() + self.distortion * self.noise.fbm(Basis::Perlin, &q, 2, 2.0, 0.5);
+        blend(&self.low, &self.high, rings - rings.floor(), u, v, p)
+    }
+}
@@ -1,26 +1,42 @@
 #![allow(dead_code)]
-extern crate rand;
-use rand::Rng;
 
 use super::math::vec3::*;
 use super::ray::*;
 use super::material::*;
+use super::aabb::*;
+use super::sampler::*;
+use super::light::*;
 
-// For initializing HitRecords with default values
-const DEFAULT_MATERIAL: Lambertian = Lambertian {
-                                        albedo: Vec3 {
-                                            x: 0.0,
-                                            y: 0.0,
-                                            z: 0.0
-                                        } 
-                                    };
+// For initializing HitRecords with default values. Takes no space, so
+// boxing it doesn't allocate
+struct NoMaterial;
+
+impl Material for NoMaterial {
+    fn scatter(&self, _ray: &Ray, _rec: &HitRecord, _attenuation: &mut Vec3, _scattered: &mut Ray, _sampler: &mut dyn Sampler) -> bool {
+        false
+    }
+    fn copy(&self) -> Box<dyn Material> {
+        Box::new(NoMaterial)
+    }
+}
 
 // For storing hit data - important for proper layering
 pub struct HitRecord {
     pub t: f32,
     pub p: Vec3,
     pub normal: Vec3,
-    pub material: Box<Material>
+    // Texture coordinates
+    pub u: f32,
+    pub v: f32,
+    // Barycentric weights of a triangle's second and third vertices, the
+    // first gets 1 - b1 - b2. Zero for other shapes
+    pub b1: f32,
+    pub b2: f32,
+    // Which way u grows along the surface, for materials that are rougher
+    // in one direction. Not unit length or at right angles to the normal,
+    // and zero where there isn't one
+    pub tangent: Vec3,
+    pub material: Box<dyn Material>
 }
 
 impl HitRecord {
@@ -29,18 +45,44 @@ impl HitRecord {
             t: 0.0,
             p: Vec3::all(0.0),
             normal: Vec3::all(0.0),
-            material: Box::new(DEFAULT_MATERIAL)
+            u: 0.0,
+            v: 0.0,
+            b1: 0.0,
+            b2: 0.0,
+            tangent: Vec3::all(0.0),
+            material: Box::new(NoMaterial)
+        }
+    }
+    // For objects whose lights aren't in the scene's Lights
+    pub fn hide_light(&mut self) {
+        if self.material.is_light() {
+            let material = std::mem::replace(&mut self.material, Box::new(NoMaterial));
+            self.material = Box::new(UnsampledLight(material));
         }
     }
 }
 
 // Common traits for objects that can be queried for collisions etc
-pub trait Object {
+// Send + Sync so scenes can be shared between render threads
+pub trait Object: Send + Sync {
     fn check_hit(&self, r: &Ray, t_min: f32, t_max: f32, rec: &mut HitRecord) -> bool;
+    // None for objects without finite bounds, the BVH tests those separately
+    fn bounding_box(&self) -> Option<Aabb>;
+    // Adds the surfaces to sample for any parts that give off light
+    fn add_light_shapes(&self, _shapes: &mut Vec<LightShape>) {}
+    // How much light gets through the media along r between t_min and
+    // t_max, for shadow rays. Surfaces are left to check_hit
+    fn transmittance(&self, _r: &Ray, _t_min: f32, _t_max: f32) -> f32 {
+        1.0
+    }
+    // Whether transmittance can be anything but 1
+    fn has_media(&self) -> bool {
+        false
+    }
 }
 
 pub struct World {
-    objects: Vec<Box<Object>>
+    objects: Vec<Box<dyn Object>>
 }
 
 impl World {
@@ -49,14 +91,34 @@ impl World {
             objects: Vec::new()
         }
     }
-    pub fn add_object(&mut self, obj: Box<Object>) {
+    pub fn add_object(&mut self, obj: Box<dyn Object>) {
         self.objects.push(obj);
     }
-    pub fn pop_object(&mut self) -> Option<Box<Object>> {
+    pub fn pop_object(&mut self) -> Option<Box<dyn Object>> {
         self.objects.pop()
     }
-    pub fn random() -> World {
-        let mut rng = rand::thread_rng();
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+    // Everything that emits, gathered before the objects go into a BVH
+    pub fn lights(&self) -> Lights {
+        let mut shapes = Vec::new();
+        for o in &self.objects {
+            o.add_light_shapes(&mut shapes);
+        }
+        Lights::new(shapes)
+    }
+    // Hands over the objects in the order they were added
+    pub fn into_objects(self) -> Vec<Box<dyn Object>> {
+        self.objects
+    }
+    // Scatters small spheres around the three big ones, the same seed always
+    // gives the same layout
+    pub fn random(seed: u64) -> World {
+        let mut sampler = RandomSampler::new(seed);
         let mut world = World::new();
         // Add the ground
         world.add_object(Box::new(
@@ -67,15 +129,15 @@ impl World {
         // Add a bunch of random spheres
         for _ in 0..100 {
             world.add_object(Box::new(
-                Sphere::new(Vec3::new(25.0 * (rng.gen::<f32>() - 0.5), 0.2, 25.0 * (rng.gen::<f32>() - 0.5)),
-                    0.2, Box::new(Lambertian::new(Vec3::new(rng.gen::<f32>(), rng.gen::<f32>(), rng.gen::<f32>())))
+                Sphere::new(Vec3::new(25.0 * (sampler.next_f32() - 0.5), 0.2, 25.0 * (sampler.next_f32() - 0.5)),
+                    0.2, Box::new(Lambertian::new(Vec3::new(sampler.next_f32(), sampler.next_f32(), sampler.next_f32())))
                 )
             ));
         }
         // Add the three big spheres
         world.add_object(Box::new(
             Sphere::new(Vec3::new(0.0, 1.0, 0.0),
-                1.0, Box::new(Dielectric { ref_idx: 1.5 })
+                1.0, Box::new(Dielectric::new(1.5))
             )
         ));
         world.add_object(Box::new(
@@ -95,6 +157,7 @@ impl World {
 // TODO: impl Object for World
 
 impl Object for World {
+    #[allow(clippy::needless_return)]
     fn check_hit(&self, r: &Ray, t_min: f32, t_max: f32, rec: &mut HitRecord) -> bool {
         let mut temp = HitRecord::default();
         let mut hit_anything = false;
@@ -106,21 +169,40 @@ impl Object for World {
                 rec.t = temp.t;
                 rec.p = temp.p.copy();
                 rec.normal = temp.normal.copy();
+                rec.u = temp.u;
+                rec.v = temp.v;
+                rec.b1 = temp.b1;
+                rec.b2 = temp.b2;
+                rec.tangent = temp.tangent.copy();
                 rec.material = temp.material.copy()
             }
         }
         return hit_anything;
     }
+    fn bounding_box(&self) -> Option<Aabb> {
+        let mut res = Aabb::empty();
+        for i in &self.objects {
+            res.grow_eq(&i.bounding_box()?);
+        }
+        Some(res)
+    }
+    fn transmittance(&self, r: &Ray, t_min: f32, t_max: f32) -> f32 {
+        self.objects.iter().map(|i| i.transmittance(r, t_min, t_max)).product()
+    }
+    fn has_media(&self) -> bool {
+        self.objects.iter().any(|i| i.has_media())
+    }
 }
 
 pub struct Sphere {
     center: Vec3,
     radius: f32,
-    material: Box<Material>
+    material: Box<dyn Material>
 }
 
 impl Sphere {
-    pub fn new(center: Vec3, radius: f32, material: Box<Material>) -> Sphere {
+    #[allow(clippy::redundant_field_names)]
+    pub fn new(center: Vec3, radius: f32, material: Box<dyn Material>) -> Sphere {
         Sphere {
             center: center,
             radius: radius,
@@ -129,7 +211,71 @@ impl Sphere {
     }
 }
 
+impl Sphere {
+    // u goes once around the equator starting from -x, v from the bottom
+    // pole to the top one
+    fn set_uv(&self, rec: &mut HitRecord) {
+        let d = rec.p.sub_by_vec(&self.center).div(self.radius.abs());
+        let theta = (-d.y).clamp(-1.0, 1.0).acos();
+        let phi = (-d.z).atan2(d.x) + std::f32::consts::PI;
+        rec.u = phi / (2.0 * std::f32::consts::PI);
+        rec.v = theta / std::f32::consts::PI;
+        rec.b1 = 0.0;
+        rec.b2 = 0.0;
+        // Around the equator, vanishing at the poles
+        rec.tangent = Vec3::new(d.z, 0.0, -d.x);
+    }
+}
+
+// A sphere going in a straight line from center0 at time0 to center1 at
+// time1, and staying put before and after
+pub struct MovingSphere {
+    sphere: Sphere,
+    offset: Vec3,
+    time0: f32,
+    time1: f32
+}
+
+impl MovingSphere {
+    pub fn new(center0: Vec3, center1: Vec3, time0: f32, time1: f32, radius: f32, material: Box<dyn Material>) -> MovingSphere {
+        MovingSphere {
+            offset: center1.sub_by_vec(&center0),
+            sphere: Sphere::new(center0, radius, material),
+            time0,
+            time1
+        }
+    }
+    // How far it has moved from center0 by time
+    fn offset_at(&self, time: f32) -> Vec3 {
+        if self.time1 <= self.time0 {
+            return Vec3::all(0.0);
+        }
+        self.offset.mul(((time - self.time0) / (self.time1 - self.time0)).clamp(0.0, 1.0))
+    }
+}
+
+impl Object for MovingSphere {
+    fn check_hit(&self, r: &Ray, t_min: f32, t_max: f32, rec: &mut HitRecord) -> bool {
+        // Moving the ray back instead of the sphere forward
+        let offset = self.offset_at(r.time);
+        let moved = r.moved(r.origin.sub_by_vec(&offset), r.direction.copy());
+        if !self.sphere.check_hit(&moved, t_min, t_max, rec) {
+            return false;
+        }
+        rec.p.add_by_vec_eq(&offset);
+        rec.hide_light();
+        true
+    }
+    fn bounding_box(&self) -> Option<Aabb> {
+        let mut res = self.sphere.bounding_box()?;
+        let end = Aabb::new(res.min.add_by_vec(&self.offset), res.max.add_by_vec(&self.offset));
+        res.grow_eq(&end);
+        Some(res)
+    }
+}
+
 impl Object for Sphere {
+    #[allow(clippy::needless_return)]
     fn check_hit(&self, r: &Ray, t_min: f32, t_max: f32, rec: &mut HitRecord) -> bool {
         let oc = r.origin.sub_by_vec(&self.center);
         let a = dot_product(&r.direction, &r.direction);
@@ -143,6 +289,7 @@ impl Object for Sphere {
                 rec.t = temp;
                 rec.p = r.point_at_parameter(temp);
                 rec.normal = rec.p.sub_by_vec(&self.center).div(self.radius);
+                self.set_uv(rec);
                 rec.material = self.material.copy();
                 return true;
             }
@@ -151,10 +298,21 @@ impl Object for Sphere {
                 rec.t = temp;
                 rec.p = r.point_at_parameter(temp);
                 rec.normal = rec.p.sub_by_vec(&self.center).div(self.radius);
+                self.set_uv(rec);
                 rec.material = self.material.copy();
                 return true;
             }
         }
         return false;
     }
+    fn bounding_box(&self) -> Option<Aabb> {
+        // Negative radii are used for hollow glass, so take the magnitude
+        let r = Vec3::all(self.radius.abs());
+        Some(Aabb::new(self.center.sub_by_vec(&r), self.center.add_by_vec(&r)))
+    }
+    fn add_light_shapes(&self, shapes: &mut Vec<LightShape>) {
+        if self.material.is_light() {
+            shapes.push(LightShape::Sphere { center: self.center.copy(), radius: self.radius });
+        }
+    }
 }
\ No newline at end of file
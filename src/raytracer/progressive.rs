@@ -0,0 +1,271 @@
+#![allow(dead_code)]
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+
+use super::math::vec3::*;
+use super::film::*;
+
+const CHECKPOINT_MAGIC: [u8; 4] = *b"RTCK";
+// Version 1 seeded samples per tile and pass, its sums can't be continued
+const CHECKPOINT_VERSION: u32 = 2;
+
+// Running sums for a render built up over several passes. Sums are f64 so
+// adding thousands of samples doesn't lose the small ones
+pub struct Accumulator {
+    width: u32,
+    height: u32,
+    sums: Vec<[f64; 3]>,
+    counts: Vec<u32>,
+    // Passes rendered so far, only used for reporting progress
+    pub passes: u32,
+    pub seed: u64,
+    // Identifies the scene and settings the sums belong to
+    pub scene_hash: u64
+}
+
+impl Accumulator {
+    pub fn new(width: u32, height: u32, seed: u64, scene_hash: u64) -> Accumulator {
+        let n = width as usize * height as usize;
+        Accumulator {
+            width,
+            height,
+            sums: vec![[0.0; 3]; n],
+            counts: vec![0; n],
+            passes: 0,
+            seed,
+            scene_hash
+        }
+    }
+    pub fn get_dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+    // Sum of every sample a pixel has so far
+    pub fn sum(&self, x: u32, y: u32) -> [f64; 3] {
+        self.sums[y as usize * self.width as usize + x as usize]
+    }
+    // Replaces a pixel's running sum after more samples were added to it.
+    // The renderer keeps adding to the old sum one sample at a time, so the
+    // result doesn't depend on how samples were split into passes
+    pub fn set(&mut self, x: u32, y: u32, sum: [f64; 3], count: u32) {
+        let i = y as usize * self.width as usize + x as usize;
+        self.sums[i] = sum;
+        self.counts[i] = count;
+    }
+    // Samples a pixel has so far, which is also the number its next
+    // sample gets for seeding
+    pub fn count(&self, x: u32, y: u32) -> u32 {
+        self.counts[y as usize * self.width as usize + x as usize]
+    }
+    // Fewest samples any pixel has, which is how far the render has got
+    pub fn samples_done(&self) -> u32 {
+        self.counts.iter().copied().min().unwrap_or(0)
+    }
+    // Averages what has been gathered so far
+    pub fn to_film(&self) -> Film {
+        let mut film = Film::new(self.width, self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let i = y as usize * self.width as usize + x as usize;
+                let n = self.counts[i].max(1) as f64;
+                let s = &self.sums[i];
+                film.set(x, y, Vec3::new((s[0] / n) as f32, (s[1] / n) as f32, (s[2] / n) as f32));
+            }
+        }
+        film
+    }
+    // Writes a checkpoint next to path and then moves it into place, so a
+    // crash halfway through never leaves a broken checkpoint behind
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let tmp = format!("{}.tmp", path);
+        {
+            let mut out = BufWriter::new(File::create(&tmp)?);
+            out.write_all(&CHECKPOINT_MAGIC)?;
+            out.write_all(&CHECKPOINT_VERSION.to_le_bytes())?;
+            out.write_all(&self.width.to_le_bytes())?;
+            out.write_all(&self.height.to_le_bytes())?;
+            out.write_all(&self.seed.to_le_bytes())?;
+            out.write_all(&self.scene_hash.to_le_bytes())?;
+            out.write_all(&self.passes.to_le_bytes())?;
+            for (sum, count) in self.sums.iter().zip(&self.counts) {
+                for c in sum {
+                    out.write_all(&c.to_le_bytes())?;
+                }
+                out.write_all(&count.to_le_bytes())?;
+            }
+            out.flush()?;
+        }
+        std::fs::rename(&tmp, path)
+    }
+    // Reads a checkpoint written by save
+    pub fn load(path: &str) -> Result<Accumulator, String> {
+        let (header, mut input) = read_header(path)?;
+        read_body(header, &mut input, path)
+    }
+    // Loads a checkpoint to carry on with, as long as it was made from the
+    // same scene and settings, and with the seed asked for if there is one.
+    // The header is checked before anything gets allocated for the pixels
+    pub fn resume(path: &str, width: u32, height: u32, scene_hash: u64, seed: Option<u64>) -> Result<Accumulator, String> {
+        let (header, mut input) = read_header(path)?;
+        if header.scene_hash != scene_hash {
+            return Err(format!("checkpoint {} was made from a different scene or settings", path));
+        }
+        if (header.width, header.height) != (width, height) {
+            return Err(format!("checkpoint {} is {}x{}, expected {}x{}", path, header.width, header.height, width, height));
+        }
+        if seed.is_some_and(|seed| seed != header.seed) {
+            return Err(format!("checkpoint {} was rendered with --seed {}", path, header.seed));
+        }
+        read_body(header, &mut input, path)
+    }
+}
+
+// Everything in a checkpoint before the pixels
+struct Header {
+    width: u32,
+    height: u32,
+    seed: u64,
+    scene_hash: u64,
+    passes: u32
+}
+
+const HEADER_BYTES: u64 = 4 + 4 + 4 + 4 + 8 + 8 + 4;
+// Three f64 sums and a u32 count
+const PIXEL_BYTES: u64 = 3 * 8 + 4;
+
+// Reads the header and makes sure the file holds exactly as many pixels as
+// it says, so a damaged one can't ask for more memory than it could fill
+fn read_header(path: &str) -> Result<(Header, BufReader<File>), String> {
+    let file = File::open(path).map_err(|e| format!("could not open checkpoint {}: {}", path, e))?;
+    let file_size = file.metadata().map_err(|e| format!("could not read checkpoint {}: {}", path, e))?.len();
+    let mut input = BufReader::new(file);
+    let truncated = |_| format!("checkpoint {} is truncated", path);
+
+    let mut magic = [0u8; 4];
+    input.read_exact(&mut magic).map_err(truncated)?;
+    if magic != CHECKPOINT_MAGIC {
+        return Err(format!("{} is not a checkpoint file", path));
+    }
+    let version = read_u32(&mut input).map_err(truncated)?;
+    if version != CHECKPOINT_VERSION {
+        return Err(format!("checkpoint {} has version {}, expected {}", path, version, CHECKPOINT_VERSION));
+    }
+    let header = Header {
+        width: read_u32(&mut input).map_err(truncated)?,
+        height: read_u32(&mut input).map_err(truncated)?,
+        seed: read_u64(&mut input).map_err(truncated)?,
+        scene_hash: read_u64(&mut input).map_err(truncated)?,
+        passes: read_u32(&mut input).map_err(truncated)?
+    };
+    let expected = (header.width as u64).checked_mul(header.height as u64)
+        .and_then(|n| n.checked_mul(PIXEL_BYTES))
+        .and_then(|n| n.checked_add(HEADER_BYTES));
+    if expected != Some(file_size) {
+        return Err(format!("checkpoint {} is {} bytes, a {}x{} one should be {}", path, file_size,
+            header.width, header.height, expected.map_or("more".to_string(), |n| n.to_string())));
+    }
+    Ok((header, input))
+}
+
+fn read_body(header: Header, input: &mut BufReader<File>, path: &str) -> Result<Accumulator, String> {
+    let truncated = |_| format!("checkpoint {} is truncated", path);
+    let mut acc = Accumulator::new(header.width, header.height, header.seed, header.scene_hash);
+    acc.passes = header.passes;
+    for i in 0..acc.sums.len() {
+        for c in 0..3 {
+            acc.sums[i][c] = f64::from_bits(read_u64(input).map_err(truncated)?);
+        }
+        acc.counts[i] = read_u32(input).map_err(truncated)?;
+    }
+    Ok(acc)
+}
+
+fn read_u32<R: Read>(input: &mut R) -> io::Result<u32> {
+    let mut b = [0u8; 4];
+    input.read_exact(&mut b)?;
+    Ok(u32::from_le_bytes(b))
+}
+
+fn read_u64<R: Read>(input: &mut R) -> io::Result<u64> {
+    let mut b = [0u8; 8];
+    input.read_exact(&mut b)?;
+    Ok(u64::from_le_bytes(b))
+}
+
+// 64-bit FNV-1a, stable across builds unlike std's DefaultHasher
+pub fn fnv1a(bytes: &[u8], mut hash: u64) -> u64 {
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+pub const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::render::*;
+    use super::super::render::tests::Fixture;
+
+    fn new_accumulator(fixture: &Fixture) -> Accumulator {
+        let settings = &fixture.settings;
+        Accumulator::new(settings.width, settings.height, settings.seed, fixture.hash)
+    }
+
+    #[test]
+    fn resuming_matches_one_run() {
+        let fixture = Fixture::new();
+        let path = std::env::temp_dir().join(format!("rtck-test-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let mut whole = new_accumulator(&fixture);
+        render_pass(&fixture.view(), &fixture.camera, &fixture.settings, 5, &mut whole);
+
+        let mut first = new_accumulator(&fixture);
+        render_pass(&fixture.view(), &fixture.camera, &fixture.settings, 2, &mut first);
+        first.save(path).unwrap();
+        let mut resumed = Accumulator::resume(path, fixture.settings.width, fixture.settings.height, fixture.hash, Some(fixture.settings.seed)).unwrap();
+        assert_eq!(resumed.passes, 1);
+        render_pass(&fixture.view(), &fixture.camera, &fixture.settings, 3, &mut resumed);
+
+        let bits = |acc: &Accumulator| acc.sums.iter().flatten().map(|s| s.to_bits()).collect::<Vec<_>>();
+        assert!(bits(&whole) == bits(&resumed));
+        assert_eq!(whole.counts, resumed.counts);
+        assert_eq!(resumed.samples_done(), 5);
+
+        // Anything else about the render changing makes the sums useless
+        let (width, height) = (fixture.settings.width, fixture.settings.height);
+        assert!(Accumulator::resume(path, width, height, fixture.hash ^ 1, None).is_err());
+        assert!(Accumulator::resume(path, width, height, fixture.hash, Some(fixture.settings.seed + 1)).is_err());
+        assert!(Accumulator::resume(path, width + 1, height, fixture.hash, None).is_err());
+        assert!(Accumulator::resume(path, width, height, fixture.hash, None).is_ok());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn damaged_checkpoints_are_rejected_before_allocating() {
+        let path = std::env::temp_dir().join(format!("rtck-damaged-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let acc = Accumulator::new(3, 2, 7, 11);
+        acc.save(path).unwrap();
+        let good = std::fs::read(path).unwrap();
+        assert!(Accumulator::load(path).is_ok());
+
+        // A header claiming a huge image, on a file that only holds six pixels
+        let mut huge = good.clone();
+        huge[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        huge[12..16].copy_from_slice(&u32::MAX.to_le_bytes());
+        std::fs::write(path, &huge).unwrap();
+        assert!(Accumulator::load(path).is_err());
+        assert!(Accumulator::resume(path, 3, 2, 11, None).is_err());
+
+        // Cut short, or with bytes left over
+        std::fs::write(path, &good[..good.len() - 1]).unwrap();
+        assert!(Accumulator::load(path).is_err());
+        std::fs::write(path, [&good[..], &[0]].concat()).unwrap();
+        assert!(Accumulator::load(path).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,66 +1,172 @@
 #![allow(dead_code)]
-extern crate rand;
-use rand::Rng;
-
 use super::math::vec3::*;
 use super::object::*;
+use super::sampler::*;
+use super::environment::*;
+use super::light::*;
+use super::medium::*;
 
 pub struct Ray {
     pub origin: Vec3,
-    pub direction: Vec3
+    pub direction: Vec3,
+    // When the ray was sent while the shutter was open, for moving objects
+    pub time: f32,
+    // Shadow rays go straight through media in check_hit, and ask for
+    // their transmittance afterwards instead
+    pub skip_media: bool
 }
 
 impl Ray {
-    pub fn new(origin: Vec3, direction: Vec3) -> Ray {
+    pub fn new(origin: Vec3, direction: Vec3, time: f32) -> Ray {
         Ray {
-            origin: origin,
-            direction: direction
+            origin,
+            direction,
+            time,
+            skip_media: false
+        }
+    }
+    // Same path but starting somewhere else, for objects that move rays
+    // into their own space
+    pub fn moved(&self, origin: Vec3, direction: Vec3) -> Ray {
+        Ray {
+            origin,
+            direction,
+            time: self.time,
+            skip_media: self.skip_media
         }
     }
     // p(t) = Origin + Direction * t
     pub fn point_at_parameter(&self, t: f32) -> Vec3 {
         self.origin.add_by_vec(&self.direction.mul(t))
     }
-    // Get the color for a ray
-    pub fn get_color(&self, world: &dyn Object, depth: i32) -> Vec3 {
+    // Get the color for a ray, bouncing at most max_depth times
+    // The sampler belongs to the sample being traced
+    pub fn get_color(&self, scene: &SceneView, max_depth: u32, sampler: &mut dyn Sampler) -> Vec3 {
+        // Nothing to weigh the first hit's emission against
+        self.trace(scene, 0, max_depth, 0.0, sampler)
+    }
+    // bsdf_pdf is the density the last bounce picked this ray's direction
+    // with, zero for camera rays and single direction bounces
+    fn trace(&self, scene: &SceneView, depth: u32, max_depth: u32, bsdf_pdf: f32, sampler: &mut dyn Sampler) -> Vec3 {
         // Check hits
         let mut temp = HitRecord::default();
-        let hit = world.check_hit(&self, 0.001, std::f32::MAX, &mut temp);
-
-        if hit {
-            // Predefine structs
-            let mut scattered = Ray::new(Vec3::all(0.0), Vec3::all(0.0));
-            let mut attenuation = Vec3::all(0.0);
-            if depth < 50 && temp.material.scatter(&self, &temp, &mut attenuation, &mut scattered) {
-                return attenuation.mul_by_vec(&scattered.get_color(world, depth + 1));
-            } else {
-                return Vec3::all(0.0);
+        let mut hit = scene.world.check_hit(self, 0.001, f32::MAX, &mut temp);
+        // Camera rays can stop in the fog before they reach anything
+        if let (0, Some(fog)) = (depth, scene.fog) {
+            let t = fog.sample_distance(self, sampler);
+            if !hit || t < temp.t {
+                temp = fog.hit_at(self, t);
+                hit = true;
+            }
+        }
+        if !hit {
+            // If there wasn't a hit, the light comes from the environment
+            let mut color = scene.environment.color(&self.direction);
+            if bsdf_pdf > 0.0 && scene.environment.is_sampled() {
+                color.mul_eq(power_heuristic(bsdf_pdf, scene.environment.pdf(&self.direction)));
             }
-        } else {
-            // If there wasn't a hit, just show the background color
-            let unit_direction = self.direction.as_unit();
-            let t = (unit_direction.y + 1.0) * 0.5;
-            // Saving memory by modifying vectors in place
-            let mut step1 = Vec3::new(0.5, 0.7, 1.0);
-            step1.mul_eq(t);
-            let mut res = Vec3::all(1.0);
-            res.mul_eq(1.0 - t);
-            res.add_by_vec_eq(&step1);
-            res
+            return color;
+        }
+
+        // Light given off by the surface itself, black for most materials
+        let mut color = temp.material.emitted(self, &temp);
+        // Lights also get reached by sampling them at the last bounce, so
+        // this only gets its share
+        if bsdf_pdf > 0.0 && temp.material.is_light() {
+            let light_pdf = scene.lights.pdf(&self.origin, &temp.p, &temp.normal);
+            color.mul_eq(power_heuristic(bsdf_pdf, light_pdf));
+        }
+        if depth >= max_depth {
+            return color;
+        }
+        if !scene.lights.is_empty() {
+            color.add_by_vec_eq(&self.sample_light(scene, &temp, sampler));
+        }
+        if scene.environment.is_sampled() {
+            color.add_by_vec_eq(&self.sample_environment(scene, &temp, sampler));
+        }
+
+        // Predefine structs, bounces happen at the same moment
+        let mut scattered = Ray::new(Vec3::all(0.0), Vec3::all(0.0), self.time);
+        let mut attenuation = Vec3::all(0.0);
+        if temp.material.scatter(self, &temp, &mut attenuation, &mut scattered, sampler) {
+            let pdf = temp.material.pdf(self, &temp, &scattered.direction.as_unit());
+            color.add_by_vec_eq(&attenuation.mul_by_vec(&scattered.trace(scene, depth + 1, max_depth, pdf, sampler)));
+        }
+        color
+    }
+    // Next event estimation: light from a point picked on one of the
+    // lights, if nothing's in the way
+    fn sample_light(&self, scene: &SceneView, rec: &HitRecord, sampler: &mut dyn Sampler) -> Vec3 {
+        let (point, normal) = scene.lights.sample(sampler);
+        let to_light = point.sub_by_vec(&rec.p);
+        let distance = to_light.magnitude();
+        let direction = to_light.div(distance);
+        let bsdf = rec.material.bsdf(self, rec, &direction);
+        let light_pdf = scene.lights.pdf(&rec.p, &point, &normal);
+        // Saves the shadow ray for mirrors, glass and lights seen edge on
+        if light_pdf <= 0.0 || (bsdf.x <= 0.0 && bsdf.y <= 0.0 && bsdf.z <= 0.0) {
+            return Vec3::all(0.0);
         }
+        // Whatever's hit has to be the sampled point, anything closer is
+        // in the way
+        let shadow = self.shadow_ray(rec, direction);
+        let mut hit = HitRecord::default();
+        if !scene.world.check_hit(&shadow, 0.001, distance * 1.001, &mut hit) || hit.t < distance * 0.999 {
+            return Vec3::all(0.0);
+        }
+        let weight = power_heuristic(light_pdf, rec.material.pdf(self, rec, &shadow.direction));
+        let transmittance = scene.world.transmittance(&shadow, 0.001, hit.t);
+        hit.material.emitted(&shadow, &hit).mul_by_vec(&bsdf).mul(transmittance * weight / light_pdf)
+    }
+    // The same for a direction picked from the environment, which counts
+    // if the shadow ray gets out without hitting anything
+    fn sample_environment(&self, scene: &SceneView, rec: &HitRecord, sampler: &mut dyn Sampler) -> Vec3 {
+        let (direction, env_pdf) = scene.environment.sample(sampler);
+        let bsdf = rec.material.bsdf(self, rec, &direction);
+        if env_pdf <= 0.0 || (bsdf.x <= 0.0 && bsdf.y <= 0.0 && bsdf.z <= 0.0) {
+            return Vec3::all(0.0);
+        }
+        let shadow = self.shadow_ray(rec, direction);
+        let mut hit = HitRecord::default();
+        if scene.world.check_hit(&shadow, 0.001, f32::MAX, &mut hit) {
+            return Vec3::all(0.0);
+        }
+        let weight = power_heuristic(env_pdf, rec.material.pdf(self, rec, &shadow.direction));
+        let transmittance = scene.world.transmittance(&shadow, 0.001, f32::MAX);
+        scene.environment.color(&shadow.direction).mul_by_vec(&bsdf).mul(transmittance * weight / env_pdf)
+    }
+    fn shadow_ray(&self, rec: &HitRecord, direction: Vec3) -> Ray {
+        let mut shadow = Ray::new(rec.p.copy(), direction, self.time);
+        shadow.skip_media = true;
+        shadow
     }
 }
 
+// Everything a path can hit or be lit by
+pub struct SceneView<'a> {
+    pub world: &'a dyn Object,
+    pub lights: &'a Lights,
+    pub environment: &'a Environment,
+    pub fog: Option<&'a Fog>
+}
+
+// Veach's power heuristic with an exponent of 2, the weight for a sample
+// taken with density pdf when other could have produced it too
+fn power_heuristic(pdf: f32, other: f32) -> f32 {
+    let (a, b) = (pdf * pdf, other * other);
+    if a + b > 0.0 { a / (a + b) } else { 0.0 }
+}
+
 // For calculating the direction of a randomly bouncing ray
-pub fn random_in_unit_sphere() -> Vec3 {
-    let mut rng = rand::thread_rng();
+pub fn random_in_unit_sphere(sampler: &mut dyn Sampler) -> Vec3 {
     let mut p = Vec3::all(0.0);
     let ones = Vec3::all(1.0); // Save memory
     loop {
         // Random point in unit cube, saving memory
-        p.x = rng.gen::<f32>();
-        p.y = rng.gen::<f32>();
-        p.z = rng.gen::<f32>();
+        p.x = sampler.next_f32();
+        p.y = sampler.next_f32();
+        p.z = sampler.next_f32();
         // Check if it's within the unit sphere
         p.sub_by_vec_eq(&ones);
         p.mul_eq(2.0);
@@ -68,4 +174,50 @@ pub fn random_in_unit_sphere() -> Vec3 {
             return p;
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::material::*;
+
+    // Mean and variance of samples of the light leaving a diffuse floor
+    // under a small lamp, one bounce deep
+    fn floor_under_lamp(lights: bool, samples: usize) -> (f64, f64) {
+        let mut world = World::new();
+        world.add_object(Box::new(Sphere::new(Vec3::new(0.0, -1000.0, 0.0), 1000.0, Box::new(Lambertian::new(Vec3::all(0.5))))));
+        world.add_object(Box::new(Sphere::new(Vec3::new(0.0, 2.0, 0.0), 0.1, Box::new(DiffuseLight::new(Vec3::all(1.0), 100.0)))));
+        // Without any lights the lamp can only be found by bouncing into it
+        let lights = if lights { world.lights() } else { Lights::new(Vec::new()) };
+        let environment = Environment::new(Source::Color(Vec3::all(0.0)), 1.0, 0.0);
+        let scene = SceneView {
+            world: &world,
+            lights: &lights,
+            environment: &environment,
+            fog: None
+        };
+        let mut sampler = RandomSampler::new(5);
+        let (mut sum, mut sum_squared) = (0.0, 0.0);
+        for _ in 0..samples {
+            let r = Ray::new(Vec3::new(0.5, 1.0, 0.5), Vec3::new(-0.5, -1.0, -0.5), 0.0);
+            let c = r.get_color(&scene, 1, &mut sampler).x as f64;
+            sum += c;
+            sum_squared += c * c;
+        }
+        let mean = sum / samples as f64;
+        (mean, sum_squared / samples as f64 - mean * mean)
+    }
+
+    #[test]
+    fn light_sampling_converges_faster_without_bias() {
+        // albedo * intensity * sin² of the angle the lamp takes up
+        let expected = 0.5 * 100.0 * (0.1f64 / 2.0).powi(2);
+        let samples = 40000;
+        let (mis, mis_variance) = floor_under_lamp(true, samples);
+        let (bsdf, bsdf_variance) = floor_under_lamp(false, samples);
+        let error = |variance: f64| 4.0 * (variance / samples as f64).sqrt();
+        assert!((mis - expected).abs() < error(mis_variance), "{} with light sampling, expected {}", mis, expected);
+        assert!((bsdf - expected).abs() < error(bsdf_variance), "{} from bounces alone, expected {}", bsdf, expected);
+        assert!(mis_variance * 50.0 < bsdf_variance, "variance {} with light sampling, {} without", mis_variance, bsdf_variance);
+    }
+}
@@ -0,0 +1,302 @@
+#![allow(dead_code)]
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use super::camera::*;
+use super::bvh::*;
+use super::film::*;
+use super::progressive::*;
+use super::sampler::*;
+use super::ray::*;
+
+// Edge length of the square tiles handed out to the worker threads
+pub const TILE_SIZE: u32 = 32;
+
+// Everything the renderer needs to know that isn't part of the scene
+pub struct RenderSettings {
+    pub width: u32,
+    pub height: u32,
+    pub samples: u16, // Samples per pixel
+    pub max_depth: u32, // Bounces before a path is cut off
+    pub threads: usize, // Worker threads, 0 means one per core
+    pub seed: u64 // Base seed every pixel's samplers are derived from
+}
+
+impl Default for RenderSettings {
+    fn default() -> RenderSettings {
+        RenderSettings {
+            width: 2000,
+            height: 1000,
+            samples: 100,
+            max_depth: 50,
+            threads: 0,
+            seed: 0
+        }
+    }
+}
+
+// A rectangular block of pixels, x1 and y1 are exclusive
+// Rows count down from the top of the image
+pub struct Tile {
+    pub x0: u32,
+    pub y0: u32,
+    pub x1: u32,
+    pub y1: u32
+}
+
+impl Tile {
+    pub fn width(&self) -> u32 {
+        self.x1 - self.x0
+    }
+    pub fn height(&self) -> u32 {
+        self.y1 - self.y0
+    }
+}
+
+// Splits the image into tiles, left to right then top to bottom
+// Tiles on the right and bottom edges may be smaller than TILE_SIZE
+pub fn make_tiles(width: u32, height: u32) -> Vec<Tile> {
+    let mut tiles = Vec::new();
+    for y0 in (0..height).step_by(TILE_SIZE as usize) {
+        for x0 in (0..width).step_by(TILE_SIZE as usize) {
+            tiles.push(Tile {
+                x0,
+                y0,
+                x1: (x0 + TILE_SIZE).min(width),
+                y1: (y0 + TILE_SIZE).min(height)
+            });
+        }
+    }
+    tiles
+}
+
+// Resolves a thread count of 0 to the number of available cores
+pub fn thread_count(requested: usize) -> usize {
+    if requested > 0 {
+        requested
+    } else {
+        thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+    }
+}
+
+// Renders the whole image in one pass. Returns the film along with the BVH
+// counters gathered by the workers
+pub fn render(scene: &SceneView, cam: &Camera, settings: &RenderSettings) -> (Film, TraversalStats) {
+    let mut acc = Accumulator::new(settings.width, settings.height, settings.seed, 0);
+    let stats = render_pass(scene, cam, settings, settings.samples as u32, &mut acc);
+    (acc.to_film(), stats)
+}
+
+// Adds `samples` more samples to every pixel of acc, with a pool of worker
+// threads pulling tiles off a shared counter. The seed and sample counts
+// come from acc so resumed renders pick up where they left off
+pub fn render_pass(scene: &SceneView, cam: &Camera, settings: &RenderSettings, samples: u32, acc: &mut Accumulator) -> TraversalStats {
+    let tiles = make_tiles(settings.width, settings.height);
+    let next_tile = AtomicUsize::new(0);
+    let finished: Mutex<Vec<(usize, Vec<[f64; 3]>)>> = Mutex::new(Vec::with_capacity(tiles.len()));
+    let stats = Mutex::new(TraversalStats::default());
+    let workers = thread_count(settings.threads).min(tiles.len());
+    let done: &Accumulator = acc;
+
+    thread::scope(|s| {
+        for _ in 0..workers {
+            s.spawn(|| {
+                take_thread_stats();
+                loop {
+                    let index = next_tile.fetch_add(1, Ordering::Relaxed);
+                    if index >= tiles.len() {
+                        break;
+                    }
+                    let pixels = render_tile(scene, cam, settings, &tiles[index], samples, done);
+                    finished.lock().unwrap().push((index, pixels));
+                }
+                stats.lock().unwrap().add_eq(&take_thread_stats());
+            });
+        }
+    });
+
+    // Add the finished tiles to the running sums
+    for (index, sums) in finished.into_inner().unwrap() {
+        let tile = &tiles[index];
+        for (i, sum) in sums.into_iter().enumerate() {
+            let (x, y) = (tile.x0 + i as u32 % tile.width(), tile.y0 + i as u32 / tile.width());
+            let count = acc.count(x, y) + samples;
+            acc.set(x, y, sum, count);
+        }
+    }
+    acc.passes += 1;
+    stats.into_inner().unwrap()
+}
+
+// Renders a single tile, returning each pixel's new running sum in
+// row-major order. Samples are numbered on from the ones already in done,
+// and each one gets a sampler seeded from its pixel and number
+fn render_tile(scene: &SceneView, cam: &Camera, settings: &RenderSettings, tile: &Tile, samples: u32, done: &Accumulator) -> Vec<[f64; 3]> {
+    let mut pixels = Vec::with_capacity((tile.width() * tile.height()) as usize);
+    for row in tile.y0..tile.y1 {
+        // The camera's v axis points up, image rows count down
+        let y = settings.height - row - 1;
+        for x in tile.x0..tile.x1 {
+            // Carry on from the samples earlier passes gathered
+            let mut sum = done.sum(x, row);
+            // Loops for antialiasing
+            let first = done.count(x, row);
+            for sample in first..first + samples {
+                let mut sampler = RandomSampler::for_sample(done.seed, x, row, sample);
+                let (du, dv) = sampler.next_2d();
+                let u = (x as f32 + du) / settings.width as f32;
+                let v = (y as f32 + dv) / settings.height as f32;
+                let ray = cam.get_ray(u, v, &mut sampler);
+                let color = ray.get_color(scene, settings.max_depth, &mut sampler);
+                sum[0] += color.x as f64;
+                sum[1] += color.y as f64;
+                sum[2] += color.z as f64;
+            }
+            pixels.push(sum);
+        }
+    }
+    pixels
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use super::super::environment::*;
+    use super::super::light::*;
+    use super::super::scene::*;
+
+    // A small scene with every kind of bounce, over a few tiles
+    const SCENE: &str = r#"
+[render]
+width = 70
+height = 40
+samples = 3
+max_depth = 8
+
+[camera]
+look_from = [0.0, 1.0, 5.0]
+look_at = [0.0, 0.5, 0.0]
+vfov = 40.0
+focus_distance = 5.0
+aperture = 0.1
+
+[materials.ground]
+type = "lambertian"
+albedo = [0.5, 0.5, 0.5]
+
+[materials.shiny]
+type = "metal"
+albedo = [0.8, 0.6, 0.4]
+fuzz = 0.2
+
+[materials.glass]
+type = "dielectric"
+ref_idx = 1.5
+
+[materials.lamp]
+type = "diffuse_light"
+intensity = 4.0
+
+[[objects]]
+type = "sphere"
+center = [0.0, -100.0, 0.0]
+radius = 100.0
+material = "ground"
+
+[[objects]]
+type = "sphere"
+center = [-0.8, 0.5, 0.0]
+radius = 0.5
+material = "shiny"
+
+[[objects]]
+type = "sphere"
+center = [0.8, 0.5, 0.0]
+radius = 0.5
+material = "glass"
+
+[[objects]]
+type = "sphere"
+center = [0.0, 3.0, 1.0]
+radius = 0.5
+material = "lamp"
+"#;
+
+    // The parts of a parsed scene a SceneView borrows
+    pub struct Fixture {
+        pub world: Bvh,
+        pub lights: Lights,
+        pub environment: Environment,
+        pub camera: Camera,
+        pub settings: RenderSettings,
+        pub hash: u64
+    }
+
+    impl Fixture {
+        pub fn new() -> Fixture {
+            let scene = parse_scene(SCENE, Path::new(".")).unwrap();
+            let settings = scene.settings;
+            Fixture {
+                lights: scene.world.lights(),
+                world: Bvh::new(scene.world),
+                environment: scene.environment,
+                camera: scene.camera.build(settings.width as f32 / settings.height as f32),
+                settings,
+                hash: scene.hash
+            }
+        }
+        pub fn view(&self) -> SceneView<'_> {
+            SceneView {
+                world: &self.world,
+                lights: &self.lights,
+                environment: &self.environment,
+                fog: None
+            }
+        }
+    }
+
+    fn bits(film: &Film) -> Vec<[u32; 3]> {
+        film.pixels().iter().map(|p| [p.x.to_bits(), p.y.to_bits(), p.z.to_bits()]).collect()
+    }
+
+    #[test]
+    fn same_image_on_any_thread_count() {
+        let mut fixture = Fixture::new();
+        fixture.settings.threads = 1;
+        let (single, _) = render(&fixture.view(), &fixture.camera, &fixture.settings);
+        assert!(single.pixels().iter().any(|p| p.x > 0.0));
+        for threads in [2, 3, 8] {
+            fixture.settings.threads = threads;
+            let (film, _) = render(&fixture.view(), &fixture.camera, &fixture.settings);
+            assert!(bits(&single) == bits(&film), "{} threads rendered a different image", threads);
+        }
+    }
+    // Each sample is seeded from the seed, the pixel and its own number, so
+    // how the samples are split into passes doesn't matter
+    #[test]
+    fn samples_are_seeded_on_their_own() {
+        let mut fixture = Fixture::new();
+        let settings = &fixture.settings;
+        let in_passes = |passes: &[u32]| {
+            let mut acc = Accumulator::new(settings.width, settings.height, settings.seed, 0);
+            for samples in passes {
+                render_pass(&fixture.view(), &fixture.camera, settings, *samples, &mut acc);
+            }
+            assert_eq!(acc.samples_done(), passes.iter().sum::<u32>());
+            bits(&acc.to_film())
+        };
+        let whole = in_passes(&[5]);
+        assert!(whole == in_passes(&[2, 3]));
+        assert!(whole == in_passes(&[1, 1, 1, 1, 1]));
+
+        let (first, _) = render(&fixture.view(), &fixture.camera, &fixture.settings);
+        let (again, _) = render(&fixture.view(), &fixture.camera, &fixture.settings);
+        assert!(bits(&first) == bits(&again));
+        fixture.settings.seed += 1;
+        let (reseeded, _) = render(&fixture.view(), &fixture.camera, &fixture.settings);
+        assert!(bits(&first) != bits(&reseeded));
+    }
+}
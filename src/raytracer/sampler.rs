@@ -0,0 +1,65 @@
+#![allow(dead_code)]
+
+// Where the random numbers used to trace a sample come from
+// Passed down through the camera, rays and materials so nothing reaches for
+// a global generator and the same seed always gives the same image
+pub trait Sampler {
+    // Uniform in [0, 1)
+    fn next_f32(&mut self) -> f32;
+    // Two values at once, for things like pixel jitter and lens positions
+    fn next_2d(&mut self) -> (f32, f32) {
+        let a = self.next_f32();
+        let b = self.next_f32();
+        (a, b)
+    }
+}
+
+// SplitMix64, small and fast with good enough statistics for sampling
+// Each sample gets its own stream, so pixels don't depend on which thread
+// renders them or how the samples are split into passes
+pub struct RandomSampler {
+    state: u64
+}
+
+impl RandomSampler {
+    pub fn new(seed: u64) -> RandomSampler {
+        RandomSampler {
+            state: mix(seed)
+        }
+    }
+    // The stream for one sample of one pixel
+    pub fn for_sample(seed: u64, x: u32, y: u32, sample: u32) -> RandomSampler {
+        let pixel = (y as u64) << 32 | x as u64;
+        RandomSampler::new(mix(mix(seed ^ mix(pixel)) ^ sample as u64))
+    }
+    // A stream seeded from raw values, for the odd place that can't be
+    // handed a sampler, like media deciding where rays stop inside
+    // check_hit. The same values always give the same stream
+    pub fn from_bits(bits: &[u32]) -> RandomSampler {
+        let mut seed = 0u64;
+        for &b in bits {
+            seed = mix(seed ^ b as u64);
+        }
+        RandomSampler::new(seed)
+    }
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(GOLDEN_GAMMA);
+        mix(self.state)
+    }
+}
+
+impl Sampler for RandomSampler {
+    fn next_f32(&mut self) -> f32 {
+        // The top 24 bits fill an f32 mantissa exactly, so 1.0 can't come out
+        (self.next_u64() >> 40) as f32 * (1.0 / (1u32 << 24) as f32)
+    }
+}
+
+const GOLDEN_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+// SplitMix64's finalizer
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
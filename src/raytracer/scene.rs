@@ -0,0 +1,1234 @@
+#![allow(dead_code)]
+extern crate serde;
+extern crate toml;
+use serde::Deserialize;
+use toml::{Spanned, Value};
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::Arc;
+
+use super::math::vec3::*;
+use super::math::mat4::*;
+use super::camera::*;
+use super::object::*;
+use super::triangle::*;
+use super::material::*;
+use super::texture::*;
+use super::noise::*;
+use super::environment::*;
+use super::light::*;
+use super::bvh::*;
+use super::transform::*;
+use super::aabb::*;
+use super::medium::*;
+use super::microfacet::*;
+use super::render::*;
+use super::progressive::*;
+use super::formats::obj::*;
+use super::formats::ply::*;
+use super::formats::gltf::*;
+use super::formats::vol::*;
+
+// Everything needed to render a scene file. The camera is built once the
+// final image size is known, see CameraSetup::build
+pub struct Scene {
+    pub world: World,
+    pub camera: CameraSetup,
+    pub settings: RenderSettings,
+    pub environment: Environment,
+    pub fog: Option<Fog>,
+    // Hash of the scene file's text, so checkpoints can tell if it changed
+    pub hash: u64
+}
+
+// A problem with a scene file, pointing at where it was found
+#[derive(Debug)]
+pub struct SceneError {
+    pub line: Option<usize>,
+    pub field: Option<String>,
+    pub message: String
+}
+
+impl SceneError {
+    fn at(text: &str, offset: usize, field: String, message: String) -> SceneError {
+        SceneError {
+            line: Some(line_of(text, offset)),
+            field: Some(field),
+            message
+        }
+    }
+}
+
+impl std::fmt::Display for SceneError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "line {}: ", line)?;
+        }
+        if let Some(field) = &self.field {
+            write!(f, "{}: ", field)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for SceneError {}
+
+// 1-based line number of a byte offset
+fn line_of(text: &str, offset: usize) -> usize {
+    text[..offset.min(text.len())].matches('\n').count() + 1
+}
+
+// Works out the dotted name of the key on the line containing offset from
+// the nearest table header above it, e.g. materials.glass.ref_idx or
+// objects[2].radius. Used for errors coming straight from the TOML parser
+fn field_at(text: &str, offset: usize) -> Option<String> {
+    let line_start = text[..offset.min(text.len())].rfind('\n').map_or(0, |i| i + 1);
+    let line = text[line_start..].lines().next().unwrap_or("");
+    let is_header = line.trim_start().starts_with('[');
+    let key = line.split('=').next().filter(|_| !is_header && line.contains('=')).map(|k| k.trim().to_string());
+
+    // Find the innermost table header up to and including this line
+    let mut header: Option<String> = None;
+    let mut array_counts: HashMap<&str, usize> = HashMap::new();
+    for l in text[..line_start + line.len()].lines() {
+        let l = l.trim();
+        if let Some(name) = l.strip_prefix("[[").and_then(|r| r.split("]]").next()) {
+            let name = name.trim();
+            let count = array_counts.entry(name).or_insert(0);
+            header = Some(format!("{}[{}]", name, count));
+            *count += 1;
+        } else if let Some(name) = l.strip_prefix('[').and_then(|r| r.split(']').next()) {
+            header = Some(name.trim().to_string());
+        }
+    }
+    match (header, key) {
+        (Some(h), Some(k)) => Some(format!("{}.{}", h, k)),
+        (Some(h), None) => Some(h),
+        (None, k) => k
+    }
+}
+
+// The layout of a scene file. Values that need checking after parsing are
+// Spanned so errors can point back at the line they came from
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneFile {
+    #[serde(default)]
+    render: RenderDesc,
+    camera: CameraDesc,
+    #[serde(default)]
+    environment: EnvironmentDesc,
+    fog: Option<FogDesc>,
+    #[serde(default)]
+    textures: BTreeMap<String, TextureDesc>,
+    #[serde(default)]
+    materials: BTreeMap<String, MaterialDesc>,
+    #[serde(default)]
+    meshes: BTreeMap<String, MeshDesc>,
+    #[serde(default)]
+    objects: Vec<ObjectDesc>
+}
+
+// Anything left out of [render] falls back to RenderSettings::default()
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct RenderDesc {
+    width: Option<Spanned<u32>>,
+    height: Option<Spanned<u32>>,
+    samples: Option<Spanned<u16>>,
+    max_depth: Option<Spanned<u32>>,
+    threads: Option<usize>,
+    seed: Option<u64>
+}
+
+// Without an [environment] rays that miss everything see the sky gradient
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct EnvironmentDesc {
+    #[serde(rename = "type")]
+    kind: Option<Spanned<String>>,
+    color: Option<Spanned<[f32; 3]>>,
+    bottom: Option<Spanned<[f32; 3]>>,
+    top: Option<Spanned<[f32; 3]>>,
+    // Images, relative to the scene file
+    path: Option<Spanned<String>>,
+    faces: Option<Spanned<Vec<String>>>,
+    intensity: Option<Spanned<f32>>,
+    // Degrees around the y axis
+    rotation: Option<f32>
+}
+
+// Haze over the whole scene, seen by camera rays
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FogDesc {
+    density: Spanned<f32>,
+    // White by default
+    color: Option<Spanned<Value>>,
+    anisotropy: Option<Spanned<f32>>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CameraDesc {
+    look_from: [f32; 3],
+    look_at: Spanned<[f32; 3]>,
+    up: Option<Spanned<[f32; 3]>>,
+    vfov: Spanned<f32>,
+    aperture: Option<Spanned<f32>>,
+    focus_distance: Spanned<f32>,
+    // Open and close times for motion blur
+    shutter: Option<Spanned<[f32; 2]>>
+}
+
+// Colors in textures and materials are either [r, g, b] or the name of a
+// texture, so they're checked by hand to give a useful error
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TextureDesc {
+    #[serde(rename = "type")]
+    kind: Spanned<String>,
+    color: Option<Spanned<[f32; 3]>>,
+    even: Option<Spanned<Value>>,
+    odd: Option<Spanned<Value>>,
+    scale: Option<Spanned<f32>>,
+    space: Option<Spanned<String>>,
+    // Image file, relative to the scene file
+    path: Option<Spanned<String>>,
+    filter: Option<Spanned<String>>,
+    wrap: Option<Spanned<String>>,
+    // Procedural textures blend from low to high, black to white by default
+    low: Option<Spanned<Value>>,
+    high: Option<Spanned<Value>>,
+    // Picks the permutation table, independent of the render seed
+    seed: Option<u64>,
+    basis: Option<Spanned<String>>,
+    octaves: Option<Spanned<u32>>,
+    lacunarity: Option<Spanned<f32>>,
+    gain: Option<Spanned<f32>>,
+    turbulence: Option<bool>,
+    feature: Option<Spanned<String>>,
+    distortion: Option<Spanned<f32>>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MaterialDesc {
+    #[serde(rename = "type")]
+    kind: Spanned<String>,
+    albedo: Option<Spanned<Value>>,
+    fuzz: Option<Spanned<f32>>,
+    ref_idx: Option<Spanned<f32>>,
+    // What lights give off, white by default
+    color: Option<Spanned<Value>>,
+    intensity: Option<Spanned<f32>>,
+    // For henyey_greenstein, between -1 and 1
+    anisotropy: Option<Spanned<f32>>,
+    // Conductors are one of the named metals, or their own complex index
+    // of refraction eta + ik
+    metal: Option<Spanned<String>>,
+    eta: Option<Spanned<[f32; 3]>>,
+    k: Option<Spanned<[f32; 3]>>,
+    // 0 to 1, or [along the tangent, across it]
+    roughness: Option<Spanned<Value>>,
+    // How much light dielectrics soak up per unit of distance inside them
+    absorption: Option<Spanned<[f32; 3]>>,
+    // For dielectrics that are a single sheet instead of a solid
+    thin: Option<Spanned<bool>>,
+    // The principled material's parameters, mostly between 0 and 1
+    base_color: Option<Spanned<Value>>,
+    metallic: Option<Spanned<f32>>,
+    specular: Option<Spanned<f32>>,
+    specular_tint: Option<Spanned<f32>>,
+    sheen: Option<Spanned<f32>>,
+    sheen_tint: Option<Spanned<f32>>,
+    clearcoat: Option<Spanned<f32>>,
+    clearcoat_gloss: Option<Spanned<f32>>,
+    transmission: Option<Spanned<f32>>,
+    ior: Option<Spanned<f32>>
+}
+
+// A mesh file loaded once and shared by every instance object using it
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MeshDesc {
+    #[serde(rename = "type")]
+    kind: Spanned<String>,
+    path: Spanned<String>,
+    material: Option<Spanned<String>>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ObjectDesc {
+    #[serde(rename = "type")]
+    kind: Spanned<String>,
+    center: Option<[f32; 3]>,
+    radius: Option<Spanned<f32>>,
+    vertices: Option<Spanned<[[f32; 3]; 3]>>,
+    normals: Option<Spanned<[[f32; 3]; 3]>>,
+    uvs: Option<[[f32; 2]; 3]>,
+    // Mesh file, relative to the scene file
+    path: Option<Spanned<String>>,
+    // Required for everything but meshes, where it replaces the file's own
+    material: Option<Spanned<String>>,
+    // Name of an entry in [meshes], for instances
+    mesh: Option<Spanned<String>>,
+    // Applied to any object in the order scale, rotate, translate. Rotation
+    // is in degrees around x, then y, then z
+    translate: Option<[f32; 3]>,
+    rotate: Option<[f32; 3]>,
+    // A number or [x, y, z]
+    scale: Option<Spanned<Value>>,
+    // Poses to move through while the shutter is open, instead of the
+    // three above
+    keyframes: Option<Spanned<Vec<KeyframeDesc>>>,
+    // Where a sphere has moved to at time 1, from center at time 0
+    center1: Option<[f32; 3]>,
+    // Fills the object with a medium made of its material instead. For
+    // volumes, multiplies the grid's own density
+    density: Option<Spanned<f32>>,
+    // Size of a raw volume grid, .vol files have it in their header
+    resolution: Option<Spanned<[usize; 3]>>,
+    // Second grid for volumes that glow, in Kelvin once multiplied by
+    // temperature_scale. emission is how bright the hottest part glows
+    temperature: Option<Spanned<String>>,
+    temperature_scale: Option<Spanned<f32>>,
+    emission: Option<Spanned<f32>>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyframeDesc {
+    time: Spanned<f32>,
+    translate: Option<[f32; 3]>,
+    rotate: Option<[f32; 3]>,
+    scale: Option<Spanned<Value>>
+}
+
+// Where an object gets put, once checked
+enum Placement {
+    Still(Mat4),
+    Keyframes(Vec<Keyframe>)
+}
+
+fn to_vec3(v: &[f32; 3]) -> Vec3 {
+    Vec3::new(v[0], v[1], v[2])
+}
+
+// TOML numbers written with or without a decimal point
+fn number(v: &Value) -> Option<f32> {
+    match v {
+        Value::Float(f) => Some(*f as f32),
+        Value::Integer(i) => Some(*i as f32),
+        _ => None
+    }
+}
+
+// Degrees around x, then y, then z
+fn euler_rotation(r: &[f32; 3]) -> [f32; 4] {
+    let x = quat_axis(&Vec3::new(1.0, 0.0, 0.0), r[0]);
+    let y = quat_axis(&Vec3::new(0.0, 1.0, 0.0), r[1]);
+    let z = quat_axis(&Vec3::new(0.0, 0.0, 1.0), r[2]);
+    quat_mul(&z, &quat_mul(&y, &x))
+}
+
+// Reads and parses a scene file from disk. glTF files can be rendered as
+// they are, with their own camera and the default render settings
+pub fn load_scene(path: &str) -> Result<Scene, SceneError> {
+    let lower = path.to_lowercase();
+    if lower.ends_with(".gltf") || lower.ends_with(".glb") {
+        return load_gltf_scene(path);
+    }
+    let text = std::fs::read_to_string(path).map_err(|e| SceneError {
+        line: None,
+        field: None,
+        message: format!("could not read {}: {}", path, e)
+    })?;
+    let dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+    parse_scene(&text, dir)
+}
+
+fn load_gltf_scene(path: &str) -> Result<Scene, SceneError> {
+    let error = |message: String| SceneError {
+        line: None,
+        field: None,
+        message
+    };
+    let bytes = std::fs::read(path).map_err(|e| error(format!("could not read {}: {}", path, e)))?;
+    let dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+    let mut gltf = Gltf::read(&bytes, dir).map_err(error)?;
+    let camera = match gltf.camera.take() {
+        Some(c) => c,
+        None => return Err(error("there's no perspective camera, add the model to a scene file with one instead".to_string()))
+    };
+    let mut world = World::new();
+    gltf.add_to(&mut world);
+    Ok(Scene {
+        world,
+        camera,
+        settings: RenderSettings::default(),
+        environment: Environment::default(),
+        fog: None,
+        hash: fnv1a(&bytes, FNV_OFFSET)
+    })
+}
+
+// Builds a scene from the contents of a scene file, mesh paths in it are
+// relative to dir
+pub fn parse_scene(text: &str, dir: &Path) -> Result<Scene, SceneError> {
+    let file: SceneFile = toml::from_str(text).map_err(|e| match e.span() {
+        Some(span) => SceneError {
+            line: Some(line_of(text, span.start)),
+            field: field_at(text, span.start),
+            message: e.message().trim().replace('\n', ", ")
+        },
+        None => SceneError {
+            line: None,
+            field: None,
+            message: e.message().to_string()
+        }
+    })?;
+    let checker = Checker { text, dir };
+
+    let settings = checker.render_settings(&file.render)?;
+    let camera = checker.camera(&file.camera)?;
+    let environment = checker.environment(&file.environment)?;
+
+    let mut textures = Textures {
+        descs: &file.textures,
+        built: HashMap::new(),
+        pending: Vec::new()
+    };
+    for name in file.textures.keys() {
+        checker.texture(name, &mut textures)?;
+    }
+
+    let fog = match &file.fog {
+        Some(desc) => Some(checker.fog(desc, &mut textures)?),
+        None => None
+    };
+
+    let mut materials: HashMap<&str, Box<dyn Material>> = HashMap::new();
+    for (name, desc) in &file.materials {
+        materials.insert(name.as_str(), checker.material(name, desc, &mut textures)?);
+    }
+
+    // Each mesh gets its own BVH, the instances all point at the same one
+    let mut meshes: HashMap<&str, Arc<dyn Object>> = HashMap::new();
+    for (name, desc) in &file.meshes {
+        let field = |f: &str| format!("meshes.{}.{}", name, f);
+        let material = checker.material_named(&desc.material, &materials, field("material"))?;
+        let mesh = checker.mesh(&desc.kind, &desc.path, material, field)?;
+        meshes.insert(name.as_str(), Arc::new(Bvh::new(mesh)));
+    }
+
+    let mut world = World::new();
+    for (i, desc) in file.objects.iter().enumerate() {
+        checker.add_object(i, desc, &materials, &meshes, &mut world)?;
+    }
+
+    Ok(Scene {
+        world,
+        camera,
+        settings,
+        environment,
+        fog,
+        hash: fnv1a(text.as_bytes(), FNV_OFFSET)
+    })
+}
+
+// Textures get built the first time something refers to them
+struct Textures<'b> {
+    descs: &'b BTreeMap<String, TextureDesc>,
+    built: HashMap<&'b str, Arc<dyn Texture>>,
+    // The ones being built right now, to catch textures that refer back to
+    // themselves
+    pending: Vec<&'b str>
+}
+
+// Turns the parsed descriptions into renderer types, rejecting bad values
+struct Checker<'a> {
+    text: &'a str,
+    dir: &'a Path
+}
+
+impl<'a> Checker<'a> {
+    fn error<T>(&self, spanned: &Spanned<T>, field: String, message: String) -> SceneError {
+        SceneError::at(self.text, spanned.span().start, field, message)
+    }
+    fn require<'b, T>(&self, value: &'b Option<Spanned<T>>, owner: &Spanned<String>, field: String) -> Result<&'b Spanned<T>, SceneError> {
+        value.as_ref().ok_or_else(|| self.error(owner, field, "missing required value".to_string()))
+    }
+    // TOML allows nan and inf, which slip past comparisons
+    fn finite(&self, value: &Spanned<f32>, field: &str) -> Result<f32, SceneError> {
+        if value.get_ref().is_finite() {
+            Ok(*value.get_ref())
+        } else {
+            Err(self.error(value, field.to_string(), format!("must be finite, got {}", value.get_ref())))
+        }
+    }
+    fn positive(&self, value: &Spanned<f32>, field: &str) -> Result<f32, SceneError> {
+        if self.finite(value, field)? > 0.0 {
+            Ok(*value.get_ref())
+        } else {
+            Err(self.error(value, field.to_string(), format!("must be positive, got {}", value.get_ref())))
+        }
+    }
+    fn positive_or(&self, value: &Option<Spanned<f32>>, default: f32, field: &str) -> Result<f32, SceneError> {
+        match value {
+            Some(v) => self.positive(v, field),
+            None => Ok(default)
+        }
+    }
+    fn non_negative_or(&self, value: &Option<Spanned<f32>>, default: f32, field: &str) -> Result<f32, SceneError> {
+        match value {
+            Some(v) if self.finite(v, field)? < 0.0 => Err(self.error(v, field.to_string(), format!("must not be negative, got {}", v.get_ref()))),
+            Some(v) => Ok(*v.get_ref()),
+            None => Ok(default)
+        }
+    }
+    fn unit_or(&self, value: &Option<Spanned<f32>>, default: f32, field: &str) -> Result<f32, SceneError> {
+        match value {
+            Some(v) if !(0.0..=1.0).contains(v.get_ref()) => Err(self.error(v, field.to_string(), format!("must be between 0 and 1, got {}", v.get_ref()))),
+            Some(v) => Ok(*v.get_ref()),
+            None => Ok(default)
+        }
+    }
+    fn at_least_one<T: Copy + Into<u64>>(&self, value: &Option<Spanned<T>>, default: T, field: &str) -> Result<T, SceneError> {
+        match value {
+            Some(v) if (*v.get_ref()).into() == 0 => Err(self.error(v, field.to_string(), "must be at least 1".to_string())),
+            Some(v) => Ok(*v.get_ref()),
+            None => Ok(default)
+        }
+    }
+    fn render_settings(&self, desc: &RenderDesc) -> Result<RenderSettings, SceneError> {
+        let defaults = RenderSettings::default();
+        Ok(RenderSettings {
+            width: self.at_least_one(&desc.width, defaults.width, "render.width")?,
+            height: self.at_least_one(&desc.height, defaults.height, "render.height")?,
+            samples: self.at_least_one(&desc.samples, defaults.samples, "render.samples")?,
+            max_depth: self.at_least_one(&desc.max_depth, defaults.max_depth, "render.max_depth")?,
+            threads: desc.threads.unwrap_or(defaults.threads),
+            seed: desc.seed.unwrap_or(defaults.seed)
+        })
+    }
+    fn camera(&self, desc: &CameraDesc) -> Result<CameraSetup, SceneError> {
+        let vfov = *desc.vfov.get_ref();
+        if !(vfov > 0.0 && vfov < 180.0) {
+            return Err(self.error(&desc.vfov, "camera.vfov".to_string(), format!("must be between 0 and 180 degrees, got {}", vfov)));
+        }
+        let aperture = self.non_negative_or(&desc.aperture, 0.0, "camera.aperture")?;
+        let focus_distance = self.positive(&desc.focus_distance, "camera.focus_distance")?;
+        let from = to_vec3(&desc.look_from);
+        let at = to_vec3(desc.look_at.get_ref());
+        let up = desc.up.as_ref().map_or(Vec3::new(0.0, 1.0, 0.0), |u| to_vec3(u.get_ref()));
+        if from.sub_by_vec(&at).cross(&up).squared_length() == 0.0 {
+            return Err(match &desc.up {
+                Some(u) => self.error(u, "camera.up".to_string(), "must not be parallel to the viewing direction".to_string()),
+                None => self.error(&desc.look_at, "camera.look_at".to_string(), "looking straight up or down needs an explicit camera.up".to_string())
+            });
+        }
+        let shutter = match &desc.shutter {
+            Some(s) if s.get_ref().iter().any(|t| !t.is_finite()) => {
+                return Err(self.error(s, "camera.shutter".to_string(), "times must be finite".to_string()));
+            },
+            Some(s) if s.get_ref()[1] < s.get_ref()[0] => {
+                return Err(self.error(s, "camera.shutter".to_string(), "has to open before it closes".to_string()));
+            },
+            Some(s) => (s.get_ref()[0], s.get_ref()[1]),
+            None => (0.0, 0.0)
+        };
+        Ok(CameraSetup {
+            lookfrom: from,
+            lookat: at,
+            vup: up,
+            vfov,
+            aperture,
+            focus_dist: focus_distance,
+            shutter
+        })
+    }
+    fn environment(&self, desc: &EnvironmentDesc) -> Result<Environment, SceneError> {
+        let field = |f: &str| format!("environment.{}", f);
+        let intensity = self.non_negative_or(&desc.intensity, 1.0, &field("intensity"))?;
+        let rotation = desc.rotation.unwrap_or(0.0);
+        let kind = match &desc.kind {
+            Some(k) => k,
+            None => return Ok(Environment::new(Source::Gradient { bottom: Vec3::all(1.0), top: Vec3::new(0.5, 0.7, 1.0) }, intensity, rotation))
+        };
+        let rgb_or = |value: &Option<Spanned<[f32; 3]>>, default: Vec3, name: &str| match value {
+            Some(v) => self.rgb(v, field(name)),
+            None => Ok(default)
+        };
+        let load = |file: &str| Image::load(&self.dir.join(file).to_string_lossy());
+        let source = match kind.get_ref().as_str() {
+            "color" => Source::Color(rgb_or(&desc.color, Vec3::all(0.0), "color")?),
+            "gradient" => Source::Gradient {
+                bottom: rgb_or(&desc.bottom, Vec3::all(1.0), "bottom")?,
+                top: rgb_or(&desc.top, Vec3::new(0.5, 0.7, 1.0), "top")?
+            },
+            "image" => {
+                let path = self.require(&desc.path, kind, field("path"))?;
+                let image = load(path.get_ref()).map_err(|e| self.error(path, field("path"), e))?;
+                if image.width == 0 || image.height == 0 {
+                    return Err(self.error(path, field("path"), format!("{} is empty", path.get_ref())));
+                }
+                Source::Equirect(Arc::new(image))
+            },
+            "cubemap" => {
+                let faces = self.require(&desc.faces, kind, field("faces"))?;
+                if faces.get_ref().len() != 6 {
+                    return Err(self.error(faces, field("faces"),
+                        format!("needs 6 images in the order +x, -x, +y, -y, +z, -z, got {}", faces.get_ref().len())));
+                }
+                let mut images = Vec::new();
+                for file in faces.get_ref() {
+                    let image = load(file).map_err(|e| self.error(faces, field("faces"), e))?;
+                    if image.width != image.height || image.width == 0 {
+                        return Err(self.error(faces, field("faces"), format!("{} is {}x{}, faces have to be square", file, image.width, image.height)));
+                    }
+                    if let Some(first) = images.first().map(|f: &Arc<Image>| f.width).filter(|w| *w != image.width) {
+                        return Err(self.error(faces, field("faces"), format!("{} is {} pixels across but the first face is {}", file, image.width, first)));
+                    }
+                    images.push(Arc::new(image));
+                }
+                Source::Cubemap(images)
+            },
+            other => return Err(self.error(kind, field("type"),
+                format!("unknown environment type `{}`, expected color, gradient, image or cubemap", other)))
+        };
+        Ok(Environment::new(source, intensity, rotation))
+    }
+    fn rgb(&self, value: &Spanned<[f32; 3]>, field: String) -> Result<Vec3, SceneError> {
+        if value.get_ref().iter().any(|c| !c.is_finite()) {
+            return Err(self.error(value, field, "components must be finite".to_string()));
+        }
+        if value.get_ref().iter().any(|c| *c < 0.0) {
+            return Err(self.error(value, field, "components must not be negative".to_string()));
+        }
+        Ok(to_vec3(value.get_ref()))
+    }
+    // [r, g, b] or the name of a texture
+    fn color<'b>(&self, value: &'b Spanned<Value>, field: String, textures: &mut Textures<'b>) -> Result<Arc<dyn Texture>, SceneError> {
+        match value.get_ref() {
+            Value::Array(items) if items.len() == 3 => {
+                let mut rgb = [0.0; 3];
+                for (c, item) in rgb.iter_mut().zip(items) {
+                    *c = match item {
+                        Value::Float(f) => *f as f32,
+                        Value::Integer(i) => *i as f32,
+                        _ => return Err(self.error(value, field, "components must be numbers".to_string()))
+                    };
+                }
+                let rgb = self.rgb(&Spanned::new(value.span(), rgb), field)?;
+                Ok(Arc::new(ConstantTexture::new(rgb)))
+            },
+            Value::String(name) => {
+                if !textures.descs.contains_key(name) {
+                    return Err(self.error(value, field, format!("unknown texture `{}`", name)));
+                }
+                if textures.pending.contains(&name.as_str()) {
+                    return Err(self.error(value, field, format!("texture `{}` refers back to itself", name)));
+                }
+                self.texture(name, textures)
+            },
+            _ => Err(self.error(value, field, "must be [r, g, b] or the name of a texture".to_string()))
+        }
+    }
+    // A color that falls back to gray level default when left out
+    fn color_or<'b>(&self, value: &'b Option<Spanned<Value>>, default: f32, field: String, textures: &mut Textures<'b>) -> Result<Arc<dyn Texture>, SceneError> {
+        match value {
+            Some(v) => self.color(v, field, textures),
+            None => Ok(Arc::new(ConstantTexture::new(Vec3::all(default))))
+        }
+    }
+    fn texture<'b>(&self, name: &'b str, textures: &mut Textures<'b>) -> Result<Arc<dyn Texture>, SceneError> {
+        if let Some(t) = textures.built.get(name) {
+            return Ok(Arc::clone(t));
+        }
+        let desc = &textures.descs[name];
+        let field = |f: &str| format!("textures.{}.{}", name, f);
+        textures.pending.push(name);
+        let texture: Arc<dyn Texture> = match desc.kind.get_ref().as_str() {
+            "constant" => Arc::new(ConstantTexture::new(self.rgb(self.require(&desc.color, &desc.kind, field("color"))?, field("color"))?)),
+            "checker" => {
+                let even = self.color(self.require(&desc.even, &desc.kind, field("even"))?, field("even"), textures)?;
+                let odd = self.color(self.require(&desc.odd, &desc.kind, field("odd"))?, field("odd"), textures)?;
+                let scale = self.positive_or(&desc.scale, 1.0, &field("scale"))?;
+                let space = match desc.space.as_ref().map(|s| (s, s.get_ref().as_str())) {
+                    None | Some((_, "uv")) => CheckerSpace::Uv,
+                    Some((_, "solid")) => CheckerSpace::Solid,
+                    Some((s, other)) => return Err(self.error(s, field("space"), format!("unknown checker space `{}`, expected uv or solid", other)))
+                };
+                Arc::new(CheckerTexture { even, odd, scale, space })
+            },
+            "image" => {
+                let path = self.require(&desc.path, &desc.kind, field("path"))?;
+                let image = Image::load(&self.dir.join(path.get_ref()).to_string_lossy())
+                    .map_err(|e| self.error(path, field("path"), e))?;
+                let filter = match desc.filter.as_ref().map(|f| (f, f.get_ref().as_str())) {
+                    None | Some((_, "bilinear")) => Filter::Bilinear,
+                    Some((_, "nearest")) => Filter::Nearest,
+                    Some((f, other)) => return Err(self.error(f, field("filter"), format!("unknown filter `{}`, expected bilinear or nearest", other)))
+                };
+                let wrap = match desc.wrap.as_ref().map(|w| (w, w.get_ref().as_str())) {
+                    None | Some((_, "repeat")) => Wrap::Repeat,
+                    Some((_, "clamp")) => Wrap::Clamp,
+                    Some((_, "mirror")) => Wrap::Mirror,
+                    Some((w, other)) => return Err(self.error(w, field("wrap"), format!("unknown wrap mode `{}`, expected repeat, clamp or mirror", other)))
+                };
+                Arc::new(ImageTexture { image: Arc::new(image), filter, wrap })
+            },
+            "noise" => {
+                let basis = match desc.basis.as_ref().map(|b| (b, b.get_ref().as_str())) {
+                    None | Some((_, "perlin")) => Basis::Perlin,
+                    Some((_, "simplex")) => Basis::Simplex,
+                    Some((b, other)) => return Err(self.error(b, field("basis"), format!("unknown noise basis `{}`, expected perlin or simplex", other)))
+                };
+                Arc::new(NoiseTexture {
+                    noise: Noise::new(desc.seed.unwrap_or(0)),
+                    basis,
+                    scale: self.positive_or(&desc.scale, 1.0, &field("scale"))?,
+                    octaves: self.at_least_one(&desc.octaves, 1, &field("octaves"))?,
+                    lacunarity: self.positive_or(&desc.lacunarity, 2.0, &field("lacunarity"))?,
+                    gain: self.positive_or(&desc.gain, 0.5, &field("gain"))?,
+                    turbulence: desc.turbulence.unwrap_or(false),
+                    low: self.color_or(&desc.low, 0.0, field("low"), textures)?,
+                    high: self.color_or(&desc.high, 1.0, field("high"), textures)?
+                })
+            },
+            "worley" => {
+                let feature = match desc.feature.as_ref().map(|f| (f, f.get_ref().as_str())) {
+                    None | Some((_, "f1")) => Feature::F1,
+                    Some((_, "f2")) => Feature::F2,
+                    Some((_, "f2-f1")) => Feature::F2MinusF1,
+                    Some((f, other)) => return Err(self.error(f, field("feature"), format!("unknown worley feature `{}`, expected f1, f2 or f2-f1", other)))
+                };
+                Arc::new(WorleyTexture {
+                    noise: Noise::new(desc.seed.unwrap_or(0)),
+                    scale: self.positive_or(&desc.scale, 1.0, &field("scale"))?,
+                    feature,
+                    low: self.color_or(&desc.low, 0.0, field("low"), textures)?,
+                    high: self.color_or(&desc.high, 1.0, field("high"), textures)?
+                })
+            },
+            "marble" => {
+                Arc::new(MarbleTexture {
+                    noise: Noise::new(desc.seed.unwrap_or(0)),
+                    scale: self.positive_or(&desc.scale, 1.0, &field("scale"))?,
+                    distortion: self.non_negative_or(&desc.distortion, 5.0, &field("distortion"))?,
+                    octaves: self.at_least_one(&desc.octaves, 7, &field("octaves"))?,
+                    low: self.color_or(&desc.low, 0.0, field("low"), textures)?,
+                    high: self.color_or(&desc.high, 1.0, field("high"), textures)?
+                })
+            },
+            "wood" => {
+                Arc::new(WoodTexture {
+                    noise: Noise::new(desc.seed.unwrap_or(0)),
+                    scale: self.positive_or(&desc.scale, 1.0, &field("scale"))?,
+                    distortion: self.non_negative_or(&desc.distortion, 1.0, &field("distortion"))?,
+                    low: self.color_or(&desc.low, 0.0, field("low"), textures)?,
+                    high: self.color_or(&desc.high, 1.0, field("high"), textures)?
+                })
+            },
+            other => return Err(self.error(&desc.kind, field("type"),
+                format!("unknown texture type `{}`, expected constant, checker, image, noise, worley, marble or wood", other)))
+        };
+        textures.pending.pop();
+        textures.built.insert(name, Arc::clone(&texture));
+        Ok(texture)
+    }
+    fn material<'b>(&self, name: &str, desc: &'b MaterialDesc, textures: &mut Textures<'b>) -> Result<Box<dyn Material>, SceneError> {
+        let field = |f: &str| format!("materials.{}.{}", name, f);
+        let albedo = |this: &Checker, textures: &mut Textures<'b>| -> Result<Arc<dyn Texture>, SceneError> {
+            this.color(this.require(&desc.albedo, &desc.kind, field("albedo"))?, field("albedo"), textures)
+        };
+        match desc.kind.get_ref().as_str() {
+            "lambertian" => Ok(Box::new(Lambertian::textured(albedo(self, textures)?))),
+            "metal" => {
+                let fuzz = self.non_negative_or(&desc.fuzz, 0.0, &field("fuzz"))?;
+                Ok(Box::new(Metal::textured(albedo(self, textures)?, fuzz)))
+            },
+            "dielectric" => {
+                let ref_idx = self.positive(self.require(&desc.ref_idx, &desc.kind, field("ref_idx"))?, &field("ref_idx"))?;
+                let mut dielectric = Dielectric::new(ref_idx);
+                dielectric.distribution = self.roughness(&desc.roughness, field("roughness"))?;
+                if let Some(absorption) = &desc.absorption {
+                    if absorption.get_ref().iter().any(|c| *c < 0.0) {
+                        return Err(self.error(absorption, field("absorption"), "components must not be negative".to_string()));
+                    }
+                    dielectric.absorption = to_vec3(absorption.get_ref());
+                }
+                if let Some(thin) = &desc.thin {
+                    // Sheets have no inside to soak up light in, and bend
+                    // nothing for roughness to blur
+                    if *thin.get_ref() && desc.absorption.is_some() {
+                        return Err(self.error(thin, field("thin"), "can't be used together with absorption".to_string()));
+                    }
+                    if *thin.get_ref() && desc.roughness.is_some() {
+                        return Err(self.error(thin, field("thin"), "can't be used together with roughness".to_string()));
+                    }
+                    dielectric.thin = *thin.get_ref();
+                }
+                Ok(Box::new(dielectric))
+            },
+            "diffuse_light" => {
+                let color = self.color_or(&desc.color, 1.0, field("color"), textures)?;
+                let intensity = self.non_negative_or(&desc.intensity, 1.0, &field("intensity"))?;
+                Ok(Box::new(DiffuseLight::textured(color, intensity)))
+            },
+            "conductor" => {
+                let (eta, k) = match (&desc.metal, &desc.eta, &desc.k) {
+                    (Some(metal), None, None) => match metal.get_ref().as_str() {
+                        "gold" => GOLD,
+                        "copper" => COPPER,
+                        "aluminium" => ALUMINIUM,
+                        "silver" => SILVER,
+                        other => return Err(self.error(metal, field("metal"),
+                            format!("unknown metal `{}`, expected gold, copper, aluminium or silver", other)))
+                    },
+                    (None, Some(eta), Some(k)) => {
+                        if eta.get_ref().iter().any(|c| *c <= 0.0) {
+                            return Err(self.error(eta, field("eta"), "components must be positive".to_string()));
+                        }
+                        if k.get_ref().iter().any(|c| *c < 0.0) {
+                            return Err(self.error(k, field("k"), "components must not be negative".to_string()));
+                        }
+                        (*eta.get_ref(), *k.get_ref())
+                    },
+                    (Some(metal), _, _) => return Err(self.error(metal, field("metal"), "can't be used together with eta and k".to_string())),
+                    (None, Some(eta), None) => return Err(self.error(eta, field("k"), "missing required value, eta needs k".to_string())),
+                    (None, None, Some(k)) => return Err(self.error(k, field("eta"), "missing required value, k needs eta".to_string())),
+                    _ => return Err(self.error(&desc.kind, field("metal"), "missing required value, or eta and k".to_string()))
+                };
+                let distribution = self.roughness(&desc.roughness, field("roughness"))?;
+                Ok(Box::new(Conductor::new(to_vec3(&eta), to_vec3(&k), distribution)))
+            },
+            "principled" => {
+                let mut principled = Principled::new(self.color_or(&desc.base_color, 0.8, field("base_color"), textures)?);
+                if desc.roughness.is_some() {
+                    principled.set_roughness(self.roughness(&desc.roughness, field("roughness"))?);
+                }
+                principled.metallic = self.unit_or(&desc.metallic, 0.0, &field("metallic"))?;
+                principled.specular = self.non_negative_or(&desc.specular, 0.5, &field("specular"))?;
+                principled.specular_tint = self.unit_or(&desc.specular_tint, 0.0, &field("specular_tint"))?;
+                principled.sheen = self.non_negative_or(&desc.sheen, 0.0, &field("sheen"))?;
+                principled.sheen_tint = self.unit_or(&desc.sheen_tint, 0.5, &field("sheen_tint"))?;
+                principled.clearcoat = self.non_negative_or(&desc.clearcoat, 0.0, &field("clearcoat"))?;
+                principled.clearcoat_gloss = self.unit_or(&desc.clearcoat_gloss, 1.0, &field("clearcoat_gloss"))?;
+                principled.transmission = self.unit_or(&desc.transmission, 0.0, &field("transmission"))?;
+                principled.ior = self.positive_or(&desc.ior, 1.5, &field("ior"))?;
+                Ok(Box::new(principled))
+            },
+            "isotropic" => Ok(Box::new(Isotropic::textured(albedo(self, textures)?))),
+            "henyey_greenstein" => {
+                let anisotropy = self.anisotropy(self.require(&desc.anisotropy, &desc.kind, field("anisotropy"))?, field("anisotropy"))?;
+                Ok(Box::new(HenyeyGreenstein::textured(albedo(self, textures)?, anisotropy)))
+            },
+            other => Err(self.error(&desc.kind, field("type"),
+                format!("unknown material type `{}`, expected lambertian, metal, conductor, dielectric, principled, diffuse_light, isotropic or henyey_greenstein", other)))
+        }
+    }
+    // A number or [along the tangent, across it], all between 0 and 1.
+    // Perfectly smooth when missing
+    fn roughness(&self, value: &Option<Spanned<Value>>, field: String) -> Result<Ggx, SceneError> {
+        let value = match value {
+            Some(v) => v,
+            None => return Ok(Ggx::from_roughness(0.0, 0.0))
+        };
+        let (u, v) = match value.get_ref() {
+            Value::Array(items) if items.len() == 2 => match (number(&items[0]), number(&items[1])) {
+                (Some(u), Some(v)) => (u, v),
+                _ => return Err(self.error(value, field, "components must be numbers".to_string()))
+            },
+            v => match number(v) {
+                Some(r) => (r, r),
+                None => return Err(self.error(value, field, "must be a number or [u, v]".to_string()))
+            }
+        };
+        if !(0.0..=1.0).contains(&u) || !(0.0..=1.0).contains(&v) {
+            return Err(self.error(value, field, "must be between 0 and 1".to_string()));
+        }
+        Ok(Ggx::from_roughness(u, v))
+    }
+    fn anisotropy(&self, value: &Spanned<f32>, field: String) -> Result<f32, SceneError> {
+        let g = *value.get_ref();
+        if g.abs() < 1.0 {
+            Ok(g)
+        } else {
+            Err(self.error(value, field, format!("must be between -1 and 1, got {}", g)))
+        }
+    }
+    fn fog<'b>(&self, desc: &'b FogDesc, textures: &mut Textures<'b>) -> Result<Fog, SceneError> {
+        let density = self.positive(&desc.density, "fog.density")?;
+        let color = self.color_or(&desc.color, 1.0, "fog.color".to_string(), textures)?;
+        let phase: Box<dyn Material> = match &desc.anisotropy {
+            Some(g) => Box::new(HenyeyGreenstein::textured(color, self.anisotropy(g, "fog.anisotropy".to_string())?)),
+            None => Box::new(Isotropic::textured(color))
+        };
+        Ok(Fog { density, phase })
+    }
+    // A copy of the named material, None when there is no name
+    fn material_named(&self, name: &Option<Spanned<String>>, materials: &HashMap<&str, Box<dyn Material>>, field: String) -> Result<Option<Box<dyn Material>>, SceneError> {
+        match name {
+            Some(name) => match materials.get(name.get_ref().as_str()) {
+                Some(m) => Ok(Some(m.copy())),
+                None => Err(self.error(name, field, format!("unknown material `{}`", name.get_ref())))
+            },
+            None => Ok(None)
+        }
+    }
+    // The triangles in a mesh file, all given material if there is one
+    fn mesh(&self, kind: &Spanned<String>, path: &Spanned<String>, material: Option<Box<dyn Material>>, field: impl Fn(&str) -> String) -> Result<World, SceneError> {
+        let full_path = self.dir.join(path.get_ref()).to_string_lossy().to_string();
+        let triangles = match kind.get_ref().as_str() {
+            "obj" => {
+                let obj = Obj::load(&full_path).map_err(|e| self.error(path, field("path"), e))?;
+                obj.groups.into_iter().flat_map(|g| g.triangles).collect::<Vec<_>>()
+            },
+            "gltf" => Gltf::load(&full_path).map_err(|e| self.error(path, field("path"), e))?.triangles,
+            "ply" => {
+                let ply = Ply::load(&full_path).map_err(|e| self.error(path, field("path"), e))?;
+                if ply.triangles.is_empty() {
+                    return Err(self.error(path, field("path"),
+                        format!("{} has {} vertices but no faces, point clouds can't be rendered", path.get_ref(), ply.vertex_count)));
+                }
+                ply.triangles
+            },
+            other => return Err(self.error(kind, field("type"), format!("unknown mesh type `{}`, expected obj, ply or gltf", other)))
+        };
+        let mut world = World::new();
+        for mut t in triangles {
+            if let Some(material) = &material {
+                t.set_material(material.copy());
+            }
+            world.add_object(Box::new(t));
+        }
+        Ok(world)
+    }
+    // A voxel grid medium filling the box given by its .vol file, or 0..1
+    fn volume(&self, desc: &ObjectDesc, material: Option<Box<dyn Material>>, field: &dyn Fn(&str) -> String) -> Result<Box<dyn Object>, SceneError> {
+        let phase = match material {
+            Some(m) => m,
+            None => return Err(self.error(&desc.kind, field("material"), "missing required value, volumes are made of it".to_string()))
+        };
+        let resolution = desc.resolution.as_ref().map(|r| *r.get_ref());
+        let load = |path: &Spanned<String>, name: &str| {
+            let full_path = self.dir.join(path.get_ref()).to_string_lossy().to_string();
+            Grid::load(&full_path, resolution).map_err(|e| self.error(path, field(name), e))
+        };
+        let density = load(self.require(&desc.path, &desc.kind, field("path"))?, "path")?;
+        let temperature = match &desc.temperature {
+            Some(path) => {
+                let mut grid = load(path, "temperature")?;
+                let scale = self.positive_or(&desc.temperature_scale, 1.0, &field("temperature_scale"))?;
+                for v in grid.values.iter_mut() {
+                    *v *= scale;
+                }
+                Some(grid)
+            },
+            None => None
+        };
+        let (min, max) = density.bounds.unwrap_or(([0.0; 3], [1.0; 3]));
+        if (0..3).any(|i| min[i] >= max[i]) {
+            return Err(self.error(desc.path.as_ref().unwrap(), field("path"), "the grid's box is empty".to_string()));
+        }
+        let scale = self.positive_or(&desc.density, 1.0, &field("density"))?;
+        let emission = self.non_negative_or(&desc.emission, 1.0, &field("emission"))?;
+        Ok(Box::new(GridMedium::new(density, scale, temperature, emission, Aabb::new(to_vec3(&min), to_vec3(&max)), phase)))
+    }
+    // A number or [x, y, z], none of them zero or infinite
+    fn scale(&self, value: &Spanned<Value>, field: String) -> Result<Vec3, SceneError> {
+        let s = match value.get_ref() {
+            Value::Array(items) if items.len() == 3 => match (number(&items[0]), number(&items[1]), number(&items[2])) {
+                (Some(x), Some(y), Some(z)) => Vec3::new(x, y, z),
+                _ => return Err(self.error(value, field, "components must be numbers".to_string()))
+            },
+            v => match number(v) {
+                Some(s) => Vec3::all(s),
+                None => return Err(self.error(value, field, "must be a number or [x, y, z]".to_string()))
+            }
+        };
+        if s.x == 0.0 || s.y == 0.0 || s.z == 0.0 {
+            return Err(self.error(value, field, "can't be zero, that would flatten the object".to_string()));
+        }
+        if !(s.x.is_finite() && s.y.is_finite() && s.z.is_finite()) {
+            return Err(self.error(value, field, "must be finite".to_string()));
+        }
+        Ok(s)
+    }
+    // None if the object stays where it is
+    fn placement(&self, desc: &ObjectDesc, field: &dyn Fn(&str) -> String) -> Result<Option<Placement>, SceneError> {
+        let still = desc.translate.is_some() || desc.rotate.is_some() || desc.scale.is_some();
+        if let Some(keyframes) = &desc.keyframes {
+            if still {
+                return Err(self.error(keyframes, field("keyframes"), "can't be used together with translate, rotate or scale".to_string()));
+            }
+            if keyframes.get_ref().is_empty() {
+                return Err(self.error(keyframes, field("keyframes"), "needs at least one keyframe".to_string()));
+            }
+            let mut res: Vec<Keyframe> = Vec::new();
+            for (i, k) in keyframes.get_ref().iter().enumerate() {
+                let time = self.finite(&k.time, &field(&format!("keyframes[{}].time", i)))?;
+                if res.last().is_some_and(|last| time <= last.time) {
+                    return Err(self.error(&k.time, field(&format!("keyframes[{}].time", i)), "keyframes have to be in order of time".to_string()));
+                }
+                let scale = match &k.scale {
+                    Some(value) => self.scale(value, field(&format!("keyframes[{}].scale", i)))?,
+                    None => Vec3::all(1.0)
+                };
+                if let Some(first) = res.first() {
+                    let s = &first.scale;
+                    if scale.x.signum() != s.x.signum() || scale.y.signum() != s.y.signum() || scale.z.signum() != s.z.signum() {
+                        let at = k.scale.as_ref().map_or(k.time.span().start, |s| s.span().start);
+                        return Err(SceneError::at(self.text, at, field(&format!("keyframes[{}].scale", i)),
+                            "can't flip sign between keyframes, the object would be flattened on the way".to_string()));
+                    }
+                }
+                let keyframe = Keyframe {
+                    time,
+                    translation: to_vec3(&k.translate.unwrap_or([0.0; 3])),
+                    rotation: euler_rotation(&k.rotate.unwrap_or([0.0; 3])),
+                    scale
+                };
+                if !keyframes_invertible(std::slice::from_ref(&keyframe)) {
+                    let at = k.scale.as_ref().map_or(k.time.span().start, |s| s.span().start);
+                    return Err(SceneError::at(self.text, at, field(&format!("keyframes[{}].scale", i)),
+                        "is too small or too large for the object to be placed".to_string()));
+                }
+                res.push(keyframe);
+            }
+            // Each keyframe is fine on its own, but blending one axis's
+            // smallest scale with another's can still underflow
+            if !keyframes_invertible(&res) {
+                return Err(self.error(keyframes, field("keyframes"), "scales get too small or too large for the object to be placed on the way".to_string()));
+            }
+            return Ok(Some(Placement::Keyframes(res)));
+        }
+        if !still {
+            return Ok(None);
+        }
+        let scale = match &desc.scale {
+            Some(value) => self.scale(value, field("scale"))?,
+            None => Vec3::all(1.0)
+        };
+        let rotation = euler_rotation(&desc.rotate.unwrap_or([0.0; 3]));
+        let m = Mat4::from_trs(&to_vec3(&desc.translate.unwrap_or([0.0; 3])), &rotation, &scale);
+        // Scales that are tiny or huge but not zero can still be too much
+        // for f32 to undo
+        if m.inverse().is_none() {
+            let at = desc.scale.as_ref().map_or(desc.kind.span().start, |s| s.span().start);
+            return Err(SceneError::at(self.text, at, field("scale"), "is too small or too large for the object to be placed".to_string()));
+        }
+        Ok(Some(Placement::Still(m)))
+    }
+    fn add_object(&self, index: usize, desc: &ObjectDesc, materials: &HashMap<&str, Box<dyn Material>>,
+                  meshes: &HashMap<&str, Arc<dyn Object>>, world: &mut World) -> Result<(), SceneError> {
+        let field = |f: &str| format!("objects[{}].{}", index, f);
+        let material = self.material_named(&desc.material, materials, field("material"))?;
+        let placement = self.placement(desc, &field)?;
+        // Scales have already been checked, so these can't fail
+        let place = |object: Arc<dyn Object>, placement: Placement| -> Box<dyn Object> {
+            match placement {
+                Placement::Still(m) => Box::new(Transformed::new(object, m).unwrap()),
+                Placement::Keyframes(keyframes) => Box::new(Animated::new(object, keyframes).unwrap())
+            }
+        };
+        if let "volume" = desc.kind.get_ref().as_str() {
+            let volume = self.volume(desc, material, &field)?;
+            world.add_object(match placement {
+                Some(p) => place(Arc::from(volume), p),
+                None => volume
+            });
+            return Ok(());
+        }
+        // Media are made of the object's material and only use the object
+        // itself as their boundary
+        let medium = match &desc.density {
+            Some(d) => match &material {
+                Some(m) => Some((self.positive(d, &field("density"))?, m.copy())),
+                None => return Err(self.error(&desc.kind, field("material"), "missing required value, media are made of it".to_string()))
+            },
+            None => None
+        };
+        let fill = |object: Box<dyn Object>| -> Box<dyn Object> {
+            match medium {
+                Some((density, phase)) => Box::new(ConstantMedium::new(Arc::from(object), density, phase)),
+                None => object
+            }
+        };
+        if let "obj" | "ply" | "gltf" = desc.kind.get_ref().as_str() {
+            let path = self.require(&desc.path, &desc.kind, field("path"))?;
+            let mesh = self.mesh(&desc.kind, path, material, field)?;
+            if placement.is_none() && desc.density.is_none() {
+                for t in mesh.into_objects() {
+                    world.add_object(t);
+                }
+                return Ok(());
+            }
+            // Moved meshes get a BVH of their own like instances, and so do
+            // boundaries so the medium can find its way in and out
+            let object: Box<dyn Object> = Box::new(Bvh::new(mesh));
+            world.add_object(fill(match placement {
+                Some(p) => place(Arc::from(object), p),
+                None => object
+            }));
+            return Ok(());
+        }
+        if let "instance" = desc.kind.get_ref().as_str() {
+            let name = self.require(&desc.mesh, &desc.kind, field("mesh"))?;
+            let mesh = match meshes.get(name.get_ref().as_str()) {
+                Some(mesh) => Arc::clone(mesh),
+                None => return Err(self.error(name, field("mesh"), format!("unknown mesh `{}`", name.get_ref())))
+            };
+            if let (Some(material), None) = (&desc.material, &desc.density) {
+                return Err(self.error(material, field("material"), "instances use their mesh's materials, set it in [meshes] instead".to_string()));
+            }
+            world.add_object(fill(place(mesh, placement.unwrap_or_else(|| Placement::Still(Mat4::identity())))));
+            return Ok(());
+        }
+        let material = match material {
+            Some(m) => m,
+            None => return Err(self.error(&desc.kind, field("material"), "missing required value".to_string()))
+        };
+        let object: Box<dyn Object> = match desc.kind.get_ref().as_str() {
+            "sphere" => {
+                let center = match &desc.center {
+                    Some(c) => to_vec3(c),
+                    None => return Err(self.error(&desc.kind, field("center"), "missing required value".to_string()))
+                };
+                let radius = self.positive(self.require(&desc.radius, &desc.kind, field("radius"))?, &field("radius"))?;
+                if let (Some(Placement::Still(m)), Some(scale)) = (&placement, &desc.scale) {
+                    let shape = LightShape::Sphere { center: center.copy(), radius };
+                    if material.is_light() && shape.transformed(m).is_none() {
+                        return Err(self.error(scale, field("scale"), "spheres that give off light have to be scaled evenly".to_string()));
+                    }
+                }
+                match &desc.center1 {
+                    Some(c) => Box::new(MovingSphere::new(center, to_vec3(c), 0.0, 1.0, radius, material)),
+                    None => Box::new(Sphere::new(center, radius, material))
+                }
+            },
+            "triangle" => {
+                let vertices = self.require(&desc.vertices, &desc.kind, field("vertices"))?;
+                let [v0, v1, v2] = vertices.get_ref();
+                let mut triangle = match &desc.normals {
+                    Some(normals) => {
+                        let [n0, n1, n2] = normals.get_ref();
+                        if [n0, n1, n2].iter().any(|n| n.iter().all(|c| *c == 0.0)) {
+                            return Err(self.error(normals, field("normals"), "normals can't be zero".to_string()));
+                        }
+                        Triangle::with_normals(to_vec3(v0), to_vec3(v1), to_vec3(v2), [to_vec3(n0), to_vec3(n1), to_vec3(n2)], material)
+                    },
+                    None => Triangle::new(to_vec3(v0), to_vec3(v1), to_vec3(v2), material)
+                };
+                if triangle.double_area() == 0.0 {
+                    return Err(self.error(vertices, field("vertices"), "triangle has no area".to_string()));
+                }
+                if let Some(uvs) = desc.uvs {
+                    triangle.set_uvs(uvs);
+                }
+                Box::new(triangle)
+            },
+            other => return Err(self.error(&desc.kind, field("type"),
+                format!("unknown object type `{}`, expected sphere, triangle, obj, ply, gltf, instance or volume", other)))
+        };
+        world.add_object(fill(match placement {
+            Some(p) => place(Arc::from(object), p),
+            None => object
+        }));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A scene with one sphere, with extra appended to its object
+    fn sphere_with(extra: &str) -> String {
+        format!("[camera]\nlook_from = [0.0, 0.0, 5.0]\nlook_at = [0.0, 0.0, 0.0]\nvfov = 40.0\nfocus_distance = 5.0\n\n\
+                 [materials.white]\ntype = \"lambertian\"\nalbedo = [0.5, 0.5, 0.5]\n\n\
+                 [[objects]]\ntype = \"sphere\"\ncenter = [0.0, 0.0, 0.0]\nradius = 1.0\nmaterial = \"white\"\n{}\n", extra)
+    }
+
+    // Line number of the first line starting with key
+    fn line_starting(text: &str, key: &str) -> Option<usize> {
+        text.lines().position(|l| l.starts_with(key)).map(|i| i + 1)
+    }
+
+    fn error_in(text: &str) -> SceneError {
+        match parse_scene(text, Path::new(".")) {
+            Ok(_) => panic!("scene should have been rejected"),
+            Err(e) => e
+        }
+    }
+
+    #[test]
+    fn scales_that_cant_be_undone_are_rejected() {
+        for scale in ["1e-20", "1e30", "nan", "inf", "[1e-20, 1e-20, 1.0]"] {
+            let text = sphere_with(&format!("scale = {}", scale));
+            let e = error_in(&text);
+            assert_eq!(e.field.as_deref(), Some("objects[0].scale"), "scale = {}: {}", scale, e.message);
+            assert_eq!(e.line, line_starting(&text, "scale"), "scale = {}", scale);
+        }
+        assert!(parse_scene(&sphere_with("scale = 1e-3"), Path::new(".")).is_ok());
+    }
+
+    #[test]
+    fn errors_point_at_the_line_and_field() {
+        let plain = sphere_with("");
+        let cases = [
+            (plain.replace("material = \"white\"", "material = \"chrome\""), "material", "objects[0].material", "unknown material `chrome`"),
+            (plain.replace("radius = 1.0", "radius = -2.0"), "radius", "objects[0].radius", "must be positive"),
+            (sphere_with("scale = 0.0"), "scale", "objects[0].scale", "can't be zero"),
+            (sphere_with("scale = \"big\""), "scale", "objects[0].scale", "must be a number"),
+            (plain.replace("type = \"sphere\"", "type = \"cube\""), "type = \"cube\"", "objects[0].type", "unknown object type `cube`"),
+            (plain.replace("type = \"lambertian\"", "type = \"velvet\""), "type = \"velvet\"", "materials.white.type", "unknown material type `velvet`")
+        ];
+        for (text, key, field, message) in &cases {
+            let e = error_in(text);
+            assert_eq!(e.field.as_deref(), Some(*field), "{}", e.message);
+            assert_eq!(e.line, line_starting(text, key), "{}", field);
+            assert!(e.message.contains(message), "{:?} should mention {:?}", e.message, message);
+        }
+    }
+
+    #[test]
+    fn non_finite_numbers_are_rejected() {
+        let plain = sphere_with("");
+        let metal = "type = \"metal\"\nalbedo = [0.5, 0.5, 0.5]\nfuzz = ";
+        let cases = [
+            (plain.replace("radius = 1.0", "radius = inf"), "radius", "objects[0].radius"),
+            (plain.replace("radius = 1.0", "radius = nan"), "radius", "objects[0].radius"),
+            (plain.replace("albedo = [0.5, 0.5, 0.5]", "albedo = [0.5, nan, 0.5]"), "albedo", "materials.white.albedo"),
+            (plain.replace("type = \"lambertian\"\nalbedo = [0.5, 0.5, 0.5]", &format!("{}nan", metal)), "fuzz", "materials.white.fuzz"),
+            (plain.replace("type = \"lambertian\"\nalbedo = [0.5, 0.5, 0.5]", &format!("{}inf", metal)), "fuzz", "materials.white.fuzz"),
+            (plain.replace("vfov = 40.0", "vfov = 40.0\naperture = nan"), "aperture", "camera.aperture"),
+            (sphere_with("keyframes = [{ time = nan }]"), "keyframes", "objects[0].keyframes[0].time")
+        ];
+        for (text, key, field) in &cases {
+            let e = error_in(text);
+            assert_eq!(e.field.as_deref(), Some(*field), "{}", e.message);
+            assert_eq!(e.line, line_starting(text, key), "{}", field);
+        }
+    }
+
+    #[test]
+    fn keyframe_scales_that_cant_be_undone_are_rejected() {
+        for scale in ["1e-20", "1e30", "nan"] {
+            let text = sphere_with(&format!("keyframes = [{{ time = 0.0 }}, {{ time = 1.0, scale = {} }}]", scale));
+            let e = error_in(&text);
+            assert_eq!(e.field.as_deref(), Some("objects[0].keyframes[1].scale"), "scale = {}: {}", scale, e.message);
+            assert_eq!(e.line, line_starting(&text, "keyframes"), "scale = {}", scale);
+        }
+        // Fine at both ends, but the blend passes through 1e-20 on two axes
+        let text = sphere_with("keyframes = [{ time = 0.0, scale = [1e-20, 1.0, 1e20] }, { time = 1.0, scale = [1e20, 1e-20, 1e-20] }]");
+        let e = error_in(&text);
+        assert_eq!(e.field.as_deref(), Some("objects[0].keyframes"), "{}", e.message);
+        let text = sphere_with("keyframes = [{ time = 0.0, scale = 0.01 }, { time = 1.0, scale = 100.0 }]");
+        assert!(parse_scene(&text, Path::new(".")).is_ok());
+    }
+}
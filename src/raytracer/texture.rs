@@ -0,0 +1,218 @@
+#![allow(dead_code)]
+extern crate image;
+
+use std::io::Cursor;
+use std::sync::Arc;
+
+use super::math::vec3::*;
+use super::formats::exr::*;
+
+// A color that varies over a surface, looked up by texture coordinates or
+// by the hit point itself. Materials hold them in an Arc so copying a
+// material doesn't copy images
+// Send + Sync so scenes can be shared between render threads
+pub trait Texture: Send + Sync {
+    fn value(&self, u: f32, v: f32, p: &Vec3) -> Vec3;
+}
+
+pub struct ConstantTexture {
+    pub color: Vec3
+}
+
+impl ConstantTexture {
+    pub fn new(color: Vec3) -> ConstantTexture {
+        ConstantTexture {
+            color
+        }
+    }
+}
+
+impl Texture for ConstantTexture {
+    fn value(&self, _u: f32, _v: f32, _p: &Vec3) -> Vec3 {
+        self.color.copy()
+    }
+}
+
+// Another texture with every color multiplied by scale
+pub struct ScaledTexture {
+    pub texture: Arc<dyn Texture>,
+    pub scale: Vec3
+}
+
+impl Texture for ScaledTexture {
+    fn value(&self, u: f32, v: f32, p: &Vec3) -> Vec3 {
+        self.texture.value(u, v, p).mul_by_vec(&self.scale)
+    }
+}
+
+// Which coordinates a checker pattern is laid out in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CheckerSpace {
+    // u and v, so the checks follow the surface
+    Uv,
+    // The hit point, as if the object was carved out of a block of checks
+    Solid
+}
+
+pub struct CheckerTexture {
+    pub even: Arc<dyn Texture>,
+    pub odd: Arc<dyn Texture>,
+    // Checks per unit of u and v, or per unit of distance for solid ones
+    pub scale: f32,
+    pub space: CheckerSpace
+}
+
+impl Texture for CheckerTexture {
+    fn value(&self, u: f32, v: f32, p: &Vec3) -> Vec3 {
+        let cell = |c: f32| (c * self.scale).floor() as i64;
+        let sum = match self.space {
+            CheckerSpace::Uv => cell(u) + cell(v),
+            CheckerSpace::Solid => cell(p.x) + cell(p.y) + cell(p.z)
+        };
+        if sum.rem_euclid(2) == 0 {
+            self.even.value(u, v, p)
+        } else {
+            self.odd.value(u, v, p)
+        }
+    }
+}
+
+// Linear colors, top row first
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pixels: Vec<Vec3>
+}
+
+impl Image {
+    pub fn new(width: u32, height: u32, pixels: Vec<Vec3>) -> Image {
+        assert_eq!(pixels.len(), width as usize * height as usize);
+        Image {
+            width,
+            height,
+            pixels
+        }
+    }
+    pub fn load(path: &str) -> Result<Image, String> {
+        let bytes = std::fs::read(path).map_err(|e| format!("could not read {}: {}", path, e))?;
+        Image::decode(&bytes).map_err(|e| format!("{}: {}", path, e))
+    }
+    // Radiance .hdr and OpenEXR files are read as they are, anything else
+    // the image crate can open is taken to be sRGB
+    pub fn decode(bytes: &[u8]) -> Result<Image, String> {
+        if bytes.starts_with(&[0x76, 0x2f, 0x31, 0x01]) {
+            return read_exr(bytes);
+        }
+        if bytes.starts_with(b"#?") {
+            let decoder = image::hdr::HDRDecoder::new(Cursor::new(bytes)).map_err(|e| e.to_string())?;
+            let meta = decoder.metadata();
+            let pixels = decoder.read_image_hdr().map_err(|e| e.to_string())?;
+            return Ok(Image::new(meta.width, meta.height, pixels.iter().map(|p| Vec3::new(p[0], p[1], p[2])).collect()));
+        }
+        let img = image::load_from_memory(bytes).map_err(|e| e.to_string())?.to_rgb();
+        // Only 256 possible values, so look them up instead of calling powf
+        let table: Vec<f32> = (0..256).map(|i| srgb_to_linear(i as f32 / 255.0)).collect();
+        let pixels = img.pixels().map(|p| Vec3::new(table[p[0] as usize], table[p[1] as usize], table[p[2] as usize])).collect();
+        Ok(Image::new(img.width(), img.height(), pixels))
+    }
+    pub fn pixel(&self, x: u32, y: u32) -> &Vec3 {
+        &self.pixels[y as usize * self.width as usize + x as usize]
+    }
+    // Average color over the whole image
+    pub fn average(&self) -> Vec3 {
+        let mut sum = [0.0f64; 3];
+        for p in &self.pixels {
+            for c in 0..3 {
+                sum[c] += p[c] as f64;
+            }
+        }
+        let n = (self.pixels.len() as f64).max(1.0);
+        Vec3::new((sum[0] / n) as f32, (sum[1] / n) as f32, (sum[2] / n) as f32)
+    }
+}
+
+pub fn srgb_to_linear(v: f32) -> f32 {
+    if v <= 0.040_45 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+// What happens to texture coordinates outside 0 to 1
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Wrap {
+    Repeat,
+    // Keep using the edge pixels
+    Clamp,
+    // Repeat, flipping every other copy
+    Mirror
+}
+
+impl Wrap {
+    // Maps a pixel index onto 0..n
+    fn apply(self, i: i64, n: u32) -> u32 {
+        let n = n as i64;
+        let res = match self {
+            Wrap::Repeat => i.rem_euclid(n),
+            Wrap::Clamp => i.clamp(0, n - 1),
+            Wrap::Mirror => {
+                let m = i.rem_euclid(2 * n);
+                if m < n { m } else { 2 * n - 1 - m }
+            }
+        };
+        res as u32
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+    // The closest pixel, blocky up close
+    Nearest,
+    // Blends the four closest pixels
+    Bilinear
+}
+
+// An image stretched over 0 to 1 in u and v, with v = 0 at the bottom row
+pub struct ImageTexture {
+    pub image: Arc<Image>,
+    pub filter: Filter,
+    pub wrap: Wrap
+}
+
+impl ImageTexture {
+    pub fn new(image: Arc<Image>) -> ImageTexture {
+        ImageTexture {
+            image,
+            filter: Filter::Bilinear,
+            wrap: Wrap::Repeat
+        }
+    }
+}
+
+impl Texture for ImageTexture {
+    fn value(&self, u: f32, v: f32, _p: &Vec3) -> Vec3 {
+        let img = &self.image;
+        if img.width == 0 || img.height == 0 {
+            return Vec3::all(0.0);
+        }
+        // Pixel space, with rows counting down from the top
+        let x = u * img.width as f32;
+        let y = (1.0 - v) * img.height as f32;
+        match self.filter {
+            Filter::Nearest => img.pixel(self.wrap.apply(x.floor() as i64, img.width), self.wrap.apply(y.floor() as i64, img.height)).copy(),
+            Filter::Bilinear => {
+                // Pixel centers sit at half-integer coordinates
+                let (x, y) = (x - 0.5, y - 0.5);
+                let (x0, y0) = (x.floor(), y.floor());
+                let (fx, fy) = (x - x0, y - y0);
+                let (x0, y0) = (x0 as i64, y0 as i64);
+                let xs = [self.wrap.apply(x0, img.width), self.wrap.apply(x0.saturating_add(1), img.width)];
+                let ys = [self.wrap.apply(y0, img.height), self.wrap.apply(y0.saturating_add(1), img.height)];
+                let top = img.pixel(xs[0], ys[0]).mul(1.0 - fx).add_by_vec(&img.pixel(xs[1], ys[0]).mul(fx));
+                let bottom = img.pixel(xs[0], ys[1]).mul(1.0 - fx).add_by_vec(&img.pixel(xs[1], ys[1]).mul(fx));
+                top.mul(1.0 - fy).add_by_vec(&bottom.mul(fy))
+            }
+        }
+    }
+}
@@ -0,0 +1,270 @@
+#![allow(dead_code)]
+
+use super::math::vec3::*;
+use super::film::*;
+
+// Ways of squeezing unbounded radiance into the 0-1 display range
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ToneMap {
+    // Anything above 1.0 just clips
+    Clamp,
+    // L / (1 + L) on luminance, never quite reaches white
+    Reinhard,
+    // Reinhard with a luminance that maps to pure white, see white_point
+    ExtendedReinhard,
+    // Stephen Hill's fit of the ACES reference and output transforms
+    Aces,
+    // Troy Sobotka's AgX with the default look
+    Agx
+}
+
+impl ToneMap {
+    pub fn from_name(name: &str) -> Option<ToneMap> {
+        match name.to_ascii_lowercase().as_str() {
+            "clamp" => Some(ToneMap::Clamp),
+            "reinhard" => Some(ToneMap::Reinhard),
+            "reinhard-extended" => Some(ToneMap::ExtendedReinhard),
+            "aces" => Some(ToneMap::Aces),
+            "agx" => Some(ToneMap::Agx),
+            _ => None
+        }
+    }
+}
+
+// Turns film radiance into 8-bit sRGB: exposure, then the operator, then
+// the sRGB transfer curve, then optional dithering before quantizing
+pub struct ToneMapper {
+    pub operator: ToneMap,
+    pub exposure: f32, // In stops, each one doubles the brightness
+    pub white_point: f32, // Luminance that ExtendedReinhard maps to 1.0
+    pub dither: bool
+}
+
+impl ToneMapper {
+    pub fn new(operator: ToneMap, exposure: f32) -> ToneMapper {
+        ToneMapper {
+            operator,
+            exposure,
+            white_point: 1.0,
+            dither: true
+        }
+    }
+    // Uses the brightest pixel on the film as the white point
+    pub fn white_from_film(&mut self, film: &Film) {
+        let scale = 2f32.powf(self.exposure);
+        let max = film.pixels().iter().map(|p| luminance(p) * scale).fold(0.0, f32::max);
+        self.white_point = if max > 0.0 { max } else { 1.0 };
+    }
+    // Maps a linear color to display-linear values in [0, 1]
+    pub fn apply(&self, color: &Vec3) -> Vec3 {
+        let c = color.mul(2f32.powf(self.exposure));
+        let mapped = match self.operator {
+            ToneMap::Clamp => c,
+            ToneMap::Reinhard => scale_luminance(&c, |l| l / (1.0 + l)),
+            ToneMap::ExtendedReinhard => {
+                let w2 = self.white_point * self.white_point;
+                scale_luminance(&c, |l| l * (1.0 + l / w2) / (1.0 + l))
+            },
+            ToneMap::Aces => aces(&c),
+            ToneMap::Agx => agx(&c)
+        };
+        Vec3::new(saturate(mapped.x), saturate(mapped.y), saturate(mapped.z))
+    }
+    // Full pipeline down to 8 bits, x and y seed the dither pattern
+    pub fn to_rgb8(&self, color: &Vec3, x: u32, y: u32) -> [u8; 3] {
+        let c = self.apply(color);
+        let mut out = [0u8; 3];
+        for (i, v) in [c.x, c.y, c.z].iter().enumerate() {
+            let noise = if self.dither { tpdf_noise(x, y, i as u32) } else { 0.0 };
+            out[i] = (srgb_oetf(*v) * 255.0 + noise).round().clamp(0.0, 255.0) as u8;
+        }
+        out
+    }
+}
+
+fn saturate(v: f32) -> f32 {
+    // NaN from broken samples ends up black instead of poisoning the cast
+    if v.is_nan() { 0.0 } else { v.clamp(0.0, 1.0) }
+}
+
+// Rec. 709 luminance
+pub fn luminance(c: &Vec3) -> f32 {
+    0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z
+}
+
+// Tone maps luminance only, which keeps hues from shifting
+fn scale_luminance<F: Fn(f32) -> f32>(c: &Vec3, curve: F) -> Vec3 {
+    let l = luminance(c);
+    if l <= 0.0 {
+        return Vec3::all(0.0);
+    }
+    c.mul(curve(l) / l)
+}
+
+// The exact piecewise sRGB encoding from IEC 61966-2-1
+pub fn srgb_oetf(v: f32) -> f32 {
+    if v <= 0.003_130_8 {
+        12.92 * v
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// Triangular noise in (-0.5, 0.5) from a hash of the pixel and channel, so
+// dithering is the same every time an image is saved. Keeping it under half
+// a step leaves pure black and white alone
+fn tpdf_noise(x: u32, y: u32, channel: u32) -> f32 {
+    let h = hash(x.wrapping_mul(0x8da6_b343) ^ y.wrapping_mul(0xd816_3841) ^ channel.wrapping_mul(0xcb1a_b31f));
+    let a = (h & 0xffff) as f32 / 65536.0;
+    let b = (h >> 16) as f32 / 65536.0;
+    (a - b) / 2.0
+}
+
+// Integer hash by Chris Wellons (lowbias32)
+fn hash(mut x: u32) -> u32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb_352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846c_a68b);
+    x ^= x >> 16;
+    x
+}
+
+fn mul_mat(m: &[[f32; 3]; 3], v: &Vec3) -> Vec3 {
+    Vec3::new(
+        m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+        m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+        m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z
+    )
+}
+
+// sRGB to the ACES fit's working space, with the RRT's desaturation folded in
+const ACES_INPUT: [[f32; 3]; 3] = [
+    [0.59719, 0.35458, 0.04823],
+    [0.07600, 0.90834, 0.01566],
+    [0.02840, 0.13383, 0.83777]
+];
+const ACES_OUTPUT: [[f32; 3]; 3] = [
+    [1.60475, -0.53108, -0.07367],
+    [-0.10208, 1.10813, -0.00605],
+    [-0.00327, -0.07276, 1.07602]
+];
+
+fn aces(c: &Vec3) -> Vec3 {
+    let v = mul_mat(&ACES_INPUT, c);
+    let fit = |x: f32| (x * (x + 0.024_578_6) - 0.000_090_537) / (x * (0.983_729 * x + 0.432_951) + 0.238_081);
+    mul_mat(&ACES_OUTPUT, &Vec3::new(fit(v.x), fit(v.y), fit(v.z)))
+}
+
+// AgX inset and outset matrices for sRGB primaries
+const AGX_INSET: [[f32; 3]; 3] = [
+    [0.842_479_06, 0.078_433_6, 0.079_223_745],
+    [0.042_328_242, 0.878_468_6, 0.079_166_13],
+    [0.042_375_655, 0.078_433_6, 0.879_143]
+];
+const AGX_OUTSET: [[f32; 3]; 3] = [
+    [1.196_879, -0.098_020_88, -0.099_029_74],
+    [-0.052_896_85, 1.151_903_1, -0.098_961_18],
+    [-0.052_971_635, -0.098_043_45, 1.151_073_7]
+];
+// Range of stops around middle grey the log encoding covers
+const AGX_MIN_EV: f32 = -12.473_93;
+const AGX_MAX_EV: f32 = 4.026_069;
+
+fn agx(c: &Vec3) -> Vec3 {
+    let v = mul_mat(&AGX_INSET, c);
+    let encode = |x: f32| {
+        let ev = x.max(1e-10).log2().clamp(AGX_MIN_EV, AGX_MAX_EV);
+        let x = (ev - AGX_MIN_EV) / (AGX_MAX_EV - AGX_MIN_EV);
+        // Polynomial fit of the default sigmoid
+        let x2 = x * x;
+        let x4 = x2 * x2;
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - 0.002_32
+    };
+    let v = mul_mat(&AGX_OUTSET, &Vec3::new(encode(v.x), encode(v.y), encode(v.z)));
+    // The curve produces display-encoded values, undo the 2.2 gamma so the
+    // sRGB step doesn't apply it twice
+    Vec3::new(v.x.max(0.0).powf(2.2), v.y.max(0.0).powf(2.2), v.z.max(0.0).powf(2.2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn srgb_curve_is_exact() {
+        // Straight line up to the cutoff, the power curve past it
+        for v in [0.0f32, 1e-4, 0.001, 0.003] {
+            assert_eq!(srgb_oetf(v), 12.92 * v);
+        }
+        for v in [0.004f64, 0.01, 0.18, 0.5, 0.9, 1.0] {
+            let expected = 1.055 * v.powf(1.0 / 2.4) - 0.055;
+            assert!((srgb_oetf(v as f32) as f64 - expected).abs() < 1e-6, "{}", v);
+        }
+        // The two pieces meet at the cutoff
+        let cutoff = 0.003_130_8f32;
+        assert!((srgb_oetf(cutoff) - srgb_oetf(cutoff + 1e-7)).abs() < 1e-5);
+        assert!((srgb_oetf(1.0) - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn operators_keep_their_fixed_points() {
+        let grey = |operator: ToneMap, l: f32| ToneMapper::new(operator, 0.0).apply(&Vec3::all(l)).x;
+        for operator in [ToneMap::Clamp, ToneMap::Reinhard, ToneMap::ExtendedReinhard, ToneMap::Aces, ToneMap::Agx] {
+            assert_eq!(grey(operator, 0.0), 0.0, "{:?} should keep black", operator);
+            // Brighter in never comes out darker
+            let ramp: Vec<f32> = (0..200).map(|i| grey(operator, i as f32 * 0.05)).collect();
+            assert!(ramp.windows(2).all(|w| w[0] <= w[1]), "{:?} isn't monotonic", operator);
+        }
+        assert_eq!(grey(ToneMap::Clamp, 0.5), 0.5);
+        assert_eq!(grey(ToneMap::Clamp, 1.0), 1.0);
+        assert_eq!(grey(ToneMap::Clamp, 7.0), 1.0);
+        assert!((grey(ToneMap::Reinhard, 1.0) - 0.5).abs() < 1e-6);
+        assert!(grey(ToneMap::Reinhard, 1e6) < 1.0);
+        assert_eq!(grey(ToneMap::Aces, 1e6), 1.0);
+        assert!(grey(ToneMap::Agx, 1e6) > 0.99);
+        // The white point is exactly white
+        let mut mapper = ToneMapper::new(ToneMap::ExtendedReinhard, 0.0);
+        mapper.white_point = 4.0;
+        assert!((mapper.apply(&Vec3::all(4.0)).x - 1.0).abs() < 1e-6);
+        // Each stop of exposure doubles what goes in
+        assert_eq!(ToneMapper::new(ToneMap::Clamp, 1.0).apply(&Vec3::all(0.25)).x, 0.5);
+        assert_eq!(ToneMapper::new(ToneMap::Clamp, -2.0).apply(&Vec3::all(2.0)).x, 0.5);
+    }
+
+    #[test]
+    fn dither_stays_within_half_a_step() {
+        let mut plain = ToneMapper::new(ToneMap::Clamp, 0.0);
+        plain.dither = false;
+        let dithered = ToneMapper::new(ToneMap::Clamp, 0.0);
+        for channel in 0..3 {
+            for y in 0..64 {
+                for x in 0..64 {
+                    assert!(tpdf_noise(x, y, channel).abs() < 0.5);
+                }
+            }
+        }
+        // Black and white are never dithered away from
+        for y in 0..64 {
+            for x in 0..64 {
+                assert_eq!(dithered.to_rgb8(&Vec3::all(0.0), x, y), [0, 0, 0]);
+                assert_eq!(dithered.to_rgb8(&Vec3::all(1.0), x, y), [255, 255, 255]);
+            }
+        }
+        // A level between two steps lands on one of them, and on average
+        // closer to the true level than rounding gets
+        let level = 0.3f32;
+        let exact = srgb_oetf(level) as f64 * 255.0;
+        let rounded = plain.to_rgb8(&Vec3::all(level), 0, 0)[0] as f64;
+        let mut sum = 0.0;
+        for y in 0..64 {
+            for x in 0..64 {
+                let v = dithered.to_rgb8(&Vec3::all(level), x, y)[0] as f64;
+                assert!(v == exact.floor() || v == exact.ceil(), "{} for a level of {}", v, exact);
+                sum += v;
+            }
+        }
+        let mean = sum / (64.0 * 64.0);
+        assert!((mean - exact).abs() < (rounded - exact).abs(), "dithered to {} on average, rounding gives {}, exact is {}", mean, rounded, exact);
+    }
+}
@@ -0,0 +1,225 @@
+#![allow(dead_code)]
+
+use std::sync::Arc;
+
+use super::math::vec3::*;
+use super::math::mat4::*;
+use super::ray::*;
+use super::object::*;
+use super::aabb::*;
+use super::light::*;
+
+// Places an object somewhere else in the scene. The object itself is
+// shared, so one mesh can be put down thousands of times while its
+// triangles and BVH are only kept in memory once
+pub struct Transformed {
+    object: Arc<dyn Object>,
+    to_world: Mat4,
+    to_object: Mat4,
+    // Worked out once up front, None if the object has no bounds
+    bounds: Option<Aabb>
+}
+
+impl Transformed {
+    // None if to_world squashes space flat and can't be undone
+    pub fn new(object: Arc<dyn Object>, to_world: Mat4) -> Option<Transformed> {
+        let to_object = to_world.inverse()?;
+        let bounds = object.bounding_box().map(|b| transform_box(&b, &to_world));
+        Some(Transformed {
+            object,
+            to_world,
+            to_object,
+            bounds
+        })
+    }
+}
+
+// The box around b once it's been moved by to_world
+fn transform_box(b: &Aabb, to_world: &Mat4) -> Aabb {
+    let mut res = Aabb::empty();
+    for i in 0..8 {
+        let corner = Vec3::new(
+            if i & 1 == 0 { b.min.x } else { b.max.x },
+            if i & 2 == 0 { b.min.y } else { b.max.y },
+            if i & 4 == 0 { b.min.z } else { b.max.z }
+        );
+        res.grow_by_point_eq(&to_world.transform_point(&corner));
+    }
+    res
+}
+
+// Hits object in its own space and moves the hit back out
+fn check_hit_through(object: &dyn Object, to_world: &Mat4, to_object: &Mat4, r: &Ray, t_min: f32, t_max: f32, rec: &mut HitRecord) -> bool {
+    // The direction isn't normalized, so distances along the ray are
+    // the same in both spaces and t can be used as it is
+    let local = r.moved(to_object.transform_point(&r.origin), to_object.transform_vector(&r.direction));
+    if !object.check_hit(&local, t_min, t_max, rec) {
+        return false;
+    }
+    rec.p = to_world.transform_point(&rec.p);
+    rec.normal = to_object.transform_normal(&rec.normal).as_unit();
+    rec.tangent = to_world.transform_vector(&rec.tangent);
+    true
+}
+
+fn transmittance_through(object: &dyn Object, to_object: &Mat4, r: &Ray, t_min: f32, t_max: f32) -> f32 {
+    let local = r.moved(to_object.transform_point(&r.origin), to_object.transform_vector(&r.direction));
+    object.transmittance(&local, t_min, t_max)
+}
+
+impl Object for Transformed {
+    fn check_hit(&self, r: &Ray, t_min: f32, t_max: f32, rec: &mut HitRecord) -> bool {
+        check_hit_through(self.object.as_ref(), &self.to_world, &self.to_object, r, t_min, t_max, rec)
+    }
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.bounds.as_ref().map(|b| b.copy())
+    }
+    // Spheres only stay spheres if they're scaled evenly, stretched ones
+    // can't be sampled and get left out
+    fn add_light_shapes(&self, shapes: &mut Vec<LightShape>) {
+        let mut inner = Vec::new();
+        self.object.add_light_shapes(&mut inner);
+        shapes.extend(inner.iter().filter_map(|s| s.transformed(&self.to_world)));
+    }
+    fn transmittance(&self, r: &Ray, t_min: f32, t_max: f32) -> f32 {
+        transmittance_through(self.object.as_ref(), &self.to_object, r, t_min, t_max)
+    }
+    fn has_media(&self) -> bool {
+        self.object.has_media()
+    }
+}
+
+// Where an animated object is at one moment. Scale is applied first, then
+// the rotation and then the translation
+pub struct Keyframe {
+    pub time: f32,
+    pub translation: Vec3,
+    pub rotation: [f32; 4],
+    pub scale: Vec3
+}
+
+impl Keyframe {
+    fn matrix(&self) -> Mat4 {
+        Mat4::from_trs(&self.translation, &self.rotation, &self.scale)
+    }
+}
+
+// Whether every pose along the way can be undone. Blended scales stay
+// between the smallest and largest the keyframes use on each axis, so
+// checking those two covers everything in between
+pub fn keyframes_invertible(keyframes: &[Keyframe]) -> bool {
+    let (mut lo, mut hi) = (Vec3::all(f32::INFINITY), Vec3::all(0.0));
+    for k in keyframes {
+        if k.matrix().inverse().is_none() {
+            return false;
+        }
+        for axis in 0..3 {
+            lo[axis] = lo[axis].min(k.scale[axis].abs());
+            hi[axis] = hi[axis].max(k.scale[axis].abs());
+        }
+    }
+    Mat4::scale(&lo).inverse().is_some() && Mat4::scale(&hi).inverse().is_some()
+}
+
+// Steps each stretch between keyframes is split into to find the bounds
+const BOUNDS_STEPS: usize = 32;
+
+// An object moving through its keyframes while the shutter is open.
+// Positions and scales are blended in a straight line and rotations turn
+// at a steady rate, before the first keyframe and after the last one it
+// stays where those put it
+pub struct Animated {
+    object: Arc<dyn Object>,
+    keyframes: Vec<Keyframe>,
+    bounds: Option<Aabb>
+}
+
+impl Animated {
+    // keyframes have to be in order of time. None if there are none or the
+    // scale gets too small or large to undo somewhere along the way
+    pub fn new(object: Arc<dyn Object>, keyframes: Vec<Keyframe>) -> Option<Animated> {
+        let first = keyframes.first()?;
+        let flips = |a: f32, b: f32| a == 0.0 || a.signum() != b.signum();
+        for k in &keyframes {
+            let s = &first.scale;
+            if flips(k.scale.x, s.x) || flips(k.scale.y, s.y) || flips(k.scale.z, s.z) {
+                return None;
+            }
+        }
+        if !keyframes_invertible(&keyframes) {
+            return None;
+        }
+        let mut res = Animated {
+            object,
+            keyframes,
+            bounds: None
+        };
+        res.bounds = res.sweep_bounds();
+        Some(res)
+    }
+    fn to_world(&self, time: f32) -> Mat4 {
+        let next = self.keyframes.partition_point(|k| k.time <= time);
+        if next == 0 {
+            return self.keyframes[0].matrix();
+        }
+        if next == self.keyframes.len() {
+            return self.keyframes[next - 1].matrix();
+        }
+        let (a, b) = (&self.keyframes[next - 1], &self.keyframes[next]);
+        let t = (time - a.time) / (b.time - a.time);
+        let lerp = |x: &Vec3, y: &Vec3| x.mul(1.0 - t).add_by_vec(&y.mul(t));
+        Mat4::from_trs(&lerp(&a.translation, &b.translation), &slerp(&a.rotation, &b.rotation, t), &lerp(&a.scale, &b.scale))
+    }
+    // The box around everywhere the object goes. Turning objects sweep
+    // along arcs that bulge out between the steps, so the box gets grown by
+    // as much as an arc can stray from its chord
+    fn sweep_bounds(&self) -> Option<Aabb> {
+        let b = self.object.bounding_box()?;
+        let mut res = transform_box(&b, &self.keyframes[0].matrix());
+        // Furthest the object reaches from its own origin
+        let reach = b.min.x.abs().max(b.max.x.abs()).powi(2)
+            + b.min.y.abs().max(b.max.y.abs()).powi(2)
+            + b.min.z.abs().max(b.max.z.abs()).powi(2);
+        for pair in self.keyframes.windows(2) {
+            let (a, k) = (&pair[0], &pair[1]);
+            let cos = a.rotation.iter().zip(&k.rotation).map(|(x, y)| x * y).sum::<f32>().abs().min(1.0);
+            let step_angle = 2.0 * cos.acos() / BOUNDS_STEPS as f32;
+            let largest_scale = [&a.scale, &k.scale].iter().map(|s| s.x.abs().max(s.y.abs()).max(s.z.abs())).fold(0.0, f32::max);
+            let bulge = reach.sqrt() * largest_scale * (1.0 - (step_angle / 2.0).cos());
+            for i in 1..=BOUNDS_STEPS {
+                let time = a.time + (k.time - a.time) * i as f32 / BOUNDS_STEPS as f32;
+                let step = transform_box(&b, &self.to_world(time));
+                res.grow_eq(&Aabb::new(step.min.sub_by_vec(&Vec3::all(bulge)), step.max.add_by_vec(&Vec3::all(bulge))));
+            }
+        }
+        Some(res)
+    }
+}
+
+impl Object for Animated {
+    fn check_hit(&self, r: &Ray, t_min: f32, t_max: f32, rec: &mut HitRecord) -> bool {
+        let to_world = self.to_world(r.time);
+        // new() made sure there's always an inverse
+        let to_object = match to_world.inverse() {
+            Some(m) => m,
+            None => return false
+        };
+        if !check_hit_through(self.object.as_ref(), &to_world, &to_object, r, t_min, t_max, rec) {
+            return false;
+        }
+        rec.hide_light();
+        true
+    }
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.bounds.as_ref().map(|b| b.copy())
+    }
+    fn transmittance(&self, r: &Ray, t_min: f32, t_max: f32) -> f32 {
+        match self.to_world(r.time).inverse() {
+            Some(to_object) => transmittance_through(self.object.as_ref(), &to_object, r, t_min, t_max),
+            None => 1.0
+        }
+    }
+    fn has_media(&self) -> bool {
+        self.object.has_media()
+    }
+}
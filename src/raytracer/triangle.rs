@@ -0,0 +1,255 @@
+#![allow(dead_code)]
+
+use super::math::vec3::*;
+use super::ray::*;
+use super::material::*;
+use super::object::*;
+use super::aabb::*;
+use super::light::*;
+
+// A single triangle. The front face is the side the vertices go around
+// counterclockwise from, and the geometric normal points out of it
+pub struct Triangle {
+    vertices: [Vec3; 3],
+    // Per-vertex normals for smooth shading, the flat normal is used without them
+    normals: Option<[Vec3; 3]>,
+    // Texture coordinates from mesh files, kept for when textures need them
+    uvs: Option<[[f32; 2]; 3]>,
+    material: Box<dyn Material>
+}
+
+impl Triangle {
+    pub fn new(v0: Vec3, v1: Vec3, v2: Vec3, material: Box<dyn Material>) -> Triangle {
+        Triangle {
+            vertices: [v0, v1, v2],
+            normals: None,
+            uvs: None,
+            material
+        }
+    }
+    // Normals don't need to be unit length, they get normalized after
+    // interpolating
+    pub fn with_normals(v0: Vec3, v1: Vec3, v2: Vec3, normals: [Vec3; 3], material: Box<dyn Material>) -> Triangle {
+        Triangle {
+            vertices: [v0, v1, v2],
+            normals: Some(normals),
+            uvs: None,
+            material
+        }
+    }
+    pub fn vertices(&self) -> &[Vec3; 3] {
+        &self.vertices
+    }
+    pub fn uvs(&self) -> Option<&[[f32; 2]; 3]> {
+        self.uvs.as_ref()
+    }
+    pub fn set_uvs(&mut self, uvs: [[f32; 2]; 3]) {
+        self.uvs = Some(uvs);
+    }
+    pub fn set_material(&mut self, material: Box<dyn Material>) {
+        self.material = material;
+    }
+    // Unit normal of the plane the triangle lies in
+    pub fn geometric_normal(&self) -> Vec3 {
+        let [v0, v1, v2] = &self.vertices;
+        v1.sub_by_vec(v0).cross(&v2.sub_by_vec(v0)).as_unit()
+    }
+    // Twice the area, zero for triangles that collapsed into a line or point
+    pub fn double_area(&self) -> f32 {
+        let [v0, v1, v2] = &self.vertices;
+        v1.sub_by_vec(v0).cross(&v2.sub_by_vec(v0)).magnitude()
+    }
+}
+
+impl Object for Triangle {
+    // Woop, Benthin and Wald's watertight test: the ray is sheared so it
+    // runs along +z, and the edge functions are evaluated in that 2D space.
+    // Rays hitting a shared edge or vertex can't slip between neighbors
+    fn check_hit(&self, r: &Ray, t_min: f32, t_max: f32, rec: &mut HitRecord) -> bool {
+        let dir = &r.direction;
+        // Put the largest direction component on z, then swap x and y if
+        // needed to keep the winding the same
+        let kz = largest_component(dir);
+        let mut kx = (kz + 1) % 3;
+        let mut ky = (kx + 1) % 3;
+        if dir[kz] < 0.0 {
+            std::mem::swap(&mut kx, &mut ky);
+        }
+        let sx = dir[kx] / dir[kz];
+        let sy = dir[ky] / dir[kz];
+        let sz = 1.0 / dir[kz];
+
+        // Vertices relative to the ray origin, then sheared
+        let a = self.vertices[0].sub_by_vec(&r.origin);
+        let b = self.vertices[1].sub_by_vec(&r.origin);
+        let c = self.vertices[2].sub_by_vec(&r.origin);
+        let (ax, ay) = (a[kx] - sx * a[kz], a[ky] - sy * a[kz]);
+        let (bx, by) = (b[kx] - sx * b[kz], b[ky] - sy * b[kz]);
+        let (cx, cy) = (c[kx] - sx * c[kz], c[ky] - sy * c[kz]);
+
+        let mut u = cx * by - cy * bx;
+        let mut v = ax * cy - ay * cx;
+        let mut w = bx * ay - by * ax;
+        // Exactly zero means the ray is on an edge as far as f32 can tell,
+        // so redo those in f64 to decide which side it's really on
+        if u == 0.0 || v == 0.0 || w == 0.0 {
+            u = (cx as f64 * by as f64 - cy as f64 * bx as f64) as f32;
+            v = (ax as f64 * cy as f64 - ay as f64 * cx as f64) as f32;
+            w = (bx as f64 * ay as f64 - by as f64 * ax as f64) as f32;
+        }
+        // Mixed signs mean the ray passes outside one of the edges
+        if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+            return false;
+        }
+        let det = u + v + w;
+        if det == 0.0 {
+            return false;
+        }
+        // A ray right on an edge is inside both triangles that share it, so
+        // the edge only belongs to the one it runs down in, or left if it's
+        // level, once the triangle is turned to face the ray. Neighbors run
+        // along their shared edge in opposite directions, so exactly one
+        // of them keeps the hit
+        let owns = |px: f32, py: f32, qx: f32, qy: f32| {
+            let (dx, dy) = if det > 0.0 { (qx - px, qy - py) } else { (px - qx, py - qy) };
+            dy < 0.0 || (dy == 0.0 && dx < 0.0)
+        };
+        if (u == 0.0 && !owns(bx, by, cx, cy)) || (v == 0.0 && !owns(cx, cy, ax, ay)) || (w == 0.0 && !owns(ax, ay, bx, by)) {
+            return false;
+        }
+
+        let t = (u * sz * a[kz] + v * sz * b[kz] + w * sz * c[kz]) / det;
+        if !(t > t_min && t < t_max) {
+            return false;
+        }
+        // u weights the first vertex, so the second and third get v and w
+        let (b1, b2) = (v / det, w / det);
+        let b0 = 1.0 - b1 - b2;
+
+        rec.t = t;
+        rec.p = r.point_at_parameter(t);
+        rec.normal = match &self.normals {
+            Some([n0, n1, n2]) => n0.mul(b0).add_by_vec(&n1.mul(b1)).add_by_vec(&n2.mul(b2)).as_unit(),
+            None => self.geometric_normal()
+        };
+        // Without texture coordinates the barycentrics stand in for them
+        let [v0, v1, v2] = &self.vertices;
+        let (e1, e2) = (v1.sub_by_vec(v0), v2.sub_by_vec(v0));
+        match &self.uvs {
+            Some([t0, t1, t2]) => {
+                rec.u = t0[0] * b0 + t1[0] * b1 + t2[0] * b2;
+                rec.v = t0[1] * b0 + t1[1] * b1 + t2[1] * b2;
+                let (du1, dv1, du2, dv2) = (t1[0] - t0[0], t1[1] - t0[1], t2[0] - t0[0], t2[1] - t0[1]);
+                let uv_det = du1 * dv2 - dv1 * du2;
+                rec.tangent = if uv_det != 0.0 { e1.mul(dv2).sub_by_vec(&e2.mul(dv1)).div(uv_det) } else { e1 };
+            },
+            None => {
+                rec.u = b1;
+                rec.v = b2;
+                rec.tangent = e1;
+            }
+        }
+        rec.b1 = b1;
+        rec.b2 = b2;
+        rec.material = self.material.copy();
+        true
+    }
+    fn bounding_box(&self) -> Option<Aabb> {
+        let mut res = Aabb::empty();
+        for v in &self.vertices {
+            res.grow_by_point_eq(v);
+        }
+        Some(res)
+    }
+    fn add_light_shapes(&self, shapes: &mut Vec<LightShape>) {
+        if self.material.is_light() {
+            let [v0, v1, v2] = &self.vertices;
+            shapes.push(LightShape::Triangle { vertices: [v0.copy(), v1.copy(), v2.copy()] });
+        }
+    }
+}
+
+fn largest_component(v: &Vec3) -> usize {
+    let (x, y, z) = (v.x.abs(), v.y.abs(), v.z.abs());
+    if x > y && x > z {
+        0
+    } else if y > z {
+        1
+    } else {
+        2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::sampler::*;
+
+    // Eight triangles around the origin in the z = 0 plane, all wound the
+    // same way, so they share spokes along the axes and diagonals
+    fn fan() -> Vec<Triangle> {
+        let ring = [(1.0, 0.0), (1.0, 1.0), (0.0, 1.0), (-1.0, 1.0), (-1.0, 0.0), (-1.0, -1.0), (0.0, -1.0), (1.0, -1.0)];
+        (0..ring.len()).map(|i| {
+            let (a, b) = (ring[i], ring[(i + 1) % ring.len()]);
+            Triangle::new(Vec3::all(0.0), Vec3::new(a.0, a.1, 0.0), Vec3::new(b.0, b.1, 0.0), Box::new(Lambertian::new(Vec3::all(0.5))))
+        }).collect()
+    }
+
+    fn hits(triangles: &[Triangle], r: &Ray) -> usize {
+        triangles.iter().filter(|t| t.check_hit(r, 0.001, f32::MAX, &mut HitRecord::default())).count()
+    }
+
+    #[test]
+    fn shared_edges_and_vertices_are_hit_once() {
+        let fan = fan();
+        // Straight through the shared vertex and along each spoke, from
+        // either side
+        let mut targets = vec![(0.0, 0.0)];
+        for (x, y) in [(1.0, 0.0), (1.0, 1.0), (0.0, 1.0), (-1.0, 1.0), (-1.0, 0.0), (-1.0, -1.0), (0.0, -1.0), (1.0, -1.0)] {
+            targets.push((x * 0.5, y * 0.5));
+            targets.push((x * 0.25, y * 0.25));
+        }
+        for (x, y) in &targets {
+            for z in [1.0, -1.0] {
+                let r = Ray::new(Vec3::new(*x, *y, z), Vec3::new(0.0, 0.0, -z), 0.0);
+                assert_eq!(hits(&fan, &r), 1, "ray down to {}, {} from z = {}", x, y, z);
+            }
+        }
+        // Slanted rays aimed at the spokes and the middle, which land on
+        // or a rounding error away from them
+        let mut sampler = RandomSampler::new(1);
+        for i in 0..20000 {
+            let (sx, sy) = sampler.next_2d();
+            let along = sampler.next_f32() * 0.9 * (i % 2) as f32;
+            let spoke = [(1.0, 0.0), (1.0, 1.0), (0.0, 1.0), (-1.0, 1.0)][i % 4];
+            let target = Vec3::new(spoke.0 * along, spoke.1 * along, 0.0);
+            let direction = Vec3::new(sx - 0.5, sy - 0.5, if i % 3 == 0 { 1.0 } else { -1.0 });
+            let r = Ray::new(target.sub_by_vec(&direction.mul(3.0)), direction, 0.0);
+            assert_eq!(hits(&fan, &r), 1, "ray {} through {:?}", i, (target.x, target.y));
+        }
+    }
+
+    #[test]
+    fn barycentrics_and_normals_are_interpolated() {
+        let normals = [Vec3::new(0.0, 0.0, 1.0), Vec3::new(1.0, 0.0, 1.0), Vec3::new(0.0, 1.0, 1.0)];
+        let triangle = Triangle::with_normals(Vec3::new(0.0, 0.0, 0.0), Vec3::new(4.0, 0.0, 0.0), Vec3::new(0.0, 2.0, 0.0),
+            normals, Box::new(Lambertian::new(Vec3::all(0.5))));
+        // A quarter of the way to the second vertex and half way to the third
+        let r = Ray::new(Vec3::new(1.0, 1.0, 3.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let mut rec = HitRecord::default();
+        assert!(triangle.check_hit(&r, 0.001, f32::MAX, &mut rec));
+        assert_eq!(rec.t, 3.0);
+        assert_eq!((rec.b1, rec.b2), (0.25, 0.5));
+        // Without texture coordinates u and v are the barycentrics
+        assert_eq!((rec.u, rec.v), (0.25, 0.5));
+        let expected = Vec3::new(0.25, 0.5, 1.0).as_unit();
+        assert!(rec.normal.sub_by_vec(&expected).magnitude() < 1e-6);
+
+        // Texture coordinates get the same weights
+        let mut triangle = triangle;
+        triangle.set_uvs([[0.2, 0.2], [1.0, 0.2], [0.2, 1.0]]);
+        let mut rec = HitRecord::default();
+        assert!(triangle.check_hit(&r, 0.001, f32::MAX, &mut rec));
+        assert!((rec.u - 0.4).abs() < 1e-6 && (rec.v - 0.6).abs() < 1e-6);
+    }
+}